@@ -1,23 +1,26 @@
 use std::path::Path;
-use rodio::{Decoder, OutputStream, Sink, Source};
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
 use std::fs::File;
 use std::io::BufReader;
-use anyhow::Result;
+use std::sync::mpsc;
+use std::thread;
+use anyhow::{anyhow, Result};
 use std::time::Duration;
 
 pub struct AudioPlayer {
     sink: Option<Sink>,
-    _stream: Option<OutputStream>,
-    _stream_handle: Option<rodio::OutputStreamHandle>,
+    _stream: Option<mpsc::Sender<()>>, // keeps the output thread open; see `open_output`
+    _stream_handle: Option<OutputStreamHandle>,
     duration: Option<Duration>,
     start_time: Option<std::time::Instant>,
     paused_time: Option<std::time::Instant>,
     total_paused_duration: Duration,
+    volume: f32,
 }
 
 impl AudioPlayer {
     pub fn new() -> Result<Self> {
-        let (stream, stream_handle) = OutputStream::try_default()?;
+        let (stream, stream_handle) = open_output()?;
         Ok(Self {
             sink: None,
             _stream: Some(stream),
@@ -26,6 +29,7 @@ impl AudioPlayer {
             start_time: None,
             paused_time: None,
             total_paused_duration: Duration::ZERO,
+            volume: 1.0,
         })
     }
 
@@ -36,6 +40,7 @@ impl AudioPlayer {
         // Create a new sink
         if let Some(handle) = &self._stream_handle {
             let sink = Sink::try_new(handle)?;
+            sink.set_volume(self.volume);
             
             // Open the file
             let file = File::open(path)?;
@@ -117,27 +122,76 @@ impl AudioPlayer {
         }
     }
 
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    // 0.0 is silent and 1.0 full volume; kept for the tracks that follow
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        if let Some(sink) = &self.sink {
+            sink.set_volume(self.volume);
+        }
+    }
+
+    // Seek within the current track. Not every decoder supports seeking.
+    pub fn seek(&mut self, position: Duration) -> Result<()> {
+        if let Some(sink) = &self.sink {
+            sink.try_seek(position)
+                .map_err(|e| anyhow::anyhow!("Seeking not supported for this track: {}", e))?;
+            
+            // Restart the clock so that `position()` reports the new position
+            let now = std::time::Instant::now();
+            self.start_time = Some(now.checked_sub(position).unwrap_or(now));
+            self.total_paused_duration = Duration::ZERO;
+            self.paused_time = if sink.is_paused() { Some(now) } else { None };
+        }
+        Ok(())
+    }
+
+    // Time played in the current track, not counting pauses
+    pub fn position(&self) -> Option<Duration> {
+        let start_time = self.start_time?;
+        let mut elapsed = start_time.elapsed();
+        
+        // Subtract total paused time
+        elapsed = elapsed.saturating_sub(self.total_paused_duration);
+        
+        // If currently paused, don't add the current pause time
+        if let Some(paused_time) = self.paused_time {
+            // We're currently paused, so don't add time since pause started
+            elapsed = elapsed.saturating_sub(paused_time.elapsed());
+        }
+        
+        Some(elapsed)
+    }
+
     pub fn get_progress_with_duration(&self, total_duration_secs: f32) -> Option<f32> {
-        if let Some(start_time) = self.start_time {
-            if total_duration_secs > 0.0 {
-                let mut elapsed = start_time.elapsed();
-                
-                // Subtract total paused time
-                elapsed = elapsed.saturating_sub(self.total_paused_duration);
-                
-                // If currently paused, don't add the current pause time
-                if let Some(paused_time) = self.paused_time {
-                    // We're currently paused, so don't add time since pause started
-                    elapsed = elapsed.saturating_sub(paused_time.elapsed());
-                }
-                
-                let progress = elapsed.as_secs_f32() / total_duration_secs;
-                Some(progress.clamp(0.0, 1.0))
-            } else {
-                None
-            }
+        if total_duration_secs > 0.0 {
+            let progress = self.position()?.as_secs_f32() / total_duration_secs;
+            Some(progress.clamp(0.0, 1.0))
         } else {
             None
         }
     }
-} 
\ No newline at end of file
+}
+
+// An output stream can't move between threads, so it's opened on a thread of
+// its own that holds it until the returned sender is dropped. That lets the
+// player itself move to the playback engine's thread.
+fn open_output() -> Result<(mpsc::Sender<()>, OutputStreamHandle)> {
+    let (handle_tx, handle_rx) = mpsc::channel();
+    let (close_tx, close_rx) = mpsc::channel::<()>();
+    thread::spawn(move || match OutputStream::try_default() {
+        Ok((_stream, handle)) => {
+            let _ = handle_tx.send(Ok(handle));
+            // Returns once the player is dropped
+            let _ = close_rx.recv();
+        }
+        Err(e) => {
+            let _ = handle_tx.send(Err(e));
+        }
+    });
+    let handle = handle_rx.recv().map_err(|_| anyhow!("The audio output thread stopped"))??;
+    Ok((close_tx, handle))
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use crate::library::TrackStats;
+use crate::metadata::SongMetadata;
+use crate::query::Query;
+
+pub struct IndexedTrack {
+    pub path: PathBuf,
+    pub metadata: SongMetadata,
+    // Lowercased title, artist, album and path, searched as one string
+    haystack: String,
+}
+
+/// What the library browser is narrowed to. `None` selects everything in a column.
+#[derive(Clone, Default, PartialEq)]
+pub struct BrowserFilter {
+    pub search: String,
+    pub genre: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+/// Values for the genre, artist and album columns with their track counts, and
+/// the tracks that pass the whole filter. Each column is narrowed by the
+/// selections to its left.
+#[derive(Default)]
+pub struct BrowserResults {
+    pub genres: Vec<(String, usize)>,
+    pub artists: Vec<(String, usize)>,
+    pub albums: Vec<(String, usize)>,
+    pub tracks: Vec<usize>,
+}
+
+/// Metadata for every track in the library, kept in memory for searching.
+#[derive(Default)]
+pub struct LibraryIndex {
+    pub tracks: Vec<IndexedTrack>,
+}
+
+impl LibraryIndex {
+    pub fn new(entries: Vec<(PathBuf, SongMetadata)>) -> Self {
+        let mut tracks: Vec<IndexedTrack> = entries
+            .into_iter()
+            .map(|(path, metadata)| {
+                let haystack = format!(
+                    "{}\n{}\n{}\n{}",
+                    metadata.title,
+                    metadata.artist,
+                    metadata.album,
+                    path.to_string_lossy()
+                )
+                .to_lowercase();
+                IndexedTrack { path, metadata, haystack }
+            })
+            .collect();
+        tracks.sort_by_cached_key(|t| {
+            (t.metadata.artist.to_lowercase(), t.metadata.album.to_lowercase(), t.metadata.title.to_lowercase())
+        });
+        Self { tracks }
+    }
+
+    // Every word of the search box has to appear somewhere in the track's text
+    fn matches_search(&self, index: usize, words: &[String]) -> bool {
+        let haystack = &self.tracks[index].haystack;
+        words.iter().all(|word| haystack.contains(word.as_str()))
+    }
+
+    pub fn apply(&self, filter: &BrowserFilter) -> BrowserResults {
+        let words: Vec<String> = filter.search.to_lowercase().split_whitespace().map(String::from).collect();
+        let searched: Vec<usize> = (0..self.tracks.len())
+            .filter(|&i| self.matches_search(i, &words))
+            .collect();
+
+        let genres = self.facet(&searched, |m| &m.genre);
+        let in_genre = self.narrow(searched, &filter.genre, |m| &m.genre);
+        let artists = self.facet(&in_genre, |m| &m.artist);
+        let by_artist = self.narrow(in_genre, &filter.artist, |m| &m.artist);
+        let albums = self.facet(&by_artist, |m| &m.album);
+        let tracks = self.narrow(by_artist, &filter.album, |m| &m.album);
+
+        BrowserResults { genres, artists, albums, tracks }
+    }
+
+    /// Paths of the tracks `query` matches, in library order.
+    pub fn query(&self, query: &Query, stats: &HashMap<PathBuf, TrackStats>) -> Vec<PathBuf> {
+        let unknown = TrackStats::default();
+        self.tracks
+            .iter()
+            .filter(|track| query.matches(&track.path, &track.metadata, stats.get(&track.path).unwrap_or(&unknown)))
+            .map(|track| track.path.clone())
+            .collect()
+    }
+
+    pub fn paths(&self, tracks: &[usize]) -> Vec<PathBuf> {
+        tracks.iter().map(|&i| self.tracks[i].path.clone()).collect()
+    }
+
+    fn facet(&self, tracks: &[usize], value: impl Fn(&SongMetadata) -> &String) -> Vec<(String, usize)> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for &i in tracks {
+            *counts.entry(value(&self.tracks[i].metadata).clone()).or_default() += 1;
+        }
+        let mut values: Vec<(String, usize)> = counts.into_iter().collect();
+        values.sort_by_key(|(name, _)| name.to_lowercase());
+        values
+    }
+
+    fn narrow(&self, tracks: Vec<usize>, selected: &Option<String>, value: impl Fn(&SongMetadata) -> &String) -> Vec<usize> {
+        match selected {
+            Some(selected) => tracks.into_iter().filter(|&i| value(&self.tracks[i].metadata) == selected).collect(),
+            None => tracks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, title: &str, artist: &str, album: &str, genre: &str) -> (PathBuf, SongMetadata) {
+        let metadata = SongMetadata {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            genre: genre.to_string(),
+            ..Default::default()
+        };
+        (PathBuf::from(path), metadata)
+    }
+
+    fn index() -> LibraryIndex {
+        LibraryIndex::new(vec![
+            track("/music/3.mp3", "Giant Steps", "John Coltrane", "Giant Steps", "Jazz"),
+            track("/music/1.mp3", "So What", "Miles Davis", "Kind of Blue", "Jazz"),
+            track("/music/2.mp3", "Blue in Green", "Miles Davis", "Kind of Blue", "Jazz"),
+            track("/music/4.mp3", "Paranoid", "Black Sabbath", "Paranoid", "Metal"),
+        ])
+    }
+
+    fn titles(index: &LibraryIndex, tracks: &[usize]) -> Vec<String> {
+        tracks.iter().map(|&i| index.tracks[i].metadata.title.clone()).collect()
+    }
+
+    fn count(name: &str, tracks: usize) -> (String, usize) {
+        (name.to_string(), tracks)
+    }
+
+    #[test]
+    fn columns_narrow_from_left_to_right() {
+        let index = index();
+        let all = index.apply(&BrowserFilter::default());
+        // Sorted by artist, album, then title
+        assert_eq!(titles(&index, &all.tracks), ["Paranoid", "Giant Steps", "Blue in Green", "So What"]);
+        assert_eq!(all.genres, [count("Jazz", 3), count("Metal", 1)]);
+        assert_eq!(all.artists, [count("Black Sabbath", 1), count("John Coltrane", 1), count("Miles Davis", 2)]);
+
+        let jazz = BrowserFilter { genre: Some("Jazz".to_string()), ..Default::default() };
+        let results = index.apply(&jazz);
+        // The genre column still offers every genre to switch to
+        assert_eq!(results.genres.len(), 2);
+        assert_eq!(results.artists, [count("John Coltrane", 1), count("Miles Davis", 2)]);
+        assert_eq!(results.albums, [count("Giant Steps", 1), count("Kind of Blue", 2)]);
+
+        let miles = BrowserFilter { artist: Some("Miles Davis".to_string()), ..jazz };
+        let results = index.apply(&miles);
+        assert_eq!(results.albums, [count("Kind of Blue", 2)]);
+        let album = BrowserFilter { album: Some("Kind of Blue".to_string()), ..miles };
+        let results = index.apply(&album);
+        assert_eq!(index.paths(&results.tracks), [PathBuf::from("/music/2.mp3"), PathBuf::from("/music/1.mp3")]);
+    }
+
+    #[test]
+    fn every_search_word_has_to_match_somewhere() {
+        let index = index();
+        let search = |text: &str| {
+            let results = index.apply(&BrowserFilter { search: text.to_string(), ..Default::default() });
+            titles(&index, &results.tracks)
+        };
+        assert_eq!(search("blue"), ["Blue in Green", "So What"]);
+        assert_eq!(search("MILES green"), ["Blue in Green"]);
+        assert_eq!(search("music/4"), ["Paranoid"]);
+        assert!(search("miles paranoid").is_empty());
+        assert_eq!(search("  ").len(), 4);
+
+        let results = index.apply(&BrowserFilter { search: "blue".to_string(), ..Default::default() });
+        assert_eq!(results.genres, [count("Jazz", 2)]);
+    }
+}
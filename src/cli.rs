@@ -0,0 +1,434 @@
+// Headless use: scripting playlists on a media server, or playing over SSH.
+// The subcommands work on the same library, settings and duplicate choices as
+// the window, so a scan from here is what the window opens next time.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, style, terminal, QueueableCommand};
+use music_shuffler::audio::AudioPlayer;
+use music_shuffler::config::{get_config_path, Settings};
+use music_shuffler::library::{self, LibraryDb, StatsRange};
+use music_shuffler::engine::{Command as PlayerCommand, Event as PlayerEvent};
+use music_shuffler::player::Listen;
+use music_shuffler::scrobble::Scrobbler;
+use music_shuffler::{music, playlist_file, storage, Library, Player, PlayerEngine, RepeatMode, SongMetadata};
+use crate::{format_listening_time, format_time};
+
+/// Shuffle and play a music library. Without a command the window opens.
+#[derive(Parser)]
+#[command(name = "music-shuffler", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Scan a music directory into the library and read its tags
+    Scan { directory: PathBuf },
+    /// Pick a shuffled playlist and write it to a file, or print its paths
+    Shuffle {
+        #[command(flatten)]
+        shuffle: ShuffleArgs,
+        /// Playlist file to write (.m3u8, .m3u, .pls or .xspf)
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Play in the terminal: a playlist file, or a fresh shuffle
+    Play {
+        /// Playlist file to play instead of shuffling the library
+        playlist: Option<PathBuf>,
+        #[command(flatten)]
+        shuffle: ShuffleArgs,
+    },
+    /// Print listening statistics
+    Stats {
+        #[arg(short, long, value_enum, default_value_t = Range::Month)]
+        range: Range,
+    },
+    /// Inspect or clear the cached tags and album art
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(clap::Args)]
+pub struct ShuffleArgs {
+    /// Number of tracks to pick
+    #[arg(short, long, default_value_t = 100)]
+    count: usize,
+    /// Only pick tracks matching a filter, e.g. "genre:jazz year:<1970"
+    #[arg(short, long)]
+    filter: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum CacheAction {
+    /// Show what the library database holds
+    Info,
+    /// Drop album art and make tags be read from the files again
+    Clear,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Range {
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl From<Range> for StatsRange {
+    fn from(range: Range) -> Self {
+        match range {
+            Range::Week => StatsRange::Week,
+            Range::Month => StatsRange::Month,
+            Range::Year => StatsRange::Year,
+            Range::All => StatsRange::AllTime,
+        }
+    }
+}
+
+pub fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Scan { directory } => scan(&directory),
+        Command::Shuffle { shuffle: args, out } => {
+            let entries = shuffle(&Library::open()?, &args)?;
+            match out {
+                Some(path) => {
+                    playlist_file::export(&path, &entries, Settings::load().relative_playlist_paths)?;
+                    println!("Wrote {} tracks to {}", entries.len(), path.display());
+                }
+                None => {
+                    for (path, _) in &entries {
+                        println!("{}", path.display());
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Play { playlist, shuffle: args } => {
+            let entries = match playlist {
+                Some(path) => {
+                    let imported = playlist_file::import(&path)?;
+                    for missing in &imported.missing {
+                        eprintln!("Skipped {}", missing);
+                    }
+                    imported.entries
+                }
+                None => shuffle(&Library::open()?, &args)?,
+            };
+            play(entries)
+        }
+        Command::Stats { range } => stats(range.into()),
+        Command::Cache { action: CacheAction::Info } => cache_info(),
+        Command::Cache { action: CacheAction::Clear } => {
+            LibraryDb::open()?.clear_metadata_cache()?;
+            println!("Cleared cached tags and album art; they are read again from the files as needed.");
+            Ok(())
+        }
+    }
+}
+
+fn scan(directory: &Path) -> Result<()> {
+    let directory = directory.canonicalize()?;
+    let settings = Settings::load();
+    let (tx, rx) = mpsc::channel();
+    let walker = {
+        let directory = directory.clone();
+        let options = settings.scan_options;
+        thread::spawn(move || Library::open()?.scan(&directory, &options, &music::CancelToken::new(), &tx))
+    };
+
+    let mut progress = music::ScanProgress::default();
+    let mut last_report = Instant::now();
+    let mut files = Vec::new();
+    for event in rx {
+        progress.apply(&event);
+        match event {
+            music::ScanEvent::Error { .. } => eprintln!("\r{}", progress.last_error.as_deref().unwrap_or_default()),
+            music::ScanEvent::Finished(found) => files = found,
+            _ => {}
+        }
+        if last_report.elapsed() >= Duration::from_millis(200) {
+            eprint!("\rScanning: {} folders, {} tracks", progress.dirs_visited, progress.files_matched);
+            last_report = Instant::now();
+        }
+    }
+    walker.join().map_err(|_| anyhow!("The scan stopped unexpectedly"))??;
+    eprintln!("\rScanned {} folders, {} tracks", progress.dirs_visited, files.len());
+
+    // The window opens this directory next time
+    if let Some(config_path) = get_config_path() {
+        storage::write_atomic(&config_path, directory.to_string_lossy().as_bytes())?;
+    }
+
+    // Read tags now so shuffles with filters, the browser and statistics don't wait for them
+    let library = Library::open()?;
+    let reader = library.metadata_reader(settings.sync_rating_tags);
+    for (i, path) in files.iter().enumerate() {
+        reader.read(path);
+        if last_report.elapsed() >= Duration::from_millis(200) || i + 1 == files.len() {
+            eprint!("\rReading tags: {}/{}", i + 1, files.len());
+            last_report = Instant::now();
+        }
+    }
+    eprintln!();
+    println!("{} tracks in {}", files.len(), directory.display());
+    Ok(())
+}
+
+// A shuffled batch from the library, leaving out duplicates and banned tracks
+// just as the window's shuffles do
+fn shuffle(library: &Library, args: &ShuffleArgs) -> Result<Vec<(PathBuf, SongMetadata)>> {
+    if library.files()?.is_empty() {
+        return Err(anyhow!("The library is empty; run `music-shuffler scan <directory>` first"));
+    }
+    library.shuffle(args.count, args.filter.as_deref(), Settings::load().sync_rating_tags)
+}
+
+fn stats(range: StatsRange) -> Result<()> {
+    let stats = LibraryDb::open()?.listening_stats(range)?;
+    println!("{}", range.label());
+    println!(
+        "Listening time: {} ({} plays, {} skips)",
+        format_listening_time(stats.seconds_played),
+        stats.plays,
+        stats.skips
+    );
+    let heard = if stats.library_size > 0 { stats.tracks_heard as f32 / stats.library_size as f32 } else { 0.0 };
+    println!("Library heard: {} of {} tracks ({:.1}%)", stats.tracks_heard, stats.library_size, heard * 100.0);
+
+    let sections = [
+        ("Top artists", &stats.top_artists, "plays"),
+        ("Top albums", &stats.top_albums, "plays"),
+        ("Top tracks", &stats.top_tracks, "plays"),
+        ("Most skipped", &stats.most_skipped, "skips"),
+        ("Artists with the most tracks not played", &stats.unheard_artists, "tracks"),
+    ];
+    for (title, entries, unit) in sections {
+        println!();
+        println!("{}:", title);
+        if entries.is_empty() {
+            println!("  (none)");
+        }
+        for (i, (name, count)) in entries.iter().enumerate() {
+            println!("  {:>2}. {} ({} {})", i + 1, name, count, unit);
+        }
+    }
+
+    let busiest = (0..7)
+        .flat_map(|day| (0..24).map(move |hour| (day, hour)))
+        .max_by_key(|&(day, hour)| stats.by_hour[day][hour])
+        .filter(|&(day, hour)| stats.by_hour[day][hour] > 0);
+    if let Some((day, hour)) = busiest {
+        const DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+        println!();
+        println!("Busiest hour: {} {}:00-{}:00 ({} plays)", DAYS[day], hour, hour + 1, stats.by_hour[day][hour]);
+    }
+    Ok(())
+}
+
+fn cache_info() -> Result<()> {
+    let info = LibraryDb::open()?.cache_info()?;
+    if let Some(path) = library::get_database_path() {
+        let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        println!("Library database: {} ({:.1} MB)", path.display(), size as f64 / 1_000_000.0);
+    }
+    println!("Tracks: {} ({} with cached tags)", info.tracks, info.cached);
+    println!("Album art: {:.1} MB", info.album_art_bytes as f64 / 1_000_000.0);
+    println!("Play history: {} entries", info.plays);
+    Ok(())
+}
+
+// What a key press asks the terminal player to do
+enum Key {
+    PlayPause,
+    Next,
+    Previous,
+    Seek(f32),
+    Volume(f32),
+    Quit,
+}
+
+fn read_key(timeout: Duration) -> Result<Option<Key>> {
+    if !event::poll(timeout)? {
+        return Ok(None);
+    }
+    let Event::Key(key) = event::read()? else {
+        return Ok(None);
+    };
+    if key.kind != KeyEventKind::Press {
+        return Ok(None);
+    }
+    // Raw mode turns Ctrl+C into a key press instead of a signal
+    let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c');
+    Ok(match key.code {
+        _ if ctrl_c => Some(Key::Quit),
+        KeyCode::Char(' ') => Some(Key::PlayPause),
+        KeyCode::Char('n') => Some(Key::Next),
+        KeyCode::Char('p') => Some(Key::Previous),
+        KeyCode::Right => Some(Key::Seek(10.0)),
+        KeyCode::Left => Some(Key::Seek(-10.0)),
+        KeyCode::Char('+') | KeyCode::Char('=') => Some(Key::Volume(0.1)),
+        KeyCode::Char('-') => Some(Key::Volume(-0.1)),
+        KeyCode::Char('q') | KeyCode::Esc => Some(Key::Quit),
+        _ => None,
+    })
+}
+
+// Raw mode needs explicit carriage returns
+fn print_line(text: &str) -> Result<()> {
+    let mut out = std::io::stdout();
+    out.queue(cursor::MoveToColumn(0))?
+        .queue(terminal::Clear(terminal::ClearType::CurrentLine))?
+        .queue(style::Print(text))?
+        .queue(style::Print("\r\n"))?;
+    out.flush()?;
+    Ok(())
+}
+
+fn print_status(text: &str) -> Result<()> {
+    let width = terminal::size().map(|(columns, _)| columns as usize).unwrap_or(80);
+    let text: String = text.chars().take(width.saturating_sub(1)).collect();
+    let mut out = std::io::stdout();
+    out.queue(cursor::MoveToColumn(0))?
+        .queue(terminal::Clear(terminal::ClearType::CurrentLine))?
+        .queue(style::Print(text))?;
+    out.flush()?;
+    Ok(())
+}
+
+fn play(entries: Vec<(PathBuf, SongMetadata)>) -> Result<()> {
+    if entries.is_empty() {
+        return Err(anyhow!("Nothing to play"));
+    }
+    let engine = PlayerEngine::start(Player::new(Some(AudioPlayer::new()?)), RepeatMode::Off);
+    engine.send(PlayerCommand::Load(entries));
+    let library = Library::open().map_err(|e| eprintln!("Plays won't be recorded: {}", e)).ok();
+    let settings = Settings::load();
+    let scrobbler = settings.scrobble.any_enabled()
+        .then(|| Scrobbler::start(&settings.scrobble, &engine, settings.sync_rating_tags));
+
+    let result = {
+        let _raw_mode = RawMode::enable()?;
+        play_entries(&engine, library.as_ref())
+    };
+    println!();
+    if let Some(scrobbler) = scrobbler {
+        scrobbler.finish();
+    }
+    result
+}
+
+// Keys are read one at a time while playing; the terminal is put back however
+// that ends, errors and panics included
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Count listens the same way the window does
+fn record_listen(library: Option<&Library>, listen: &Listen) -> Result<()> {
+    if let Some(Err(e)) = library.map(|library| library.record_listen(listen)) {
+        print_line(&format!("Could not record play of {}: {}", listen.path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn play_entries(engine: &PlayerEngine, library: Option<&Library>) -> Result<()> {
+    print_line("space pause/resume · n next · p previous · ←/→ seek 10s · +/- volume · q quit")?;
+
+    let events = engine.subscribe();
+    engine.send(PlayerCommand::Play(Some(0)));
+    'playing: loop {
+        for event in events.try_iter() {
+            match event {
+                PlayerEvent::TrackStarted { index, .. } => {
+                    let player = engine.player();
+                    if let Some((_, metadata)) = player.current() {
+                        print_line(&format!("[{}/{}] {} - {}", index + 1, player.playlist.len(), metadata.artist, metadata.title))?;
+                    }
+                }
+                PlayerEvent::Listened(listen) => record_listen(library, &listen)?,
+                PlayerEvent::Error { path, message } => {
+                    print_line(&message)?;
+                    // Move past tracks that can't be played
+                    if path.is_some() {
+                        if engine.player().at_end() {
+                            break 'playing;
+                        }
+                        engine.send(PlayerCommand::Next);
+                    }
+                }
+                PlayerEvent::TrackEnded { .. } if engine.player().is_stopped() => break 'playing,
+                PlayerEvent::TrackEnded { .. }
+                | PlayerEvent::PositionChanged(_)
+                | PlayerEvent::PlaylistChanged
+                | PlayerEvent::VolumeChanged(_) => {}
+            }
+        }
+
+        match read_key(Duration::from_millis(250))? {
+            Some(Key::PlayPause) => engine.send(PlayerCommand::TogglePlayback),
+            Some(Key::Next) if engine.player().at_end() => break,
+            Some(Key::Next) => engine.send(PlayerCommand::Next),
+            Some(Key::Previous) => engine.send(PlayerCommand::Previous),
+            Some(Key::Seek(offset)) => {
+                let position = engine.player().position().map(|p| p.as_secs_f32()).unwrap_or(0.0) + offset;
+                engine.send(PlayerCommand::Seek(Duration::from_secs_f32(position.max(0.0))));
+            }
+            Some(Key::Volume(change)) => {
+                let volume = engine.player().audio().map(|audio| audio.volume());
+                if let Some(volume) = volume {
+                    engine.send(PlayerCommand::SetVolume(volume + change));
+                }
+            }
+            Some(Key::Quit) => break,
+            None => {}
+        }
+
+        let status = {
+            let player = engine.player();
+            let duration = player.current().and_then(|(_, metadata)| metadata.duration).unwrap_or(0.0);
+            format!(
+                "{} {} / {}  vol {:.0}%",
+                if player.is_paused() { "⏸" } else { "▶" },
+                format_time(player.position().map(|p| p.as_secs_f32()).unwrap_or(0.0)),
+                format_time(duration),
+                player.audio().map(|audio| audio.volume()).unwrap_or(0.0) * 100.0
+            )
+        };
+        print_status(&status)?;
+    }
+
+    // Leaving mid-track counts like moving on from it. Stopping through the
+    // engine lets every subscriber, the scrobbler too, hear about that listen.
+    engine.send(PlayerCommand::Stop);
+    loop {
+        match events.recv_timeout(Duration::from_millis(100)) {
+            Ok(PlayerEvent::Listened(listen)) => record_listen(library, &listen)?,
+            Ok(_) => {}
+            // Once stopped, what stopping sent has arrived when things go quiet
+            Err(mpsc::RecvTimeoutError::Timeout) if !engine.player().is_stopped() => {}
+            Err(_) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::path::PathBuf;
+use directories::ProjectDirs;
+use serde::{Serialize, Deserialize};
+use crate::music::ScanOptions;
+use crate::storage;
+
+pub fn get_config_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "yourorg", "music-shuffler")
+        .map(|proj_dirs| proj_dirs.config_dir().join("config.txt"))
+}
+
+// Everything else lives in the same directory as config.txt
+pub fn get_config_dir() -> Option<PathBuf> {
+    get_config_path().map(|p| p.parent().unwrap().to_path_buf())
+}
+
+fn get_settings_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("settings.json"))
+}
+
+// What happens when a track finishes
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum RepeatMode {
+    /// Stop after the last track.
+    Off,
+    /// Start the playlist over from the first track.
+    #[default]
+    All,
+    /// Keep playing the current track.
+    One,
+    /// Append another shuffled batch and keep going.
+    AutoExtend,
+}
+
+impl RepeatMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "Repeat: Off",
+            RepeatMode::All => "Repeat: All",
+            RepeatMode::One => "Repeat: One",
+            RepeatMode::AutoExtend => "Auto-extend",
+        }
+    }
+
+    // Order the toggle button cycles through
+    pub fn next(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::AutoExtend,
+            RepeatMode::AutoExtend => RepeatMode::Off,
+        }
+    }
+}
+
+/// The HTTP remote control, off until turned on.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteSettings {
+    pub enabled: bool,
+    /// Listen on every network interface instead of only this machine.
+    pub lan: bool,
+    pub port: u16,
+    /// Every API request has to carry it.
+    pub token: String,
+}
+
+impl Default for RemoteSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lan: false,
+            port: 8765,
+            token: String::new(),
+        }
+    }
+}
+
+/// The MPD protocol server, for MPD clients. Off until turned on.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MpdSettings {
+    pub enabled: bool,
+    /// Listen on every network interface instead of only this machine.
+    pub lan: bool,
+    pub port: u16,
+    /// Clients have to send it with `password` first; empty lets anyone in.
+    pub password: String,
+}
+
+impl Default for MpdSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lan: false,
+            port: 6600,
+            password: String::new(),
+        }
+    }
+}
+
+/// Where listens are scrobbled. Each service is off until turned on.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScrobbleSettings {
+    pub listenbrainz: bool,
+    /// API root of ListenBrainz or a self-hosted or compatible server.
+    pub listenbrainz_url: String,
+    /// User token from the ListenBrainz settings page.
+    pub listenbrainz_token: String,
+    pub lastfm: bool,
+    /// API endpoint of Last.fm or a compatible service such as Libre.fm.
+    pub lastfm_url: String,
+    pub lastfm_api_key: String,
+    pub lastfm_api_secret: String,
+    pub lastfm_username: String,
+    /// From logging in; the password itself isn't kept.
+    pub lastfm_session_key: String,
+}
+
+impl Default for ScrobbleSettings {
+    fn default() -> Self {
+        Self {
+            listenbrainz: false,
+            listenbrainz_url: "https://api.listenbrainz.org".to_string(),
+            listenbrainz_token: String::new(),
+            lastfm: false,
+            lastfm_url: "https://ws.audioscrobbler.com/2.0/".to_string(),
+            lastfm_api_key: String::new(),
+            lastfm_api_secret: String::new(),
+            lastfm_username: String::new(),
+            lastfm_session_key: String::new(),
+        }
+    }
+}
+
+impl ScrobbleSettings {
+    pub fn any_enabled(&self) -> bool {
+        self.listenbrainz || self.lastfm
+    }
+}
+
+/// Keyboard shortcuts in the window, written like "Ctrl+F", "Space" or
+/// "Shift+ArrowLeft". Ctrl is Cmd on macOS; an empty binding turns one off.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub play_pause: String,
+    pub seek_back: String,
+    pub seek_forward: String,
+    pub previous: String,
+    pub next: String,
+    pub volume_up: String,
+    pub volume_down: String,
+    pub search: String,
+    pub generate_playlist: String,
+    pub save_playlist: String,
+    pub help: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            play_pause: "Space".to_string(),
+            seek_back: "ArrowLeft".to_string(),
+            seek_forward: "ArrowRight".to_string(),
+            previous: "Ctrl+ArrowLeft".to_string(),
+            next: "Ctrl+ArrowRight".to_string(),
+            volume_up: "Plus".to_string(),
+            volume_down: "Minus".to_string(),
+            search: "Ctrl+F".to_string(),
+            generate_playlist: "Ctrl+G".to_string(),
+            save_playlist: "Ctrl+S".to_string(),
+            help: "F1".to_string(),
+        }
+    }
+}
+
+/// Layout version of settings.json. Bump it, with a step in `Settings::load`,
+/// when an existing field changes meaning.
+pub const SETTINGS_VERSION: u32 = 1;
+
+// User preferences. Missing fields fall back to their defaults so older
+// settings files keep loading as new options are added.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Layout version the file was written with, 0 for files from before it was recorded.
+    #[serde(default)]
+    pub version: u32,
+    pub scan_options: ScanOptions,
+    pub relative_playlist_paths: bool,
+    /// Restore the last session paused instead of resuming playback.
+    pub restore_paused: bool,
+    pub repeat_mode: RepeatMode,
+    /// Filter expression narrowing which tracks shuffles pick from.
+    pub shuffle_query: String,
+    /// Read ratings from and write them to POPM / RATING / FMPS_RATING tags.
+    pub sync_rating_tags: bool,
+    pub remote: RemoteSettings,
+    pub mpd: MpdSettings,
+    pub scrobble: ScrobbleSettings,
+    pub key_bindings: KeyBindings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            scan_options: ScanOptions::default(),
+            relative_playlist_paths: false,
+            restore_paused: true,
+            repeat_mode: RepeatMode::default(),
+            shuffle_query: String::new(),
+            sync_rating_tags: false,
+            remote: RemoteSettings::default(),
+            mpd: MpdSettings::default(),
+            scrobble: ScrobbleSettings::default(),
+            key_bindings: KeyBindings::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        let mut settings: Settings = get_settings_path()
+            .and_then(|path| storage::read_json(&path))
+            .unwrap_or_default();
+        if settings.version > SETTINGS_VERSION {
+            eprintln!(
+                "Settings were saved by a newer version (layout {}, this build reads {}); options it doesn't know are ignored",
+                settings.version, SETTINGS_VERSION
+            );
+        }
+        // Versions 0 and 1 only differ in the version field itself
+        settings.version = SETTINGS_VERSION;
+        settings
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = get_settings_path() {
+            if let Err(e) = storage::write_json(&path, self) {
+                eprintln!("Could not save {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_without_a_version_are_layout_zero() {
+        let settings: Settings = serde_json::from_str(r#"{ "restore_paused": false }"#).unwrap();
+        assert_eq!(settings.version, 0);
+        assert!(!settings.restore_paused);
+        assert_eq!(Settings::default().version, SETTINGS_VERSION);
+    }
+}
@@ -0,0 +1,544 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+use crate::config::get_config_dir;
+use crate::storage;
+use crate::metadata::SongMetadata;
+
+// How much audio goes into a fingerprint, and the length of each energy frame
+const FINGERPRINT_SECS: f32 = 30.0;
+const FINGERPRINT_FRAME_SECS: f32 = 0.1;
+// Share of fingerprint bits that must agree for two tracks to count as the same recording
+const FINGERPRINT_MATCH: f32 = 0.85;
+
+/// How the copies in a group were found to be the same song, strongest first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MatchKind {
+    ContentHash,
+    Fingerprint,
+    Tags,
+}
+
+impl MatchKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            MatchKind::ContentHash => "Identical files",
+            MatchKind::Fingerprint => "Same audio",
+            MatchKind::Tags => "Same tags",
+        }
+    }
+}
+
+/// Rule for picking the copy that stays in shuffles.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Preference {
+    #[default]
+    HighestBitrate,
+    PreferLossless,
+    LargestFile,
+}
+
+impl Preference {
+    pub const ALL: [Preference; 3] = [
+        Preference::HighestBitrate,
+        Preference::PreferLossless,
+        Preference::LargestFile,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Preference::HighestBitrate => "Highest bitrate",
+            Preference::PreferLossless => "Lossless (FLAC/WAV) first",
+            Preference::LargestFile => "Largest file",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DuplicateTrack {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub duration: Option<f32>,
+    pub title: String,
+    pub artist: String,
+}
+
+impl DuplicateTrack {
+    /// Average bitrate in kbit/s, when the duration is known.
+    pub fn bitrate_kbps(&self) -> Option<f32> {
+        self.duration
+            .filter(|d| *d > 0.0)
+            .map(|d| self.file_size as f32 * 8.0 / d / 1000.0)
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        self.path
+            .extension()
+            .map(|ext| matches!(ext.to_string_lossy().to_lowercase().as_str(), "flac" | "wav"))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    pub kind: MatchKind,
+    pub tracks: Vec<DuplicateTrack>,
+    /// Index into `tracks` of the copy that is kept in shuffles.
+    pub preferred: usize,
+}
+
+impl DuplicateGroup {
+    pub fn apply_preference(&mut self, preference: Preference) {
+        let score = |t: &DuplicateTrack| -> (bool, f32) {
+            match preference {
+                Preference::HighestBitrate => (false, t.bitrate_kbps().unwrap_or(0.0)),
+                Preference::PreferLossless => (t.is_lossless(), t.bitrate_kbps().unwrap_or(0.0)),
+                Preference::LargestFile => (false, t.file_size as f32),
+            }
+        };
+        self.preferred = self
+            .tracks
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| score(a).partial_cmp(&score(b)).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DetectionOptions {
+    pub content_hash: bool,
+    pub tags: bool,
+    pub fingerprint: bool,
+    /// Largest duration difference, in seconds, for tag and fingerprint matches.
+    pub duration_tolerance: f32,
+}
+
+impl Default for DetectionOptions {
+    fn default() -> Self {
+        Self {
+            content_hash: true,
+            tags: true,
+            fingerprint: false,
+            duration_tolerance: 2.0,
+        }
+    }
+}
+
+// Saved results of the last detection run, with the user's choice per group
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicateReport {
+    pub preference: Preference,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+fn get_report_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("duplicates.json"))
+}
+
+impl DuplicateReport {
+    pub fn load() -> Self {
+        get_report_path()
+            .and_then(|path| storage::read_json(&path))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = get_report_path() {
+            if let Err(e) = storage::write_json(&path, self) {
+                eprintln!("Could not save {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Every copy that is not the preferred one of its group.
+    pub fn excluded_paths(&self) -> HashSet<PathBuf> {
+        self.groups
+            .iter()
+            .flat_map(|group| {
+                group
+                    .tracks
+                    .iter()
+                    .enumerate()
+                    .filter(move |(i, _)| *i != group.preferred)
+                    .map(|(_, t)| t.path.clone())
+            })
+            .collect()
+    }
+}
+
+// Minimal union-find that remembers the strongest match kind linking each set
+struct Groups {
+    parent: Vec<usize>,
+    kind: Vec<Option<MatchKind>>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            kind: vec![None; len],
+        }
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut i = i;
+        while self.parent[i] != root {
+            let next = self.parent[i];
+            self.parent[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize, kind: MatchKind) {
+        let (a, b) = (self.find(a), self.find(b));
+        let strongest = [self.kind[a], self.kind[b], Some(kind)].into_iter().flatten().min();
+        if a != b {
+            self.parent[b] = a;
+        }
+        self.kind[a] = strongest;
+    }
+}
+
+// SHA-256 rather than std's hasher, which is seeded differently in every run
+fn hash_file(path: &Path) -> Option<[u8; 32]> {
+    let mut file = File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).ok()?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Some(hasher.finalize().into())
+}
+
+// Lowercase and keep only letters and digits, so "AC/DC " and "acdc" compare equal
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Fingerprint bits packed 64 to a word, so comparing two takes a few XORs
+struct Fingerprint {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Fingerprint {
+    fn from_bits(bits: &[bool]) -> Self {
+        let words = bits
+            .chunks(64)
+            .map(|chunk| chunk.iter().enumerate().fold(0u64, |word, (i, &bit)| word | (bit as u64) << i))
+            .collect();
+        Self { words, len: bits.len() }
+    }
+}
+
+// Crude fingerprint: one bit per frame saying whether its energy rose compared
+// to the previous frame, starting from the first non-silent frame so leading
+// silence differences between rips don't shift it
+fn fingerprint(path: &Path) -> Option<Fingerprint> {
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let probe = symphonia::default::get_probe()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .ok()?;
+    let mut format = probe.format;
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .ok()?;
+
+    let frame_len = ((sample_rate as f32 * FINGERPRINT_FRAME_SECS) as usize).max(1);
+    let max_frames = (FINGERPRINT_SECS / FINGERPRINT_FRAME_SECS) as usize;
+    let mut energies = Vec::new();
+    let mut frame_energy = 0.0f32;
+    let mut frame_samples = 0;
+    let mut started = false;
+
+    while energies.len() < max_frames {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(_) => break,
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(_) => break,
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+
+        for frame in samples.samples().chunks(channels) {
+            let mono = frame.iter().sum::<f32>() / channels as f32;
+            frame_energy += mono * mono;
+            frame_samples += 1;
+            if frame_samples == frame_len {
+                let energy = frame_energy / frame_len as f32;
+                started |= energy > 1e-6;
+                if started {
+                    energies.push(energy);
+                }
+                frame_energy = 0.0;
+                frame_samples = 0;
+            }
+        }
+    }
+
+    if energies.len() < 10 {
+        return None;
+    }
+    let bits: Vec<bool> = energies.windows(2).map(|w| w[1] > w[0]).collect();
+    Some(Fingerprint::from_bits(&bits))
+}
+
+fn fingerprint_similarity(a: &Fingerprint, b: &Fingerprint) -> f32 {
+    let len = a.len.min(b.len);
+    if len == 0 {
+        return 0.0;
+    }
+    let mut differing = 0;
+    for (w, (x, y)) in a.words.iter().zip(&b.words).enumerate() {
+        let bits = (len - w * 64).min(64);
+        let mask = if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 };
+        differing += ((x ^ y) & mask).count_ones() as usize;
+        if bits < 64 {
+            break;
+        }
+    }
+    (len - differing) as f32 / len as f32
+}
+
+// Find groups of tracks that are the same song. `lookup` supplies metadata
+// (normally from the file cache) and `progress` receives (stage, current, total).
+pub fn find_duplicates<L, P>(
+    files: &[PathBuf],
+    options: &DetectionOptions,
+    mut lookup: L,
+    progress: P,
+) -> Vec<DuplicateGroup>
+where
+    L: FnMut(&Path) -> Option<SongMetadata>,
+    P: Fn(&'static str, usize, usize),
+{
+    let total = files.len();
+    let mut tracks = Vec::with_capacity(total);
+    for (i, path) in files.iter().enumerate() {
+        let metadata = lookup(path).unwrap_or_default();
+        tracks.push(DuplicateTrack {
+            path: path.clone(),
+            file_size: std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            duration: metadata.duration,
+            title: metadata.title,
+            artist: metadata.artist,
+        });
+        progress("Reading metadata", i + 1, total);
+    }
+
+    let mut groups = Groups::new(tracks.len());
+
+    if options.content_hash {
+        // Only files with the same size can have the same contents
+        let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, track) in tracks.iter().enumerate() {
+            if track.file_size > 0 {
+                by_size.entry(track.file_size).or_default().push(i);
+            }
+        }
+        let candidates: Vec<Vec<usize>> = by_size.into_values().filter(|v| v.len() > 1).collect();
+        let to_hash: usize = candidates.iter().map(Vec::len).sum();
+        let mut hashed = 0;
+        for candidate in candidates {
+            let mut by_hash: HashMap<[u8; 32], usize> = HashMap::new();
+            for i in candidate {
+                if let Some(hash) = hash_file(&tracks[i].path) {
+                    if let Some(&first) = by_hash.get(&hash) {
+                        groups.union(first, i, MatchKind::ContentHash);
+                    } else {
+                        by_hash.insert(hash, i);
+                    }
+                }
+                hashed += 1;
+                progress("Comparing file contents", hashed, to_hash);
+            }
+        }
+    }
+
+    // Tag and fingerprint matches both need durations that agree within the tolerance,
+    // so work on tracks sorted by duration. A broken tag can give a NaN or infinite
+    // duration, which can't match anything.
+    let mut by_duration: Vec<usize> = (0..tracks.len())
+        .filter(|&i| tracks[i].duration.is_some_and(f32::is_finite))
+        .collect();
+    let duration = |i: usize| tracks[i].duration.unwrap_or(0.0);
+    by_duration.sort_by(|&a, &b| duration(a).total_cmp(&duration(b)));
+
+    if options.tags {
+        let mut by_tags: HashMap<(String, String), Vec<usize>> = HashMap::new();
+        for &i in &by_duration {
+            let track = &tracks[i];
+            // Without a real artist the title is usually just the file name
+            if track.artist.is_empty() || track.artist == "Unknown Artist" {
+                continue;
+            }
+            let key = (normalize(&track.artist), normalize(&track.title));
+            if !key.0.is_empty() && !key.1.is_empty() {
+                by_tags.entry(key).or_default().push(i);
+            }
+        }
+        for indices in by_tags.values() {
+            for pair in indices.windows(2) {
+                if duration(pair[1]) - duration(pair[0]) <= options.duration_tolerance {
+                    groups.union(pair[0], pair[1], MatchKind::Tags);
+                }
+            }
+        }
+    }
+
+    if options.fingerprint {
+        // Buckets as wide as the tolerance, so a track only has to be compared
+        // with the tracks in its own bucket and the next one up
+        let width = options.duration_tolerance.max(0.1);
+        let mut buckets: BTreeMap<i64, Vec<usize>> = BTreeMap::new();
+        for &i in &by_duration {
+            buckets.entry((duration(i) / width).floor() as i64).or_default().push(i);
+        }
+        let count = by_duration.len();
+        let mut done = 0;
+        let mut prints: HashMap<usize, Option<Fingerprint>> = HashMap::new();
+        for (bucket, members) in &buckets {
+            let next = buckets.get(&(bucket + 1)).map(Vec::as_slice).unwrap_or_default();
+            for (n, &i) in members.iter().enumerate() {
+                for &j in members[n + 1..].iter().chain(next) {
+                    if duration(j) - duration(i) > options.duration_tolerance {
+                        continue;
+                    }
+                    // Fingerprints are only computed for tracks that have a neighbour to compare with
+                    for k in [i, j] {
+                        prints.entry(k).or_insert_with(|| fingerprint(&tracks[k].path));
+                    }
+                    if let (Some(Some(a)), Some(Some(b))) = (prints.get(&i), prints.get(&j)) {
+                        if fingerprint_similarity(a, b) >= FINGERPRINT_MATCH {
+                            groups.union(i, j, MatchKind::Fingerprint);
+                        }
+                    }
+                }
+                done += 1;
+                progress("Fingerprinting audio", done, count);
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..tracks.len() {
+        let root = groups.find(i);
+        members.entry(root).or_default().push(i);
+    }
+
+    let mut result: Vec<DuplicateGroup> = members
+        .into_iter()
+        .filter(|(_, m)| m.len() > 1)
+        .map(|(root, m)| DuplicateGroup {
+            kind: groups.kind[root].unwrap_or(MatchKind::Tags),
+            tracks: m.into_iter().map(|i| tracks[i].clone()).collect(),
+            preferred: 0,
+        })
+        .collect();
+    result.sort_by(|a, b| a.tracks[0].path.cmp(&b.tracks[0].path));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(artist: &str, title: &str, duration: f32) -> SongMetadata {
+        SongMetadata {
+            artist: artist.to_string(),
+            title: title.to_string(),
+            duration: Some(duration),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn broken_durations_are_skipped_instead_of_panicking() {
+        let files: Vec<PathBuf> = ["a.mp3", "b.mp3", "c.mp3", "d.mp3"].iter().map(PathBuf::from).collect();
+        let durations = [f32::NAN, 180.0, 181.0, f32::INFINITY];
+        let options = DetectionOptions { content_hash: false, ..Default::default() };
+        let groups = find_duplicates(
+            &files,
+            &options,
+            |path| {
+                let i = files.iter().position(|f| f == path)?;
+                Some(metadata("Artist", "Song", durations[i]))
+            },
+            |_, _, _| {},
+        );
+        assert_eq!(groups.len(), 1);
+        let paths: Vec<&Path> = groups[0].tracks.iter().map(|t| t.path.as_path()).collect();
+        assert_eq!(paths, [Path::new("b.mp3"), Path::new("c.mp3")]);
+        assert!(groups[0].kind == MatchKind::Tags);
+    }
+
+    #[test]
+    fn identical_files_are_grouped_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = ["one.mp3", "two.mp3", "other.mp3"].iter().map(|name| dir.path().join(name)).collect();
+        std::fs::write(&files[0], b"same bytes").unwrap();
+        std::fs::write(&files[1], b"same bytes").unwrap();
+        std::fs::write(&files[2], b"diff bytes").unwrap();
+        assert_eq!(hash_file(&files[0]), hash_file(&files[1]));
+
+        let options = DetectionOptions { tags: false, ..Default::default() };
+        let groups = find_duplicates(&files, &options, |_| None, |_, _, _| {});
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].kind == MatchKind::ContentHash);
+        assert_eq!(groups[0].tracks.len(), 2);
+        assert!(groups[0].tracks.iter().all(|t| t.path != files[2]));
+    }
+
+    #[test]
+    fn packed_fingerprints_compare_only_the_shorter_length() {
+        let a: Vec<bool> = (0..100).map(|i| i % 3 == 0).collect();
+        let mut b = a.clone();
+        for bit in b.iter_mut().take(10) {
+            *bit = !*bit;
+        }
+        // Extra bits past the shorter fingerprint don't count
+        b.extend([true; 50]);
+        let similarity = fingerprint_similarity(&Fingerprint::from_bits(&a), &Fingerprint::from_bits(&b));
+        assert!((similarity - 0.9).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,468 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use crate::config::RepeatMode;
+use crate::library::LibraryDb;
+use crate::metadata::{self, MetadataReader, SongMetadata};
+use crate::player::{Listen, Player};
+use crate::queue::Entry;
+
+// How often the engine checks for the end of a track when no commands arrive
+const TICK: Duration = Duration::from_millis(100);
+// How often `PositionChanged` is sent while playing
+const POSITION_INTERVAL: Duration = Duration::from_millis(500);
+/// Events held for a subscriber that isn't reading them; a few minutes' worth.
+pub const SUBSCRIBER_BACKLOG: usize = 1024;
+// How often tags read by `load_files` are handed to the player
+const TAG_BATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Supplies the tracks appended once the last one starts with
+/// `RepeatMode::AutoExtend`. Called on a thread of its own, one batch at a time.
+pub type Refill = Box<dyn FnMut() -> Result<Vec<Entry>> + Send>;
+
+/// What a front end asks the engine to do.
+pub enum Command {
+    /// Play a playlist entry, or with `None` resume or start the current one.
+    Play(Option<usize>),
+    Pause,
+    /// Pause when playing, otherwise like `Play(None)`.
+    TogglePlayback,
+    Stop,
+    Next,
+    Previous,
+    /// Jump within the current track; past its end moves on to the next one.
+    Seek(Duration),
+    /// Replace the playlist, stopping playback.
+    Load(Vec<Entry>),
+    /// Add tracks to the end of the playlist.
+    Append(Vec<Entry>),
+    /// Move a playlist entry, keeping the current track current.
+    Move { from: usize, to: usize },
+    Remove(usize),
+    /// Replace the tags shown for entries, given with the index they were
+    /// loaded at. Entries that moved since are found by path.
+    UpdateMetadata(Vec<(usize, Entry)>),
+    /// Add tracks to the Up Next queue, ahead of what's queued with `next`.
+    Enqueue { entries: Vec<Entry>, next: bool },
+    /// Take one track out of the Up Next queue, or all of them with `None`.
+    Unqueue(Option<usize>),
+    /// Start playing entry `index` from `position`, as when resuming an earlier session.
+    Resume { index: usize, position: Duration, paused: bool },
+    /// 0.0 is silent and 1.0 full volume.
+    SetVolume(f32),
+    /// What to do when a track ends.
+    SetRepeat(RepeatMode),
+    /// Where auto-extend gets more tracks; without one it stops at the end like `RepeatMode::Off`.
+    SetRefill(Option<Refill>),
+}
+
+/// What happened, sent to every subscriber.
+#[derive(Clone)]
+pub enum Event {
+    TrackStarted { index: usize, path: PathBuf },
+    /// The track played to the end.
+    TrackEnded { index: usize, path: PathBuf },
+    /// Where playback is in the current track, sent twice a second while
+    /// playing and after seeks.
+    PositionChanged(Duration),
+    /// A track that couldn't be played has its path; other errors don't.
+    Error { path: Option<PathBuf>, message: String },
+    /// A listen to record in the library.
+    Listened(Listen),
+    /// The playlist or the Up Next queue was edited, or their tags updated.
+    PlaylistChanged,
+    VolumeChanged(f32),
+}
+
+/// Runs a `Player` on its own thread: it takes `Command`s, moves on when
+/// tracks end whether or not any front end is looking, and reports `Event`s.
+/// Clones drive the same engine, so the window, media controls and remote
+/// front ends can all share one.
+///
+/// Changes go through `send`, so every front end hears about them; `player`
+/// is for reading the state.
+#[derive(Clone)]
+pub struct PlayerEngine {
+    player: Arc<Mutex<Player>>,
+    repeat: Arc<Mutex<RepeatMode>>,
+    commands: Sender<Command>,
+    subscribers: Arc<Mutex<Vec<SyncSender<Event>>>>,
+    tag_loading: Arc<Mutex<TagLoading>>,
+}
+
+// How far reading the tags of the last `load_files` has got. Every loaded
+// playlist is a new generation, and readers for older ones stop.
+#[derive(Default)]
+struct TagLoading {
+    generation: u64,
+    read: usize,
+    total: usize,
+}
+
+impl PlayerEngine {
+    /// Start the engine thread. It stops once every clone has been dropped.
+    pub fn start(player: Player, repeat: RepeatMode) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let engine = Self {
+            player: Arc::new(Mutex::new(player)),
+            repeat: Arc::new(Mutex::new(repeat)),
+            commands: tx,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            tag_loading: Arc::new(Mutex::new(TagLoading::default())),
+        };
+        let player = Arc::clone(&engine.player);
+        let repeat = Arc::clone(&engine.repeat);
+        let subscribers = Arc::clone(&engine.subscribers);
+        let commands = engine.commands.clone();
+        thread::spawn(move || run(player, rx, commands, subscribers, repeat));
+        engine
+    }
+
+    pub fn send(&self, command: Command) {
+        // Tags still being read for the old playlist aren't wanted any more
+        if matches!(command, Command::Load(_)) {
+            let mut loading = lock(&self.tag_loading);
+            *loading = TagLoading { generation: loading.generation + 1, ..Default::default() };
+        }
+        // The thread only stops once no one can send
+        let _ = self.commands.send(command);
+    }
+
+    /// Replace the playlist with `files`, shown under their file names until
+    /// a thread of its own has read their tags and handed them over in
+    /// batches. Loading another playlist stops the reading.
+    pub fn load_files(&self, files: Vec<PathBuf>, sync_ratings: bool) {
+        let placeholders = files
+            .iter()
+            .map(|path| {
+                let metadata = SongMetadata {
+                    artist: "Loading...".to_string(),
+                    album: "Loading...".to_string(),
+                    ..SongMetadata::from_file_name(path)
+                };
+                (path.clone(), metadata)
+            })
+            .collect();
+        self.send(Command::Load(placeholders));
+        let generation = {
+            let mut loading = lock(&self.tag_loading);
+            loading.total = files.len();
+            loading.generation
+        };
+
+        let commands = self.commands.clone();
+        let tag_loading = Arc::clone(&self.tag_loading);
+        thread::spawn(move || {
+            let db = LibraryDb::open().map_err(|e| eprintln!("Could not open library: {}", e)).ok();
+            let reader = MetadataReader::new(db.as_ref(), sync_ratings);
+            let mut batch = Vec::new();
+            let mut last_batch = Instant::now();
+            for (i, path) in files.iter().enumerate() {
+                batch.push((i, (path.clone(), reader.read_or_placeholder(path))));
+                if i + 1 < files.len() && last_batch.elapsed() < TAG_BATCH_INTERVAL {
+                    continue;
+                }
+                // Checked and sent together, so a newer playlist never gets these
+                let mut loading = lock(&tag_loading);
+                if loading.generation != generation {
+                    return;
+                }
+                let _ = commands.send(Command::UpdateMetadata(std::mem::take(&mut batch)));
+                loading.read = i + 1;
+                last_batch = Instant::now();
+            }
+        });
+    }
+
+    /// How many of the files given to `load_files` have had their tags read,
+    /// out of how many, while that is still going on.
+    pub fn tag_progress(&self) -> Option<(usize, usize)> {
+        let loading = lock(&self.tag_loading);
+        (loading.read < loading.total).then_some((loading.read, loading.total))
+    }
+
+    /// Counts the playlists loaded with `Command::Load`, so it changes when
+    /// the playlist is replaced rather than edited.
+    pub fn loads(&self) -> u64 {
+        lock(&self.tag_loading).generation
+    }
+
+    /// A receiver for every event from now on. A subscriber that falls
+    /// `SUBSCRIBER_BACKLOG` events behind misses the ones that follow until
+    /// it catches up.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+        lock(&self.subscribers).push(tx);
+        rx
+    }
+
+    /// What happens when a track ends, as last set with `Command::SetRepeat`.
+    pub fn repeat(&self) -> RepeatMode {
+        *lock(&self.repeat)
+    }
+
+    /// The player, for reading its state. Hold it briefly: the engine waits
+    /// for it on every tick.
+    pub fn player(&self) -> MutexGuard<'_, Player> {
+        lock(&self.player)
+    }
+}
+
+// A panic while holding the lock leaves the player usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn run(
+    player: Arc<Mutex<Player>>,
+    commands: Receiver<Command>,
+    sender: Sender<Command>,
+    subscribers: Arc<Mutex<Vec<SyncSender<Event>>>>,
+    repeat: Arc<Mutex<RepeatMode>>,
+) {
+    let mut last_position = Instant::now();
+    let mut refill: Option<Arc<Mutex<Refill>>> = None;
+    // Set while a batch is being fetched, so the end of the playlist doesn't ask again every tick
+    let refilling = Arc::new(AtomicBool::new(false));
+    loop {
+        let command = match commands.recv_timeout(TICK) {
+            Ok(command) => Some(command),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let (events, extend) = {
+            let mut player = lock(&player);
+            let mut events = Vec::new();
+            let mut seeked = false;
+            // Turning auto-extend on near the end extends right away, as does a new source for it
+            let mut extend = false;
+            if let Some(command) = command {
+                seeked = matches!(command, Command::Seek(_));
+                extend = matches!(command, Command::SetRepeat(RepeatMode::AutoExtend) | Command::SetRefill(Some(_)));
+                if let Err(e) = apply(&mut player, command, &mut lock(&repeat), &mut refill) {
+                    events.push(Event::Error { path: None, message: e.to_string() });
+                }
+            }
+            if player.is_finished() {
+                player.track_finished(*lock(&repeat));
+            }
+            player.update_listening();
+
+            events.extend(player.take_events());
+            if player.is_playing() && (seeked || last_position.elapsed() >= POSITION_INTERVAL) {
+                events.push(Event::PositionChanged(player.position().unwrap_or_default()));
+                last_position = Instant::now();
+            }
+            // The next batch is added once the last track starts, so playback
+            // moves straight on to it when that one ends
+            extend |= events.iter().any(|event| matches!(event, Event::TrackStarted { .. }));
+            extend &= *lock(&repeat) == RepeatMode::AutoExtend && !player.playlist.is_empty() && player.at_end();
+            (events, extend)
+        };
+
+        // Ratings written while their track was open go in once it's closed
+        metadata::finish_deferred_writes();
+
+        // Shuffling a large library takes a while, so it happens off this
+        // thread and the batch comes back as an `Append`
+        if let (true, Some(refill)) = (extend, refill.as_ref()) {
+            if !refilling.swap(true, Ordering::SeqCst) {
+                let refill = Arc::clone(refill);
+                let refilling = Arc::clone(&refilling);
+                let sender = sender.clone();
+                let subscribers = Arc::clone(&subscribers);
+                thread::spawn(move || {
+                    let result = (lock(&refill))();
+                    match result {
+                        Ok(entries) => {
+                            let _ = sender.send(Command::Append(entries));
+                        }
+                        Err(e) => {
+                            let message = format!("Could not extend the playlist: {}", e);
+                            broadcast(&subscribers, &[Event::Error { path: None, message }]);
+                        }
+                    }
+                    refilling.store(false, Ordering::SeqCst);
+                });
+            }
+        }
+
+        broadcast(&subscribers, &events);
+    }
+}
+
+fn broadcast(subscribers: &Mutex<Vec<SyncSender<Event>>>, events: &[Event]) {
+    if events.is_empty() {
+        return;
+    }
+    // Drop subscribers that went away; one that is behind just misses these
+    lock(subscribers).retain(|subscriber| {
+        events.iter().all(|event| !matches!(subscriber.try_send(event.clone()), Err(TrySendError::Disconnected(_))))
+    });
+}
+
+fn apply(player: &mut Player, command: Command, repeat: &mut RepeatMode, refill: &mut Option<Arc<Mutex<Refill>>>) -> Result<()> {
+    match command {
+        Command::Play(Some(index)) => {
+            if index != player.current_index() && index < player.playlist.len() {
+                player.skip_listening();
+            }
+            player.play_index(index);
+        }
+        Command::Play(None) => player.play(),
+        Command::Pause => player.pause(),
+        Command::TogglePlayback => player.toggle_playback(),
+        Command::Stop => {
+            player.skip_listening();
+            player.stop();
+        }
+        Command::Next => {
+            player.skip_listening();
+            player.next_track(false);
+        }
+        Command::Previous => player.previous_track(),
+        Command::Seek(position) => {
+            let duration = player.current().and_then(|(_, metadata)| metadata.duration);
+            if duration.is_some_and(|d| position.as_secs_f32() >= d) {
+                player.skip_listening();
+                player.next_track(false);
+            } else {
+                player.seek(position)?;
+            }
+        }
+        Command::Load(entries) => {
+            player.skip_listening();
+            player.load(entries);
+        }
+        Command::Append(entries) => player.append(entries),
+        Command::Move { from, to } => player.move_entry(from, to),
+        Command::Remove(index) => {
+            player.remove_entry(index);
+        }
+        Command::UpdateMetadata(updates) => player.update_metadata(updates),
+        Command::Enqueue { entries, next } => player.enqueue(entries, next),
+        Command::Unqueue(index) => player.unqueue(index),
+        Command::Resume { index, position, paused } => player.resume_at(index, position, paused),
+        Command::SetVolume(volume) => player.set_volume(volume)?,
+        Command::SetRepeat(mode) => *repeat = mode,
+        Command::SetRefill(source) => *refill = source.map(|source| Arc::new(Mutex::new(source))),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SongMetadata;
+
+    fn entry(name: &str) -> Entry {
+        (PathBuf::from(name), SongMetadata::default())
+    }
+
+    // Wait for the engine thread to catch up with the commands sent so far
+    fn settle(engine: &PlayerEngine, done: impl Fn(&Player) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&engine.player()) {
+            assert!(Instant::now() < deadline, "engine didn't settle");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn auto_extend_refills_at_the_end_of_the_playlist() {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        engine.player().load(vec![entry("a.mp3")]);
+        let mut batch = 0;
+        engine.send(Command::SetRefill(Some(Box::new(move || {
+            batch += 1;
+            Ok(vec![entry(&format!("batch{}.mp3", batch))])
+        }))));
+        // Not while auto-extend is off
+        thread::sleep(TICK * 3);
+        assert_eq!(engine.player().playlist.len(), 1);
+
+        engine.send(Command::SetRepeat(RepeatMode::AutoExtend));
+        settle(&engine, |player| player.playlist.len() == 2);
+        assert_eq!(engine.player().playlist[1].0, PathBuf::from("batch1.mp3"));
+    }
+
+    #[test]
+    fn slow_refills_run_one_at_a_time_without_holding_up_commands() {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        engine.player().load(vec![entry("a.mp3")]);
+        let (release, released) = mpsc::channel::<()>();
+        let calls = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&calls);
+        engine.send(Command::SetRefill(Some(Box::new(move || {
+            *lock(&counted) += 1;
+            let _ = released.recv();
+            Ok(vec![entry("batch.mp3")])
+        }))));
+        engine.send(Command::SetRepeat(RepeatMode::AutoExtend));
+        settle(&engine, |_| *lock(&calls) == 1);
+
+        // Asking again while the first batch is on its way doesn't start another
+        engine.send(Command::SetRepeat(RepeatMode::AutoExtend));
+        engine.send(Command::Enqueue { entries: vec![entry("queued.mp3")], next: false });
+        settle(&engine, |player| !player.up_next.is_empty());
+        assert_eq!(*lock(&calls), 1);
+
+        release.send(()).unwrap();
+        settle(&engine, |player| player.playlist.len() == 2);
+        assert_eq!(engine.player().playlist[1].0, PathBuf::from("batch.mp3"));
+        assert_eq!(*lock(&calls), 1);
+    }
+
+    #[test]
+    fn failed_refills_are_reported() {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::AutoExtend);
+        let events = engine.subscribe();
+        engine.player().load(vec![entry("a.mp3")]);
+        engine.send(Command::SetRefill(Some(Box::new(|| Err(anyhow::anyhow!("The library is empty"))))));
+        let message = events.iter().find_map(|event| match event {
+            Event::Error { message, .. } => Some(message),
+            _ => None,
+        });
+        assert_eq!(message.as_deref(), Some("Could not extend the playlist: The library is empty"));
+        assert_eq!(engine.player().playlist.len(), 1);
+    }
+
+    #[test]
+    fn playlist_and_volume_changes_are_announced() {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        let events = engine.subscribe();
+        engine.send(Command::Load(vec![entry("a.mp3"), entry("b.mp3")]));
+        engine.send(Command::Append(vec![entry("c.mp3")]));
+        engine.send(Command::Move { from: 2, to: 0 });
+        engine.send(Command::Remove(1));
+        // Without an audio device the volume can't change
+        engine.send(Command::SetVolume(0.5));
+
+        let received: Vec<Event> = events.iter().take(5).collect();
+        assert_eq!(received.iter().filter(|event| matches!(event, Event::PlaylistChanged)).count(), 4);
+        assert!(matches!(&received[4], Event::Error { message, .. } if message == "No audio device"));
+        let paths: Vec<PathBuf> = engine.player().playlist.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(paths, [PathBuf::from("c.mp3"), PathBuf::from("b.mp3")]);
+    }
+
+    #[test]
+    fn subscribers_that_stop_reading_miss_events_instead_of_piling_them_up() {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        let idle = engine.subscribe();
+        let total = SUBSCRIBER_BACKLOG + 10;
+        for _ in 0..total {
+            engine.send(Command::Append(vec![entry("a.mp3")]));
+        }
+        settle(&engine, |player| player.playlist.len() == total);
+        assert_eq!(idle.try_iter().count(), SUBSCRIBER_BACKLOG);
+
+        // Once it has caught up it gets new events again
+        engine.send(Command::Append(vec![entry("b.mp3")]));
+        assert!(matches!(idle.recv_timeout(Duration::from_secs(5)), Ok(Event::PlaylistChanged)));
+    }
+}
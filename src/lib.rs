@@ -0,0 +1,60 @@
+//! The core of Music Shuffler, without the window: a music library kept in
+//! SQLite, shuffled playlists, tag reading and playback.
+//!
+//! ```no_run
+//! use music_shuffler::engine::{Command, Event};
+//! use music_shuffler::audio::AudioPlayer;
+//! use music_shuffler::{Library, Player, PlayerEngine, RepeatMode};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let library = Library::open()?;
+//! let reader = library.metadata_reader(false);
+//! let files = library.playlist_generator(20)?.generate(&library.files()?);
+//!
+//! let engine = PlayerEngine::start(Player::new(Some(AudioPlayer::new()?)), RepeatMode::Off);
+//! let events = engine.subscribe();
+//! engine.send(Command::Load(files.into_iter().map(|path| {
+//!     let metadata = reader.read_or_placeholder(&path);
+//!     (path, metadata)
+//! }).collect()));
+//! engine.send(Command::Play(Some(0)));
+//! for event in events {
+//!     match event {
+//!         Event::Listened(listen) => library.record_listen(&listen)?,
+//!         Event::TrackEnded { .. } if engine.player().is_stopped() => break,
+//!         _ => {}
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything shares the app's config directory, so a library scanned here is
+//! the one the app opens, and the other way round.
+
+pub mod audio;
+pub mod browser;
+pub mod config;
+pub mod duplicates;
+pub mod engine;
+pub mod library;
+pub mod metadata;
+pub mod mpd;
+pub mod music;
+pub mod player;
+pub mod playlist_file;
+pub mod query;
+pub mod remote;
+pub mod queue;
+pub mod saved_playlists;
+pub mod scrobble;
+pub mod session;
+pub mod smart_playlists;
+pub mod storage;
+
+pub use config::RepeatMode;
+pub use engine::PlayerEngine;
+pub use library::Library;
+pub use metadata::{MetadataReader, SongMetadata};
+pub use music::PlaylistGenerator;
+pub use player::{Listen, Player};
@@ -0,0 +1,1240 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use serde::Deserialize;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use crate::browser::LibraryIndex;
+use crate::config::get_config_dir;
+use crate::duplicates::DuplicateReport;
+use crate::engine::Event;
+use crate::metadata::{self, MetadataReader, SongMetadata};
+use crate::music::{self, CancelToken, PlaylistGenerator, ScanEvent, ScanOptions};
+use crate::player::Listen;
+use crate::query::Query;
+use crate::queue::Entry;
+use crate::storage::backup_path;
+
+// Each entry upgrades the schema by one version; the current version is kept in
+// SQLite's `user_version` pragma. Never edit an entry once released, add a new one.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial library schema
+    "CREATE TABLE library_info (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE artists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE albums (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        UNIQUE (title, artist_id)
+    );
+    CREATE TABLE tracks (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        title TEXT,
+        artist_id INTEGER REFERENCES artists(id),
+        album_id INTEGER REFERENCES albums(id),
+        duration REAL,
+        album_art BLOB,
+        file_size INTEGER,
+        modified_ns INTEGER,
+        added_at INTEGER NOT NULL
+    );
+    CREATE TABLE play_history (
+        id INTEGER PRIMARY KEY,
+        track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+        played_at INTEGER NOT NULL,
+        seconds_played REAL NOT NULL,
+        skipped INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX play_history_track ON play_history(track_id);",
+    // 2: genres; clearing modified_ns makes stored tags be read again to fill them in
+    "ALTER TABLE tracks ADD COLUMN genre TEXT;
+    UPDATE tracks SET modified_ns = NULL;",
+    // 3: release years, read again the same way
+    "ALTER TABLE tracks ADD COLUMN year INTEGER;
+    UPDATE tracks SET modified_ns = NULL;",
+    // 4: star ratings (NULL is unrated) and tracks banned from shuffles
+    "ALTER TABLE tracks ADD COLUMN rating INTEGER;
+    ALTER TABLE tracks ADD COLUMN banned INTEGER NOT NULL DEFAULT 0;",
+    // 5: tracks the last scan didn't find are kept, with their history and
+    // ratings, and marked with when they went missing
+    "ALTER TABLE tracks ADD COLUMN missing_since INTEGER;",
+    // 6: tracks played or rated from outside the scanned directory count as
+    // missing too, so they stay out of shuffles
+    "UPDATE tracks SET missing_since = added_at
+    WHERE missing_since IS NULL
+      AND NOT EXISTS (
+        SELECT 1 FROM library_info
+        WHERE key = 'directory'
+          AND substr(tracks.path, 1, length(rtrim(value, '/\\')) + 1) IN (rtrim(value, '/\\') || '/', rtrim(value, '/\\') || '\\')
+      );",
+];
+
+pub fn get_database_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("library.db"))
+}
+
+fn get_legacy_cache_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("file_cache.json"))
+}
+
+// Raised when the integrity check finds problems
+#[derive(Debug)]
+struct Damaged(String);
+
+impl std::fmt::Display for Damaged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "integrity check failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for Damaged {}
+
+// Only corruption is recovered from; a locked database or one written by a newer
+// build must not be thrown away
+fn is_damage(error: &anyhow::Error) -> bool {
+    if let Some(e) = error.downcast_ref::<rusqlite::Error>() {
+        return matches!(e.sqlite_error_code(), Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase));
+    }
+    error.downcast_ref::<Damaged>().is_some()
+}
+
+// How often a healthy library is snapshotted for recovery
+const BACKUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn backup_due(backup: &Path) -> bool {
+    let age = std::fs::metadata(backup).and_then(|m| m.modified()).map(|modified| modified.elapsed());
+    !matches!(age, Ok(Ok(age)) if age < BACKUP_INTERVAL)
+}
+
+fn to_nanos(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+fn from_secs(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+/// Per-track facts kept by the library rather than read from tags.
+#[derive(Clone, Copy, Default)]
+pub struct TrackStats {
+    pub added_at: Option<SystemTime>,
+    pub last_played: Option<SystemTime>,
+    pub play_count: u32,
+    pub skip_count: u32,
+    /// 1-5 stars, `None` when unrated.
+    pub rating: Option<u8>,
+    /// Never picked by shuffles.
+    pub banned: bool,
+}
+
+impl TrackStats {
+    // Mirror of `LibraryDb::record_play` for copies held in memory
+    pub fn record(&mut self, skipped: bool, at: SystemTime) {
+        if skipped {
+            self.skip_count += 1;
+        } else {
+            self.play_count += 1;
+            self.last_played = Some(at);
+        }
+    }
+}
+
+/// Whether listening this far into a track counts as playing it: half of it,
+/// or four minutes of a long one.
+pub fn counts_as_play(seconds_played: f32, duration: Option<f32>) -> bool {
+    seconds_played >= 240.0 || duration.is_some_and(|d| d > 0.0 && seconds_played >= d * 0.5)
+}
+
+/// What the library database holds, for `cache info`.
+pub struct CacheInfo {
+    pub tracks: u64,
+    /// Tracks whose tags are stored and still match the file.
+    pub cached: u64,
+    pub album_art_bytes: u64,
+    pub plays: u64,
+}
+
+/// How far back the statistics view looks.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StatsRange {
+    Week,
+    Month,
+    Year,
+    AllTime,
+}
+
+impl StatsRange {
+    pub const ALL: [StatsRange; 4] = [StatsRange::Week, StatsRange::Month, StatsRange::Year, StatsRange::AllTime];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            StatsRange::Week => "Last 7 days",
+            StatsRange::Month => "Last 30 days",
+            StatsRange::Year => "Last year",
+            StatsRange::AllTime => "All time",
+        }
+    }
+
+    // Earliest `played_at` that falls in the range
+    fn since(self) -> i64 {
+        let days = match self {
+            StatsRange::Week => 7,
+            StatsRange::Month => 30,
+            StatsRange::Year => 365,
+            StatsRange::AllTime => return 0,
+        };
+        to_nanos(SystemTime::now()) / 1_000_000_000 - days * 86400
+    }
+}
+
+/// Listening over a time range, drawn from the play history. Rankings are
+/// (name, count) with the largest count first.
+#[derive(Default)]
+pub struct ListeningStats {
+    /// Everything listened to, skips included.
+    pub seconds_played: f64,
+    pub plays: u64,
+    pub skips: u64,
+    /// Library tracks played through at least once in the range.
+    pub tracks_heard: u64,
+    pub library_size: u64,
+    pub top_artists: Vec<(String, u64)>,
+    pub top_albums: Vec<(String, u64)>,
+    pub top_tracks: Vec<(String, u64)>,
+    pub most_skipped: Vec<(String, u64)>,
+    /// Artists with the most tracks that weren't played in the range.
+    pub unheard_artists: Vec<(String, u64)>,
+    /// Plays by local weekday (0 is Sunday) and hour.
+    pub by_hour: [[u64; 24]; 7],
+}
+
+/// The music library: scanned files, their metadata, artists, albums and play history.
+pub struct LibraryDb {
+    conn: Connection,
+}
+
+impl LibraryDb {
+    /// Open the library in the config directory, creating it if needed. A freshly
+    /// created library imports an existing `file_cache.json` once.
+    pub fn open() -> Result<Self> {
+        let path = Self::default_path()?;
+        let mut db = Self::open_at(&path)?;
+        db.import_legacy_if_needed()?;
+        Ok(db)
+    }
+
+    /// Like `open`, but checks the database first. A damaged library is moved aside
+    /// and restored from the backup taken at the last healthy start, or recreated
+    /// empty if that fails too. The message describes what happened, for the UI.
+    pub fn open_with_recovery() -> Result<(Self, Option<String>)> {
+        let path = Self::default_path()?;
+        let backup = backup_path(&path);
+
+        let error = match Self::open_checked(&path) {
+            Ok((mut db, migrated)) => {
+                db.import_legacy_if_needed()?;
+                // Snapshotting a large library takes a while, so it happens off
+                // the caller's thread and only daily or when the schema changed
+                if migrated || backup_due(&backup) {
+                    std::thread::spawn(move || {
+                        if let Err(e) = Self::open_at(&path).and_then(|db| db.backup_to(&backup)) {
+                            eprintln!("Could not back up library: {}", e);
+                        }
+                    });
+                }
+                return Ok((db, None));
+            }
+            Err(e) if !is_damage(&e) => return Err(e),
+            Err(e) => e,
+        };
+        eprintln!("Library database is damaged: {}", error);
+
+        // Keep the damaged file around for inspection and drop its journal files
+        let mut damaged = path.as_os_str().to_owned();
+        damaged.push(".damaged");
+        std::fs::rename(&path, &damaged)?;
+        for suffix in ["-wal", "-shm"] {
+            let mut journal = path.as_os_str().to_owned();
+            journal.push(suffix);
+            let _ = std::fs::remove_file(journal);
+        }
+
+        if backup.exists() {
+            std::fs::copy(&backup, &path)?;
+            match Self::open_checked(&path) {
+                Ok((db, _)) => {
+                    let message = format!("The library database was damaged ({}) and has been restored from its last backup.", error);
+                    return Ok((db, Some(message)));
+                }
+                Err(e) => {
+                    eprintln!("Library backup is unusable too: {}", e);
+                    std::fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        let db = Self::open_at(&path)?;
+        let message = format!("The library database was damaged ({}) and has been rebuilt; your music folder will be rescanned.", error);
+        Ok((db, Some(message)))
+    }
+
+    fn default_path() -> Result<PathBuf> {
+        let path = get_database_path().ok_or_else(|| anyhow!("No config directory available"))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(path)
+    }
+
+    // Open and check the database before migrating it. Also says whether any
+    // migrations ran.
+    fn open_checked(path: &Path) -> Result<(Self, bool)> {
+        let mut db = Self::connect(path)?;
+        let result: String = db.conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+        if result != "ok" {
+            return Err(Damaged(result).into());
+        }
+        let migrated = db.migrate()?;
+        Ok((db, migrated))
+    }
+
+    // Snapshot the database; VACUUM INTO refuses to overwrite, so write beside
+    // the backup and swap it in
+    fn backup_to(&self, dest: &Path) -> Result<()> {
+        let mut temp = dest.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let _ = std::fs::remove_file(&temp);
+        self.conn.execute("VACUUM INTO ?1", [path_key(&temp)])?;
+        std::fs::rename(&temp, dest)?;
+        Ok(())
+    }
+
+    fn import_legacy_if_needed(&mut self) -> Result<()> {
+        if let Some(legacy_path) = get_legacy_cache_path() {
+            if legacy_path.exists() && self.is_empty()? {
+                match self.import_legacy_cache(&legacy_path) {
+                    Ok(count) => {
+                        println!("Imported {} tracks from {}", count, legacy_path.display());
+                        let _ = std::fs::rename(&legacy_path, legacy_path.with_extension("json.imported"));
+                    }
+                    Err(e) => eprintln!("Could not import {}: {}", legacy_path.display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn open_at(path: &Path) -> Result<Self> {
+        let mut db = Self::connect(path)?;
+        db.migrate()?;
+        Ok(db)
+    }
+
+    fn connect(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        // WAL lets the UI read while a background thread writes
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Ok(Self { conn })
+    }
+
+    // Bring the schema up to date; true when anything changed
+    fn migrate(&mut self) -> Result<bool> {
+        let version: usize = self.conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(anyhow!(
+                "Library database is version {}, newer than this build supports ({})",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+            println!("Library database migrated to version {}", i + 1);
+        }
+        Ok(version < MIGRATIONS.len())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let count: i64 = self.conn.query_row("SELECT COUNT(*) FROM tracks", [], |row| row.get(0))?;
+        Ok(count == 0)
+    }
+
+    fn info(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM library_info WHERE key = ?1", [key], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn set_info(conn: &Connection, key: &str, value: &str) -> Result<()> {
+        conn.execute(
+            "INSERT INTO library_info (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Directory the stored file list was scanned from.
+    pub fn directory(&self) -> Result<Option<PathBuf>> {
+        Ok(self.info("directory")?.map(PathBuf::from))
+    }
+
+    /// Tracks found by the last scan.
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.conn.prepare("SELECT path FROM tracks WHERE missing_since IS NULL ORDER BY path")?;
+        let files = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|r| r.ok())
+            .map(PathBuf::from)
+            .collect();
+        Ok(files)
+    }
+
+    pub fn has_track(&self, path: &Path) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT 1 FROM tracks WHERE path = ?1 AND missing_since IS NULL", [path_key(path)], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    /// Tracks whose tags were never read, or were cleared so a migration reads them again.
+    pub fn untagged_files(&self) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path FROM tracks WHERE missing_since IS NULL AND (title IS NULL OR modified_ns IS NULL) ORDER BY path",
+        )?;
+        let files = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(|r| r.ok())
+            .map(PathBuf::from)
+            .collect();
+        Ok(files)
+    }
+
+    /// Every track with its stored tags, sorted by artist, album and path.
+    /// Tracks whose tags haven't been read yet are titled after their file.
+    /// Album art is left out.
+    pub fn tracks(&self) -> Result<Vec<Entry>> {
+        self.query_tracks("ORDER BY ar.name, al.title, t.path", params![])
+    }
+
+    /// Up to `limit` tracks whose title, artist, album or path contains `text`,
+    /// ignoring case, as `tracks` gives them.
+    pub fn search(&self, text: &str, limit: usize) -> Result<Vec<Entry>> {
+        let pattern = format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        self.query_tracks(
+            "AND (t.title LIKE ?1 ESCAPE '\\' OR ar.name LIKE ?1 ESCAPE '\\'
+                OR al.title LIKE ?1 ESCAPE '\\' OR t.path LIKE ?1 ESCAPE '\\')
+             ORDER BY ar.name, al.title, t.path
+             LIMIT ?2",
+            params![pattern, limit as i64],
+        )
+    }
+
+    // Tracks in the library with their tags, narrowed (starting with `AND`) and
+    // ordered by `rest` of the query
+    fn query_tracks(&self, rest: &str, params: impl rusqlite::Params) -> Result<Vec<Entry>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT t.path, t.title, ar.name, al.title, t.duration, t.genre, t.year
+             FROM tracks t
+             LEFT JOIN artists ar ON ar.id = t.artist_id
+             LEFT JOIN albums al ON al.id = t.album_id
+             WHERE t.missing_since IS NULL
+             {}",
+            rest
+        ))?;
+        let tracks = stmt
+            .query_map(params, |row| {
+                let path = PathBuf::from(row.get::<_, String>(0)?);
+                let title: Option<String> = row.get(1)?;
+                let mut metadata = SongMetadata {
+                    artist: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    album: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                    duration: row.get::<_, Option<f64>>(4)?.map(|d| d as f32),
+                    genre: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                    year: row.get(6)?,
+                    ..SongMetadata::from_file_name(&path)
+                };
+                if let Some(title) = title {
+                    metadata.title = title;
+                }
+                Ok((path, metadata))
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(tracks)
+    }
+
+    /// Stats for every track in the library, keyed by path.
+    pub fn track_stats(&self) -> Result<HashMap<PathBuf, TrackStats>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT t.path, t.added_at,
+                    MAX(CASE WHEN h.skipped = 0 THEN h.played_at END),
+                    COUNT(CASE WHEN h.skipped = 0 THEN 1 END),
+                    COUNT(CASE WHEN h.skipped = 1 THEN 1 END),
+                    t.rating, t.banned
+             FROM tracks t
+             LEFT JOIN play_history h ON h.track_id = t.id
+             GROUP BY t.id",
+        )?;
+        let stats = stmt
+            .query_map([], |row| {
+                let stats = TrackStats {
+                    added_at: Some(from_secs(row.get(1)?)),
+                    last_played: row.get::<_, Option<i64>>(2)?.map(from_secs),
+                    play_count: row.get(3)?,
+                    skip_count: row.get(4)?,
+                    rating: row.get(5)?,
+                    banned: row.get(6)?,
+                };
+                Ok((PathBuf::from(row.get::<_, String>(0)?), stats))
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(stats)
+    }
+
+    /// Statistics for plays since the start of `range`.
+    pub fn listening_stats(&self, range: StatsRange) -> Result<ListeningStats> {
+        let since = range.since();
+        let mut stats = ListeningStats::default();
+
+        let (seconds, plays, skips, heard): (f64, u64, u64, u64) = self.conn.query_row(
+            "SELECT COALESCE(SUM(seconds_played), 0),
+                    COUNT(CASE WHEN skipped = 0 THEN 1 END),
+                    COUNT(CASE WHEN skipped = 1 THEN 1 END),
+                    COUNT(DISTINCT CASE WHEN skipped = 0 THEN track_id END)
+             FROM play_history WHERE played_at >= ?1",
+            [since],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        stats.seconds_played = seconds;
+        stats.plays = plays;
+        stats.skips = skips;
+        stats.tracks_heard = heard;
+        stats.library_size = self.conn.query_row("SELECT COUNT(*) FROM tracks WHERE missing_since IS NULL", [], |row| row.get(0))?;
+
+        // Each ranking names a group and counts its plays (or skips)
+        let played = "FROM play_history h
+                      JOIN tracks t ON t.id = h.track_id
+                      LEFT JOIN artists ar ON ar.id = t.artist_id
+                      LEFT JOIN albums al ON al.id = t.album_id
+                      WHERE h.played_at >= ?1";
+        stats.top_artists = self.ranking(
+            &format!("SELECT COALESCE(ar.name, 'Unknown Artist'), COUNT(*) {} AND h.skipped = 0 GROUP BY 1", played),
+            since,
+        )?;
+        stats.top_albums = self.ranking(
+            &format!(
+                "SELECT COALESCE(al.title, 'Unknown Album') || ' - ' || COALESCE(ar.name, 'Unknown Artist'), COUNT(*)
+                 {} AND h.skipped = 0 GROUP BY t.album_id",
+                played
+            ),
+            since,
+        )?;
+        let track_name = "COALESCE(ar.name, 'Unknown Artist') || ' - ' || COALESCE(t.title, t.path)";
+        stats.top_tracks = self.ranking(
+            &format!("SELECT {}, COUNT(*) {} AND h.skipped = 0 GROUP BY t.id", track_name, played),
+            since,
+        )?;
+        stats.most_skipped = self.ranking(
+            &format!("SELECT {}, COUNT(*) {} AND h.skipped = 1 GROUP BY t.id", track_name, played),
+            since,
+        )?;
+        stats.unheard_artists = self.ranking(
+            "SELECT COALESCE(ar.name, 'Unknown Artist'), COUNT(*)
+             FROM tracks t
+             LEFT JOIN artists ar ON ar.id = t.artist_id
+             WHERE t.missing_since IS NULL AND NOT EXISTS (
+                 SELECT 1 FROM play_history h
+                 WHERE h.track_id = t.id AND h.skipped = 0 AND h.played_at >= ?1
+             )
+             GROUP BY 1",
+            since,
+        )?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT CAST(strftime('%w', played_at, 'unixepoch', 'localtime') AS INTEGER),
+                    CAST(strftime('%H', played_at, 'unixepoch', 'localtime') AS INTEGER),
+                    COUNT(*)
+             FROM play_history WHERE played_at >= ?1 AND skipped = 0
+             GROUP BY 1, 2",
+        )?;
+        let cells = stmt.query_map([since], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, usize>(1)?, row.get::<_, u64>(2)?)))?;
+        for (weekday, hour, count) in cells.filter_map(|r| r.ok()) {
+            if weekday < 7 && hour < 24 {
+                stats.by_hour[weekday][hour] = count;
+            }
+        }
+        Ok(stats)
+    }
+
+    // The ten largest groups of a `SELECT name, count ... GROUP BY` taking `since` as ?1
+    fn ranking(&self, sql: &str, since: i64) -> Result<Vec<(String, u64)>> {
+        let mut stmt = self.conn.prepare(&format!("{} ORDER BY 2 DESC, 1 LIMIT 10", sql))?;
+        let rows = stmt
+            .query_map([since], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(rows)
+    }
+
+    /// Add a play, or a skip when the track was left early, to its history.
+    /// Tracks from outside the library are added to it first.
+    pub fn record_play(&self, path: &Path, seconds_played: f32, skipped: bool) -> Result<()> {
+        self.ensure_track(path)?;
+        let now = to_nanos(SystemTime::now()) / 1_000_000_000;
+        self.conn.execute(
+            "INSERT INTO play_history (track_id, played_at, seconds_played, skipped)
+             SELECT id, ?2, ?3, ?4 FROM tracks WHERE path = ?1",
+            params![path_key(path), now, seconds_played as f64, skipped],
+        )?;
+        Ok(())
+    }
+
+    /// Set a track's star rating; `None` clears it.
+    pub fn set_rating(&self, path: &Path, rating: Option<u8>) -> Result<()> {
+        self.ensure_track(path)?;
+        self.conn.execute("UPDATE tracks SET rating = ?2 WHERE path = ?1", params![path_key(path), rating])?;
+        Ok(())
+    }
+
+    /// Take a rating found in the file's tags, unless the track already has one.
+    pub fn import_rating(&self, path: &Path, rating: u8) -> Result<()> {
+        self.conn.execute(
+            "UPDATE tracks SET rating = ?2 WHERE path = ?1 AND rating IS NULL",
+            params![path_key(path), rating],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_banned(&self, path: &Path, banned: bool) -> Result<()> {
+        self.ensure_track(path)?;
+        self.conn.execute("UPDATE tracks SET banned = ?2 WHERE path = ?1", params![path_key(path), banned])?;
+        Ok(())
+    }
+
+    // Tracks from outside the library get a row so they can carry history and
+    // ratings. It's marked missing, like a track the last scan didn't find, so
+    // it stays out of the library until a scan finds it.
+    fn ensure_track(&self, path: &Path) -> Result<()> {
+        let now = to_nanos(SystemTime::now()) / 1_000_000_000;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO tracks (path, added_at, missing_since) VALUES (?1, ?2, ?2)",
+            params![path_key(path), now],
+        )?;
+        Ok(())
+    }
+
+    /// Replace the file list with a fresh scan of `directory`. Tracks that are still
+    /// present keep their metadata and history. The rest are only marked missing,
+    /// so a scan of another directory, or of a drive that wasn't mounted, never
+    /// loses history or ratings; they come back when a scan finds them again.
+    pub fn replace_files(&mut self, directory: &Path, files: &[PathBuf]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let existing: HashSet<String> = {
+            let mut stmt = tx.prepare("SELECT path FROM tracks")?;
+            let paths = stmt.query_map([], |row| row.get(0))?.filter_map(|r| r.ok()).collect();
+            paths
+        };
+        let scanned: HashSet<String> = files.iter().map(|p| path_key(p)).collect();
+        let now = to_nanos(SystemTime::now()) / 1_000_000_000;
+
+        {
+            let mut insert = tx.prepare("INSERT INTO tracks (path, added_at) VALUES (?1, ?2)")?;
+            for path in scanned.difference(&existing) {
+                insert.execute(params![path, now])?;
+            }
+            let mut found = tx.prepare("UPDATE tracks SET missing_since = NULL WHERE path = ?1 AND missing_since IS NOT NULL")?;
+            for path in scanned.intersection(&existing) {
+                found.execute([path])?;
+            }
+            let mut missing = tx.prepare("UPDATE tracks SET missing_since = ?2 WHERE path = ?1 AND missing_since IS NULL")?;
+            for path in existing.difference(&scanned) {
+                missing.execute(params![path, now])?;
+            }
+        }
+        // Artists and albums no track refers to any more, after tags changed
+        tx.execute_batch(
+            "DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM tracks WHERE album_id IS NOT NULL);
+             DELETE FROM artists
+             WHERE id NOT IN (SELECT artist_id FROM tracks WHERE artist_id IS NOT NULL)
+               AND id NOT IN (SELECT artist_id FROM albums);",
+        )?;
+
+        Self::set_info(&tx, "directory", &path_key(directory))?;
+        Self::set_info(&tx, "last_scan", &now.to_string())?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Stored metadata for `path`, if the file still has the given size and modification time.
+    pub fn cached_metadata(&self, path: &Path, file_size: u64, modified: SystemTime) -> Result<Option<SongMetadata>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT t.title, ar.name, al.title, t.duration, t.album_art, t.genre, t.year
+                 FROM tracks t
+                 LEFT JOIN artists ar ON ar.id = t.artist_id
+                 LEFT JOIN albums al ON al.id = t.album_id
+                 WHERE t.path = ?1 AND t.file_size = ?2 AND t.modified_ns = ?3 AND t.title IS NOT NULL",
+                params![path_key(path), file_size as i64, to_nanos(modified)],
+                |row| {
+                    Ok(SongMetadata {
+                        title: row.get(0)?,
+                        artist: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                        album: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                        duration: row.get::<_, Option<f64>>(3)?.map(|d| d as f32),
+                        album_art: row.get(4)?,
+                        genre: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                        year: row.get(6)?,
+                        tag_rating: None,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn cache_info(&self) -> Result<CacheInfo> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*),
+                    COUNT(CASE WHEN title IS NOT NULL AND modified_ns IS NOT NULL THEN 1 END),
+                    COALESCE(SUM(LENGTH(album_art)), 0),
+                    (SELECT COUNT(*) FROM play_history)
+             FROM tracks",
+            [],
+            |row| {
+                Ok(CacheInfo {
+                    tracks: row.get(0)?,
+                    cached: row.get(1)?,
+                    album_art_bytes: row.get(2)?,
+                    plays: row.get(3)?,
+                })
+            },
+        )?)
+    }
+
+    /// Drop stored album art and mark every track's tags as stale so they are
+    /// read from the files again. Play history, ratings and bans are kept, as
+    /// are the names statistics are grouped by until the tags are re-read.
+    pub fn clear_metadata_cache(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "UPDATE tracks SET album_art = NULL, file_size = NULL, modified_ns = NULL;
+             VACUUM;",
+        )?;
+        Ok(())
+    }
+
+    /// Store metadata for a single track, adding it to the library if it is new.
+    pub fn store_metadata(&self, path: &Path, metadata: &SongMetadata, file_size: u64, modified: SystemTime) -> Result<()> {
+        Self::upsert_track(&self.conn, path, metadata, file_size, to_nanos(modified))
+    }
+
+    fn upsert_track(conn: &Connection, path: &Path, metadata: &SongMetadata, file_size: u64, modified_ns: i64) -> Result<()> {
+        conn.execute("INSERT OR IGNORE INTO artists (name) VALUES (?1)", [&metadata.artist])?;
+        let artist_id: i64 = conn.query_row("SELECT id FROM artists WHERE name = ?1", [&metadata.artist], |row| row.get(0))?;
+        conn.execute(
+            "INSERT OR IGNORE INTO albums (title, artist_id) VALUES (?1, ?2)",
+            params![metadata.album, artist_id],
+        )?;
+        let album_id: i64 = conn.query_row(
+            "SELECT id FROM albums WHERE title = ?1 AND artist_id = ?2",
+            params![metadata.album, artist_id],
+            |row| row.get(0),
+        )?;
+
+        conn.execute(
+            "INSERT INTO tracks (path, title, artist_id, album_id, duration, album_art, file_size, modified_ns, added_at, genre, year)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+             ON CONFLICT(path) DO UPDATE SET
+                title = excluded.title,
+                genre = excluded.genre,
+                year = excluded.year,
+                artist_id = excluded.artist_id,
+                album_id = excluded.album_id,
+                duration = excluded.duration,
+                album_art = excluded.album_art,
+                file_size = excluded.file_size,
+                modified_ns = excluded.modified_ns",
+            params![
+                path_key(path),
+                metadata.title,
+                artist_id,
+                album_id,
+                metadata.duration.map(|d| d as f64),
+                metadata.album_art,
+                file_size as i64,
+                modified_ns,
+                to_nanos(SystemTime::now()) / 1_000_000_000,
+                metadata.genre,
+                metadata.year,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// One-time import of the JSON cache used by earlier versions.
+    pub fn import_legacy_cache(&mut self, path: &Path) -> Result<usize> {
+        let contents = std::fs::read_to_string(path)?;
+        let cache: LegacyFileCache = serde_json::from_str(&contents)?;
+
+        self.replace_files(&cache.directory, &cache.files)?;
+        let tx = self.conn.transaction()?;
+        for (path, cached) in &cache.metadata_cache {
+            Self::upsert_track(&tx, path, &cached.metadata, cached.file_size, to_nanos(cached.modified_time))?;
+        }
+        Self::set_info(&tx, "last_scan", &(to_nanos(cache.last_scan) / 1_000_000_000).to_string())?;
+        tx.commit()?;
+
+        Ok(cache.files.len())
+    }
+}
+
+/// The music library as a whole: scanning it, reading its tags, shuffling it
+/// and keeping its listening history. `db` gives the database underneath for
+/// everything else.
+pub struct Library {
+    db: LibraryDb,
+}
+
+impl Library {
+    /// Open the library in the config directory, shared with the app.
+    pub fn open() -> Result<Self> {
+        Ok(Self { db: LibraryDb::open()? })
+    }
+
+    /// Open or create a library database at `path`.
+    pub fn open_at(path: &Path) -> Result<Self> {
+        Ok(Self { db: LibraryDb::open_at(path)? })
+    }
+
+    /// Like `open`, checking the database first; see `LibraryDb::open_with_recovery`.
+    pub fn open_with_recovery() -> Result<(Self, Option<String>)> {
+        let (db, warning) = LibraryDb::open_with_recovery()?;
+        Ok((Self { db }, warning))
+    }
+
+    pub fn db(&self) -> &LibraryDb {
+        &self.db
+    }
+
+    /// Directory the library was last scanned from.
+    pub fn directory(&self) -> Result<Option<PathBuf>> {
+        self.db.directory()
+    }
+
+    /// Every track in the library, sorted by path.
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        self.db.files()
+    }
+
+    /// The tracks of the last scan if it was of `directory`, or `None` when
+    /// the library was last scanned from somewhere else.
+    pub fn files_in(&self, directory: &Path) -> Result<Option<Vec<PathBuf>>> {
+        if self.directory()?.as_deref() != Some(directory) {
+            return Ok(None);
+        }
+        self.files().map(Some)
+    }
+
+    pub fn track_stats(&self) -> Result<HashMap<PathBuf, TrackStats>> {
+        self.db.track_stats()
+    }
+
+    pub fn listening_stats(&self, range: StatsRange) -> Result<ListeningStats> {
+        self.db.listening_stats(range)
+    }
+
+    /// Rate a track, and with `sync_tags` store the rating in the file's tags
+    /// too. When only the tags couldn't be written the library keeps the
+    /// rating; the error says so.
+    pub fn rate(&self, path: &Path, rating: Option<u8>, sync_tags: bool) -> Result<()> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.db.set_rating(path, rating).map_err(|e| anyhow!("Could not save the rating of {}: {}", name, e))?;
+        if sync_tags {
+            metadata::write_rating(path, rating).map_err(|e| match e.downcast_ref::<metadata::WriteDeferred>() {
+                Some(_) => anyhow!("{} is open, so its rating goes into its tags once it stops playing", name),
+                None => anyhow!("Could not write the rating to {} ({}); the library still has it", name, e),
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn set_banned(&self, path: &Path, banned: bool) -> Result<()> {
+        self.db.set_banned(path, banned)
+    }
+
+    /// Scan `directory` and make its music files the library's tracks. Progress
+    /// is reported on `events` as the scan goes, ending with `Finished` once the
+    /// tracks are saved, `Cancelled` or `Failed`. Tags are read later, as needed.
+    pub fn scan(&mut self, directory: &Path, options: &ScanOptions, cancel: &CancelToken, events: &Sender<ScanEvent>) -> Result<()> {
+        let result = music::scan_music_directory_streaming(directory, options, cancel, events).and_then(|files| {
+            // A scan cancelled after its walk is as incomplete as one stopped
+            // midway, and a newer scan may already have saved its results
+            if cancel.is_cancelled() {
+                let _ = events.send(ScanEvent::Cancelled);
+                return Err(anyhow!("Scan cancelled"));
+            }
+            self.db.replace_files(directory, &files)?;
+            Ok(files)
+        });
+        match result {
+            Ok(files) => {
+                let _ = events.send(ScanEvent::Finished(files));
+                Ok(())
+            }
+            Err(e) => {
+                if !cancel.is_cancelled() {
+                    let _ = events.send(ScanEvent::Failed(e.to_string()));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Run `scan` on a thread of its own with its own connection, returning
+    /// where its events arrive. Failing to open the library ends them with `Failed`.
+    pub fn spawn_scan(directory: PathBuf, options: ScanOptions, cancel: CancelToken) -> Receiver<ScanEvent> {
+        let (events, receiver) = mpsc::channel();
+        thread::spawn(move || match Library::open() {
+            // `scan` reports its own failures
+            Ok(mut library) => {
+                if let Err(e) = library.scan(&directory, &options, &cancel, &events) {
+                    eprintln!("Failed to scan {}: {}", directory.display(), e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not open library: {}", e);
+                if !cancel.is_cancelled() {
+                    let _ = events.send(ScanEvent::Failed(e.to_string()));
+                }
+            }
+        });
+        receiver
+    }
+
+    /// The tracks with their stored tags for browsing and filters, after
+    /// reading the tags of files that have none stored; see `read_missing_tags`.
+    pub fn index(&self, sync_ratings: bool, progress: impl FnMut(usize, usize)) -> Result<LibraryIndex> {
+        self.read_missing_tags(sync_ratings, progress);
+        Ok(LibraryIndex::new(self.db.tracks()?))
+    }
+
+    /// A reader that caches tags in this library.
+    pub fn metadata_reader(&self, sync_ratings: bool) -> MetadataReader<'_> {
+        MetadataReader::new(Some(&self.db), sync_ratings)
+    }
+
+    /// Read the tags of tracks whose tags were never stored, so the library's
+    /// columns can answer filters and searches. `progress` is told how many of
+    /// how many files are done.
+    pub fn read_missing_tags(&self, sync_ratings: bool, mut progress: impl FnMut(usize, usize)) {
+        let files = match self.db.untagged_files() {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Could not list untagged tracks: {}", e);
+                return;
+            }
+        };
+        let reader = self.metadata_reader(sync_ratings);
+        for (i, path) in files.iter().enumerate() {
+            reader.read(path);
+            progress(i + 1, files.len());
+        }
+    }
+
+    /// A generator for playlists of `count` tracks that leaves out the duplicates
+    /// chosen in the app and tracks banned from shuffles.
+    pub fn playlist_generator(&self, count: usize) -> Result<PlaylistGenerator> {
+        Ok(PlaylistGenerator::new(count)
+            .exclude(DuplicateReport::load().excluded_paths())
+            .exclude_banned(&self.db.track_stats()?))
+    }
+
+    /// A shuffled playlist of `count` tracks, narrowed by a filter expression
+    /// when there is one, with their tags. Album art is left out so long
+    /// playlists stay small.
+    pub fn shuffle(&self, count: usize, filter: Option<&str>, sync_ratings: bool) -> Result<Vec<Entry>> {
+        let files = self.files()?;
+        if files.is_empty() {
+            return Err(anyhow!("The library is empty"));
+        }
+        let reader = self.metadata_reader(sync_ratings);
+        let metadata_for = |path: &Path| SongMetadata { album_art: None, ..reader.read_or_placeholder(path) };
+
+        let pool = match filter.map(str::trim).filter(|filter| !filter.is_empty()) {
+            Some(filter) => {
+                let query = Query::parse(filter)?;
+                self.read_missing_tags(sync_ratings, |_, _| {});
+                let stats = self.track_stats()?;
+                let unknown = TrackStats::default();
+                self.db
+                    .tracks()?
+                    .into_iter()
+                    .filter(|(path, metadata)| query.matches(path, metadata, stats.get(path).unwrap_or(&unknown)))
+                    .map(|(path, _)| path)
+                    .collect()
+            }
+            None => files,
+        };
+
+        let picked = self.playlist_generator(count)?.generate(&pool);
+        if picked.is_empty() {
+            return Err(anyhow!("No tracks to shuffle"));
+        }
+        Ok(picked
+            .into_iter()
+            .map(|path| {
+                let metadata = metadata_for(&path);
+                (path, metadata)
+            })
+            .collect())
+    }
+
+    /// Tracks whose tags or path contain `text`; see `LibraryDb::search`.
+    pub fn search(&self, text: &str, limit: usize) -> Result<Vec<Entry>> {
+        self.db.search(text, limit)
+    }
+
+    pub fn record_listen(&self, listen: &Listen) -> Result<()> {
+        self.db.record_play(&listen.path, listen.seconds, listen.skipped)
+    }
+
+    /// Record every listen `events` brings on a thread of its own, keeping
+    /// this library's connection open, until the engine sending them stops.
+    pub fn record_listens(self, events: Receiver<Event>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for event in events {
+                if let Event::Listened(listen) = event {
+                    if let Err(e) = self.record_listen(&listen) {
+                        eprintln!("Could not record play of {}: {}", listen.path.display(), e);
+                    }
+                }
+            }
+        })
+    }
+}
+
+// Layout of the old file_cache.json, kept only for the importer
+#[derive(Deserialize)]
+struct LegacyCachedMetadata {
+    metadata: SongMetadata,
+    file_size: u64,
+    modified_time: SystemTime,
+}
+
+#[derive(Deserialize)]
+struct LegacyFileCache {
+    directory: PathBuf,
+    last_scan: SystemTime,
+    files: Vec<PathBuf>,
+    metadata_cache: HashMap<PathBuf, LegacyCachedMetadata>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_temp() -> (tempfile::TempDir, LibraryDb) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = LibraryDb::open_at(&dir.path().join("library.db")).unwrap();
+        (dir, db)
+    }
+
+    fn metadata(artist: &str, album: &str) -> SongMetadata {
+        SongMetadata {
+            title: "Song".to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn scans_that_miss_tracks_keep_their_history() {
+        let (_dir, mut db) = open_temp();
+        let played = PathBuf::from("/music/a.mp3");
+        let other = PathBuf::from("/music/b.mp3");
+        let elsewhere = PathBuf::from("/other/c.mp3");
+        db.replace_files(Path::new("/music"), &[played.clone(), other.clone()]).unwrap();
+        db.record_play(&played, 200.0, false).unwrap();
+        db.set_rating(&played, Some(4)).unwrap();
+
+        // Pointing at another directory, or at an unmounted drive, hides the tracks
+        db.replace_files(Path::new("/other"), std::slice::from_ref(&elsewhere)).unwrap();
+        assert_eq!(db.files().unwrap(), vec![elsewhere.clone()]);
+        assert!(!db.has_track(&played).unwrap());
+        assert_eq!(db.listening_stats(StatsRange::AllTime).unwrap().library_size, 1);
+
+        // and they come back as they were
+        db.replace_files(Path::new("/music"), &[played.clone(), other.clone()]).unwrap();
+        assert_eq!(db.files().unwrap(), vec![played.clone(), other]);
+        let stats = db.track_stats().unwrap();
+        assert_eq!(stats[&played].play_count, 1);
+        assert_eq!(stats[&played].rating, Some(4));
+    }
+
+    #[test]
+    fn tracks_played_from_outside_the_library_stay_out_of_it() {
+        let (_dir, mut db) = open_temp();
+        let inside = PathBuf::from("/music/a.mp3");
+        let outside = PathBuf::from("/downloads/b.mp3");
+        db.replace_files(Path::new("/music"), std::slice::from_ref(&inside)).unwrap();
+        db.record_play(&outside, 200.0, false).unwrap();
+        db.set_rating(&outside, Some(5)).unwrap();
+
+        assert_eq!(db.files().unwrap(), std::slice::from_ref(&inside));
+        assert!(!db.has_track(&outside).unwrap());
+        assert_eq!(db.listening_stats(StatsRange::AllTime).unwrap().library_size, 1);
+        let stats = db.track_stats().unwrap();
+        assert_eq!(stats[&outside].play_count, 1);
+        assert_eq!(stats[&outside].rating, Some(5));
+
+        // A scan that finds it brings it in with its history
+        db.replace_files(Path::new("/"), &[inside.clone(), outside.clone()]).unwrap();
+        assert_eq!(db.files().unwrap(), [outside.clone(), inside]);
+        assert_eq!(db.track_stats().unwrap()[&outside].play_count, 1);
+    }
+
+    #[test]
+    fn upgrading_takes_tracks_from_outside_the_directory_out_of_the_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.db");
+        {
+            // As version 5 left them: played tracks from elsewhere weren't missing
+            let mut db = LibraryDb::connect(&path).unwrap();
+            for migration in &MIGRATIONS[..5] {
+                db.conn.execute_batch(migration).unwrap();
+            }
+            db.conn.pragma_update(None, "user_version", 5).unwrap();
+            db.replace_files(Path::new("/music/"), &[PathBuf::from("/music/a.mp3")]).unwrap();
+            for path in ["/downloads/b.mp3", "/musical/c.mp3"] {
+                db.conn.execute("INSERT INTO tracks (path, added_at) VALUES (?1, 0)", [path]).unwrap();
+            }
+        }
+        let db = LibraryDb::open_at(&path).unwrap();
+        assert_eq!(db.files().unwrap(), [PathBuf::from("/music/a.mp3")]);
+    }
+
+    #[test]
+    fn scans_drop_artists_and_albums_no_track_uses() {
+        let (_dir, mut db) = open_temp();
+        let path = PathBuf::from("/music/a.mp3");
+        db.replace_files(Path::new("/music"), std::slice::from_ref(&path)).unwrap();
+        db.store_metadata(&path, &metadata("Old Artist", "Old Album"), 1, SystemTime::now()).unwrap();
+        db.store_metadata(&path, &metadata("New Artist", "New Album"), 1, SystemTime::now()).unwrap();
+        db.replace_files(Path::new("/music"), &[path]).unwrap();
+
+        let names = |sql: &str| -> Vec<String> {
+            let mut stmt = db.conn.prepare(sql).unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.map(|r| r.unwrap()).collect()
+        };
+        assert_eq!(names("SELECT name FROM artists"), vec!["New Artist"]);
+        assert_eq!(names("SELECT title FROM albums"), vec!["New Album"]);
+    }
+
+    #[test]
+    fn filters_use_the_stored_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut library = Library::open_at(&dir.path().join("library.db")).unwrap();
+        let jazz = PathBuf::from("/music/jazz.mp3");
+        let rock = PathBuf::from("/music/rock.mp3");
+        library.db.replace_files(Path::new("/music"), &[jazz.clone(), rock.clone()]).unwrap();
+        assert_eq!(library.db.untagged_files().unwrap(), vec![jazz.clone(), rock.clone()]);
+
+        // The files don't exist, so only the stored tags can match
+        library.db.store_metadata(&jazz, &metadata("Miles Davis", "Kind of Blue"), 1, SystemTime::now()).unwrap();
+        library.db.store_metadata(&rock, &metadata("The Who", "Tommy"), 1, SystemTime::now()).unwrap();
+        assert!(library.db.untagged_files().unwrap().is_empty());
+
+        let picked: Vec<PathBuf> = library.shuffle(10, Some("artist:miles"), false).unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(picked, vec![jazz]);
+        assert_eq!(library.shuffle(10, None, false).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn listens_are_recorded_until_the_events_stop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.db");
+        let track = PathBuf::from("/music/a.mp3");
+        let mut library = Library::open_at(&path).unwrap();
+        library.db.replace_files(Path::new("/music"), std::slice::from_ref(&track)).unwrap();
+
+        let (events, receiver) = std::sync::mpsc::sync_channel(4);
+        let recorder = library.record_listens(receiver);
+        for skipped in [false, true] {
+            events.send(Event::Listened(Listen { path: track.clone(), seconds: 30.0, skipped })).unwrap();
+        }
+        events.send(Event::PositionChanged(Duration::ZERO)).unwrap();
+        drop(events);
+        recorder.join().unwrap();
+
+        let stats = LibraryDb::open_at(&path).unwrap().track_stats().unwrap();
+        assert_eq!((stats[&track].play_count, stats[&track].skip_count), (1, 1));
+    }
+
+    #[test]
+    fn scans_save_the_music_they_find() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        std::fs::create_dir_all(music.join("album")).unwrap();
+        for name in ["album/a.mp3", "b.flac", "cover.jpg"] {
+            std::fs::write(music.join(name), b"").unwrap();
+        }
+        let mut library = Library::open_at(&dir.path().join("library.db")).unwrap();
+
+        let (events, receiver) = mpsc::channel();
+        library.scan(&music, &ScanOptions::default(), &CancelToken::new(), &events).unwrap();
+        let found = vec![music.join("album/a.mp3"), music.join("b.flac")];
+        assert!(receiver.try_iter().any(|event| matches!(event, ScanEvent::Finished(files) if files == found)));
+        assert_eq!(library.files_in(&music).unwrap(), Some(found));
+        assert_eq!(library.files_in(dir.path()).unwrap(), None);
+
+        // A cancelled scan leaves the last results alone
+        std::fs::write(music.join("c.mp3"), b"").unwrap();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert!(library.scan(&music, &ScanOptions::default(), &cancel, &events).is_err());
+        assert_eq!(library.files().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn backups_are_taken_after_migrations_and_daily() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.db");
+        let backup = backup_path(&path);
+        let (db, migrated) = LibraryDb::open_checked(&path).unwrap();
+        assert!(migrated);
+        assert!(backup_due(&backup));
+
+        db.backup_to(&backup).unwrap();
+        assert!(!backup_due(&backup));
+        drop(db);
+        let (_db, migrated) = LibraryDb::open_checked(&path).unwrap();
+        assert!(!migrated);
+    }
+}
@@ -1,336 +1,1921 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod music;
-mod audio;
-mod metadata;
+mod cli;
+#[cfg(target_os = "linux")]
+mod mpris;
+mod shortcuts;
 
+use clap::Parser;
 use eframe::egui;
+use egui_plot::{Bar, BarChart, Plot, Polygon};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use rfd::FileDialog;
-use audio::AudioPlayer;
-use metadata::SongMetadata;
-use directories::ProjectDirs;
-use symphonia::core::probe::Hint;
-use symphonia::default::get_probe;
-use std::fs::File;
-use symphonia::core::io::MediaSourceStream;
-use rodio::{Decoder, Source};
-use std::collections::HashMap;
-use std::time::SystemTime;
-use serde::{Serialize, Deserialize};
-use std::sync::{Arc, Mutex};
+use music_shuffler::audio::AudioPlayer;
+use music_shuffler::config::{get_config_path, Settings};
+use music_shuffler::library;
+use music_shuffler::engine::{Command, Event, PlayerEngine};
+use music_shuffler::player::{Listen, Player};
+use music_shuffler::config::{MpdSettings, RemoteSettings, ScrobbleSettings};
+use music_shuffler::mpd::MpdServer;
+use music_shuffler::remote::{self, RemoteServer};
+use music_shuffler::scrobble::{self, Scrobbler};
+use music_shuffler::{browser, duplicates, metadata, music, playlist_file, query, saved_playlists, session, smart_playlists, storage};
+use music_shuffler::{Library, MetadataReader, PlaylistGenerator};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
+// How often cached smart playlist and filter results that depend on track ages are redone
+const RULE_AGE_REFRESH: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq)]
+enum View {
+    Player,
+    Library,
+    SavedPlaylists,
+    SmartPlaylists,
+    Statistics,
+    Duplicates,
+}
+
+// Edits picked from a playlist row, applied once the list has been drawn
+enum RowAction {
+    Play(usize),
+    PlayNext(usize),
+    AddToQueue(usize),
+    Move(usize, usize), // (from, to)
+    Remove(usize),
+    Rate(usize, Option<u8>),
+    Ban(usize, bool),
+}
+
 struct MusicShuffler {
+    view: View,
     music_directory: Option<PathBuf>,
-    playlist: Vec<(PathBuf, SongMetadata)>,
-    current_song_index: usize,
+    engine: PlayerEngine,
+    player_events: mpsc::Receiver<Event>,
     music_files: Vec<PathBuf>,
-    audio_player: Option<AudioPlayer>,
     metadata_loading: bool,
-    pending_metadata: Arc<Mutex<Vec<(usize, PathBuf, SongMetadata)>>>,
     last_metadata_check: SystemTime,
     cached_progress: f32,
     cached_duration: f32,
     last_progress_update: SystemTime,
-    metadata_progress: Arc<Mutex<(usize, usize)>>, // (current, total)
     scanning: bool,
-    scan_progress: Arc<Mutex<String>>, // progress message
-    pending_scan_results: Arc<Mutex<Option<Vec<PathBuf>>>>,
+    scan_progress: music::ScanProgress,
+    scan_events: Option<mpsc::Receiver<music::ScanEvent>>,
+    scan_cancel: music::CancelToken,
+    scan_cancelled: bool,
+    scan_error: Option<String>, // why the last scan failed; its previous tracks were kept
+    settings: Settings,
+    library_warning: Option<String>,
+    rating_error: Arc<Mutex<Option<String>>>, // why the last rating couldn't be saved, or written to the file's tags
+    import_missing: Option<Vec<String>>,
+    saved_playlists: saved_playlists::SavedPlaylists,
+    new_playlist_name: String,
+    renaming_playlist: Option<(String, String)>, // (current name, edited name)
+    confirm_delete_playlist: Option<String>,
+    saved_playlist_error: Option<String>,
+    saved_as: Option<(String, u64)>, // name the playlist was last saved or loaded under, and the engine's load count then
+    playlist_saved: Option<String>, // shown after saving with the shortcut
+    duplicate_report: duplicates::DuplicateReport,
+    duplicate_options: duplicates::DetectionOptions,
+    duplicates_running: bool,
+    duplicate_progress: Arc<Mutex<(&'static str, usize, usize)>>, // (stage, current, total)
+    pending_duplicates: Arc<Mutex<Option<Vec<duplicates::DuplicateGroup>>>>,
+    shuffle_count: usize,
+    last_session_save: SystemTime,
+    library_index: Option<browser::LibraryIndex>,
+    library_index_loading: bool,
+    library_index_progress: Arc<Mutex<(usize, usize)>>, // (current, total)
+    pending_library_index: Arc<Mutex<Option<browser::LibraryIndex>>>,
+    browser_filter: browser::BrowserFilter,
+    browser_results: Option<(browser::BrowserFilter, browser::BrowserResults)>, // cached for the filter it was built from
+    rule_matches: HashMap<String, Result<Vec<PathBuf>, String>>, // rule expression -> matching tracks or parse error
+    rules_aged_at: Instant, // when rules on how long ago tracks were added or played were last re-evaluated
+    smart_playlists: smart_playlists::SmartPlaylists,
+    smart_name: String,
+    smart_rules: String,
+    smart_error: Option<String>,
+    track_stats: HashMap<PathBuf, library::TrackStats>,
+    stats_range: library::StatsRange,
+    listening_stats: Option<(library::StatsRange, library::ListeningStats)>, // cached for the range it was built from
+    remote: Option<RemoteServer>,
+    remote_applied: RemoteSettings, // what `remote` was started from, or left stopped for
+    remote_error: Option<String>,
+    mpd: Option<MpdServer>,
+    mpd_applied: MpdSettings, // what `mpd` was started from, or left stopped for
+    mpd_error: Option<String>,
+    scrobbler: Option<Scrobbler>,
+    scrobble_applied: ScrobbleSettings, // what `scrobbler` was started from
+    lastfm_password: String, // only held until logged in
+    lastfm_login: Option<mpsc::Receiver<Result<String, String>>>, // session key from a login in progress
+    lastfm_error: Option<String>,
+    refill_applied: Option<(usize, String, bool)>, // (count, filter, sync ratings) auto-extend was given
+    show_shortcuts: bool,
+    focus_library_search: bool, // set by the search shortcut until the field is shown
+    #[cfg(target_os = "linux")]
+    mpris: Option<mpris::Mpris>,
 }
 
 impl Default for MusicShuffler {
     fn default() -> Self {
+        let settings = Settings::load();
+        let engine = PlayerEngine::start(Player::new(AudioPlayer::new().ok()), settings.repeat_mode);
+        let player_events = engine.subscribe();
+        // Listens are stored even while the window isn't redrawn
+        match Library::open() {
+            Ok(library) => {
+                library.record_listens(engine.subscribe());
+            }
+            Err(e) => eprintln!("Could not open library: {}", e),
+        }
         Self {
+            view: View::Player,
             music_directory: None,
-            playlist: Vec::new(),
-            current_song_index: 0,
+            engine,
+            player_events,
             music_files: Vec::new(),
-            audio_player: AudioPlayer::new().ok(),
             metadata_loading: false,
-            pending_metadata: Arc::new(Mutex::new(Vec::new())),
             last_metadata_check: SystemTime::now(),
             cached_progress: 0.0,
             cached_duration: 0.0,
             last_progress_update: SystemTime::now(),
-            metadata_progress: Arc::new(Mutex::new((0, 0))),
             scanning: false,
-            scan_progress: Arc::new(Mutex::new(String::new())),
-            pending_scan_results: Arc::new(Mutex::new(None)),
+            scan_progress: music::ScanProgress::default(),
+            scan_events: None,
+            scan_cancel: music::CancelToken::new(),
+            scan_cancelled: false,
+            scan_error: None,
+            settings,
+            library_warning: None,
+            rating_error: Arc::new(Mutex::new(None)),
+            import_missing: None,
+            saved_playlists: saved_playlists::SavedPlaylists::load(),
+            new_playlist_name: String::new(),
+            renaming_playlist: None,
+            confirm_delete_playlist: None,
+            saved_playlist_error: None,
+            saved_as: None,
+            playlist_saved: None,
+            duplicate_report: duplicates::DuplicateReport::load(),
+            duplicate_options: duplicates::DetectionOptions::default(),
+            duplicates_running: false,
+            duplicate_progress: Arc::new(Mutex::new(("", 0, 0))),
+            pending_duplicates: Arc::new(Mutex::new(None)),
+            shuffle_count: 100,
+            last_session_save: SystemTime::now(),
+            library_index: None,
+            library_index_loading: false,
+            library_index_progress: Arc::new(Mutex::new((0, 0))),
+            pending_library_index: Arc::new(Mutex::new(None)),
+            browser_filter: browser::BrowserFilter::default(),
+            browser_results: None,
+            rule_matches: HashMap::new(),
+            rules_aged_at: Instant::now(),
+            smart_playlists: smart_playlists::SmartPlaylists::load(),
+            smart_name: String::new(),
+            smart_rules: String::new(),
+            smart_error: None,
+            track_stats: HashMap::new(),
+            stats_range: library::StatsRange::Month,
+            listening_stats: None,
+            remote: None,
+            remote_applied: RemoteSettings::default(),
+            remote_error: None,
+            mpd: None,
+            mpd_applied: MpdSettings::default(),
+            mpd_error: None,
+            scrobbler: None,
+            scrobble_applied: ScrobbleSettings::default(),
+            lastfm_password: String::new(),
+            lastfm_login: None,
+            lastfm_error: None,
+            refill_applied: None,
+            show_shortcuts: false,
+            focus_library_search: false,
+            #[cfg(target_os = "linux")]
+            mpris: None,
+        }
+    }
+}
+
+impl MusicShuffler {
+    fn save_directory(&self) {
+        if let Some(dir) = &self.music_directory {
+            if let Some(config_path) = get_config_path() {
+                if let Err(e) = storage::write_atomic(&config_path, dir.to_string_lossy().as_bytes()) {
+                    eprintln!("Could not save directory: {}", e);
+                }
+            }
+        }
+    }
+    fn load_directory(&mut self) {
+        if let Some(config_path) = get_config_path() {
+            if let Ok(contents) = std::fs::read_to_string(config_path) {
+                let path = std::path::PathBuf::from(contents.trim());
+                if path.exists() && path.is_dir() {
+                    self.music_directory = Some(path.clone());
+                    
+                    // Try to load from the library first
+                    match Library::open_with_recovery() {
+                        Ok((library, warning)) => {
+                            self.track_stats = library.track_stats().unwrap_or_default();
+                            match library.files_in(&path) {
+                                Ok(Some(files)) => {
+                                    println!("Loaded {} files from library", files.len());
+                                    self.music_files = files;
+                                }
+                                Ok(None) => println!("Library is for another directory - will scan on first playlist generation"),
+                                Err(e) => eprintln!("Could not load the library's tracks: {}", e),
+                            }
+                            
+                            // A library that had to be rebuilt is rescanned right away
+                            if warning.is_some() && self.music_files.is_empty() {
+                                self.start_scan(path);
+                            }
+                            self.library_warning = warning;
+                        }
+                        Err(e) => {
+                            eprintln!("Could not open library: {}", e);
+                            self.library_warning = Some(format!("Could not open the library database: {}", e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn format_time(secs: f32) -> String {
+    let total_seconds = secs as u64;
+    let hours = total_seconds / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let seconds = total_seconds % 60;
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn stars(rating: u8) -> String {
+    "★".repeat(rating as usize)
+}
+
+fn open_library() -> Option<Library> {
+    Library::open()
+        .map_err(|e| eprintln!("Could not open library: {}", e))
+        .ok()
+}
+
+// One column of the library browser; returns true when the selection changed
+fn facet_column(ui: &mut egui::Ui, title: &str, values: &[(String, usize)], selected: &mut Option<String>) -> bool {
+    let before = selected.clone();
+    ui.strong(title);
+    let total: usize = values.iter().map(|(_, count)| count).sum();
+    egui::ScrollArea::vertical()
+        .id_salt(title)
+        .max_height(150.0)
+        .auto_shrink([false, true])
+        .show_rows(ui, 18.0, values.len() + 1, |ui, row_range| {
+            for row in row_range {
+                if row == 0 {
+                    ui.selectable_value(selected, None, format!("All ({})", total));
+                    continue;
+                }
+                let (value, count) = &values[row - 1];
+                let label = if value.is_empty() { "(none)" } else { value.as_str() };
+                ui.selectable_value(selected, Some(value.clone()), format!("{} ({})", label, count));
+            }
+        });
+    *selected != before
+}
+
+// Hours and minutes for the statistics summary
+fn format_listening_time(secs: f64) -> String {
+    let minutes = (secs / 60.0).round() as u64;
+    if minutes < 60 {
+        format!("{} min", minutes)
+    } else {
+        format!("{} h {} min", minutes / 60, minutes % 60)
+    }
+}
+
+// A horizontal bar per entry, largest at the top, with the names down the side
+fn ranking_chart(ui: &mut egui::Ui, title: &str, entries: &[(String, u64)]) {
+    ui.strong(title);
+    if entries.is_empty() {
+        ui.label("Nothing played in this period");
+        return;
+    }
+    let count = entries.len();
+    let bars: Vec<Bar> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, (name, plays))| Bar::new((count - 1 - i) as f64, *plays as f64).name(name).width(0.7))
+        .collect();
+    let names: Vec<String> = entries.iter().rev().map(|(name, _)| name.clone()).collect();
+    Plot::new(title)
+        .height(24.0 * count as f32 + 30.0)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .allow_boxed_zoom(false)
+        .show_grid([true, false])
+        .include_x(0.0)
+        .y_axis_min_width(180.0)
+        .y_axis_formatter(move |mark, _range| {
+            let row = mark.value.round();
+            if (mark.value - row).abs() > 1e-6 || row < 0.0 {
+                return String::new();
+            }
+            names.get(row as usize).cloned().unwrap_or_default()
+        })
+        .show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars).horizontal()));
+}
+
+// Plays by weekday and hour, one shaded square per hour
+fn listening_heatmap(ui: &mut egui::Ui, by_hour: &[[u64; 24]; 7]) {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    ui.strong("Listening by Hour");
+    let busiest = by_hour.iter().flatten().copied().max().unwrap_or(0).max(1);
+    let accent = ui.visuals().selection.bg_fill;
+    let hovered = Plot::new("listening_heatmap")
+        .height(200.0)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .allow_boxed_zoom(false)
+        .show_grid(false)
+        .x_axis_formatter(|mark, _range| {
+            let hour = mark.value.round();
+            if (mark.value - hour).abs() > 1e-6 || !(0.0..24.0).contains(&hour) {
+                return String::new();
+            }
+            format!("{}:00", hour)
+        })
+        .y_axis_formatter(|mark, _range| {
+            // Rows are drawn Sunday at the top
+            let row = (mark.value - 0.5).round();
+            if (mark.value - 0.5 - row).abs() > 1e-6 || !(0.0..7.0).contains(&row) {
+                return String::new();
+            }
+            DAYS[6 - row as usize].to_string()
+        })
+        .show(ui, |plot_ui| {
+            for (day, hours) in by_hour.iter().enumerate() {
+                let y = (6 - day) as f64;
+                for (hour, &plays) in hours.iter().enumerate() {
+                    let x = hour as f64;
+                    let strength = 0.08 + 0.92 * plays as f32 / busiest as f32;
+                    let cell = vec![[x, y], [x + 0.95, y], [x + 0.95, y + 0.95], [x, y + 0.95]];
+                    plot_ui.polygon(
+                        Polygon::new(cell)
+                            .fill_color(accent.gamma_multiply(strength))
+                            .stroke(egui::Stroke::NONE)
+                            .allow_hover(false),
+                    );
+                }
+            }
+            plot_ui.pointer_coordinate()
+        })
+        .inner;
+
+    // Name the square under the pointer
+    let cell = hovered.and_then(|point| {
+        let (hour, row) = (point.x.floor(), point.y.floor());
+        ((0.0..24.0).contains(&hour) && (0.0..7.0).contains(&row)).then(|| (6 - row as usize, hour as usize))
+    });
+    match cell {
+        Some((day, hour)) => ui.label(format!("{} {}:00-{}:00: {} plays", DAYS[day], hour, hour + 1, by_hour[day][hour])),
+        None => ui.label("Hover over a square to see its plays"),
+    };
+}
+
+impl MusicShuffler {
+    fn check_tag_loading(&mut self) {
+        let loading = self.engine.tag_progress().is_some();
+        // Reading the tags stored any ratings found in them in the library
+        if self.metadata_loading && !loading && self.settings.sync_rating_tags {
+            self.reload_track_stats();
+        }
+        self.metadata_loading = loading;
+    }
+    
+    fn start_scan(&mut self, dir: PathBuf) {
+        // An older scan still walking must not save its results over this one's
+        self.scan_cancel.cancel();
+        let cancel = music::CancelToken::new();
+        self.scanning = true;
+        self.scan_cancelled = false;
+        self.scan_error = None;
+        self.scan_progress = music::ScanProgress::default();
+        println!("Scanning {}...", dir.display());
+        // Each scan gets its own channel, so events from an older one never arrive
+        self.scan_events = Some(Library::spawn_scan(dir, self.settings.scan_options, cancel.clone()));
+        self.scan_cancel = cancel;
+    }
+
+    fn check_scan_events(&mut self) {
+        let Some(rx) = &self.scan_events else {
+            return;
+        };
+
+        let mut done = false;
+        let mut finished = false;
+        while let Ok(event) = rx.try_recv() {
+            self.scan_progress.apply(&event);
+            match event {
+                music::ScanEvent::Finished(files) => {
+                    self.music_files = files;
+                    self.library_index = None;
+                    self.browser_results = None;
+                    finished = true;
+                    done = true;
+                    println!("Scan results received in UI thread");
+                }
+                music::ScanEvent::Cancelled => {
+                    self.scan_cancelled = true;
+                    done = true;
+                }
+                // The tracks from the last good scan stay
+                music::ScanEvent::Failed(message) => {
+                    self.scan_error = Some(message);
+                    done = true;
+                }
+                _ => {}
+            }
+        }
+
+        if done {
+            self.scanning = false;
+            self.scan_events = None;
+        }
+        if finished {
+            self.reload_track_stats();
+        }
+    }
+}
+
+impl MusicShuffler {
+    fn start_duplicate_detection(&mut self) {
+        self.duplicates_running = true;
+        let files = self.music_files.clone();
+        let options = self.duplicate_options;
+        let sync_ratings = self.settings.sync_rating_tags;
+        let progress = Arc::clone(&self.duplicate_progress);
+        let pending = Arc::clone(&self.pending_duplicates);
+
+        thread::spawn(move || {
+            let library = open_library();
+            let reader = match &library {
+                Some(library) => library.metadata_reader(sync_ratings),
+                None => MetadataReader::new(None, sync_ratings),
+            };
+            let groups = duplicates::find_duplicates(
+                &files,
+                &options,
+                |path| reader.read(path),
+                |stage, current, total| {
+                    if let Ok(mut progress) = progress.lock() {
+                        *progress = (stage, current, total);
+                    }
+                },
+            );
+
+            println!("Duplicate detection found {} groups", groups.len());
+            if let Ok(mut pending) = pending.lock() {
+                *pending = Some(groups);
+            }
+        });
+    }
+
+    // A shuffled selection of `shuffle_count` tracks from `pool`
+    fn shuffled_batch(&self, pool: &[PathBuf]) -> Vec<PathBuf> {
+        PlaylistGenerator::new(self.shuffle_count)
+            .exclude(self.duplicate_report.excluded_paths())
+            .exclude_banned(&self.track_stats)
+            .generate(pool)
+    }
+
+    // Tracks a shuffle may pick from: the whole library, or what the filter
+    // matches. `None` while the filter is invalid or still being evaluated.
+    fn shuffle_pool(&mut self) -> Option<Vec<PathBuf>> {
+        let query = self.settings.shuffle_query.clone();
+        if query.trim().is_empty() {
+            return Some(self.music_files.clone());
+        }
+        match self.evaluate_rules(&query) {
+            Some(Ok(paths)) => Some(paths.clone()),
+            _ => None,
+        }
+    }
+
+    // Tracks matching a rule expression, or why it doesn't parse. Results are
+    // cached until the library index changes, or for a minute with `added:` or
+    // `played:`; `None` while the index, which supplies the tags, is still being built.
+    fn evaluate_rules(&mut self, rules: &str) -> Option<&Result<Vec<PathBuf>, String>> {
+        let rules = rules.trim();
+        // Tracks age into and out of `added:<7d` without anything else changing
+        if self.rules_aged_at.elapsed() >= RULE_AGE_REFRESH {
+            self.rule_matches.retain(|cached, _| !query::Query::parse(cached).is_ok_and(|query| query.uses_ages()));
+            self.rules_aged_at = Instant::now();
+        }
+        if !self.rule_matches.contains_key(rules) {
+            let Some(index) = &self.library_index else {
+                if !self.library_index_loading && !self.scanning && !self.music_files.is_empty() {
+                    self.start_library_index();
+                }
+                return None;
+            };
+            let matches = query::Query::parse(rules)
+                .map(|query| index.query(&query, &self.track_stats))
+                .map_err(|e| e.to_string());
+
+            // Only keep results something still refers to, so typing doesn't pile them up
+            let (filter, editor, smart) = (self.settings.shuffle_query.trim(), self.smart_rules.trim(), &self.smart_playlists);
+            self.rule_matches.retain(|cached, _| cached == filter || cached == editor || smart.playlists.iter().any(|p| p.rules == *cached));
+            self.rule_matches.insert(rules.to_string(), matches);
+        }
+        self.rule_matches.get(rules)
+    }
+
+    fn show_query_bar(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Filter:");
+            let response = ui.add(egui::TextEdit::singleline(&mut self.settings.shuffle_query)
+                .hint_text("e.g. genre:jazz year:<1970 duration:>180 -album:live")
+                .desired_width(400.0));
+            if response.lost_focus() {
+                self.settings.save();
+            }
+
+            if self.settings.shuffle_query.trim().is_empty() {
+                ui.label(format!("All {} tracks", self.music_files.len()));
+                return;
+            }
+            let query = self.settings.shuffle_query.clone();
+            let loading = self.library_index_loading;
+            match self.evaluate_rules(&query) {
+                Some(Ok(paths)) => {
+                    ui.label(format!("{} matching tracks", paths.len()));
+                }
+                Some(Err(e)) => {
+                    ui.colored_label(egui::Color32::from_rgb(220, 80, 80), e);
+                }
+                None if loading => {
+                    ui.label("Reading tags...");
+                }
+                None => {
+                    ui.label("Waiting for the library scan");
+                }
+            }
+        });
+        ui.separator();
+    }
+
+    // Shuffle a new playlist from the library, scanning it first if needed
+    fn generate_playlist(&mut self) {
+        if self.metadata_loading || self.scanning {
+            return;
+        }
+        // First scan directory if not already done
+        if self.music_files.is_empty() {
+            if let Some(dir) = self.music_directory.clone() {
+                // Playlist generation waits for the next click
+                self.start_scan(dir);
+            } else {
+                println!("No directory selected");
+            }
+            return;
+        }
+
+        match self.shuffle_pool() {
+            Some(pool) => {
+                println!("Generating playlist...");
+                let files = self.shuffled_batch(&pool);
+                self.load_playlist(files);
+            }
+            None => println!("The shuffle filter isn't ready yet"),
+        }
+    }
+
+    // Replace the playlist with `files`, showing placeholders until the
+    // engine has read their tags
+    fn load_playlist(&mut self, files: Vec<PathBuf>) {
+        self.metadata_loading = !files.is_empty();
+        self.engine.load_files(files, self.settings.sync_rating_tags);
+    }
+
+    fn seek_to(&mut self, position: std::time::Duration) {
+        self.engine.send(Command::Seek(position));
+    }
+
+    // Seek `offset` seconds from the current position
+    fn seek_by(&mut self, offset: f32) {
+        let (position, duration) = {
+            let player = self.engine.player();
+            (player.position(), player.current().and_then(|(_, m)| m.duration))
+        };
+        let target = position.map(|p| p.as_secs_f32()).unwrap_or(0.0) + offset;
+        // Seeking past the end moves on to the next track
+        if duration.is_some_and(|d| target >= d) {
+            self.engine.send(Command::Next);
+        } else {
+            self.seek_to(std::time::Duration::from_secs_f32(target.max(0.0)));
+        }
+    }
+
+    fn change_volume(&mut self, change: f32) {
+        let volume = self.engine.player().audio().map(|audio| audio.volume());
+        if let Some(volume) = volume {
+            self.engine.send(Command::SetVolume((volume + change).clamp(0.0, 1.0)));
+        }
+    }
+
+    fn handle_shortcuts(&mut self, ctx: &egui::Context) {
+        for action in shortcuts::pressed(ctx, &self.settings.key_bindings) {
+            match action {
+                shortcuts::Action::PlayPause => self.engine.send(Command::TogglePlayback),
+                shortcuts::Action::SeekBack => self.seek_by(-10.0),
+                shortcuts::Action::SeekForward => self.seek_by(10.0),
+                shortcuts::Action::Previous => self.engine.send(Command::Previous),
+                shortcuts::Action::Next => self.engine.send(Command::Next),
+                shortcuts::Action::VolumeUp => self.change_volume(0.05),
+                shortcuts::Action::VolumeDown => self.change_volume(-0.05),
+                shortcuts::Action::Search => {
+                    self.view = View::Library;
+                    self.focus_library_search = true;
+                }
+                shortcuts::Action::GeneratePlaylist => self.generate_playlist(),
+                shortcuts::Action::SavePlaylist => self.quick_save_playlist(),
+                shortcuts::Action::Help => self.show_shortcuts = !self.show_shortcuts,
+            }
+        }
+    }
+
+    fn show_shortcuts_help(&mut self, ctx: &egui::Context) {
+        if !self.show_shortcuts {
+            return;
+        }
+        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+            self.show_shortcuts = false;
+        }
+        let bindings = &mut self.settings.key_bindings;
+        let mut changed = false;
+        egui::Window::new("Keyboard Shortcuts")
+            .open(&mut self.show_shortcuts)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                egui::Grid::new("shortcuts").num_columns(3).spacing([24.0, 6.0]).show(ui, |ui| {
+                    for action in shortcuts::Action::ALL {
+                        ui.label(action.label());
+                        let field = ui.add(egui::TextEdit::singleline(action.binding_mut(bindings)).desired_width(120.0));
+                        // Saved once the field is left, not on every letter
+                        changed |= field.lost_focus();
+                        match shortcuts::parse(action.binding(bindings)) {
+                            Ok(Some(shortcut)) => {
+                                ui.strong(ctx.format_shortcut(&shortcut));
+                            }
+                            Ok(None) => {
+                                ui.weak("Off");
+                            }
+                            Err(e) => {
+                                ui.colored_label(egui::Color32::from_rgb(220, 80, 80), e);
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+                ui.small("Write a key name after any of Ctrl, Shift and Alt, joined with \"+\", e.g. \"Ctrl+Shift+S\".");
+                ui.small("Keys: A-Z, 0-9, F1-F20, Space, Enter, Tab, Escape, Backspace, Delete, Insert, Home, End,");
+                ui.small("PageUp, PageDown, ArrowLeft/Right/Up/Down, Plus (or +), Minus, Equals, Comma, Period.");
+                ui.small("Leave one empty to turn it off. They are kept under \"key_bindings\" in settings.json.");
+                if ui.button("Restore Defaults").clicked() {
+                    *bindings = Default::default();
+                    changed = true;
+                }
+            });
+        if changed {
+            self.settings.save();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start_mpris(&mut self, ctx: egui::Context) {
+        match mpris::Mpris::start(self.engine.clone(), ctx) {
+            Ok(mpris) => self.mpris = Some(mpris),
+            Err(e) => eprintln!("Media controls unavailable: {}", e),
+        }
+    }
+
+    // Start, restart or stop the remote control to match the settings
+    fn apply_remote_settings(&mut self) {
+        // The old server has to give up the port first
+        self.remote = None;
+        self.remote_error = None;
+        let settings = &mut self.settings.remote;
+        if settings.enabled && settings.token.is_empty() {
+            settings.token = remote::generate_token();
+            self.settings.save();
+        }
+        self.remote_applied = self.settings.remote.clone();
+        if !self.remote_applied.enabled {
+            return;
+        }
+        match RemoteServer::start(&self.remote_applied, self.engine.clone(), self.settings.sync_rating_tags) {
+            Ok(server) => {
+                println!("Remote control at {}", server.url());
+                self.remote = Some(server);
+            }
+            Err(e) => {
+                eprintln!("Remote control unavailable: {}", e);
+                self.remote_error = Some(e.to_string());
+            }
+        }
+    }
+
+    // Start, restart or stop the MPD server to match the settings
+    fn apply_mpd_settings(&mut self) {
+        // The old server has to give up the port first
+        self.mpd = None;
+        self.mpd_error = None;
+        self.mpd_applied = self.settings.mpd.clone();
+        if !self.mpd_applied.enabled {
+            return;
+        }
+        match MpdServer::start(&self.mpd_applied, self.engine.clone(), self.settings.sync_rating_tags) {
+            Ok(server) => {
+                println!("MPD server on port {}", server.port());
+                self.mpd = Some(server);
+            }
+            Err(e) => {
+                eprintln!("MPD server unavailable: {}", e);
+                self.mpd_error = Some(e.to_string());
+            }
+        }
+    }
+
+    // Start, restart or stop scrobbling to match the settings
+    fn apply_scrobble_settings(&mut self) {
+        self.scrobbler = None;
+        self.scrobble_applied = self.settings.scrobble.clone();
+        if self.scrobble_applied.any_enabled() {
+            self.scrobbler = Some(Scrobbler::start(&self.scrobble_applied, &self.engine, self.settings.sync_rating_tags));
+        }
+    }
+
+    fn show_scrobble_menu(&mut self, ui: &mut egui::Ui) {
+        if let Some(result) = self.lastfm_login.as_ref().and_then(|login| login.try_recv().ok()) {
+            self.lastfm_login = None;
+            match result {
+                Ok(session_key) => {
+                    self.settings.scrobble.lastfm_session_key = session_key;
+                    self.lastfm_password.clear();
+                    self.lastfm_error = None;
+                }
+                Err(e) => self.lastfm_error = Some(e),
+            }
+        }
+
+        let settings = &mut self.settings.scrobble;
+        let mut fields = Vec::new();
+        ui.checkbox(&mut settings.listenbrainz, "Scrobble to ListenBrainz");
+        egui::Grid::new("listenbrainz_settings").num_columns(2).show(ui, |ui| {
+            ui.label("Server:");
+            fields.push(ui.text_edit_singleline(&mut settings.listenbrainz_url));
+            ui.end_row();
+            ui.label("User token:");
+            fields.push(ui.add(egui::TextEdit::singleline(&mut settings.listenbrainz_token).password(true)));
+            ui.end_row();
+        });
+
+        ui.separator();
+        ui.checkbox(&mut settings.lastfm, "Scrobble to Last.fm")
+            .on_hover_text("Or a compatible service such as Libre.fm, with its API URL");
+        egui::Grid::new("lastfm_settings").num_columns(2).show(ui, |ui| {
+            ui.label("API URL:");
+            fields.push(ui.text_edit_singleline(&mut settings.lastfm_url));
+            ui.end_row();
+            ui.label("API key:");
+            fields.push(ui.text_edit_singleline(&mut settings.lastfm_api_key));
+            ui.end_row();
+            ui.label("API secret:");
+            fields.push(ui.add(egui::TextEdit::singleline(&mut settings.lastfm_api_secret).password(true)));
+            ui.end_row();
+            ui.label("");
+            ui.label(egui::RichText::new("Stored unencrypted in settings.json, like the tokens").small().weak());
+            ui.end_row();
+            if settings.lastfm_session_key.is_empty() {
+                ui.label("Username:");
+                fields.push(ui.text_edit_singleline(&mut settings.lastfm_username));
+                ui.end_row();
+                ui.label("Password:");
+                fields.push(ui.add(egui::TextEdit::singleline(&mut self.lastfm_password).password(true)));
+                ui.end_row();
+            }
+        });
+        if !settings.lastfm_session_key.is_empty() {
+            ui.horizontal(|ui| {
+                ui.label(format!("Logged in as {}", settings.lastfm_username));
+                if ui.small_button("Log Out").clicked() {
+                    settings.lastfm_session_key.clear();
+                }
+            });
+        } else if self.lastfm_login.is_some() {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Logging in...");
+            });
+            ui.ctx().request_repaint_after(std::time::Duration::from_millis(200));
+        } else if ui.button("Log In").clicked() {
+            let (tx, rx) = mpsc::channel();
+            let settings = settings.clone();
+            let password = self.lastfm_password.clone();
+            thread::spawn(move || {
+                let result = scrobble::lastfm_login(&settings, &settings.lastfm_username, &password);
+                let _ = tx.send(result.map_err(|e| e.to_string()));
+            });
+            self.lastfm_login = Some(rx);
+        }
+        if let Some(error) = &self.lastfm_error {
+            ui.colored_label(egui::Color32::from_rgb(220, 80, 80), error);
+        }
+
+        // Restart once nothing is being typed
+        let editing = fields.iter().any(|field| field.has_focus());
+        if self.settings.scrobble != self.scrobble_applied && !editing {
+            self.settings.save();
+            self.apply_scrobble_settings();
+        }
+        let queued = self.scrobbler.as_ref().map_or(0, |scrobbler| scrobbler.queued());
+        let refused = self.scrobbler.as_ref().map(Scrobbler::refused).unwrap_or_default();
+        if queued > 0 || !refused.is_empty() {
+            ui.separator();
+        }
+        for message in &refused {
+            ui.colored_label(egui::Color32::from_rgb(220, 80, 80), message);
+        }
+        if queued > 0 {
+            ui.label(format!("{} listens waiting to be sent", queued));
+        }
+    }
+
+    fn show_remote_menu(&mut self, ui: &mut egui::Ui) {
+        let settings = &mut self.settings.remote;
+        ui.checkbox(&mut settings.enabled, "Enable remote control");
+        ui.checkbox(&mut settings.lan, "Allow other devices on the network")
+            .on_hover_text("Otherwise only this computer can connect");
+        let port = ui.horizontal(|ui| {
+            ui.label("Port:");
+            ui.add(egui::DragValue::new(&mut settings.port).range(1024..=65535))
+        }).inner;
+        ui.horizontal(|ui| {
+            ui.label("Token:");
+            ui.monospace(if settings.token.is_empty() { "(made when enabled)" } else { settings.token.as_str() });
+            if ui.small_button("New").on_hover_text("Devices using the old token have to enter the new one").clicked() {
+                settings.token = remote::generate_token();
+            }
+        });
+
+        // Restart once the port is no longer being edited
+        let editing_port = port.dragged() || port.has_focus();
+        if self.settings.remote != self.remote_applied && !editing_port {
+            self.settings.save();
+            self.apply_remote_settings();
+        }
+
+        if let Some(server) = &self.remote {
+            ui.separator();
+            let url = server.url();
+            ui.label("Open on a phone or browser:");
+            ui.hyperlink_to(&url, &url);
+            if ui.small_button("Copy Link").clicked() {
+                ui.ctx().copy_text(url);
+            }
+        } else if let Some(error) = &self.remote_error {
+            ui.separator();
+            ui.colored_label(egui::Color32::from_rgb(220, 80, 80), error);
+        }
+
+        ui.separator();
+        let settings = &mut self.settings.mpd;
+        ui.checkbox(&mut settings.enabled, "Enable MPD server")
+            .on_hover_text("For MPD clients such as ncmpcpp, M.A.L.P. or Cantata");
+        ui.checkbox(&mut settings.lan, "Allow MPD clients on the network");
+        let (port, password) = ui.horizontal(|ui| {
+            ui.label("Port:");
+            let port = ui.add(egui::DragValue::new(&mut settings.port).range(1024..=65535));
+            ui.label("Password:");
+            let password = ui.add(egui::TextEdit::singleline(&mut settings.password).hint_text("none").desired_width(100.0));
+            (port, password)
+        }).inner;
+
+        let editing = port.dragged() || port.has_focus() || password.has_focus();
+        if self.settings.mpd != self.mpd_applied && !editing {
+            self.settings.save();
+            self.apply_mpd_settings();
+        }
+        if let Some(error) = &self.mpd_error {
+            ui.colored_label(egui::Color32::from_rgb(220, 80, 80), error);
+        }
+    }
+
+    fn note_listen(&mut self, listen: Listen) {
+        // The recorder started with the engine stores it; this keeps the
+        // in-memory stats current for smart playlists and filters
+        self.track_stats.entry(listen.path.clone()).or_default().record(listen.skipped, SystemTime::now());
+        self.rule_matches.clear();
+    }
+
+    fn set_rating(&mut self, path: PathBuf, rating: Option<u8>) {
+        self.track_stats.entry(path.clone()).or_default().rating = rating;
+        self.rule_matches.clear();
+        let sync = self.settings.sync_rating_tags;
+        let rating_error = Arc::clone(&self.rating_error);
+        thread::spawn(move || {
+            if let Some(Err(e)) = open_library().map(|library| library.rate(&path, rating, sync)) {
+                eprintln!("{}", e);
+                if let Ok(mut error) = rating_error.lock() {
+                    *error = Some(e.to_string());
+                }
+            }
+        });
+    }
+
+    fn set_banned(&mut self, path: PathBuf, banned: bool) {
+        self.track_stats.entry(path.clone()).or_default().banned = banned;
+        self.rule_matches.clear();
+        thread::spawn(move || {
+            if let Some(library) = open_library() {
+                if let Err(e) = library.set_banned(&path, banned) {
+                    eprintln!("Could not save ban of {}: {}", path.display(), e);
+                }
+            }
+        });
+    }
+
+    fn reload_track_stats(&mut self) {
+        if let Some(library) = open_library() {
+            self.track_stats = library.track_stats().unwrap_or_default();
+            self.rule_matches.clear();
+        }
+    }
+
+    fn handle_player_events(&mut self) {
+        let events: Vec<Event> = self.player_events.try_iter().collect();
+        for event in events {
+            match event {
+                Event::Listened(listen) => self.note_listen(listen),
+                Event::Error { path, message } => {
+                    eprintln!("{}", message);
+                    if path.is_some() {
+                        eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
+                    }
+                }
+                Event::TrackStarted { .. }
+                | Event::TrackEnded { .. }
+                | Event::PositionChanged(_)
+                | Event::PlaylistChanged
+                | Event::VolumeChanged(_) => {}
+            }
+        }
+    }
+
+    // Give the engine what auto-extend needs to shuffle the next batch from
+    // the library, whenever the shuffle settings change
+    fn apply_refill(&mut self) {
+        let wanted = (self.shuffle_count, self.settings.shuffle_query.clone(), self.settings.sync_rating_tags);
+        if self.refill_applied.as_ref() == Some(&wanted) {
+            return;
+        }
+        let (count, query, sync_ratings) = wanted.clone();
+        self.engine.send(Command::SetRefill(Some(Box::new(move || {
+            let entries = Library::open()?.shuffle(count, Some(&query), sync_ratings)?;
+            println!("Extending playlist with {} tracks", entries.len());
+            Ok(entries)
+        }))));
+        self.refill_applied = Some(wanted);
+    }
+
+    fn apply_row_action(&mut self, action: RowAction) {
+        match action {
+            RowAction::Play(i) => self.engine.send(Command::Play(Some(i))),
+            RowAction::PlayNext(i) => {
+                if let Some(entry) = self.engine.player().playlist.get(i).cloned() {
+                    self.engine.send(Command::Enqueue { entries: vec![entry], next: true });
+                }
+            }
+            RowAction::AddToQueue(i) => {
+                if let Some(entry) = self.engine.player().playlist.get(i).cloned() {
+                    self.engine.send(Command::Enqueue { entries: vec![entry], next: false });
+                }
+            }
+            RowAction::Move(from, to) => self.engine.send(Command::Move { from, to }),
+            RowAction::Remove(i) => self.engine.send(Command::Remove(i)),
+            RowAction::Rate(i, rating) => {
+                let path = self.engine.player().playlist.get(i).map(|(path, _)| path.clone());
+                if let Some(path) = path {
+                    self.set_rating(path, rating);
+                }
+            }
+            RowAction::Ban(i, banned) => {
+                let path = self.engine.player().playlist.get(i).map(|(path, _)| path.clone());
+                if let Some(path) = path {
+                    self.set_banned(path, banned);
+                }
+            }
+        }
+    }
+
+    fn show_up_next(&mut self, ui: &mut egui::Ui) {
+        // Copied out so the engine isn't kept waiting while this is drawn
+        let titles: Vec<String> = self.engine.player().up_next.iter().map(|(_, metadata)| metadata.title.clone()).collect();
+        if titles.is_empty() {
+            return;
+        }
+        let mut remove = None;
+        let mut clear = false;
+        egui::CollapsingHeader::new(format!("Up Next ({})", titles.len()))
+            .id_salt("up_next")
+            .default_open(true)
+            .show(ui, |ui| {
+                for (i, title) in titles.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.small_button("✖").on_hover_text("Remove from queue").clicked() {
+                            remove = Some(i);
+                        }
+                        ui.label(title);
+                    });
+                }
+                if ui.small_button("Clear Queue").clicked() {
+                    clear = true;
+                }
+            });
+        if clear {
+            self.engine.send(Command::Unqueue(None));
+        } else if let Some(i) = remove {
+            self.engine.send(Command::Unqueue(Some(i)));
+        }
+        ui.separator();
+    }
+
+    fn show_playlist(&mut self, ui: &mut egui::Ui) {
+        self.show_up_next(ui);
+
+        let mut action = None;
+        let (len, current) = {
+            let player = self.engine.player();
+            (player.playlist.len(), player.current_index())
+        };
+        let available_height = ui.available_height();
+        egui::ScrollArea::vertical()
+            .max_height(available_height)
+            .show_rows(ui, 20.0, len, |ui, row_range| {
+                // Only the visible rows are copied out, and the engine isn't kept
+                // waiting while they are drawn
+                let rows: Vec<(usize, PathBuf, String)> = {
+                    let player = self.engine.player();
+                    row_range
+                        .filter_map(|i| player.playlist.get(i).map(|(path, metadata)| (i, path.clone(), metadata.title.clone())))
+                        .collect()
+                };
+                for (i, path, title) in rows {
+                    let is_current = current == i;
+                    let stats = self.track_stats.get(&path).copied().unwrap_or_default();
+                    let mut label = title;
+                    if let Some(rating) = stats.rating {
+                        label = format!("{}  {}", label, stars(rating));
+                    }
+                    if stats.banned {
+                        label = format!("{}  (banned)", label);
+                    }
+
+                    // Rows can be dragged onto each other to reorder them
+                    let inner = ui.dnd_drag_source(egui::Id::new(("playlist_row", i)), i, |ui| {
+                        ui.selectable_label(is_current, label)
+                    });
+                    let row = inner.response;
+
+                    if inner.inner.clicked() {
+                        action = Some(RowAction::Play(i));
+                    }
+                    if row.dnd_hover_payload::<usize>().is_some() {
+                        let stroke = ui.visuals().selection.stroke;
+                        ui.painter().hline(row.rect.x_range(), row.rect.top(), stroke);
+                    }
+                    if let Some(from) = row.dnd_release_payload::<usize>() {
+                        action = Some(RowAction::Move(*from, i));
+                    }
+
+                    row.context_menu(|ui| {
+                        if ui.button("Play Next").clicked() {
+                            action = Some(RowAction::PlayNext(i));
+                            ui.close_menu();
+                        }
+                        if ui.button("Add to Queue").clicked() {
+                            action = Some(RowAction::AddToQueue(i));
+                            ui.close_menu();
+                        }
+                        ui.separator();
+                        if ui.add_enabled(i > 0, egui::Button::new("Move Up")).clicked() {
+                            action = Some(RowAction::Move(i, i - 1));
+                            ui.close_menu();
+                        }
+                        if ui.add_enabled(i + 1 < len, egui::Button::new("Move Down")).clicked() {
+                            action = Some(RowAction::Move(i, i + 1));
+                            ui.close_menu();
+                        }
+                        if ui.add_enabled(!is_current, egui::Button::new("Remove")).clicked() {
+                            action = Some(RowAction::Remove(i));
+                            ui.close_menu();
+                        }
+                        ui.separator();
+                        ui.menu_button("Rating", |ui| {
+                            if ui.selectable_label(stats.rating.is_none(), "No rating").clicked() {
+                                action = Some(RowAction::Rate(i, None));
+                                ui.close_menu();
+                            }
+                            for rating in 1..=5 {
+                                if ui.selectable_label(stats.rating == Some(rating), stars(rating)).clicked() {
+                                    action = Some(RowAction::Rate(i, Some(rating)));
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                        let ban_label = if stats.banned { "Allow in Shuffles" } else { "Ban from Shuffles" };
+                        if ui.button(ban_label).clicked() {
+                            action = Some(RowAction::Ban(i, !stats.banned));
+                            ui.close_menu();
+                        }
+                    });
+                }
+            });
+
+        if let Some(action) = action {
+            self.apply_row_action(action);
+        }
+    }
+
+    fn export_playlist(&mut self) {
+        let Some(mut path) = FileDialog::new()
+            .add_filter("M3U8 playlist", &["m3u8"])
+            .add_filter("PLS playlist", &["pls"])
+            .add_filter("XSPF playlist", &["xspf"])
+            .set_file_name("playlist.m3u8")
+            .save_file()
+        else {
+            return;
+        };
+        // Not every platform's dialog adds the extension of the chosen filter
+        if playlist_file::PlaylistFormat::from_path(&path).is_none() {
+            path.set_extension("m3u8");
+        }
+        if let Err(e) = playlist_file::export(&path, &self.engine.player().playlist, self.settings.relative_playlist_paths) {
+            eprintln!("Error exporting playlist to {}: {}", path.display(), e);
+        }
+    }
+
+    fn import_playlist(&mut self) {
+        let Some(path) = FileDialog::new()
+            .add_filter("Playlists", &["m3u8", "m3u", "pls", "xspf"])
+            .pick_file()
+        else {
+            return;
+        };
+        match playlist_file::import(&path) {
+            Ok(result) => {
+                if !result.missing.is_empty() {
+                    self.import_missing = Some(result.missing);
+                }
+                // The playlist's own titles and durations are used as they are, as
+                // `play` on the command line does, instead of reading every file's tags
+                self.engine.send(Command::Load(result.entries));
+                self.metadata_loading = false;
+            }
+            Err(e) => eprintln!("Error importing playlist {}: {}", path.display(), e),
+        }
+    }
+
+    fn show_import_report(&mut self, ctx: &egui::Context) {
+        let Some(missing) = &self.import_missing else {
+            return;
+        };
+        let mut open = true;
+        egui::Window::new("Missing Playlist Entries")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.label(format!("{} entries could not be found and were skipped:", missing.len()));
+                egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    for entry in missing {
+                        ui.small(entry.as_str());
+                    }
+                });
+            });
+        if !open {
+            self.import_missing = None;
+        }
+    }
+
+    fn save_session(&self) {
+        let player = self.engine.player();
+        let session = session::Session {
+            playlist: player.playlist.iter().map(|(path, _)| path.clone()).collect(),
+            current_index: player.current_index(),
+            position_secs: player.position().map(|p| p.as_secs_f32()).unwrap_or(0.0),
+            playing: player.is_playing(),
+            shuffle_count: self.shuffle_count,
+        };
+        session.save();
+    }
+
+    fn restore_session(&mut self) {
+        let session = session::Session::load();
+        self.shuffle_count = session.shuffle_count.max(1);
+
+        // Tracks deleted since the last run are dropped
+        let Some(restored) = session.restore() else {
+            return;
+        };
+        println!("Restoring session with {} tracks", restored.playlist.len());
+        self.load_playlist(restored.playlist);
+        let paused = !session.playing || self.settings.restore_paused;
+        self.engine.send(Command::Resume { index: restored.index, position: restored.position, paused });
+    }
+
+    fn save_current_playlist(&mut self, name: &str) {
+        let tracks = self.engine.player().playlist.iter().map(|(path, _)| path.clone()).collect();
+        match self.saved_playlists.store(name, tracks) {
+            Ok(()) => {
+                self.saved_playlists.save();
+                self.saved_playlist_error = None;
+                self.saved_as = Some((name.trim().to_string(), self.engine.loads()));
+            }
+            Err(e) => self.saved_playlist_error = Some(e.to_string()),
+        }
+    }
+
+    // Save without asking for a name: the one the playlist was last saved or
+    // loaded under, unless it has been replaced since, or a new one
+    fn quick_save_playlist(&mut self) {
+        if self.engine.player().playlist.is_empty() {
+            return;
+        }
+        let name = match &self.saved_as {
+            Some((name, loads)) if *loads == self.engine.loads() && self.saved_playlists.get(name).is_some() => name.clone(),
+            _ => self.saved_playlists.unused_name("Playlist"),
+        };
+        self.save_current_playlist(&name);
+        self.playlist_saved = Some(match &self.saved_playlist_error {
+            Some(error) => format!("Could not save the playlist: {}", error),
+            None => format!("Saved the playlist as \"{}\"", name),
+        });
+    }
+
+    fn load_saved_playlist(&mut self, name: &str) {
+        let Some(saved) = self.saved_playlists.get(name) else {
+            return;
+        };
+        let (present, missing): (Vec<PathBuf>, Vec<PathBuf>) =
+            saved.tracks.iter().cloned().partition(|path| path.is_file());
+        if !missing.is_empty() {
+            self.import_missing = Some(missing.iter().map(|p| p.display().to_string()).collect());
+        }
+        self.load_playlist(present);
+        self.saved_as = Some((name.to_string(), self.engine.loads()));
+        self.view = View::Player;
+    }
+
+    fn show_saved_playlists_view(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Saved Playlists");
+        ui.horizontal(|ui| {
+            ui.label("Name:");
+            let name = ui.text_edit_singleline(&mut self.new_playlist_name);
+            let enter = name.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            let can_save = !self.engine.player().playlist.is_empty() && !self.new_playlist_name.trim().is_empty();
+            if ui.add_enabled(can_save, egui::Button::new("Save Current Playlist")).clicked() || (enter && can_save) {
+                let name = std::mem::take(&mut self.new_playlist_name);
+                self.save_current_playlist(&name);
+            }
+        });
+        if let Some(error) = &self.saved_playlist_error {
+            ui.colored_label(egui::Color32::from_rgb(230, 90, 90), error.as_str());
+        }
+        ui.separator();
+
+        if self.saved_playlists.playlists.is_empty() {
+            ui.label("No saved playlists yet");
+            return;
+        }
+
+        // Actions are collected first and applied after the list has been drawn
+        let mut load = None;
+        let mut rename = None;
+        let mut delete = None;
+        let mut append = None;
+        let names: Vec<String> = self.saved_playlists.playlists.iter().map(|p| p.name.clone()).collect();
+        egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+            for playlist in &self.saved_playlists.playlists {
+                ui.horizontal(|ui| {
+                    match &mut self.renaming_playlist {
+                        Some((current, edited)) if *current == playlist.name => {
+                            let response = ui.text_edit_singleline(edited);
+                            let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                            if ui.button("OK").clicked() || enter {
+                                rename = Some((current.clone(), edited.clone()));
+                            }
+                        }
+                        _ => {
+                            ui.strong(&playlist.name);
+                        }
+                    }
+                    ui.label(format!("{} tracks", playlist.tracks.len()));
+                    if ui.button("Load").clicked() {
+                        load = Some(playlist.name.clone());
+                    }
+                    if ui.button("Rename").clicked() {
+                        self.renaming_playlist = Some((playlist.name.clone(), playlist.name.clone()));
+                    }
+                    ui.menu_button("Append to", |ui| {
+                        for target in names.iter().filter(|n| **n != playlist.name) {
+                            if ui.button(target).clicked() {
+                                append = Some((playlist.name.clone(), target.clone()));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                    if self.confirm_delete_playlist.as_deref() == Some(playlist.name.as_str()) {
+                        if ui.button("Confirm Delete").clicked() {
+                            delete = Some(playlist.name.clone());
+                        }
+                        if ui.button("Cancel").clicked() {
+                            self.confirm_delete_playlist = None;
+                        }
+                    } else if ui.button("Delete").clicked() {
+                        self.confirm_delete_playlist = Some(playlist.name.clone());
+                    }
+                });
+            }
+        });
+
+        if let Some(name) = load {
+            self.load_saved_playlist(&name);
+        }
+        if let Some((old_name, new_name)) = rename {
+            match self.saved_playlists.rename(&old_name, &new_name) {
+                Ok(()) => {
+                    self.saved_playlists.save();
+                    self.renaming_playlist = None;
+                    self.saved_playlist_error = None;
+                    if let Some((name, _)) = self.saved_as.as_mut().filter(|(name, _)| *name == old_name) {
+                        *name = new_name.trim().to_string();
+                    }
+                }
+                Err(e) => self.saved_playlist_error = Some(e.to_string()),
+            }
+        }
+        if let Some(name) = delete {
+            self.saved_playlists.delete(&name);
+            self.saved_playlists.save();
+            self.confirm_delete_playlist = None;
+        }
+        if let Some((source, target)) = append {
+            match self.saved_playlists.append(&source, &target) {
+                Ok(()) => self.saved_playlists.save(),
+                Err(e) => self.saved_playlist_error = Some(e.to_string()),
+            }
         }
     }
-}
-
-fn get_config_path() -> Option<std::path::PathBuf> {
-    ProjectDirs::from("com", "yourorg", "music-shuffler")
-        .map(|proj_dirs| proj_dirs.config_dir().join("config.txt"))
-}
 
-// Cache entry for file metadata
-#[derive(Serialize, Deserialize, Clone)]
-struct CachedMetadata {
-    metadata: metadata::SongMetadata,
-    file_size: u64,
-    modified_time: SystemTime,
-}
+    fn show_smart_playlists_view(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Smart Playlists");
+        ui.label("Rules use the shuffle filter syntax, e.g. `added:<7d` or `rating:>=4 played:>30d`");
+        ui.horizontal(|ui| {
+            ui.label("Name:");
+            ui.add(egui::TextEdit::singleline(&mut self.smart_name).desired_width(150.0));
+            ui.label("Rules:");
+            ui.add(egui::TextEdit::singleline(&mut self.smart_rules).desired_width(300.0));
+            let can_save = !self.smart_name.trim().is_empty() && !self.smart_rules.trim().is_empty();
+            if ui.add_enabled(can_save, egui::Button::new("Save")).clicked() {
+                match self.smart_playlists.store(&self.smart_name, &self.smart_rules) {
+                    Ok(()) => {
+                        self.smart_playlists.save();
+                        self.smart_name.clear();
+                        self.smart_rules.clear();
+                        self.smart_error = None;
+                    }
+                    Err(e) => self.smart_error = Some(e.to_string()),
+                }
+            }
+        });
+        if !self.smart_rules.trim().is_empty() {
+            let rules = self.smart_rules.clone();
+            match self.evaluate_rules(&rules) {
+                Some(Ok(paths)) => {
+                    ui.label(format!("{} matching tracks", paths.len()));
+                }
+                Some(Err(e)) => {
+                    ui.colored_label(egui::Color32::from_rgb(230, 90, 90), e);
+                }
+                None => {
+                    ui.label("Reading tags...");
+                }
+            }
+        }
+        if let Some(error) = &self.smart_error {
+            ui.colored_label(egui::Color32::from_rgb(230, 90, 90), error.as_str());
+        }
+        ui.separator();
 
-// Cache for scanned file list
-#[derive(Serialize, Deserialize)]
-struct FileCache {
-    directory: std::path::PathBuf,
-    last_scan: SystemTime,
-    files: Vec<std::path::PathBuf>,
-    metadata_cache: HashMap<std::path::PathBuf, CachedMetadata>,
-}
+        if self.smart_playlists.playlists.is_empty() {
+            ui.label("No smart playlists yet");
+            return;
+        }
 
-// Simple file-based cache for metadata
-fn get_cache_file_path() -> Option<std::path::PathBuf> {
-    get_config_path().map(|p| p.parent().unwrap().join("file_cache.json"))
-}
+        // Matches are worked out before drawing so the list can borrow them freely
+        let playlists = self.smart_playlists.playlists.clone();
+        let counts: Vec<Option<Result<usize, String>>> = playlists
+            .iter()
+            .map(|p| self.evaluate_rules(&p.rules).map(|r| r.as_ref().map(Vec::len).map_err(Clone::clone)))
+            .collect();
 
-fn load_file_cache() -> Option<FileCache> {
-    if let Some(cache_path) = get_cache_file_path() {
-        if let Ok(contents) = std::fs::read_to_string(cache_path) {
-            if let Ok(cache) = serde_json::from_str(&contents) {
-                return Some(cache);
+        let mut play = None;
+        let mut shuffle = None;
+        let mut use_as_filter = None;
+        let mut edit = None;
+        let mut delete = None;
+        egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+            for (playlist, count) in playlists.iter().zip(&counts) {
+                ui.horizontal(|ui| {
+                    ui.strong(&playlist.name);
+                    ui.monospace(&playlist.rules);
+                    let ready = matches!(count, Some(Ok(n)) if *n > 0);
+                    match count {
+                        Some(Ok(n)) => ui.label(format!("{} tracks", n)),
+                        Some(Err(e)) => ui.colored_label(egui::Color32::from_rgb(230, 90, 90), e),
+                        None => ui.label("..."),
+                    };
+                    if ui.add_enabled(ready, egui::Button::new("Play")).clicked() {
+                        play = Some(playlist.rules.clone());
+                    }
+                    if ui.add_enabled(ready, egui::Button::new("Shuffle")).clicked() {
+                        shuffle = Some(playlist.rules.clone());
+                    }
+                    if ui.button("Use as Filter")
+                        .on_hover_text("Make this the pool for Generate Playlist")
+                        .clicked()
+                    {
+                        use_as_filter = Some(playlist.rules.clone());
+                    }
+                    if ui.button("Edit").clicked() {
+                        edit = Some(playlist.clone());
+                    }
+                    if ui.button("Delete").clicked() {
+                        delete = Some(playlist.name.clone());
+                    }
+                });
             }
-        }
-    }
-    None
-}
+        });
 
-fn save_file_cache(cache: &FileCache) {
-    if let Some(cache_path) = get_cache_file_path() {
-        if let Some(parent) = cache_path.parent() {
-            let _ = std::fs::create_dir_all(parent);
+        let matches = |app: &mut Self, rules: &str| match app.evaluate_rules(rules) {
+            Some(Ok(paths)) => paths.clone(),
+            _ => Vec::new(),
+        };
+        if let Some(rules) = play {
+            let files = matches(self, &rules);
+            self.load_playlist(files);
+            self.view = View::Player;
+        }
+        if let Some(rules) = shuffle {
+            let pool = matches(self, &rules);
+            let files = self.shuffled_batch(&pool);
+            self.load_playlist(files);
+            self.view = View::Player;
         }
-        if let Ok(contents) = serde_json::to_string(cache) {
-            let _ = std::fs::write(cache_path, contents);
-            println!("Cache saved with {} files and {} metadata entries", 
-                     cache.files.len(), cache.metadata_cache.len());
+        if let Some(rules) = use_as_filter {
+            self.settings.shuffle_query = rules;
+            self.settings.save();
+            self.view = View::Player;
+        }
+        if let Some(playlist) = edit {
+            self.smart_name = playlist.name;
+            self.smart_rules = playlist.rules;
+        }
+        if let Some(name) = delete {
+            self.smart_playlists.delete(&name);
+            self.smart_playlists.save();
         }
     }
-}
 
-fn is_cache_valid(cache: &FileCache, current_dir: &std::path::Path) -> bool {
-    // Check if directory matches
-    if cache.directory != current_dir {
-        return false;
+    fn check_pending_duplicates(&mut self) {
+        if let Ok(mut pending) = self.pending_duplicates.try_lock() {
+            if let Some(mut groups) = pending.take() {
+                for group in &mut groups {
+                    group.apply_preference(self.duplicate_report.preference);
+                }
+                self.duplicate_report.groups = groups;
+                self.duplicate_report.save();
+                self.duplicates_running = false;
+            }
+        }
     }
-    
-    // Check if cache is not too old (optional - could scan daily)
-    // For now, trust the cache until the directory changes
-    
-    // Could add more sophisticated validation here
-    true
-}
 
-fn get_file_info(path: &std::path::Path) -> Option<(u64, SystemTime)> {
-    if let Ok(metadata) = std::fs::metadata(path) {
-        if let Ok(modified) = metadata.modified() {
-            return Some((metadata.len(), modified));
+    fn start_library_index(&mut self) {
+        self.library_index_loading = true;
+        let sync_ratings = self.settings.sync_rating_tags;
+        let progress = Arc::clone(&self.library_index_progress);
+        let pending = Arc::clone(&self.pending_library_index);
+        if let Ok(mut progress) = progress.lock() {
+            *progress = (0, 0);
         }
-    }
-    None
-}
 
-impl MusicShuffler {
-    fn save_directory(&self) {
-        if let Some(dir) = &self.music_directory {
-            if let Some(config_path) = get_config_path() {
-                let _ = std::fs::create_dir_all(config_path.parent().unwrap());
-                let _ = std::fs::write(config_path, dir.to_string_lossy().to_string());
+        thread::spawn(move || {
+            let index = Library::open().and_then(|library| {
+                library.index(sync_ratings, |done, total| {
+                    if let Ok(mut progress) = progress.lock() {
+                        *progress = (done, total);
+                    }
+                })
+            });
+            let index = index.unwrap_or_else(|e| {
+                eprintln!("Could not load the library: {}", e);
+                browser::LibraryIndex::new(Vec::new())
+            });
+            if let Ok(mut pending) = pending.lock() {
+                *pending = Some(index);
             }
+        });
+    }
+
+    fn check_pending_library_index(&mut self) {
+        let index = match self.pending_library_index.try_lock() {
+            Ok(mut pending) => pending.take(),
+            Err(_) => None,
+        };
+        if let Some(index) = index {
+            self.library_index = Some(index);
+            self.browser_results = None;
+            // Building the index may have imported ratings from tags
+            self.reload_track_stats();
+            self.library_index_loading = false;
         }
     }
-    fn load_directory(&mut self) {
-        if let Some(config_path) = get_config_path() {
-            if let Ok(contents) = std::fs::read_to_string(config_path) {
-                let path = std::path::PathBuf::from(contents.trim());
-                if path.exists() && path.is_dir() {
-                    self.music_directory = Some(path.clone());
-                    
-                    // Try to load from cache first
-                    if let Some(cache) = load_file_cache() {
-                        if is_cache_valid(&cache, &path) {
-                            println!("Loading {} files from cache...", cache.files.len());
-                            self.music_files = cache.files;
-                            println!("Cache loaded successfully!");
-                        } else {
-                            println!("Cache invalid - directory changed");
-                        }
-                    } else {
-                        println!("No cache found - will scan on first playlist generation");
-                    }
+
+    fn show_library_view(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Library");
+        if self.library_index.is_none() {
+            if self.scanning {
+                ui.label("Waiting for the scan to finish...");
+            } else if self.library_index_loading {
+                if let Ok(progress) = self.library_index_progress.lock() {
+                    let (current, total) = *progress;
+                    let fraction = if total > 0 { current as f32 / total as f32 } else { 0.0 };
+                    let progress_bar = egui::ProgressBar::new(fraction)
+                        .text(format!("Reading tags: {}/{}", current, total));
+                    ui.add_sized([780.0, 20.0], progress_bar);
                 }
+            } else if self.music_files.is_empty() {
+                ui.label("Select a directory first");
+            } else {
+                self.start_library_index();
             }
+            return;
         }
-    }
-}
 
-fn format_time(secs: f32) -> String {
-    let total_seconds = secs as u64;
-    let hours = total_seconds / 3600;
-    let minutes = (total_seconds % 3600) / 60;
-    let seconds = total_seconds % 60;
-    if hours > 0 {
-        format!("{}:{:02}:{:02}", hours, minutes, seconds)
-    } else {
-        format!("{}:{:02}", minutes, seconds)
-    }
-}
+        ui.horizontal(|ui| {
+            ui.label("Search:");
+            let search = ui.add(egui::TextEdit::singleline(&mut self.browser_filter.search)
+                .hint_text("title, artist, album or path")
+                .desired_width(300.0));
+            if std::mem::take(&mut self.focus_library_search) {
+                search.request_focus();
+            }
+            if ui.button("Clear").clicked() {
+                self.browser_filter = browser::BrowserFilter::default();
+            }
+        });
 
-fn extract_duration_symphonia(path: &std::path::Path) -> Option<f32> {
-    // Try to extract duration using Symphonia
-    if let Ok(file) = File::open(path) {
-        let mss = MediaSourceStream::new(Box::new(file), Default::default());
-        let mut hint = Hint::new();
-        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-            hint.with_extension(ext);
+        // Results only change with the filter, so typing is the only time they're rebuilt
+        let stale = !matches!(&self.browser_results, Some((filter, _)) if *filter == self.browser_filter);
+        if stale {
+            if let Some(index) = &self.library_index {
+                let results = index.apply(&self.browser_filter);
+                self.browser_results = Some((self.browser_filter.clone(), results));
+            }
         }
-        
-        if let Ok(probe) = get_probe().format(
-            &hint,
-            mss,
-            &Default::default(),
-            &Default::default(),
-        ) {
-            let mut format = probe.format;
-            
-            // Try to get duration from metadata first
-            if let Some(metadata_rev) = format.metadata().current() {
-                for tag in metadata_rev.tags() {
-                    if tag.key.as_str().to_lowercase() == "duration" {
-                        if let Ok(duration_str) = tag.value.to_string().parse::<f32>() {
-                            return Some(duration_str);
-                        }
-                    }
-                }
+        let (Some(index), Some((_, results))) = (&self.library_index, &self.browser_results) else {
+            return;
+        };
+
+        let filter = &mut self.browser_filter;
+        let mut changed = None;
+        ui.columns(3, |columns| {
+            if facet_column(&mut columns[0], "Genre", &results.genres, &mut filter.genre) {
+                changed = Some(0);
             }
-            
-            // Try to calculate duration from track parameters
-            if let Some(track) = format.default_track() {
-                let params = &track.codec_params;
-                
-                // Method 1: Use n_frames and sample_rate
-                if let (Some(n_frames), Some(sample_rate)) = (params.n_frames, params.sample_rate) {
-                    return Some(n_frames as f32 / sample_rate as f32);
-                }
-                
-                // Method 2: Use time_base and n_frames
-                if let (Some(n_frames), Some(time_base)) = (params.n_frames, params.time_base) {
-                    let duration_secs = n_frames as f64 * time_base.numer as f64 / time_base.denom as f64;
-                    return Some(duration_secs as f32);
-                }
+            if facet_column(&mut columns[1], "Artist", &results.artists, &mut filter.artist) {
+                changed = Some(1);
             }
+            facet_column(&mut columns[2], "Album", &results.albums, &mut filter.album);
+        });
+        // A new selection on the left makes the choices to its right meaningless
+        if let Some(column) = changed {
+            if column == 0 {
+                filter.artist = None;
+            }
+            filter.album = None;
         }
-    }
-    
-    // If Symphonia fails, try using rodio as a fallback
-    if let Ok(file) = File::open(path) {
-        let reader = std::io::BufReader::new(file);
-        if let Ok(decoder) = Decoder::new(reader) {
-            if let Some(duration) = decoder.total_duration() {
-                return Some(duration.as_secs_f32());
+        ui.separator();
+
+        let mut play = None;
+        let mut queue_front = None;
+        let mut queue_back = Vec::new();
+        let mut shuffle = false;
+        ui.horizontal(|ui| {
+            ui.label(format!("{} of {} tracks", results.tracks.len(), index.tracks.len()));
+            let any = !results.tracks.is_empty();
+            if ui.add_enabled(any, egui::Button::new("Shuffle Results"))
+                .on_hover_text("Generate a playlist from the tracks shown")
+                .clicked()
+            {
+                shuffle = true;
+            }
+            if ui.add_enabled(any, egui::Button::new("Queue All")).clicked() {
+                queue_back.extend(results.tracks.iter().copied());
             }
+        });
+
+        let available_height = ui.available_height();
+        egui::ScrollArea::vertical()
+            .id_salt("library_tracks")
+            .max_height(available_height)
+            .show_rows(ui, 20.0, results.tracks.len(), |ui, row_range| {
+                for row in row_range {
+                    let i = results.tracks[row];
+                    let metadata = &index.tracks[i].metadata;
+                    let response = ui.horizontal(|ui| {
+                        let response = ui.add_sized([300.0, 18.0], egui::Button::new(&metadata.title).frame(false).truncate());
+                        ui.add_sized([200.0, 18.0], egui::Label::new(egui::RichText::new(&metadata.artist).weak()).truncate());
+                        ui.add_sized([200.0, 18.0], egui::Label::new(egui::RichText::new(&metadata.album).weak()).truncate());
+                        if let Some(duration) = metadata.duration {
+                            ui.label(format_time(duration));
+                        }
+                        response
+                    }).inner;
+
+                    if response.clicked() {
+                        play = Some(i);
+                    }
+                    response.context_menu(|ui| {
+                        if ui.button("Play").clicked() {
+                            play = Some(i);
+                            ui.close_menu();
+                        }
+                        if ui.button("Play Next").clicked() {
+                            queue_front = Some(i);
+                            ui.close_menu();
+                        }
+                        if ui.button("Add to Queue").clicked() {
+                            queue_back.push(i);
+                            ui.close_menu();
+                        }
+                    });
+                }
+            });
+
+        let entry = |i: usize| (index.tracks[i].path.clone(), index.tracks[i].metadata.clone());
+        let play = play.map(entry);
+        let queue_front = queue_front.map(entry);
+        let queue_back: Vec<_> = queue_back.into_iter().map(entry).collect();
+        let pool = if shuffle { Some(index.paths(&results.tracks)) } else { None };
+
+        if let Some(entry) = queue_front {
+            self.engine.send(Command::Enqueue { entries: vec![entry], next: true });
+        }
+        if !queue_back.is_empty() {
+            self.engine.send(Command::Enqueue { entries: queue_back, next: false });
+        }
+        if let Some(entry) = play {
+            // Jump straight to the track through the front of the queue
+            self.engine.send(Command::Enqueue { entries: vec![entry], next: true });
+            self.engine.send(Command::Next);
+        }
+        if let Some(pool) = pool {
+            let files = self.shuffled_batch(&pool);
+            self.load_playlist(files);
+            self.view = View::Player;
         }
     }
-    
-    None
-}
 
-impl MusicShuffler {
-    fn check_pending_metadata(&mut self) {
-        let updates = if let Ok(mut pending) = self.pending_metadata.try_lock() {
-            let updates: Vec<_> = pending.drain(..).collect();
-            updates
-        } else {
+    fn show_statistics_view(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Statistics");
+        let mut refresh = false;
+        ui.horizontal(|ui| {
+            ui.label("Period:");
+            for range in library::StatsRange::ALL {
+                ui.selectable_value(&mut self.stats_range, range, range.label());
+            }
+            refresh = ui.button("Refresh").clicked();
+        });
+
+        let stale = self.listening_stats.as_ref().is_none_or(|(range, _)| *range != self.stats_range);
+        if stale || refresh {
+            let Some(library) = open_library() else {
+                ui.label("The library database isn't available.");
+                return;
+            };
+            match library.listening_stats(self.stats_range) {
+                Ok(stats) => self.listening_stats = Some((self.stats_range, stats)),
+                Err(e) => {
+                    eprintln!("Could not load listening statistics: {}", e);
+                    ui.label(format!("Could not load statistics: {}", e));
+                    return;
+                }
+            }
+        }
+        let Some((_, stats)) = &self.listening_stats else {
             return;
         };
+        ui.separator();
 
-        if !updates.is_empty() {
-            for (index, path, metadata) in updates {
-                if index < self.playlist.len() {
-                    self.playlist[index] = (path, metadata);
+        egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+            ui.label(format!(
+                "Listening time: {}  ·  {} plays  ·  {} skips",
+                format_listening_time(stats.seconds_played),
+                stats.plays,
+                stats.skips
+            ));
+            let heard = if stats.library_size > 0 { stats.tracks_heard as f32 / stats.library_size as f32 } else { 0.0 };
+            ui.horizontal(|ui| {
+                ui.label("Library heard:");
+                ui.add(egui::ProgressBar::new(heard).desired_width(300.0).text(format!(
+                    "{} of {} tracks ({:.1}%)",
+                    stats.tracks_heard,
+                    stats.library_size,
+                    heard * 100.0
+                )));
+            });
+            ui.add_space(8.0);
+
+            ranking_chart(ui, "Top Artists", &stats.top_artists);
+            ranking_chart(ui, "Top Albums", &stats.top_albums);
+            ranking_chart(ui, "Top Tracks", &stats.top_tracks);
+            ui.add_space(8.0);
+            listening_heatmap(ui, &stats.by_hour);
+            ui.add_space(8.0);
+
+            ui.columns(2, |columns| {
+                columns[0].strong("Most Skipped");
+                if stats.most_skipped.is_empty() {
+                    columns[0].label("No skips in this period");
+                }
+                for (track, skips) in &stats.most_skipped {
+                    columns[0].label(format!("{} ({} skips)", track, skips));
                 }
+                columns[1].strong("Never Coming Up");
+                columns[1].label("Artists with the most tracks not played in this period:");
+                for (artist, tracks) in &stats.unheard_artists {
+                    columns[1].label(format!("{} ({} tracks)", artist, tracks));
+                }
+            });
+        });
+    }
+
+    fn show_duplicates_view(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Duplicates");
+        ui.horizontal(|ui| {
+            let options = &mut self.duplicate_options;
+            ui.checkbox(&mut options.content_hash, "Identical files");
+            ui.checkbox(&mut options.tags, "Matching artist/title");
+            ui.checkbox(&mut options.fingerprint, "Audio fingerprint (slow)");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Duration tolerance:");
+            ui.add(egui::Slider::new(&mut self.duplicate_options.duration_tolerance, 0.0..=10.0).suffix(" s"));
+            let can_run = !self.duplicates_running && !self.scanning && !self.music_files.is_empty();
+            if ui.add_enabled(can_run, egui::Button::new("Find Duplicates")).clicked() {
+                self.start_duplicate_detection();
             }
-            // Check if all metadata is loaded
-            let all_loaded = self.playlist.iter().all(|(_, meta)| meta.artist != "Loading...");
-            if all_loaded {
-                self.metadata_loading = false;
+        });
+
+        if self.duplicates_running {
+            if let Ok(progress) = self.duplicate_progress.lock() {
+                let (stage, current, total) = *progress;
+                let fraction = if total > 0 { current as f32 / total as f32 } else { 0.0 };
+                let progress_bar = egui::ProgressBar::new(fraction)
+                    .text(format!("{}: {}/{}", stage, current, total));
+                ui.add_sized([780.0, 20.0], progress_bar);
             }
+            return;
         }
-    }
-    
-    fn check_pending_scan_results(&mut self) {
-        if let Ok(mut pending) = self.pending_scan_results.try_lock() {
-            if let Some(files) = pending.take() {
-                self.music_files = files;
-                self.scanning = false;
-                println!("Scan results received in UI thread");
+
+        ui.horizontal(|ui| {
+            ui.label("Keep:");
+            let report = &mut self.duplicate_report;
+            egui::ComboBox::from_id_salt("duplicate_preference")
+                .selected_text(report.preference.label())
+                .show_ui(ui, |ui| {
+                    for preference in duplicates::Preference::ALL {
+                        ui.selectable_value(&mut report.preference, preference, preference.label());
+                    }
+                });
+            if ui.button("Apply to All Groups").clicked() {
+                for group in &mut report.groups {
+                    group.apply_preference(report.preference);
+                }
+                report.save();
+            }
+        });
+
+        let excluded: usize = self.duplicate_report.groups.iter().map(|g| g.tracks.len() - 1).sum();
+        ui.label(format!("{} groups, {} copies left out of shuffles",
+                         self.duplicate_report.groups.len(), excluded));
+        ui.separator();
+
+        let mut changed = false;
+        egui::ScrollArea::vertical().auto_shrink([false, false]).show(ui, |ui| {
+            for (group_index, group) in self.duplicate_report.groups.iter_mut().enumerate() {
+                ui.push_id(group_index, |ui| {
+                    let first = &group.tracks[0];
+                    ui.strong(format!("{} - {} ({})", first.artist, first.title, group.kind.label()));
+                    for (i, track) in group.tracks.iter().enumerate() {
+                        let bitrate = track.bitrate_kbps()
+                            .map(|kbps| format!("{:.0} kbps", kbps))
+                            .unwrap_or_else(|| "? kbps".to_string());
+                        let label = format!("{} | {:.1} MB | {}",
+                                            bitrate, track.file_size as f32 / 1_000_000.0, track.path.display());
+                        changed |= ui.radio_value(&mut group.preferred, i, label).changed();
+                    }
+                    ui.add_space(6.0);
+                });
             }
+        });
+        if changed {
+            self.duplicate_report.save();
         }
     }
 }
 
 impl eframe::App for MusicShuffler {
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.save_session();
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // Save the session every 30 seconds so a crash loses little
+        if self.last_session_save.elapsed().unwrap_or_default().as_secs() >= 30 {
+            self.save_session();
+            self.last_session_save = SystemTime::now();
+        }
+        
         // Only check metadata every 200ms to avoid constant updates
         if self.last_metadata_check.elapsed().unwrap_or_default().as_millis() > 200 {
-            self.check_pending_metadata();
-            self.check_pending_scan_results();
+            self.check_tag_loading();
+            self.check_scan_events();
+            self.check_pending_duplicates();
+            self.check_pending_library_index();
+            self.apply_refill();
             self.last_metadata_check = SystemTime::now();
         }
         
         // Update progress cache only occasionally
         if self.last_progress_update.elapsed().unwrap_or_default().as_millis() > 100 {
-            if let Some(player) = &self.audio_player {
-                if let Some((_, metadata)) = self.playlist.get(self.current_song_index) {
-                    let duration_secs = metadata.duration.unwrap_or(0.0);
-                    if duration_secs > 0.0 {
-                        self.cached_progress = player.get_progress_with_duration(duration_secs).unwrap_or(0.0).clamp(0.0, 1.0);
-                        self.cached_duration = duration_secs;
-                    }
+            let player = self.engine.player();
+            if let (Some(audio), Some((_, metadata))) = (player.audio(), player.current()) {
+                let duration_secs = metadata.duration.unwrap_or(0.0);
+                if duration_secs > 0.0 {
+                    self.cached_progress = audio.get_progress_with_duration(duration_secs).unwrap_or(0.0).clamp(0.0, 1.0);
+                    self.cached_duration = duration_secs;
                 }
             }
+            drop(player);
             self.last_progress_update = SystemTime::now();
         }
-        
-        // Auto-advance to next song when current song finishes
-        if let Some(player) = &self.audio_player {
-            if player.has_finished() && !self.playlist.is_empty() {
-                // Move to next song
-                if self.current_song_index < self.playlist.len() - 1 {
-                    self.current_song_index += 1;
-                    if let Some((path, metadata)) = self.playlist.get(self.current_song_index) {
-                        if let Err(e) = self.audio_player.as_mut().unwrap().play(path) {
-                            eprintln!("Error playing next track '{}': {}", metadata.title, e);
-                            eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
-                        }
-                    }
-                } else {
-                    // Reached end of playlist - optionally loop back to beginning
-                    self.current_song_index = 0;
-                    if let Some((path, metadata)) = self.playlist.first() {
-                        if let Err(e) = self.audio_player.as_mut().unwrap().play(path) {
-                            eprintln!("Error playing first track '{}': {}", metadata.title, e);
-                            eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
-                        }
-                    }
-                }
-            }
-        }
+
+        // The engine moves on by itself; pick up what it did since the last frame
+        self.handle_player_events();
         
         // Update every 1 second, plus immediately on mouse input when paused
         ctx.request_repaint_after(std::time::Duration::from_secs(1));
         
         // Also respond to mouse when paused for good UX
-        if let Some(player) = &self.audio_player {
-            if !player.is_playing() {
-                ctx.request_repaint_after(std::time::Duration::from_millis(16)); // ~60fps for responsiveness
-            }
+        let idle = {
+            let player = self.engine.player();
+            player.audio().is_some() && !player.is_playing()
+        };
+        if idle {
+            ctx.request_repaint_after(std::time::Duration::from_millis(16)); // ~60fps for responsiveness
         }
 
+        self.handle_shortcuts(ctx);
+        self.show_import_report(ctx);
+        self.show_shortcuts_help(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // Header
             ui.vertical_centered(|ui| {
@@ -347,176 +1932,152 @@ impl eframe::App for MusicShuffler {
                     "Select a music directory to get started".to_string()
                 };
                 ui.label(dir_label);
+                if let Some(warning) = &self.library_warning {
+                    let mut dismissed = false;
+                    ui.horizontal(|ui| {
+                        ui.colored_label(egui::Color32::from_rgb(230, 160, 40), format!("⚠ {}", warning));
+                        dismissed = ui.small_button("Dismiss").clicked();
+                    });
+                    if dismissed {
+                        self.library_warning = None;
+                    }
+                }
+                let rating_error = self.rating_error.try_lock().ok().and_then(|error| error.clone());
+                if let Some(error) = rating_error {
+                    let mut dismissed = false;
+                    ui.horizontal(|ui| {
+                        ui.colored_label(egui::Color32::from_rgb(230, 160, 40), format!("⚠ {}", error));
+                        dismissed = ui.small_button("Dismiss").clicked();
+                    });
+                    if dismissed {
+                        if let Ok(mut error) = self.rating_error.lock() {
+                            *error = None;
+                        }
+                    }
+                }
+                if let Some(message) = &self.playlist_saved {
+                    let mut dismissed = false;
+                    ui.horizontal(|ui| {
+                        ui.label(message);
+                        dismissed = ui.small_button("Dismiss").clicked();
+                    });
+                    if dismissed {
+                        self.playlist_saved = None;
+                    }
+                }
+                if let Some(error) = &self.scan_error {
+                    let mut dismissed = false;
+                    ui.horizontal(|ui| {
+                        ui.colored_label(egui::Color32::from_rgb(230, 160, 40), format!("⚠ Scan failed, keeping the last scan's tracks: {}", error));
+                        dismissed = ui.small_button("Dismiss").clicked();
+                    });
+                    if dismissed {
+                        self.scan_error = None;
+                    }
+                }
+                ui.add_space(4.0);
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut self.view, View::Player, "Player");
+                    ui.selectable_value(&mut self.view, View::Library, "Library");
+                    ui.selectable_value(&mut self.view, View::SavedPlaylists, "Saved Playlists");
+                    ui.selectable_value(&mut self.view, View::SmartPlaylists, "Smart Playlists");
+                    ui.selectable_value(&mut self.view, View::Statistics, "Statistics");
+                    ui.selectable_value(&mut self.view, View::Duplicates, "Duplicates");
+                });
                 ui.add_space(4.0);
                 ui.horizontal(|ui| {
                     if ui.button("Select Directory").clicked() {
                         if let Some(path) = FileDialog::new().pick_folder() {
                             self.music_directory = Some(path.clone());
                             self.save_directory();
-                            if let Ok(files) = music::scan_music_directory(&path) {
-                                self.music_files = files;
-                            }
+                            self.scan_cancel.cancel();
+                            self.music_files.clear();
+                            self.start_scan(path);
                         }
                     }
-                    if ui.button("Generate Playlist").clicked() && !self.metadata_loading && !self.scanning {
-                        // First scan directory if not already done
-                        if self.music_files.is_empty() {
-                            if let Some(dir) = &self.music_directory {
-                                self.scanning = true;
-                                let scan_progress = Arc::clone(&self.scan_progress);
-                                let pending_scan_results = Arc::clone(&self.pending_scan_results);
-                                let dir_clone = dir.clone();
-                                
-                                // Start scanning in background thread
-                                thread::spawn(move || {
-                                    let progress_callback = move |msg: String| {
-                                        if let Ok(mut progress) = scan_progress.lock() {
-                                            *progress = msg;
-                                        }
-                                    };
-                                    
-                                    println!("Scanning directory for the first time...");
-                                    if let Ok(files) = music::scan_music_directory_with_progress(&dir_clone, progress_callback) {
-                                        println!("Scan complete! Found {} music files", files.len());
-                                        
-                                        // Save to cache for next time
-                                        let cache = FileCache {
-                                            directory: dir_clone,
-                                            last_scan: SystemTime::now(),
-                                            files: files.clone(),
-                                            metadata_cache: HashMap::new(),
-                                        };
-                                        save_file_cache(&cache);
-                                        
-                                        // Send results back to main thread
-                                        if let Ok(mut pending) = pending_scan_results.lock() {
-                                            *pending = Some(files);
-                                        }
-                                    } else {
-                                        println!("Failed to scan directory");
-                                        // Clear scanning state even on failure
-                                        if let Ok(mut pending) = pending_scan_results.lock() {
-                                            *pending = Some(Vec::new());
-                                        }
-                                    }
-                                });
-                                
-                                // Don't continue with playlist generation yet
-                                return;
-                            } else {
-                                println!("No directory selected");
-                                return;
-                            }
+                    ui.label("Tracks:");
+                    ui.add(egui::DragValue::new(&mut self.shuffle_count).range(1..=10_000));
+                    ui.menu_button("Playlist File", |ui| {
+                        let can_export = !self.engine.player().playlist.is_empty() && !self.metadata_loading;
+                        if ui.add_enabled(can_export, egui::Button::new("Export...")).clicked() {
+                            ui.close_menu();
+                            self.export_playlist();
                         }
-                        
-                        println!("Generating playlist...");
-                        let files = music::generate_playlist(&self.music_files, 100);
-                        
-                        // Clear previous playlist and reset state
-                        self.playlist.clear();
-                        self.current_song_index = 0;
-                        self.metadata_loading = true;
-                        if let Some(player) = &mut self.audio_player {
-                            player.stop();
+                        if ui.checkbox(&mut self.settings.relative_playlist_paths, "Export relative paths").changed() {
+                            self.settings.save();
                         }
-                        
-                        // Add files with placeholder metadata first for immediate display
-                        for file in &files {
-                            let placeholder_metadata = SongMetadata {
-                                title: file.file_stem()
-                                    .map(|s| s.to_string_lossy().to_string())
-                                    .unwrap_or_else(|| "Unknown".to_string()),
-                                artist: "Loading...".to_string(),
-                                album: "Loading...".to_string(),
-                                duration: None,
-                                album_art: None,
-                            };
-                            self.playlist.push((file.clone(), placeholder_metadata));
+                        ui.separator();
+                        if ui.add_enabled(!self.metadata_loading, egui::Button::new("Import...")).clicked() {
+                            ui.close_menu();
+                            self.import_playlist();
                         }
-                        
-                        // Set loading flag
-                        self.metadata_loading = true;
-                        
-                        // Load metadata in background thread
-                        let files_for_bg = files.clone();
-                        let pending_metadata = Arc::clone(&self.pending_metadata);
-                        let metadata_progress = Arc::clone(&self.metadata_progress);
-                        let music_dir = self.music_directory.clone().unwrap();
-                        
-                        thread::spawn(move || {
-                            println!("Loading metadata for {} tracks in background...", files_for_bg.len());
-                            
-                            // Initialize progress
-                            if let Ok(mut progress) = metadata_progress.lock() {
-                                *progress = (0, files_for_bg.len());
-                            }
-                            
-                            let mut cache = load_file_cache().unwrap_or_else(|| FileCache {
-                                directory: music_dir,
-                                last_scan: SystemTime::now(),
-                                files: files_for_bg.clone(),
-                                metadata_cache: HashMap::new(),
-                            });
-                            
-                            let mut cache_updated = false;
-                            for (i, path) in files_for_bg.iter().enumerate() {
-                                let mut metadata_loaded = false;
-                                
-                                // Try to load from cache first
-                                if let Some(cached) = cache.metadata_cache.get(path) {
-                                    if let Some((file_size, modified_time)) = get_file_info(path) {
-                                        if cached.file_size == file_size && cached.modified_time == modified_time {
-                                            // Cache hit - use cached metadata
-                                            if let Ok(mut pending) = pending_metadata.lock() {
-                                                pending.push((i, path.clone(), cached.metadata.clone()));
-                                            }
-                                            metadata_loaded = true;
-                                        }
-                                    }
-                                }
-                                
-                                // If not in cache or file changed, load fresh
-                                if !metadata_loaded {
-                                    if let Ok(mut metadata) = SongMetadata::from_path(path) {
-                                        metadata.duration = extract_duration_symphonia(path);
-                                        
-                                        if let Ok(mut pending) = pending_metadata.lock() {
-                                            pending.push((i, path.clone(), metadata.clone()));
-                                        }
-                                        
-                                        // Update cache
-                                        if let Some((file_size, modified_time)) = get_file_info(path) {
-                                            cache.metadata_cache.insert(path.clone(), CachedMetadata {
-                                                metadata,
-                                                file_size,
-                                                modified_time,
-                                            });
-                                            cache_updated = true;
-                                        }
-                                    }
-                                }
-                                
-                                // Update progress
-                                if let Ok(mut progress) = metadata_progress.lock() {
-                                    *progress = (i + 1, files_for_bg.len());
-                                }
-                                
-                                if i % 10 == 0 {
-                                    println!("Loaded metadata for {}/{} tracks", i + 1, files_for_bg.len());
-                                }
-                            }
-                            
-                            // Save updated cache
-                            if cache_updated {
-                                save_file_cache(&cache);
+                    });
+                    ui.menu_button("Options", |ui| {
+                        if ui.checkbox(&mut self.settings.restore_paused, "Start paused when restoring the last session").changed() {
+                            self.settings.save();
+                        }
+                        if ui.checkbox(&mut self.settings.sync_rating_tags, "Sync ratings with file tags")
+                            .on_hover_text("Read and write POPM (MP3) and RATING/FMPS_RATING (FLAC, Ogg Vorbis and Opus) tags")
+                            .changed()
+                        {
+                            self.settings.save();
+                        }
+                        ui.separator();
+                        if ui.button("Keyboard Shortcuts").clicked() {
+                            ui.close_menu();
+                            self.show_shortcuts = true;
+                        }
+                    });
+                    ui.menu_button("Remote Control", |ui| self.show_remote_menu(ui));
+                    ui.menu_button("Scrobbling", |ui| self.show_scrobble_menu(ui));
+                    ui.menu_button("Scan Options", |ui| {
+                        let options = &mut self.settings.scan_options;
+                        let mut changed = false;
+                        changed |= ui.checkbox(&mut options.follow_symlinks, "Follow symlinks").changed();
+                        changed |= ui.checkbox(&mut options.same_file_system, "Stay on one filesystem").changed();
+                        changed |= ui.checkbox(&mut options.dedupe_by_inode, "Skip files reached through several paths").changed();
+                        if changed {
+                            self.settings.save();
+                        }
+                        ui.separator();
+                        let can_rescan = self.music_directory.is_some() && !self.scanning;
+                        if ui.add_enabled(can_rescan, egui::Button::new("Rescan Now")).clicked() {
+                            if let Some(dir) = self.music_directory.clone() {
+                                self.music_files.clear();
+                                self.start_scan(dir);
                             }
-                            
-                            println!("Background metadata loading complete!");
-                        });
+                            ui.close_menu();
+                        }
+                    });
+                    if ui.button("Generate Playlist").clicked() {
+                        self.generate_playlist();
                     }
                 });
             });
             ui.separator();
+            match self.view {
+                View::Player => self.show_query_bar(ui),
+                View::Library => {
+                    self.show_library_view(ui);
+                    return;
+                }
+                View::SavedPlaylists => {
+                    self.show_saved_playlists_view(ui);
+                    return;
+                }
+                View::SmartPlaylists => {
+                    self.show_smart_playlists_view(ui);
+                    return;
+                }
+                View::Statistics => {
+                    self.show_statistics_view(ui);
+                    return;
+                }
+                View::Duplicates => {
+                    self.show_duplicates_view(ui);
+                    return;
+                }
+            }
             // Main content: two fixed-width panels (400px each)
             ui.horizontal_top(|ui| {
                 // Playlist panel (fixed 400px)
@@ -531,102 +2092,108 @@ impl eframe::App for MusicShuffler {
                             ui.label("Scanning directory for music files...");
                             ui.add_space(10.0);
                             
-                            if let Ok(progress_msg) = self.scan_progress.lock() {
-                                ui.label(progress_msg.clone());
+                            let progress = &self.scan_progress;
+                            let progress_bar = egui::ProgressBar::new(progress.fraction())
+                                .text(format!("{}/{} folders", progress.top_level_done, progress.top_level_total));
+                            ui.add_sized([350.0, 20.0], progress_bar);
+                            ui.label(format!("{} directories visited, {} music files found",
+                                             progress.dirs_visited, progress.files_matched));
+                            if progress.duplicates_skipped > 0 {
+                                ui.label(format!("{} duplicate paths skipped", progress.duplicates_skipped));
+                            }
+                            if let Some(path) = &progress.current_path {
+                                ui.small(path.display().to_string());
+                            }
+                            if progress.errors > 0 {
+                                ui.label(format!("{} errors", progress.errors));
+                                if let Some(error) = &progress.last_error {
+                                    ui.small(error.as_str());
+                                }
                             }
                             
-                            // Simple spinner or progress indicator
                             ui.add_space(10.0);
-                            ui.spinner();
+                            if ui.button("Cancel").clicked() {
+                                // Leave the scanning view right away; the walker may be
+                                // blocked on a slow share and stops at its next entry
+                                self.scan_cancel.cancel();
+                                self.scan_events = None;
+                                self.scanning = false;
+                                self.scan_cancelled = true;
+                            }
                         });
-                    } else if self.playlist.is_empty() {
+                    } else if self.engine.player().playlist.is_empty() {
                         ui.vertical_centered(|ui| {
                             ui.add_space(50.0);
                             ui.label("No playlist loaded");
                             ui.add_space(10.0);
-                            if self.music_directory.is_some() {
+                            if self.scan_cancelled {
+                                ui.label("Scan cancelled - click 'Generate Playlist' to try again");
+                            } else if self.music_directory.is_some() {
                                 ui.label("Click 'Generate Playlist' to create one");
                             } else {
                                 ui.label("Select a directory first");
                             }
                         });
-                    } else if self.metadata_loading {
-                        // Show loading progress
-                        if let Ok(progress) = self.metadata_progress.lock() {
-                            let (current, total) = *progress;
-                            if total > 0 {
+                    } else {
+                        if self.metadata_loading {
+                            // Show loading progress
+                            if let Some((current, total)) = self.engine.tag_progress() {
                                 ui.vertical_centered(|ui| {
                                     ui.add_space(30.0);
                                     ui.label("Loading metadata...");
                                     ui.add_space(10.0);
-                                    
+                                
                                     let progress_fraction = current as f32 / total as f32;
                                     let progress_bar = egui::ProgressBar::new(progress_fraction)
                                         .text(format!("{}/{} tracks", current, total));
                                     ui.add_sized([350.0, 20.0], progress_bar);
-                                    
+                                
                                     ui.add_space(20.0);
                                 });
                             }
                         }
-                        
-                        // Still show the playlist with placeholder data
-                        let available_height = ui.available_height();
-                        egui::ScrollArea::vertical()
-                            .max_height(available_height)
-                            .show_rows(ui, 20.0, self.playlist.len(), |ui, row_range| {
-                                for i in row_range {
-                                    if let Some((_, metadata)) = self.playlist.get(i) {
-                                        let is_current = self.current_song_index == i;
-                                        
-                                        let response = ui.selectable_label(is_current, &metadata.title);
-                                        
-                                        if response.clicked() {
-                                            self.current_song_index = i;
-                                            if let Some(ref mut player) = self.audio_player {
-                                                if let Err(_e) = player.play(&self.playlist[i].0) {
-                                                    eprintln!("Error playing track");
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            });
-                    } else {
-                        let available_height = ui.available_height();
-                                                egui::ScrollArea::vertical()
-                            .max_height(available_height)
-                            .show_rows(ui, 20.0, self.playlist.len(), |ui, row_range| {
-                                for i in row_range {
-                                    if let Some((_, metadata)) = self.playlist.get(i) {
-                                        let is_current = self.current_song_index == i;
-                                        
-                                        let response = ui.selectable_label(is_current, &metadata.title);
-                                        
-                                        if response.clicked() {
-                                            self.current_song_index = i;
-                                            if let Some(ref mut player) = self.audio_player {
-                                                                                             if let Err(_e) = player.play(&self.playlist[i].0) {
-                                                 eprintln!("Error playing track");
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            });
+                        self.show_playlist(ui);
                     }
                 });
                 // Now Playing panel (fixed 400px)
                 ui.vertical_centered(|ui| {
                     ui.set_width(400.0);
                     ui.heading("Now Playing");
-                    if let Some((_path, metadata)) = self.playlist.get(self.current_song_index) {
+                    let mut rate = None;
+                    let mut ban = None;
+                    // Copied out so the engine isn't kept waiting while this is drawn
+                    let (current, volume, playing) = {
+                        let player = self.engine.player();
+                        let current = player.current().map(|(path, metadata)| {
+                            (path.clone(), metadata.title.clone(), metadata.artist.clone(), metadata.album.clone())
+                        });
+                        (current, player.audio().map(|audio| audio.volume()), player.is_playing())
+                    };
+                    if let Some((path, title, artist, album)) = &current {
                         // Simple grey square placeholder for album art
                         let (rect, _) = ui.allocate_exact_size(egui::vec2(200.0, 200.0), egui::Sense::hover());
                         ui.painter().rect_filled(rect, 8.0, egui::Color32::from_gray(128));
-                        ui.label(metadata.title.to_string());
-                        ui.label(metadata.artist.to_string());
-                        ui.label(metadata.album.to_string());
+                        ui.label(title.as_str());
+                        ui.label(artist.as_str());
+                        ui.label(album.as_str());
+                        let stats = self.track_stats.get(path).copied().unwrap_or_default();
+                        ui.horizontal(|ui| {
+                            for star in 1..=5 {
+                                let filled = stats.rating.is_some_and(|r| r >= star);
+                                let symbol = if filled { "★" } else { "☆" };
+                                if ui.add(egui::Button::new(egui::RichText::new(symbol).size(18.0)).frame(false)).clicked() {
+                                    // Clicking the current rating again clears it
+                                    let rating = if stats.rating == Some(star) { None } else { Some(star) };
+                                    rate = Some((path.clone(), rating));
+                                }
+                            }
+                            if ui.selectable_label(stats.banned, "Ban")
+                                .on_hover_text("Never pick this track in shuffles")
+                                .clicked()
+                            {
+                                ban = Some((path.clone(), !stats.banned));
+                            }
+                        });
                         // Progress bar and time (use cached values)
                         let (progress, duration_secs) = (self.cached_progress, self.cached_duration);
                                                 // Simple progress bar (read-only)
@@ -635,6 +2202,23 @@ impl eframe::App for MusicShuffler {
                         let current_secs = progress * duration_secs;
                         ui.label(format!("{} / {}", format_time(current_secs), format_time(duration_secs)));
                     }
+                    if let Some((path, rating)) = rate {
+                        self.set_rating(path, rating);
+                    }
+                    if let Some((path, banned)) = ban {
+                        self.set_banned(path, banned);
+                    }
+                    let repeat_mode = self.settings.repeat_mode;
+                    if ui.button(repeat_mode.label()).on_hover_text("What to do when a track ends").clicked() {
+                        self.settings.repeat_mode = repeat_mode.next();
+                        self.settings.save();
+                        self.engine.send(Command::SetRepeat(self.settings.repeat_mode));
+                    }
+                    if let Some(mut volume) = volume {
+                        if ui.add(egui::Slider::new(&mut volume, 0.0..=1.0).show_value(false).text("Volume")).changed() {
+                            self.engine.send(Command::SetVolume(volume));
+                        }
+                    }
                     ui.with_layout(egui::Layout::bottom_up(egui::Align::Center), |ui| {
                         ui.add_space(16.0);
                         let button_row_width = 400.0;
@@ -643,49 +2227,15 @@ impl eframe::App for MusicShuffler {
                             egui::vec2(button_row_width - 80.0, 75.0),
                             egui::Layout::left_to_right(egui::Align::Center),
                             |ui| {
-                                if ui.add_sized([50.0, 50.0], egui::Button::new(egui::RichText::new("  ⏮  ").size(25.0).monospace().strong()).frame(true).min_size(egui::vec2(50.0, 50.0)).corner_radius(25.0)).clicked() && self.current_song_index > 0 {
-                                    self.current_song_index -= 1;
-                                    if let Some((path, metadata)) = self.playlist.get(self.current_song_index) {
-                                        if let Err(e) = self.audio_player.as_mut().unwrap().play(path) {
-                                            eprintln!("Error playing track '{}': {}", metadata.title, e);
-                                            eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
-                                        }
-                                    }
+                                if ui.add_sized([50.0, 50.0], egui::Button::new(egui::RichText::new("  ⏮  ").size(25.0).monospace().strong()).frame(true).min_size(egui::vec2(50.0, 50.0)).corner_radius(25.0)).clicked() {
+                                    self.engine.send(Command::Previous);
                                 }
-                                let play_symbol = if self.audio_player.as_ref().unwrap().is_playing() { "  ⏸  " } else { "  ▶  " };
+                                let play_symbol = if playing { "  ⏸  " } else { "  ▶  " };
                                 if ui.add_sized([75.0, 75.0], egui::Button::new(egui::RichText::new(play_symbol).size(37.0).monospace().strong()).frame(true).min_size(egui::vec2(75.0, 75.0)).corner_radius(37.5)).clicked() {
-                                    if self.audio_player.as_ref().unwrap().is_playing() {
-                                        self.audio_player.as_mut().unwrap().pause();
-                                    } else {
-                                        // Check if there's a paused song to resume
-                                        if self.audio_player.as_ref().unwrap().is_paused() {
-                                            // Resume the paused song
-                                            self.audio_player.as_mut().unwrap().resume();
-                                        } else if let Some((path, metadata)) = self.playlist.get(self.current_song_index) {
-                                            // Start playing a new song
-                                            if let Err(e) = self.audio_player.as_mut().unwrap().play(path) {
-                                                eprintln!("Error playing track '{}': {}", metadata.title, e);
-                                                eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
-                                            }
-                                        } else if !self.playlist.is_empty() {
-                                            self.current_song_index = 0;
-                                            if let Some((path, metadata)) = self.playlist.first() {
-                                                if let Err(e) = self.audio_player.as_mut().unwrap().play(path) {
-                                                    eprintln!("Error playing track '{}': {}", metadata.title, e);
-                                                    eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
-                                                }
-                                            }
-                                        }
-                                    }
+                                    self.engine.send(Command::TogglePlayback);
                                 }
-                                if ui.add_sized([50.0, 50.0], egui::Button::new(egui::RichText::new("  ⏭  ").size(25.0).monospace().strong()).frame(true).min_size(egui::vec2(50.0, 50.0)).corner_radius(25.0)).clicked() && self.current_song_index < self.playlist.len() - 1 {
-                                    self.current_song_index += 1;
-                                    if let Some((path, metadata)) = self.playlist.get(self.current_song_index) {
-                                        if let Err(e) = self.audio_player.as_mut().unwrap().play(path) {
-                                            eprintln!("Error playing track '{}': {}", metadata.title, e);
-                                            eprintln!("This file may be corrupted. Try re-encoding or replacing it.");
-                                        }
-                                    }
+                                if ui.add_sized([50.0, 50.0], egui::Button::new(egui::RichText::new("  ⏭  ").size(25.0).monospace().strong()).frame(true).min_size(egui::vec2(50.0, 50.0)).corner_radius(25.0)).clicked() {
+                                    self.engine.send(Command::Next);
                                 }
                             }
                         );
@@ -698,6 +2248,15 @@ impl eframe::App for MusicShuffler {
 }
 
 fn main() {
+    // Subcommands run headless; without one the window opens
+    if let Some(command) = cli::Cli::parse().command {
+        if let Err(e) = cli::run(command) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([800.0, 600.0]),
@@ -710,6 +2269,12 @@ fn main() {
         Box::new(|_cc| {
             let mut app = MusicShuffler::default();
             app.load_directory();
+            app.restore_session();
+            app.apply_remote_settings();
+            app.apply_mpd_settings();
+            app.apply_scrobble_settings();
+            #[cfg(target_os = "linux")]
+            app.start_mpris(_cc.egui_ctx.clone());
             Ok(Box::new(app))
         }),
     ).unwrap();
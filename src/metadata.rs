@@ -1,15 +1,42 @@
-use std::path::Path;
-use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use id3::frame::Popularimeter;
 use id3::{Tag, TagLike};
 use metaflac::Tag as FlacTag;
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use rodio::{Decoder, Source};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+use symphonia::default::get_probe;
+use crate::library::LibraryDb;
+
+// POPM frames are per user; ours is replaced on write and preferred on read
+const POPM_USER: &str = "music-shuffler";
+
+// Rewritten files waiting to replace tracks that were open when they were
+// written, as (rewritten, track)
+static DEFERRED_WRITES: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(Vec::new());
 
 #[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct SongMetadata {
     pub title: String,
     pub artist: String,
     pub album: String,
+    #[serde(default)]
+    pub genre: String,
+    #[serde(default)]
+    pub year: Option<u32>,
     pub duration: Option<f32>,
     pub album_art: Option<Vec<u8>>,
+    /// Star rating found in the file's tags. The library database holds the
+    /// rating actually used; this is only read to import it.
+    #[serde(skip)]
+    pub tag_rating: Option<u8>,
 }
 
 impl SongMetadata {
@@ -29,6 +56,16 @@ impl SongMetadata {
                         metadata.title = tag.title().unwrap_or(&metadata.title).to_string();
                         metadata.artist = tag.artist().unwrap_or("Unknown Artist").to_string();
                         metadata.album = tag.album().unwrap_or("Unknown Album").to_string();
+                        metadata.genre = tag.genre_parsed().unwrap_or_default().to_string();
+                        metadata.year = tag.year()
+                            .or_else(|| tag.date_recorded().map(|d| d.year))
+                            .and_then(|y| u32::try_from(y).ok());
+                        
+                        let ratings: Vec<&Popularimeter> = tag.frames().filter_map(|f| f.content().popularimeter()).collect();
+                        metadata.tag_rating = ratings.iter()
+                            .find(|p| p.user == POPM_USER)
+                            .or(ratings.first())
+                            .and_then(|p| stars_from_popm(p.rating));
                         
                         // Get album art
                         if let Some(picture) = tag.pictures().next() {
@@ -48,6 +85,16 @@ impl SongMetadata {
                             if let Some(album) = vorbis.album() {
                                 metadata.album = album[0].to_string();
                             }
+                            if let Some(genre) = vorbis.genre() {
+                                metadata.genre = genre[0].to_string();
+                            }
+                            if let Some(date) = vorbis.get("DATE") {
+                                metadata.year = parse_year(&date[0]);
+                            }
+                            metadata.tag_rating = stars_from_comments(
+                                vorbis.get("FMPS_RATING").map(|v| v[0].as_str()),
+                                vorbis.get("RATING").map(|v| v[0].as_str()),
+                            );
                         }
                         
                         // Get album art
@@ -56,10 +103,561 @@ impl SongMetadata {
                         }
                     }
                 },
+                "ogg" | "oga" | "opus" => {
+                    if let Ok(comments) = OggComments::read(path) {
+                        if let Some(title) = comments.get("TITLE") {
+                            metadata.title = title.to_string();
+                        }
+                        if let Some(artist) = comments.get("ARTIST") {
+                            metadata.artist = artist.to_string();
+                        }
+                        if let Some(album) = comments.get("ALBUM") {
+                            metadata.album = album.to_string();
+                        }
+                        if let Some(genre) = comments.get("GENRE") {
+                            metadata.genre = genre.to_string();
+                        }
+                        metadata.year = comments.get("DATE").and_then(parse_year);
+                        metadata.tag_rating = stars_from_comments(comments.get("FMPS_RATING"), comments.get("RATING"));
+                    }
+                },
                 _ => {}
             }
         }
 
         Ok(metadata)
     }
-} 
\ No newline at end of file
+
+    /// Metadata with only a title, taken from the file name, for files whose
+    /// tags can't be read.
+    pub fn from_file_name(path: &Path) -> Self {
+        SongMetadata {
+            title: path.file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "Unknown".to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads tags and durations. With a library, unchanged files come from its
+/// cache and freshly read tags are stored in it.
+pub struct MetadataReader<'a> {
+    db: Option<&'a LibraryDb>,
+    sync_ratings: bool,
+}
+
+impl<'a> MetadataReader<'a> {
+    /// With `sync_ratings`, a rating found in a file's tags is taken into the
+    /// library when the tags are read.
+    pub fn new(db: Option<&'a LibraryDb>, sync_ratings: bool) -> Self {
+        Self { db, sync_ratings }
+    }
+
+    /// Tags and duration of `path`, or `None` if the file can't be read.
+    pub fn read(&self, path: &Path) -> Option<SongMetadata> {
+        let file_info = file_info(path);
+        if let (Some(db), Some((file_size, modified_time))) = (self.db, file_info) {
+            if let Ok(Some(metadata)) = db.cached_metadata(path, file_size, modified_time) {
+                return Some(metadata);
+            }
+        }
+
+        let mut metadata = SongMetadata::from_path(path).ok()?;
+        metadata.duration = Self::duration(path);
+        if let (Some(db), Some((file_size, modified_time))) = (self.db, file_info) {
+            if let Err(e) = db.store_metadata(path, &metadata, file_size, modified_time) {
+                eprintln!("Could not store metadata for {}: {}", path.display(), e);
+            }
+            if let (true, Some(rating)) = (self.sync_ratings, metadata.tag_rating) {
+                if let Err(e) = db.import_rating(path, rating) {
+                    eprintln!("Could not import rating for {}: {}", path.display(), e);
+                }
+            }
+        }
+        Some(metadata)
+    }
+
+    /// Like `read`, falling back to the file name as the title.
+    pub fn read_or_placeholder(&self, path: &Path) -> SongMetadata {
+        self.read(path).unwrap_or_else(|| SongMetadata::from_file_name(path))
+    }
+
+    /// Length of a track in seconds, decoded with Symphonia or else Rodio.
+    pub fn duration(path: &Path) -> Option<f32> {
+        // Try to extract duration using Symphonia
+        if let Ok(file) = File::open(path) {
+            let mss = MediaSourceStream::new(Box::new(file), Default::default());
+            let mut hint = Hint::new();
+            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                hint.with_extension(ext);
+            }
+
+            if let Ok(probe) = get_probe().format(
+                &hint,
+                mss,
+                &Default::default(),
+                &Default::default(),
+            ) {
+                let mut format = probe.format;
+
+                // Try to get duration from metadata first
+                if let Some(metadata_rev) = format.metadata().current() {
+                    for tag in metadata_rev.tags() {
+                        if tag.key.as_str().to_lowercase() == "duration" {
+                            if let Ok(duration_str) = tag.value.to_string().parse::<f32>() {
+                                return Some(duration_str);
+                            }
+                        }
+                    }
+                }
+
+                // Try to calculate duration from track parameters
+                if let Some(track) = format.default_track() {
+                    let params = &track.codec_params;
+
+                    // Method 1: Use n_frames and sample_rate
+                    if let (Some(n_frames), Some(sample_rate)) = (params.n_frames, params.sample_rate) {
+                        return Some(n_frames as f32 / sample_rate as f32);
+                    }
+
+                    // Method 2: Use time_base and n_frames
+                    if let (Some(n_frames), Some(time_base)) = (params.n_frames, params.time_base) {
+                        let duration_secs = n_frames as f64 * time_base.numer as f64 / time_base.denom as f64;
+                        return Some(duration_secs as f32);
+                    }
+                }
+            }
+        }
+
+        // If Symphonia fails, try using rodio as a fallback
+        if let Ok(file) = File::open(path) {
+            let reader = std::io::BufReader::new(file);
+            if let Ok(decoder) = Decoder::new(reader) {
+                if let Some(duration) = decoder.total_duration() {
+                    return Some(duration.as_secs_f32());
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// Size and modification time, which tell whether cached tags are still current
+fn file_info(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+// Dates in tags are free text such as "1969", "1969-08-12" or "08/1969"
+fn parse_year(date: &str) -> Option<u32> {
+    date.split(|c: char| !c.is_ascii_digit())
+        .find(|part| part.len() == 4)
+        .and_then(|part| part.parse().ok())
+}
+
+// POPM ratings run 1-255 (0 is unrated); these bands match what other players write
+fn stars_from_popm(rating: u8) -> Option<u8> {
+    match rating {
+        0 => None,
+        1..=31 => Some(1),
+        32..=95 => Some(2),
+        96..=159 => Some(3),
+        160..=223 => Some(4),
+        _ => Some(5),
+    }
+}
+
+fn popm_from_stars(stars: u8) -> u8 {
+    match stars {
+        0 => 0,
+        1 => 1,
+        2 => 64,
+        3 => 128,
+        4 => 196,
+        _ => 255,
+    }
+}
+
+// RATING is written as stars by some players and as 0-100 by others
+fn stars_from_vorbis(value: &str) -> Option<u8> {
+    let rating: f32 = value.trim().parse().ok()?;
+    let stars = if rating <= 5.0 { rating } else { rating / 20.0 };
+    Some(stars.round().clamp(0.0, 5.0) as u8)
+}
+
+// FMPS_RATING (0.0-1.0) is preferred over RATING, whose scale varies
+fn stars_from_comments(fmps_rating: Option<&str>, rating: Option<&str>) -> Option<u8> {
+    fmps_rating
+        .and_then(|r| r.trim().parse::<f32>().ok())
+        .map(|r| (r.clamp(0.0, 1.0) * 5.0).round() as u8)
+        .or_else(|| rating.and_then(stars_from_vorbis))
+        .filter(|&stars| stars > 0)
+}
+
+// The comment header of an Ogg Vorbis or Opus stream, which holds its tags
+struct OggComments {
+    magic: &'static [u8],
+    vendor: Vec<u8>,
+    comments: Vec<String>,
+    // Vorbis's framing bit, or Opus padding and extra data, kept as found
+    rest: Vec<u8>,
+}
+
+fn take<'a>(packet: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = packet.get(*pos..pos.checked_add(len)?)?;
+    *pos += len;
+    Some(bytes)
+}
+
+fn take_len(packet: &[u8], pos: &mut usize) -> Option<usize> {
+    let bytes = take(packet, pos, 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+}
+
+impl OggComments {
+    const VORBIS: &'static [u8] = b"\x03vorbis";
+    const OPUS: &'static [u8] = b"OpusTags";
+
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let magic = [Self::VORBIS, Self::OPUS].into_iter().find(|magic| packet.starts_with(magic))?;
+        let mut pos = magic.len();
+        let vendor_len = take_len(packet, &mut pos)?;
+        let vendor = take(packet, &mut pos, vendor_len)?.to_vec();
+        let count = take_len(packet, &mut pos)?;
+        let mut comments = Vec::new();
+        for _ in 0..count {
+            let len = take_len(packet, &mut pos)?;
+            comments.push(String::from_utf8_lossy(take(packet, &mut pos, len)?).into_owned());
+        }
+        Some(Self { magic, vendor, comments, rest: packet[pos..].to_vec() })
+    }
+
+    fn to_packet(&self) -> Vec<u8> {
+        let mut packet = self.magic.to_vec();
+        packet.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&self.vendor);
+        packet.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            packet.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            packet.extend_from_slice(comment.as_bytes());
+        }
+        packet.extend_from_slice(&self.rest);
+        packet
+    }
+
+    // The comment header is the second packet of the first stream
+    fn read(path: &Path) -> Result<Self> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+        let first = reader.read_packet()?.ok_or_else(|| anyhow!("Empty Ogg file"))?;
+        while let Some(packet) = reader.read_packet()? {
+            if packet.stream_serial() == first.stream_serial() {
+                return Self::parse(&packet.data).ok_or_else(|| anyhow!("No Vorbis or Opus comments found"));
+            }
+        }
+        Err(anyhow!("No Vorbis or Opus comments found"))
+    }
+
+    // Field names ignore case; the first value is used
+    fn get(&self, key: &str) -> Option<&str> {
+        self.comments.iter().find_map(|comment| {
+            let (name, value) = comment.split_once('=')?;
+            name.eq_ignore_ascii_case(key).then_some(value)
+        })
+    }
+
+    fn set(&mut self, key: &str, value: Option<String>) {
+        self.comments.retain(|comment| !comment.split_once('=').is_some_and(|(name, _)| name.eq_ignore_ascii_case(key)));
+        if let Some(value) = value {
+            self.comments.push(format!("{}={}", key, value));
+        }
+    }
+}
+
+// Copy an Ogg file to `target` with the comment header of every stream edited.
+// Pages end where they did, so only the pages around the comments change.
+fn rewrite_ogg_comments(source: &Path, target: &Path, edit: impl Fn(&mut OggComments)) -> Result<()> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(source)?));
+    let mut writer = PacketWriter::new(BufWriter::new(File::create(target)?));
+    let mut packets_seen: HashMap<u32, usize> = HashMap::new();
+    while let Some(packet) = reader.read_packet()? {
+        let serial = packet.stream_serial();
+        let seen = packets_seen.entry(serial).or_insert(0);
+        *seen += 1;
+        let end = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let granule = packet.absgp_page();
+        let mut data = packet.data;
+        if *seen == 2 {
+            let mut comments = OggComments::parse(&data).ok_or_else(|| anyhow!("No Vorbis or Opus comments found"))?;
+            edit(&mut comments);
+            data = comments.to_packet();
+        }
+        writer.write_packet(data.into_boxed_slice(), serial, end, granule)?;
+    }
+    let file = writer.into_inner().into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Returned when new tags couldn't replace a track because it is open, as it
+/// is on Windows while it plays. They are kept and put in place by
+/// `finish_deferred_writes` once it is closed.
+#[derive(Debug)]
+pub struct WriteDeferred;
+
+impl std::fmt::Display for WriteDeferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the file is in use; it will be updated once it stops playing")
+    }
+}
+
+impl std::error::Error for WriteDeferred {}
+
+// Tags are written to a file next to `path` that then replaces it, rather than
+// edited in place: a track that is playing keeps reading the file it opened.
+// Where an open file can't be replaced the new one waits for it to close.
+fn replace_with(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    let temp = PathBuf::from(name);
+    // A write still waiting for this track is superseded by this one
+    DEFERRED_WRITES.lock().unwrap_or_else(|e| e.into_inner()).retain(|(_, track)| track != path);
+    if let Err(e) = write(&temp) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    if fs::rename(&temp, path).is_err() {
+        DEFERRED_WRITES.lock().unwrap_or_else(|e| e.into_inner()).push((temp, path.to_path_buf()));
+        return Err(WriteDeferred.into());
+    }
+    Ok(())
+}
+
+/// Put in place tags that `write_rating` had to hold back with `WriteDeferred`,
+/// for the tracks that have since been closed. Cheap when there are none.
+pub fn finish_deferred_writes() {
+    let mut deferred = DEFERRED_WRITES.lock().unwrap_or_else(|e| e.into_inner());
+    deferred.retain(|(temp, track)| match fs::rename(temp, track) {
+        Ok(()) => false,
+        // Still open; a track deleted meanwhile has nothing left to update
+        Err(_) if track.exists() && temp.exists() => true,
+        Err(_) => {
+            let _ = fs::remove_file(temp);
+            false
+        }
+    });
+}
+
+/// Store a 0-5 star rating in the file's tags, or remove it when `None`: an ID3
+/// POPM frame for MP3, and RATING (0-100) and FMPS_RATING (0.0-1.0) comments for
+/// FLAC, Ogg Vorbis and Opus. Other formats are an error. The file is replaced
+/// as a whole, so a track that is playing goes on undisturbed; where the system
+/// won't replace an open file this fails with `WriteDeferred` and the rating
+/// is stored once the track is closed.
+pub fn write_rating(path: &Path, rating: Option<u8>) -> Result<()> {
+    let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "mp3" => replace_with(path, |temp| {
+            let mut tag = match Tag::read_from_path(path) {
+                Ok(tag) => tag,
+                Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => Tag::new(),
+                Err(e) => return Err(e.into()),
+            };
+            let version = tag.version();
+            // Keep other users' ratings, replace ours
+            let others: Vec<Popularimeter> = tag.frames()
+                .filter_map(|f| f.content().popularimeter())
+                .filter(|p| p.user != POPM_USER)
+                .cloned()
+                .collect();
+            tag.remove("POPM");
+            for popm in others {
+                tag.add_frame(popm);
+            }
+            if let Some(stars) = rating {
+                tag.add_frame(Popularimeter {
+                    user: POPM_USER.to_string(),
+                    rating: popm_from_stars(stars),
+                    counter: 0,
+                });
+            }
+            fs::copy(path, temp)?;
+            tag.write_to_path(temp, version)?;
+            Ok(())
+        }),
+        "flac" => replace_with(path, |temp| {
+            fs::copy(path, temp)?;
+            let mut tag = FlacTag::read_from_path(temp)?;
+            match rating {
+                Some(stars) => {
+                    tag.set_vorbis("RATING", vec![(stars as u32 * 20).to_string()]);
+                    tag.set_vorbis("FMPS_RATING", vec![(stars as f32 / 5.0).to_string()]);
+                }
+                None => {
+                    tag.remove_vorbis("RATING");
+                    tag.remove_vorbis("FMPS_RATING");
+                }
+            }
+            tag.save()?;
+            Ok(())
+        }),
+        "ogg" | "oga" | "opus" => replace_with(path, |temp| {
+            rewrite_ogg_comments(path, temp, |comments| {
+                comments.set("RATING", rating.map(|stars| (stars as u32 * 20).to_string()));
+                comments.set("FMPS_RATING", rating.map(|stars| (stars as f32 / 5.0).to_string()));
+            })
+        }),
+        "" => Err(anyhow!("Ratings can't be stored in files without an extension")),
+        other => Err(anyhow!("Ratings can't be stored in .{} files", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment_packet(magic: &'static [u8], comments: &[&str], rest: &[u8]) -> Vec<u8> {
+        OggComments {
+            magic,
+            vendor: b"test".to_vec(),
+            comments: comments.iter().map(|c| c.to_string()).collect(),
+            rest: rest.to_vec(),
+        }
+        .to_packet()
+    }
+
+    fn write_ogg(path: &Path, packets: &[Vec<u8>]) {
+        let mut writer = PacketWriter::new(File::create(path).unwrap());
+        for (i, packet) in packets.iter().enumerate() {
+            let end = match i {
+                _ if i + 1 == packets.len() => PacketWriteEndInfo::EndStream,
+                0 | 2 => PacketWriteEndInfo::EndPage,
+                _ => PacketWriteEndInfo::NormalPacket,
+            };
+            writer.write_packet(packet.clone().into_boxed_slice(), 7, end, i as u64).unwrap();
+        }
+    }
+
+    fn read_packets(path: &Path) -> Vec<Vec<u8>> {
+        let mut reader = PacketReader::new(File::open(path).unwrap());
+        std::iter::from_fn(|| reader.read_packet().unwrap().map(|p| p.data)).collect()
+    }
+
+    #[test]
+    fn ogg_ratings_are_written_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, magic, rest) in [("a.ogg", OggComments::VORBIS, &[1u8][..]), ("b.opus", OggComments::OPUS, &[][..])] {
+            let path = dir.path().join(name);
+            let packets = vec![
+                b"\x01vorbis identification".to_vec(),
+                comment_packet(magic, &["TITLE=Blue in Green", "rating=2"], rest),
+                b"\x05vorbis setup".to_vec(),
+                vec![0xAA; 300],
+                vec![0xBB; 5000],
+            ];
+            write_ogg(&path, &packets);
+            assert_eq!(SongMetadata::from_path(&path).unwrap().tag_rating, Some(2));
+
+            write_rating(&path, Some(4)).unwrap();
+            let written = read_packets(&path);
+            let comments = OggComments::parse(&written[1]).unwrap();
+            assert_eq!(comments.comments, vec!["TITLE=Blue in Green", "RATING=80", "FMPS_RATING=0.8"]);
+            assert_eq!(comments.rest, rest);
+            // Everything but the comments is copied as it was
+            assert_eq!([&written[..1], &written[2..]].concat(), [&packets[..1], &packets[2..]].concat());
+            let metadata = SongMetadata::from_path(&path).unwrap();
+            assert_eq!((metadata.title.as_str(), metadata.tag_rating), ("Blue in Green", Some(4)));
+
+            write_rating(&path, None).unwrap();
+            assert_eq!(SongMetadata::from_path(&path).unwrap().tag_rating, None);
+            assert!(!dir.path().join(format!("{}.tmp", name)).exists());
+        }
+    }
+
+    #[test]
+    fn ratings_are_replaced_without_touching_an_open_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.ogg");
+        write_ogg(&path, &[b"\x01vorbis".to_vec(), comment_packet(OggComments::VORBIS, &[], &[1]), vec![0xAA; 100]]);
+        let before = fs::read(&path).unwrap();
+        let mut playing = File::open(&path).unwrap();
+
+        write_rating(&path, Some(5)).unwrap();
+        let mut still_playing = Vec::new();
+        std::io::Read::read_to_end(&mut playing, &mut still_playing).unwrap();
+        assert_eq!(still_playing, before);
+        assert_ne!(fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn ratings_round_trip_through_popm_frames_and_flac_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mp3 = dir.path().join("a.mp3");
+        let mut tag = Tag::new();
+        tag.set_title("So What");
+        tag.add_frame(Popularimeter { user: "someone@else".to_string(), rating: 1, counter: 3 });
+        fs::write(&mp3, b"").unwrap();
+        tag.write_to_path(&mp3, id3::Version::Id3v24).unwrap();
+        let flac = dir.path().join("b.flac");
+        let mut tag = FlacTag::new();
+        tag.set_streaminfo(metaflac::block::StreamInfo {
+            sample_rate: 44100,
+            num_channels: 2,
+            bits_per_sample: 16,
+            md5: vec![0; 16],
+            ..Default::default()
+        });
+        tag.write_to(&mut File::create(&flac).unwrap()).unwrap();
+
+        for path in [&mp3, &flac] {
+            for stars in [2, 3, 5] {
+                write_rating(path, Some(stars)).unwrap();
+                assert_eq!(SongMetadata::from_path(path).unwrap().tag_rating, Some(stars), "{}", path.display());
+            }
+            write_rating(path, None).unwrap();
+        }
+        assert_eq!(SongMetadata::from_path(&flac).unwrap().tag_rating, None);
+        // Without ours, another user's POPM rating shows through; it and the other tags are kept
+        assert_eq!(SongMetadata::from_path(&mp3).unwrap().tag_rating, Some(1));
+        let tag = Tag::read_from_path(&mp3).unwrap();
+        assert_eq!(tag.title(), Some("So What"));
+        let users: Vec<&str> = tag.frames().filter_map(|f| f.content().popularimeter()).map(|p| p.user.as_str()).collect();
+        assert_eq!(users, ["someone@else"]);
+    }
+
+    #[test]
+    fn files_that_cant_be_replaced_yet_are_updated_later() {
+        let dir = tempfile::tempdir().unwrap();
+        // Renaming over a directory fails, as renaming over an open file does on Windows
+        let path = dir.path().join("a.ogg");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("in the way"), b"").unwrap();
+        let error = replace_with(&path, |temp| Ok(fs::write(temp, b"new tags")?)).unwrap_err();
+        assert!(error.is::<WriteDeferred>());
+
+        finish_deferred_writes();
+        assert!(path.is_dir());
+        fs::remove_dir_all(&path).unwrap();
+        fs::write(&path, b"old tags").unwrap();
+        finish_deferred_writes();
+        assert_eq!(fs::read(&path).unwrap(), b"new tags");
+        assert!(!dir.path().join("a.ogg.tmp").exists());
+    }
+
+    #[test]
+    fn unsupported_formats_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.m4a");
+        fs::write(&path, b"not touched").unwrap();
+        let error = write_rating(&path, Some(3)).unwrap_err();
+        assert_eq!(error.to_string(), "Ratings can't be stored in .m4a files");
+        assert_eq!(fs::read(&path).unwrap(), b"not touched");
+    }
+}
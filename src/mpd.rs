@@ -0,0 +1,855 @@
+// A subset of the MPD protocol, so MPD clients (ncmpcpp, M.A.L.P., Cantata)
+// can drive the player. MPD's queue is the playlist, song ids are playlist
+// positions, and song URIs are paths relative to the library directory.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use crate::config::{MpdSettings, RepeatMode};
+use crate::engine::{Command, PlayerEngine};
+use crate::library::Library;
+use crate::metadata::SongMetadata;
+use crate::player::Player;
+
+const GREETING: &str = "OK MPD 0.23.0\n";
+// Longest command line read; a longer one ends the connection
+const MAX_LINE: u64 = 64 * 1024;
+// How often a client waiting in `idle` is checked for changes
+const IDLE_POLL: Duration = Duration::from_millis(200);
+// Every connection keeps two threads, one of them reading its lines; clients
+// past this are turned away
+const MAX_CONNECTIONS: usize = 32;
+
+const COMMANDS: &[&str] = &[
+    "add", "clear", "close", "commands", "currentsong", "idle", "next", "noidle", "notcommands", "password", "pause",
+    "ping", "play", "playid", "playlistinfo", "plchanges", "previous", "search", "seekcur", "setvol", "status", "stop",
+    "tagtypes",
+];
+// What a client may send before giving the password
+const OPEN_COMMANDS: &[&str] = &["close", "commands", "notcommands", "password", "ping"];
+const TAG_TYPES: &[&str] = &["Artist", "Album", "Title", "Genre", "Date"];
+
+// MPD's error codes
+const ACK_ARG: u32 = 2;
+const ACK_PASSWORD: u32 = 3;
+const ACK_PERMISSION: u32 = 4;
+const ACK_UNKNOWN: u32 = 5;
+const ACK_NO_EXIST: u32 = 50;
+const ACK_SYSTEM: u32 = 52;
+
+/// The running server. Dropping it stops taking connections and closes the
+/// open ones as they next send a command or wait in `idle`.
+pub struct MpdServer {
+    port: u16,
+    stopped: Arc<AtomicBool>,
+    accepting: Option<JoinHandle<()>>,
+}
+
+impl MpdServer {
+    /// Listen on `settings.port`, on localhost or with `settings.lan` on every
+    /// interface, and drive `engine`. `add` and `search` use the library in the
+    /// config directory.
+    pub fn start(settings: &MpdSettings, engine: PlayerEngine, sync_ratings: bool) -> Result<Self> {
+        Self::start_with(settings, engine, sync_ratings, None)
+    }
+
+    // Start with `library` already open, or with none opening the default one
+    // on first use
+    fn start_with(settings: &MpdSettings, engine: PlayerEngine, sync_ratings: bool, library: Option<Library>) -> Result<Self> {
+        let ip = if settings.lan { Ipv4Addr::UNSPECIFIED } else { Ipv4Addr::LOCALHOST };
+        let listener = TcpListener::bind((ip, settings.port))
+            .map_err(|e| anyhow!("Could not listen on port {}: {}", settings.port, e))?;
+        // Port 0 picks a free one
+        let port = listener.local_addr()?.port();
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let context = Arc::new(Context {
+            engine,
+            password: settings.password.clone(),
+            sync_ratings,
+            library: Mutex::new(library),
+            stopped: Arc::clone(&stopped),
+            connections: AtomicUsize::new(0),
+        });
+        let accepting = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if context.stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                match (stream, Connection::open(&context)) {
+                    (Ok(stream), Some(connection)) => {
+                        thread::spawn(move || {
+                            // A failed write only means the client went away
+                            let _ = serve(&connection.0, stream);
+                        });
+                    }
+                    (Ok(mut stream), None) => {
+                        let mut out = String::new();
+                        Ack(ACK_SYSTEM, "Too many connections".to_string()).write(&mut out, 0, "");
+                        let _ = stream.write_all(out.as_bytes());
+                    }
+                    (Err(e), _) => eprintln!("MPD server: {}", e),
+                }
+            }
+        });
+
+        Ok(Self { port, stopped, accepting: Some(accepting) })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for MpdServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake the accept loop so it sees the flag and lets go of the port
+        let _ = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port));
+        if let Some(accepting) = self.accepting.take() {
+            let _ = accepting.join();
+        }
+    }
+}
+
+struct Context {
+    engine: PlayerEngine,
+    password: String,
+    sync_ratings: bool,
+    // Opened on first use and shared by the connections
+    library: Mutex<Option<Library>>,
+    stopped: Arc<AtomicBool>,
+    connections: AtomicUsize,
+}
+
+// Counts an open connection until dropped
+struct Connection(Arc<Context>);
+
+impl Connection {
+    fn open(context: &Arc<Context>) -> Option<Self> {
+        context
+            .connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < MAX_CONNECTIONS).then_some(open + 1))
+            .ok()
+            .map(|_| Self(Arc::clone(context)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// What a command left the connection to do next
+enum Reply {
+    Done,
+    /// Wait for changes in these subsystems, or any with none given.
+    Idle(Vec<String>),
+    Close,
+}
+
+// An error answer: one of MPD's codes and a message
+struct Ack(u32, String);
+
+impl Ack {
+    fn write(&self, out: &mut String, index: usize, command: &str) {
+        let _ = writeln!(out, "ACK [{}@{}] {{{}}} {}", self.0, index, command, self.1);
+    }
+}
+
+impl From<anyhow::Error> for Ack {
+    fn from(e: anyhow::Error) -> Self {
+        Ack(ACK_SYSTEM, e.to_string())
+    }
+}
+
+// What `idle` compares to tell which subsystems changed
+#[derive(PartialEq)]
+struct Snapshot {
+    state: &'static str,
+    song: Option<(usize, PathBuf)>,
+    playlist: u32,
+    volume: Option<u32>,
+    repeat: RepeatMode,
+}
+
+impl Snapshot {
+    fn take(engine: &PlayerEngine) -> Self {
+        let repeat = engine.repeat();
+        let player = engine.player();
+        Self {
+            state: state(&player),
+            song: player.current().map(|(path, _)| (player.current_index(), path.clone())),
+            playlist: playlist_version(&player),
+            volume: player.audio().map(|audio| volume_percent(audio.volume())),
+            repeat,
+        }
+    }
+
+    fn changes(&self, now: &Snapshot) -> Vec<&'static str> {
+        [
+            ("player", self.state != now.state || self.song != now.song),
+            ("playlist", self.playlist != now.playlist),
+            ("mixer", self.volume != now.volume),
+            ("options", self.repeat != now.repeat),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(subsystem, _)| subsystem)
+        .collect()
+    }
+}
+
+struct Session {
+    authorized: bool,
+    // What the last `idle` reported, so the next one reports what changed since
+    seen: Snapshot,
+}
+
+// Fields `search` can match on
+enum Tag {
+    Any,
+    File,
+    Base,
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Date,
+}
+
+impl FromStr for Tag {
+    type Err = Ack;
+
+    fn from_str(text: &str) -> Result<Self, Ack> {
+        Ok(match text.to_lowercase().as_str() {
+            "any" => Tag::Any,
+            "file" => Tag::File,
+            "base" => Tag::Base,
+            "title" => Tag::Title,
+            "artist" => Tag::Artist,
+            "album" => Tag::Album,
+            "genre" => Tag::Genre,
+            "date" => Tag::Date,
+            _ => return Err(Ack(ACK_ARG, format!("Unknown tag type: {}", text))),
+        })
+    }
+}
+
+impl Tag {
+    // Whether the track's field contains `value`, ignoring case; `base` takes
+    // everything in a directory
+    fn matches(&self, value: &str, uri: &str, metadata: &SongMetadata) -> bool {
+        let value = value.to_lowercase();
+        let contains = |text: &str| text.to_lowercase().contains(&value);
+        match self {
+            Tag::Any => [uri, &metadata.title, &metadata.artist, &metadata.album, &metadata.genre].into_iter().any(contains),
+            Tag::File => contains(uri),
+            Tag::Base => Path::new(&uri.to_lowercase()).starts_with(value.trim_matches('/')),
+            Tag::Title => contains(&metadata.title),
+            Tag::Artist => contains(&metadata.artist),
+            Tag::Album => contains(&metadata.album),
+            Tag::Genre => contains(&metadata.genre),
+            Tag::Date => metadata.year.is_some_and(|year| contains(&year.to_string())),
+        }
+    }
+}
+
+fn serve(context: &Context, mut stream: TcpStream) -> io::Result<()> {
+    let lines = read_lines(stream.try_clone()?);
+    stream.write_all(GREETING.as_bytes())?;
+    let mut session = Session {
+        authorized: context.password.is_empty(),
+        seen: Snapshot::take(&context.engine),
+    };
+    // Commands collected between `command_list_begin` and `command_list_end`,
+    // and whether each gets a `list_OK`
+    let mut list: Option<(bool, Vec<Vec<String>>)> = None;
+
+    while let Ok(line) = lines.recv() {
+        if context.stopped.load(Ordering::Relaxed) {
+            break;
+        }
+        let mut out = String::new();
+        let args = match split_args(&line) {
+            Ok(args) if !args.is_empty() => args,
+            Ok(_) => {
+                Ack(ACK_UNKNOWN, "No command given".to_string()).write(&mut out, 0, "");
+                stream.write_all(out.as_bytes())?;
+                continue;
+            }
+            Err(message) => {
+                Ack(ACK_ARG, message.to_string()).write(&mut out, 0, "");
+                stream.write_all(out.as_bytes())?;
+                continue;
+            }
+        };
+
+        if let Some((list_ok, commands)) = &mut list {
+            if args[0] != "command_list_end" {
+                commands.push(args);
+                continue;
+            }
+            let list_ok = *list_ok;
+            let commands = std::mem::take(commands);
+            list = None;
+            if !context.run_list(&mut session, list_ok, &commands, &mut out) {
+                break;
+            }
+        } else if args[0] == "command_list_begin" || args[0] == "command_list_ok_begin" {
+            list = Some((args[0] == "command_list_ok_begin", Vec::new()));
+            continue;
+        } else {
+            match context.execute(&mut session, &args, &mut out) {
+                Ok(Reply::Done) => out.push_str("OK\n"),
+                Ok(Reply::Idle(subsystems)) => match context.idle(&mut session, &subsystems, &lines, &mut out) {
+                    Ok(true) => out.push_str("OK\n"),
+                    Ok(false) => break,
+                    Err(ack) => ack.write(&mut out, 0, &args[0]),
+                },
+                Ok(Reply::Close) => break,
+                Err(ack) => ack.write(&mut out, 0, &args[0]),
+            }
+        }
+        stream.write_all(out.as_bytes())?;
+    }
+    // Also ends the thread reading lines
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+// Lines from the client, read on their own thread so `idle` can wait for
+// `noidle` and for changes at the same time
+fn read_lines(stream: TcpStream) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = Vec::new();
+            match (&mut reader).take(MAX_LINE).read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                // Too long, or cut off by the client going away
+                Ok(_) if !line.ends_with(b"\n") => break,
+                Ok(_) => {}
+            }
+            let line = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Split a command line into arguments. Double quotes group words, with
+// backslash escapes inside them.
+fn split_args(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+        let mut arg = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.push(chars.next().ok_or("Unterminated string")?),
+                    Some(c) => arg.push(c),
+                    None => return Err("Unterminated string"),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+impl Context {
+    fn with_library<T>(&self, f: impl FnOnce(&Library) -> Result<T, Ack>) -> Result<T, Ack> {
+        let mut library = self.library.lock().unwrap_or_else(|e| e.into_inner());
+        if library.is_none() {
+            *library = Some(Library::open()?);
+        }
+        f(library.as_ref().expect("opened above"))
+    }
+
+    // Directory song URIs are relative to
+    fn directory(&self) -> Option<PathBuf> {
+        self.with_library(|library| Ok(library.directory()?)).ok().flatten()
+    }
+
+    // Run a command list, stopping at the first error. False when the
+    // connection should close.
+    fn run_list(&self, session: &mut Session, list_ok: bool, commands: &[Vec<String>], out: &mut String) -> bool {
+        for (index, args) in commands.iter().enumerate() {
+            match self.execute(session, args, out) {
+                Ok(Reply::Done) if list_ok => out.push_str("list_OK\n"),
+                Ok(Reply::Done) => {}
+                Ok(Reply::Idle(_)) => {
+                    Ack(ACK_ARG, "idle is not allowed in a command list".to_string()).write(out, index, &args[0]);
+                    return true;
+                }
+                Ok(Reply::Close) => return false,
+                Err(ack) => {
+                    ack.write(out, index, &args[0]);
+                    return true;
+                }
+            }
+        }
+        out.push_str("OK\n");
+        true
+    }
+
+    // Wait for a change in one of `subsystems`, or any with none given, and
+    // list what changed. False when the connection should close because the
+    // client went away or the server stopped; an error when the client sent
+    // something other than `noidle`, which ends the wait.
+    fn idle(&self, session: &mut Session, subsystems: &[String], lines: &Receiver<String>, out: &mut String) -> Result<bool, Ack> {
+        loop {
+            let now = Snapshot::take(&self.engine);
+            let changed: Vec<&str> = session
+                .seen
+                .changes(&now)
+                .into_iter()
+                .filter(|subsystem| subsystems.is_empty() || subsystems.iter().any(|wanted| wanted == subsystem))
+                .collect();
+            if !changed.is_empty() {
+                for subsystem in changed {
+                    let _ = writeln!(out, "changed: {}", subsystem);
+                }
+                session.seen = now;
+                return Ok(true);
+            }
+            match lines.recv_timeout(IDLE_POLL) {
+                Ok(line) if line.trim() == "noidle" => return Ok(true),
+                Ok(line) => {
+                    let command = line.split_whitespace().next().unwrap_or_default();
+                    return Err(Ack(ACK_ARG, format!("\"{}\" isn't allowed while idle; send noidle first", command)));
+                }
+                Err(RecvTimeoutError::Timeout) if !self.stopped.load(Ordering::Relaxed) => {}
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+
+    fn execute(&self, session: &mut Session, args: &[String], out: &mut String) -> Result<Reply, Ack> {
+        let name = args[0].as_str();
+        let engine = &self.engine;
+        if !session.authorized && !OPEN_COMMANDS.contains(&name) {
+            return Err(Ack(ACK_PERMISSION, format!("you don't have permission for \"{}\"", name)));
+        }
+        match name {
+            "ping" | "noidle" => {}
+            "close" => return Ok(Reply::Close),
+            "password" => {
+                if arg(args, 1)? != self.password {
+                    return Err(Ack(ACK_PASSWORD, "incorrect password".to_string()));
+                }
+                session.authorized = true;
+            }
+            "commands" | "notcommands" => {
+                let listed = |command: &&&str| (session.authorized || OPEN_COMMANDS.contains(*command)) == (name == "commands");
+                for command in COMMANDS.iter().filter(listed) {
+                    let _ = writeln!(out, "command: {}", command);
+                }
+            }
+            // Choosing which tags to send isn't supported; all of them always are
+            "tagtypes" => {
+                if args.len() == 1 {
+                    for tag in TAG_TYPES {
+                        let _ = writeln!(out, "tagtype: {}", tag);
+                    }
+                }
+            }
+            "idle" => return Ok(Reply::Idle(args[1..].to_vec())),
+            "status" => self.status(out),
+            "currentsong" => {
+                let directory = self.directory();
+                let player = engine.player();
+                if let Some((path, metadata)) = player.current() {
+                    write_song(out, &uri(directory.as_deref(), path), metadata, Some(player.current_index()));
+                }
+            }
+            "playlistinfo" | "plchanges" => {
+                let directory = self.directory();
+                let player = engine.player();
+                let range = match name {
+                    // Songs are only told apart by position, so any change sends them all
+                    "plchanges" if number::<u32>(arg(args, 1)?)? == playlist_version(&player) => 0..0,
+                    "plchanges" => 0..player.playlist.len(),
+                    _ => song_range(args.get(1).map(String::as_str), player.playlist.len())?,
+                };
+                for (index, (path, metadata)) in player.playlist.iter().enumerate().skip(range.start).take(range.len()) {
+                    write_song(out, &uri(directory.as_deref(), path), metadata, Some(index));
+                }
+            }
+            "play" | "playid" => {
+                let index = match args.get(1) {
+                    Some(index) => Some(song_index(index, engine.player().playlist.len())?),
+                    None => None,
+                };
+                engine.send(Command::Play(index));
+            }
+            "pause" => match args.get(1).map(String::as_str) {
+                Some("1") => engine.send(Command::Pause),
+                Some("0") => engine.send(Command::Play(None)),
+                None => engine.send(Command::TogglePlayback),
+                Some(other) => return Err(Ack(ACK_ARG, format!("Boolean (0/1) expected: {}", other))),
+            },
+            "stop" => engine.send(Command::Stop),
+            "next" => engine.send(Command::Next),
+            "previous" => engine.send(Command::Previous),
+            "seekcur" => {
+                let text = arg(args, 1)?;
+                let seconds: f64 = number(text)?;
+                if !seconds.is_finite() {
+                    return Err(Ack(ACK_ARG, format!("Number expected: {}", text)));
+                }
+                // A sign makes it relative to where playback is
+                let target = if text.starts_with(['+', '-']) {
+                    engine.player().position().unwrap_or_default().as_secs_f64() + seconds
+                } else {
+                    seconds
+                };
+                engine.send(Command::Seek(Duration::from_secs_f64(target.max(0.0))));
+            }
+            "setvol" => {
+                let volume: u32 = number(arg(args, 1)?)?;
+                if volume > 100 {
+                    return Err(Ack(ACK_ARG, "Invalid volume value".to_string()));
+                }
+                if engine.player().audio().is_none() {
+                    return Err(Ack(ACK_SYSTEM, "No audio device".to_string()));
+                }
+                engine.send(Command::SetVolume(volume as f32 / 100.0));
+            }
+            "add" => {
+                let uri = arg(args, 1)?;
+                let entries = self.with_library(|library| {
+                    let reader = library.metadata_reader(self.sync_ratings);
+                    Ok(resolve(library, uri)?
+                        .into_iter()
+                        .map(|path| {
+                            let metadata = SongMetadata { album_art: None, ..reader.read_or_placeholder(&path) };
+                            (path, metadata)
+                        })
+                        .collect::<Vec<_>>())
+                })?;
+                if entries.is_empty() {
+                    return Err(Ack(ACK_NO_EXIST, "No such song or directory".to_string()));
+                }
+                engine.send(Command::Append(entries));
+            }
+            "clear" => engine.send(Command::Load(Vec::new())),
+            "search" => {
+                if args.len() == 2 && args[1].starts_with('(') {
+                    return Err(Ack(ACK_ARG, "Filter expressions aren't supported; use TYPE WHAT pairs".to_string()));
+                }
+                if args.len() < 3 || args.len().is_multiple_of(2) {
+                    return Err(Ack(ACK_ARG, "Expected TYPE WHAT pairs".to_string()));
+                }
+                let filters = args[1..]
+                    .chunks(2)
+                    .map(|pair| Ok((pair[0].parse::<Tag>()?, pair[1].as_str())))
+                    .collect::<Result<Vec<_>, Ack>>()?;
+                let directory = self.directory();
+                let tracks = self.with_library(|library| Ok(library.db().tracks()?))?;
+                for (path, metadata) in &tracks {
+                    let uri = uri(directory.as_deref(), path);
+                    if filters.iter().all(|(tag, value)| tag.matches(value, &uri, metadata)) {
+                        write_song(out, &uri, metadata, None);
+                    }
+                }
+            }
+            _ => return Err(Ack(ACK_UNKNOWN, format!("unknown command \"{}\"", name))),
+        }
+        Ok(Reply::Done)
+    }
+
+    fn status(&self, out: &mut String) {
+        let repeat = self.engine.repeat();
+        let player = self.engine.player();
+        let state = state(&player);
+        let volume = player.audio().map_or(-1, |audio| volume_percent(audio.volume()) as i32);
+        let _ = writeln!(out, "volume: {}", volume);
+        let _ = writeln!(out, "repeat: {}", u8::from(matches!(repeat, RepeatMode::All | RepeatMode::One)));
+        let _ = writeln!(out, "random: 0");
+        let _ = writeln!(out, "single: {}", u8::from(repeat == RepeatMode::One));
+        let _ = writeln!(out, "consume: 0");
+        let _ = writeln!(out, "playlist: {}", playlist_version(&player));
+        let _ = writeln!(out, "playlistlength: {}", player.playlist.len());
+        let _ = writeln!(out, "state: {}", state);
+        let Some((_, metadata)) = player.current() else {
+            return;
+        };
+        let _ = writeln!(out, "song: {}", player.current_index());
+        let _ = writeln!(out, "songid: {}", player.current_index());
+        if state != "stop" {
+            let elapsed = player.position().unwrap_or_default().as_secs_f64();
+            let duration = metadata.duration.unwrap_or(0.0);
+            let _ = writeln!(out, "time: {}:{}", elapsed as u64, duration.round() as u64);
+            let _ = writeln!(out, "elapsed: {:.3}", elapsed);
+            if metadata.duration.is_some() {
+                let _ = writeln!(out, "duration: {:.3}", duration);
+            }
+        }
+    }
+}
+
+fn arg(args: &[String], index: usize) -> Result<&str, Ack> {
+    args.get(index)
+        .map(String::as_str)
+        .ok_or_else(|| Ack(ACK_ARG, format!("missing argument for \"{}\"", args[0])))
+}
+
+fn number<T: FromStr>(text: &str) -> Result<T, Ack> {
+    text.parse().map_err(|_| Ack(ACK_ARG, format!("Number expected: {}", text)))
+}
+
+fn song_index(text: &str, len: usize) -> Result<usize, Ack> {
+    let index = number(text)?;
+    if index >= len {
+        return Err(Ack(ACK_ARG, "Bad song index".to_string()));
+    }
+    Ok(index)
+}
+
+// `POS` or `START:END`, with END left out meaning the end of the playlist
+fn song_range(text: Option<&str>, len: usize) -> Result<Range<usize>, Ack> {
+    match text.map(|text| (text, text.split_once(':'))) {
+        None => Ok(0..len),
+        Some((_, Some((start, "")))) => Ok(number(start)?..len),
+        Some((_, Some((start, end)))) => Ok(number(start)?..number::<usize>(end)?.min(len)),
+        Some((text, None)) => {
+            let index = song_index(text, len)?;
+            Ok(index..index + 1)
+        }
+    }
+}
+
+// Library tracks for a URI: one track, or everything under a directory, with
+// "" or "/" being the whole library
+fn resolve(library: &Library, uri: &str) -> Result<Vec<PathBuf>> {
+    let path = match library.directory()? {
+        Some(directory) if !Path::new(uri).is_absolute() || uri == "/" => directory.join(uri.trim_start_matches('/')),
+        _ => PathBuf::from(uri),
+    };
+    if library.db().has_track(&path)? {
+        return Ok(vec![path]);
+    }
+    Ok(library.files()?.into_iter().filter(|file| file.starts_with(&path)).collect())
+}
+
+fn uri(directory: Option<&Path>, path: &Path) -> String {
+    directory
+        .and_then(|directory| path.strip_prefix(directory).ok())
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn state(player: &Player) -> &'static str {
+    if player.is_playing() {
+        "play"
+    } else if player.is_paused() {
+        "pause"
+    } else {
+        "stop"
+    }
+}
+
+fn volume_percent(volume: f32) -> u32 {
+    (volume * 100.0).round().clamp(0.0, 100.0) as u32
+}
+
+// Changes whenever the playlist or the tags shown for it do
+fn playlist_version(player: &Player) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    for (path, metadata) in &player.playlist {
+        path.hash(&mut hasher);
+        metadata.title.hash(&mut hasher);
+        metadata.artist.hash(&mut hasher);
+    }
+    hasher.finish() as u32
+}
+
+fn write_song(out: &mut String, uri: &str, metadata: &SongMetadata, position: Option<usize>) {
+    field(out, "file", uri);
+    for (name, value) in [("Title", &metadata.title), ("Artist", &metadata.artist), ("Album", &metadata.album), ("Genre", &metadata.genre)] {
+        if !value.is_empty() {
+            field(out, name, value);
+        }
+    }
+    if let Some(year) = metadata.year {
+        let _ = writeln!(out, "Date: {}", year);
+    }
+    if let Some(duration) = metadata.duration {
+        let _ = writeln!(out, "Time: {}", duration.round() as u64);
+        let _ = writeln!(out, "duration: {:.3}", duration);
+    }
+    if let Some(position) = position {
+        let _ = writeln!(out, "Pos: {}", position);
+        let _ = writeln!(out, "Id: {}", position);
+    }
+}
+
+// One `Name: value` line; a newline in a tag can't start a line of its own
+fn field(out: &mut String, name: &str, value: &str) {
+    let _ = writeln!(out, "{}: {}", name, value.replace(['\n', '\r'], " "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::music::{CancelToken, ScanOptions};
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(server: &MpdServer) -> Self {
+            let writer = TcpStream::connect((Ipv4Addr::LOCALHOST, server.port())).unwrap();
+            writer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut client = Self { reader: BufReader::new(writer.try_clone().unwrap()), writer };
+            assert_eq!(client.line(), GREETING.trim_end());
+            client
+        }
+
+        fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }
+
+        fn send(&mut self, line: &str) {
+            writeln!(self.writer, "{}", line).unwrap();
+        }
+
+        // The lines of the answer up to and including `OK` or `ACK`
+        fn answer(&mut self) -> Vec<String> {
+            let mut lines = Vec::new();
+            loop {
+                let line = self.line();
+                let last = line == "OK" || line.starts_with("ACK ") || line.is_empty();
+                lines.push(line);
+                if last {
+                    return lines;
+                }
+            }
+        }
+
+        fn command(&mut self, line: &str) -> Vec<String> {
+            self.send(line);
+            self.answer()
+        }
+    }
+
+    fn settle(engine: &PlayerEngine, done: impl Fn(&Player) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&engine.player()) {
+            assert!(Instant::now() < deadline, "engine didn't settle");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn clients_can_queue_play_and_wait_for_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        std::fs::create_dir_all(music.join("album")).unwrap();
+        std::fs::write(music.join("album/a.mp3"), b"").unwrap();
+        let mut library = Library::open_at(&dir.path().join("library.db")).unwrap();
+        library.scan(&music, &ScanOptions::default(), &CancelToken::new(), &mpsc::channel().0).unwrap();
+
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        let settings = MpdSettings { enabled: true, lan: false, port: 0, password: String::new() };
+        let server = MpdServer::start_with(&settings, engine.clone(), false, Some(library)).unwrap();
+        let mut client = Client::connect(&server);
+
+        let status = client.command("status");
+        assert!(status.contains(&"playlistlength: 0".to_string()), "{:?}", status);
+        assert!(status.contains(&"state: stop".to_string()), "{:?}", status);
+        assert_eq!(status.last().unwrap(), "OK");
+        assert_eq!(client.command("currentsong"), ["OK"]);
+
+        assert_eq!(client.command("add album"), ["OK"]);
+        assert_eq!(client.command("add missing.mp3"), ["ACK [50@0] {add} No such song or directory"]);
+        settle(&engine, |player| player.playlist.len() == 1);
+        assert_eq!(client.command("play 0"), ["OK"]);
+        assert_eq!(client.command("play 1"), ["ACK [2@0] {play} Bad song index"]);
+        settle(&engine, |player| player.current().is_some());
+        let song = client.command("currentsong");
+        assert_eq!(song[0], "file: album/a.mp3");
+        assert!(song.contains(&"Pos: 0".to_string()), "{:?}", song);
+
+        // Changes since the connection opened are reported straight away
+        assert_eq!(client.command("idle playlist"), ["changed: playlist", "OK"]);
+        // Otherwise the wait lasts until `noidle`
+        client.send("idle");
+        thread::sleep(IDLE_POLL * 2);
+        assert_eq!(client.command("noidle"), ["OK"]);
+        // Anything else ends the wait with an error, but not the connection
+        client.send("idle");
+        assert_eq!(client.command("status"), ["ACK [2@0] {idle} \"status\" isn't allowed while idle; send noidle first"]);
+        assert_eq!(client.command("ping"), ["OK"]);
+
+        // A change ends the wait too
+        client.send("idle player");
+        engine.send(Command::Load(vec![(music.join("b.mp3"), SongMetadata::default())]));
+        assert_eq!(client.answer(), ["changed: player", "OK"]);
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_turned_away() {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        let settings = MpdSettings { enabled: true, lan: false, port: 0, password: String::new() };
+        let server = MpdServer::start(&settings, engine, false).unwrap();
+        let mut clients: Vec<Client> = (0..MAX_CONNECTIONS).map(|_| Client::connect(&server)).collect();
+
+        let mut refused = String::new();
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server.port())).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.read_to_string(&mut refused).unwrap();
+        assert_eq!(refused, "ACK [52@0] {} Too many connections\n");
+        // The ones already open carry on
+        assert_eq!(clients[0].command("ping"), ["OK"]);
+
+        // Once one closes there's room again
+        clients.pop().unwrap().send("close");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server.port())).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut greeting = [0; GREETING.len()];
+            stream.read_exact(&mut greeting).unwrap();
+            if greeting == GREETING.as_bytes() {
+                break;
+            }
+            assert!(Instant::now() < deadline, "the closed connection wasn't let go");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
@@ -0,0 +1,578 @@
+// Desktop media controls on Linux: media keys, `playerctl` and lock-screen
+// widgets talk to players through the MPRIS interfaces on the session bus.
+//
+// Calls from the bus arrive on zbus's own thread and go straight to the
+// engine as `Command`s. A thread of our own follows the engine's events and
+// publishes what is playing, so the bus stays current while the window is
+// minimized; changes go out as PropertiesChanged signals.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use eframe::egui;
+use music_shuffler::engine::{Command, Event, PlayerEngine};
+use music_shuffler::playlist_file;
+use sha2::{Digest, Sha256};
+use zbus::blocking::connection;
+use zbus::blocking::Connection;
+use zbus::fdo;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use crate::metadata::SongMetadata;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.music_shuffler";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+// Pausing and resuming send no events, so the state is also checked this often
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+// A position further than this from where playback should be was a seek
+const SEEK_THRESHOLD: f64 = 1.0;
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum PlaybackStatus {
+    Playing,
+    Paused,
+    #[default]
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct Track {
+    index: usize,
+    path: PathBuf,
+    title: String,
+    artist: String,
+    album: String,
+    length: Option<f32>,
+    art_url: Option<String>,
+}
+
+impl Track {
+    // Each playlist position is its own track id
+    fn id(&self) -> String {
+        format!("/org/musicshuffler/track/{}", self.index)
+    }
+}
+
+// What the bus sees, kept by the publishing thread and read by property getters
+#[derive(Clone, Default, PartialEq)]
+struct PlayerState {
+    status: PlaybackStatus,
+    track: Option<Track>,
+    volume: f64,
+}
+
+struct Shared {
+    state: Mutex<PlayerState>,
+    engine: PlayerEngine,
+    ctx: egui::Context,
+}
+
+impl Shared {
+    fn state(&self) -> PlayerState {
+        self.state.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    fn send(&self, command: Command) {
+        self.engine.send(command);
+        // Redraw so the window shows the change straight away
+        self.ctx.request_repaint();
+    }
+
+    fn seek(&self, position: Duration) {
+        // The engine moves on to the next track for positions past the end
+        self.send(Command::Seek(position));
+    }
+}
+
+fn metadata(track: &Option<Track>) -> HashMap<String, OwnedValue> {
+    let mut values: Vec<(&str, Value)> = Vec::new();
+    if let Some(track) = track {
+        if let Ok(id) = ObjectPath::try_from(track.id()) {
+            values.push(("mpris:trackid", Value::from(id)));
+        }
+        if let Some(length) = track.length {
+            values.push(("mpris:length", Value::from((length as f64 * 1e6) as i64)));
+        }
+        if let Some(url) = &track.art_url {
+            values.push(("mpris:artUrl", Value::from(url.clone())));
+        }
+        values.push(("xesam:title", Value::from(track.title.clone())));
+        values.push(("xesam:artist", Value::from(vec![track.artist.clone()])));
+        values.push(("xesam:album", Value::from(track.album.clone())));
+    } else if let Ok(id) = ObjectPath::try_from("/org/mpris/MediaPlayer2/TrackList/NoTrack") {
+        values.push(("mpris:trackid", Value::from(id)));
+    }
+    values
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), OwnedValue::try_from(value).ok()?)))
+        .collect()
+}
+
+fn micros(duration: Duration) -> i64 {
+    duration.as_micros() as i64
+}
+
+struct Root {
+    shared: Arc<Shared>,
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {
+        self.shared.ctx.send_viewport_cmd(egui::ViewportCommand::Focus);
+    }
+
+    fn quit(&self) {
+        self.shared.ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "Music Shuffler"
+    }
+
+    #[zbus(property)]
+    fn desktop_entry(&self) -> &str {
+        "music-shuffler"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct Player {
+    shared: Arc<Shared>,
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn play_pause(&self) {
+        self.shared.send(Command::TogglePlayback);
+    }
+
+    fn play(&self) {
+        self.shared.send(Command::Play(None));
+    }
+
+    fn pause(&self) {
+        self.shared.send(Command::Pause);
+    }
+
+    fn stop(&self) {
+        self.shared.send(Command::Stop);
+    }
+
+    fn next(&self) {
+        self.shared.send(Command::Next);
+    }
+
+    fn previous(&self) {
+        self.shared.send(Command::Previous);
+    }
+
+    fn seek(&self, offset: i64) {
+        let position = self.shared.engine.player().position().unwrap_or_default();
+        let target = (position.as_secs_f64() + offset as f64 / 1e6).max(0.0);
+        self.shared.seek(Duration::from_secs_f64(target));
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        // Requests meant for a track that has since changed are ignored
+        let current = self.shared.state().track.map(|t| t.id());
+        if position >= 0 && current.as_deref() == Some(track_id.as_str()) {
+            self.shared.seek(Duration::from_micros(position as u64));
+        }
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("Opening URIs isn't supported".to_string()))
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        self.shared.state().status.as_str()
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> &str {
+        "None"
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        metadata(&self.shared.state().track)
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.shared.state().volume
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) {
+        self.shared.send(Command::SetVolume(volume.clamp(0.0, 1.0) as f32));
+    }
+
+    // Players poll this rather than follow it through signals
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        micros(self.shared.engine.player().position().unwrap_or_default())
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+// Follows the engine and publishes its state on the bus
+struct Publisher {
+    connection: Connection,
+    shared: Arc<Shared>,
+    // Where playback was at the last check, to tell seeks from playing on
+    last_position: Option<(Duration, Instant)>,
+    // Album art is handed over as a file named after a hash of its contents
+    art_file: Option<PathBuf>,
+}
+
+impl Publisher {
+    fn run(mut self, events: Receiver<Event>, stopped: Arc<AtomicBool>) {
+        let mut track_changed = true;
+        while !stopped.load(Ordering::Relaxed) {
+            match events.recv_timeout(REFRESH_INTERVAL) {
+                // Tags read after the track started arrive as playlist changes
+                Ok(Event::PlaylistChanged) => track_changed = true,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.publish(track_changed);
+            track_changed = false;
+        }
+        if let Some(path) = self.art_file.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn publish(&mut self, track_changed: bool) {
+        let previous = self.shared.state();
+        let (status, position, volume, current) = {
+            let player = self.shared.engine.player();
+            let status = if player.is_playing() {
+                PlaybackStatus::Playing
+            } else if player.is_paused() {
+                PlaybackStatus::Paused
+            } else {
+                PlaybackStatus::Stopped
+            };
+            let volume = player.audio().map_or(1.0, |audio| audio.volume());
+            let index = player.current_index();
+            // The tags, with their art, are only copied when the track changed
+            let moved = previous.track.as_ref().map(|t| (t.index, &t.path)) != player.current().map(|(path, _)| (index, path));
+            let current = (track_changed || moved).then(|| player.current().map(|(path, metadata)| (index, path.clone(), metadata.clone())));
+            (status, player.position().unwrap_or_default(), volume, current)
+        };
+
+        let now = Instant::now();
+        let seeked = match (self.last_position, &current) {
+            (Some((last, at)), None) => {
+                let expected = if previous.status == PlaybackStatus::Playing { last + now.duration_since(at) } else { last };
+                (position.as_secs_f64() - expected.as_secs_f64()).abs() > SEEK_THRESHOLD
+            }
+            _ => false,
+        };
+        self.last_position = Some((position, now));
+
+        let track = match current {
+            Some(current) => current.map(|(index, path, metadata)| {
+                let art_url = self.art_url(&metadata);
+                Track {
+                    index,
+                    path,
+                    title: metadata.title,
+                    artist: metadata.artist,
+                    album: metadata.album,
+                    length: metadata.duration,
+                    art_url,
+                }
+            }),
+            None => previous.track.clone(),
+        };
+        let state = PlayerState { status, track, volume: volume as f64 };
+        if state != previous {
+            if let Ok(mut current) = self.shared.state.lock() {
+                *current = state.clone();
+            }
+            self.signal_changes(&previous, &state);
+        }
+        if seeked {
+            if let Err(e) = self.connection.emit_signal(None::<&str>, OBJECT_PATH, PLAYER_INTERFACE, "Seeked", &(micros(position),)) {
+                eprintln!("Could not signal MPRIS seek: {}", e);
+            }
+        }
+    }
+
+    fn signal_changes(&self, previous: &PlayerState, state: &PlayerState) {
+        let mut changed: HashMap<&str, Value> = HashMap::new();
+        if previous.status != state.status {
+            changed.insert("PlaybackStatus", Value::from(state.status.as_str()));
+        }
+        if previous.track != state.track {
+            changed.insert("Metadata", Value::from(metadata(&state.track)));
+        }
+        if previous.volume != state.volume {
+            changed.insert("Volume", Value::from(state.volume));
+        }
+        let invalidated: Vec<&str> = Vec::new();
+        if let Err(e) = self.connection.emit_signal(
+            None::<&str>,
+            OBJECT_PATH,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            &(PLAYER_INTERFACE, changed, invalidated),
+        ) {
+            eprintln!("Could not signal MPRIS changes: {}", e);
+        }
+    }
+
+    // Write the track's art to a temporary file named after its contents, so
+    // players that cache by URL pick up new art and the same art isn't
+    // written twice
+    fn art_url(&mut self, metadata: &SongMetadata) -> Option<String> {
+        let art = metadata.album_art.as_ref()?;
+        let hash: String = Sha256::digest(art)[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        let path = std::env::temp_dir().join(format!("music-shuffler-art-{}", hash));
+        if self.art_file.as_ref() != Some(&path) {
+            if let Some(old) = self.art_file.take() {
+                let _ = std::fs::remove_file(old);
+            }
+            match std::fs::write(&path, art) {
+                Ok(()) => self.art_file = Some(path),
+                Err(e) => eprintln!("Could not write album art for MPRIS: {}", e),
+            }
+        }
+        self.art_file.as_deref().map(playlist_file::file_uri)
+    }
+}
+
+/// The player as published on the session bus, until dropped.
+pub struct Mpris {
+    _connection: Connection,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Mpris {
+    /// Claim the MPRIS name on the session bus. Fails when there is no session
+    /// bus or another instance already holds the name.
+    pub fn start(engine: PlayerEngine, ctx: egui::Context) -> Result<Self> {
+        Self::start_on(connection::Builder::session()?, engine, ctx)
+    }
+
+    fn start_on(builder: connection::Builder<'_>, engine: PlayerEngine, ctx: egui::Context) -> Result<Self> {
+        let events = engine.subscribe();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PlayerState { volume: 1.0, ..Default::default() }),
+            engine,
+            ctx,
+        });
+        let connection = builder
+            .serve_at(OBJECT_PATH, Root { shared: Arc::clone(&shared) })?
+            .serve_at(OBJECT_PATH, Player { shared: Arc::clone(&shared) })?
+            .name(BUS_NAME)?
+            .build()?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let publisher = Publisher { connection: connection.clone(), shared, last_position: None, art_file: None };
+        let publisher_stopped = Arc::clone(&stopped);
+        thread::spawn(move || publisher.run(events, publisher_stopped));
+        Ok(Self { _connection: connection, stopped })
+    }
+}
+
+impl Drop for Mpris {
+    // The publisher notices within `REFRESH_INTERVAL` and cleans up after itself
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Stdio};
+    use std::path::Path;
+    use music_shuffler::{Player as EnginePlayer, RepeatMode};
+
+    // A bus daemon of our own, so tests neither need nor disturb a desktop session
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Option<Self> {
+            let mut daemon = std::process::Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?).read_line(&mut address).ok()?;
+            Some(Self { daemon, address: address.trim().to_string() })
+        }
+
+        fn connect(&self) -> Connection {
+            connection::Builder::address(self.address.as_str()).unwrap().build().unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn property(client: &Connection, name: &str) -> OwnedValue {
+        let reply = client
+            .call_method(Some(BUS_NAME), OBJECT_PATH, Some("org.freedesktop.DBus.Properties"), "Get", &(PLAYER_INTERFACE, name))
+            .unwrap();
+        reply.body().deserialize::<OwnedValue>().unwrap()
+    }
+
+    fn title(client: &Connection) -> Option<String> {
+        let metadata = HashMap::<String, OwnedValue>::try_from(property(client, "Metadata")).ok()?;
+        String::try_from(metadata.get("xesam:title")?.try_clone().ok()?).ok()
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn publishes_the_engine_and_takes_commands() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("dbus-daemon isn't available; skipping");
+            return;
+        };
+        let entry = |title: &str, art: &[u8]| {
+            let metadata = SongMetadata { title: title.to_string(), album_art: Some(art.to_vec()), ..Default::default() };
+            (PathBuf::from(format!("/music/{}.mp3", title)), metadata)
+        };
+        let engine = EnginePlayer::new(None);
+        let engine = PlayerEngine::start(engine, RepeatMode::Off);
+        engine.send(Command::Load(vec![entry("First", b"first art"), entry("Second", b"second art")]));
+
+        let server = connection::Builder::address(bus.address.as_str()).unwrap();
+        let mpris = Mpris::start_on(server, engine.clone(), egui::Context::default()).unwrap();
+        let client = bus.connect();
+        assert!(wait_for(|| title(&client).as_deref() == Some("First")));
+        assert_eq!(String::try_from(property(&client, "PlaybackStatus")).unwrap(), "Stopped");
+        assert_eq!(f64::try_from(property(&client, "Volume")).unwrap(), 1.0);
+
+        // Commands from the bus reach the engine, and the change comes back
+        client.call_method(Some(BUS_NAME), OBJECT_PATH, Some(PLAYER_INTERFACE), "Next", &()).unwrap();
+        assert!(wait_for(|| engine.player().current_index() == 1));
+        assert!(wait_for(|| title(&client).as_deref() == Some("Second")));
+
+        let metadata = HashMap::<String, OwnedValue>::try_from(property(&client, "Metadata")).unwrap();
+        let url = String::try_from(metadata["mpris:artUrl"].try_clone().unwrap()).unwrap();
+        let path = url.strip_prefix("file://").unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"second art");
+
+        // The art file goes with the publisher
+        drop(mpris);
+        assert!(wait_for(|| !Path::new(path).exists()));
+    }
+}
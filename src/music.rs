@@ -1,116 +1,212 @@
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rand::seq::SliceRandom;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::sync::mpsc;
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use crate::library::TrackStats;
 
-pub fn scan_music_directory(dir: &Path) -> Result<Vec<PathBuf>> {
-    scan_music_directory_fast(dir)
+/// Handle used to ask a running scan to stop at the next entry.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
-// Scan with progress callback
-pub fn scan_music_directory_with_progress<F>(dir: &Path, progress_callback: F) -> Result<Vec<PathBuf>>
-where
-    F: Fn(String) + Send + Sync + 'static,
-{
-    let progress_callback = Arc::new(progress_callback);
-    
-    progress_callback("Discovering files...".to_string());
-    
-    // Collect all entries first (this is usually fast)
-    let entries: Vec<_> = WalkDir::new(dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .collect();
-    
-    progress_callback(format!("Found {} total files, filtering for music files...", entries.len()));
-    
-    let mut music_files = Vec::new();
-    for (i, entry) in entries.iter().enumerate() {
-        let path = entry.path();
-        if is_music_file(path) {
-            music_files.push(path.to_path_buf());
+/// Traversal settings for directory scans.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanOptions {
+    /// Follow symlinked files and folders; walkdir reports symlink loops as errors.
+    pub follow_symlinks: bool,
+    /// Don't cross into other filesystems (mounted drives, network shares).
+    pub same_file_system: bool,
+    /// Treat entries with the same device/inode as one, so a track reached
+    /// through several links is only listed once and bind-mount loops are skipped.
+    pub dedupe_by_inode: bool,
+}
+
+/// Progress events sent by `scan_music_directory_streaming` while it walks.
+pub enum ScanEvent {
+    /// Sent once the root has been listed, with its number of subdirectories.
+    Started { top_level_dirs: usize },
+    DirectoryVisited { path: PathBuf, depth: usize },
+    FileMatched(PathBuf),
+    /// A file or folder already reached through another path was skipped.
+    DuplicateSkipped(PathBuf),
+    Error { path: Option<PathBuf>, message: String },
+    /// The scan completed and its results were saved; sent by `Library::scan`.
+    Finished(Vec<PathBuf>),
+    Cancelled,
+    /// The scan couldn't be completed; the previous results still stand.
+    Failed(String),
+}
+
+/// Running totals built from `ScanEvent`s, used to draw the scan progress view.
+#[derive(Default)]
+pub struct ScanProgress {
+    pub dirs_visited: usize,
+    pub files_matched: usize,
+    pub duplicates_skipped: usize,
+    pub errors: usize,
+    pub top_level_done: usize,
+    pub top_level_total: usize,
+    pub current_path: Option<PathBuf>,
+    pub last_error: Option<String>,
+}
+
+impl ScanProgress {
+    pub fn apply(&mut self, event: &ScanEvent) {
+        match event {
+            ScanEvent::Started { top_level_dirs } => self.top_level_total = *top_level_dirs,
+            ScanEvent::DirectoryVisited { path, depth } => {
+                self.dirs_visited += 1;
+                self.current_path = Some(path.clone());
+                // Top-level folders are walked one after another, so finishing
+                // them is the best cheap estimate of overall progress we have
+                if *depth == 1 {
+                    self.top_level_done += 1;
+                }
+            }
+            ScanEvent::FileMatched(path) => {
+                self.files_matched += 1;
+                self.current_path = Some(path.clone());
+            }
+            ScanEvent::DuplicateSkipped(path) => {
+                self.duplicates_skipped += 1;
+                self.current_path = Some(path.clone());
+            }
+            ScanEvent::Error { path, message } => {
+                self.errors += 1;
+                self.last_error = Some(match path {
+                    Some(path) => format!("{}: {}", path.display(), message),
+                    None => message.clone(),
+                });
+            }
+            ScanEvent::Failed(message) => self.last_error = Some(message.clone()),
+            ScanEvent::Finished(_) | ScanEvent::Cancelled => {}
         }
-        
-        // Update progress every 100 files or so
-        if i % 100 == 0 || i == entries.len() - 1 {
-            progress_callback(format!("Processed {}/{} files, found {} music files", i + 1, entries.len(), music_files.len()));
+    }
+
+    pub fn fraction(&self) -> f32 {
+        if self.top_level_total == 0 {
+            return 0.0;
         }
+        // A folder counts as done once the walk moves past it, so the one
+        // currently being walked is excluded
+        let done = self.top_level_done.saturating_sub(1);
+        (done as f32 / self.top_level_total as f32).clamp(0.0, 1.0)
     }
-    
-    music_files.sort(); // Sort for consistent ordering
-    progress_callback(format!("Scan complete! Found {} music files", music_files.len()));
-    
-    Ok(music_files)
 }
 
-// Fast scanning - just finds music files without loading metadata
-pub fn scan_music_directory_fast(dir: &Path) -> Result<Vec<PathBuf>> {
-    let _music_files = Arc::new(Mutex::new(Vec::<PathBuf>::new()));
-    let (tx, rx) = mpsc::channel();
-    
-    // Use multiple threads for file scanning
-    let num_threads = std::thread::available_parallelism()
-        .map(|p| p.get())
-        .unwrap_or(4)
-        .min(8); // Cap at 8 threads to avoid overwhelming I/O
-    
-    println!("Scanning music directory with {} threads...", num_threads);
-    
-    // Collect all entries first (this is usually fast)
-    let entries: Vec<_> = WalkDir::new(dir)
-        .into_iter()
+// Identity of a file on disk, used to spot the same file reached through several paths
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+#[cfg(unix)]
+fn file_id(entry: &walkdir::DirEntry) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    entry.metadata().ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(entry: &walkdir::DirEntry) -> Option<FileId> {
+    std::fs::canonicalize(entry.path()).ok()
+}
+
+// Walk the directory lazily, sending progress events as entries are visited.
+// Returns an error if the scan was cancelled through `cancel`. `Finished` is
+// left to the caller, once the results are saved.
+pub fn scan_music_directory_streaming(
+    dir: &Path,
+    options: &ScanOptions,
+    cancel: &CancelToken,
+    events: &Sender<ScanEvent>,
+) -> Result<Vec<PathBuf>> {
+    // Send errors are ignored: the receiver going away just means nobody is watching
+    let top_level_dirs = std::fs::read_dir(dir)?
         .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .collect();
-    
-    println!("Found {} total files, filtering for music files...", entries.len());
-    
-    // Split entries among threads
-    let chunk_size = entries.len().div_ceil(num_threads);
-    let mut handles = Vec::new();
-    
-    for chunk in entries.chunks(chunk_size) {
-        let chunk = chunk.to_vec();
-        let tx = tx.clone();
-        
-        let handle = thread::spawn(move || {
-            let mut local_music_files = Vec::new();
-            
-            for entry in chunk {
-                let path = entry.path();
-                if is_music_file(path) {
-                    local_music_files.push(path.to_path_buf());
+        .filter(|e| {
+            if options.follow_symlinks {
+                e.path().is_dir()
+            } else {
+                e.file_type().map(|t| t.is_dir()).unwrap_or(false)
+            }
+        })
+        .count();
+    let _ = events.send(ScanEvent::Started { top_level_dirs });
+
+    let mut seen: HashSet<FileId> = HashSet::new();
+    let mut music_files = Vec::new();
+    let mut walker = WalkDir::new(dir)
+        .follow_links(options.follow_symlinks)
+        .same_file_system(options.same_file_system)
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        if cancel.is_cancelled() {
+            let _ = events.send(ScanEvent::Cancelled);
+            return Err(anyhow!("Scan cancelled"));
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let _ = events.send(ScanEvent::Error {
+                    path: e.path().map(Path::to_path_buf),
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let is_dir = entry.file_type().is_dir();
+        let is_music = entry.file_type().is_file() && is_music_file(entry.path());
+        if !is_dir && !is_music {
+            continue;
+        }
+
+        if options.dedupe_by_inode {
+            if let Some(id) = file_id(&entry) {
+                if !seen.insert(id) {
+                    let _ = events.send(ScanEvent::DuplicateSkipped(entry.path().to_path_buf()));
+                    if is_dir {
+                        walker.skip_current_dir();
+                    }
+                    continue;
                 }
             }
-            
-            let _ = tx.send(local_music_files);
-        });
-        
-        handles.push(handle);
-    }
-    
-    // Drop the original sender
-    drop(tx);
-    
-    // Collect results from all threads
-    let mut all_music_files = Vec::new();
-    for received_files in rx {
-        all_music_files.extend(received_files);
-    }
-    
-    // Wait for all threads to complete
-    for handle in handles {
-        let _ = handle.join();
-    }
-    
-    all_music_files.sort(); // Sort for consistent ordering
-    println!("Found {} music files", all_music_files.len());
-    
-    Ok(all_music_files)
+        }
+
+        if is_dir {
+            let _ = events.send(ScanEvent::DirectoryVisited {
+                path: entry.path().to_path_buf(),
+                depth: entry.depth(),
+            });
+        } else {
+            let path = entry.into_path();
+            let _ = events.send(ScanEvent::FileMatched(path.clone()));
+            music_files.push(path);
+        }
+    }
+
+    music_files.sort(); // Sort for consistent ordering
+    Ok(music_files)
 }
 
 fn is_music_file(path: &Path) -> bool {
@@ -122,11 +218,159 @@ fn is_music_file(path: &Path) -> bool {
     }
 }
 
-pub fn generate_playlist(music_files: &[PathBuf], count: usize) -> Vec<PathBuf> {
+// Pick `count` random tracks, never returning any path in `excluded`
+pub fn generate_playlist(music_files: &[PathBuf], count: usize, excluded: &HashSet<PathBuf>) -> Vec<PathBuf> {
     let mut rng = rand::rng();
-    let mut files_vec = music_files.to_vec();
+    let mut files_vec: Vec<PathBuf> = music_files
+        .iter()
+        .filter(|path| !excluded.contains(*path))
+        .cloned()
+        .collect();
     files_vec.shuffle(&mut rng);
     files_vec.into_iter().take(count).collect()
 }
 
- 
\ No newline at end of file
+/// Picks shuffled playlists from a pool of tracks, leaving out the tracks
+/// that shouldn't come up: duplicates and tracks banned from shuffles.
+pub struct PlaylistGenerator {
+    count: usize,
+    excluded: HashSet<PathBuf>,
+}
+
+impl PlaylistGenerator {
+    /// A generator for playlists of up to `count` tracks.
+    pub fn new(count: usize) -> Self {
+        Self { count, excluded: HashSet::new() }
+    }
+
+    /// Never pick any of `paths`.
+    pub fn exclude(mut self, paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.excluded.extend(paths);
+        self
+    }
+
+    /// Never pick tracks banned from shuffles.
+    pub fn exclude_banned(self, stats: &HashMap<PathBuf, TrackStats>) -> Self {
+        self.exclude(stats.iter().filter(|(_, stats)| stats.banned).map(|(path, _)| path.clone()))
+    }
+
+    /// A random selection from `pool`, in playing order.
+    pub fn generate(&self, pool: &[PathBuf]) -> Vec<PathBuf> {
+        generate_playlist(pool, self.count, &self.excluded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(count: usize) -> Vec<PathBuf> {
+        (0..count).map(|i| PathBuf::from(format!("/music/{}.mp3", i))).collect()
+    }
+
+    #[test]
+    fn playlists_hold_up_to_count_distinct_tracks() {
+        let tracks = pool(20);
+        let picked = PlaylistGenerator::new(5).generate(&tracks);
+        assert_eq!(picked.len(), 5);
+        assert_eq!(picked.iter().collect::<HashSet<_>>().len(), 5);
+        assert!(picked.iter().all(|path| tracks.contains(path)));
+
+        // A small pool gives all it has, and nothing gives nothing
+        assert_eq!(PlaylistGenerator::new(50).generate(&tracks).len(), 20);
+        assert!(PlaylistGenerator::new(0).generate(&tracks).is_empty());
+        assert!(PlaylistGenerator::new(5).generate(&[]).is_empty());
+    }
+
+    #[test]
+    fn excluded_and_banned_tracks_are_never_picked() {
+        let tracks = pool(6);
+        let banned = TrackStats { banned: true, ..Default::default() };
+        let rated = TrackStats { rating: Some(5), ..Default::default() };
+        let stats = HashMap::from([(tracks[0].clone(), banned), (tracks[1].clone(), rated)]);
+        let generator = PlaylistGenerator::new(10).exclude([tracks[2].clone(), tracks[3].clone()]).exclude_banned(&stats);
+
+        let picked: HashSet<PathBuf> = generator.generate(&tracks).into_iter().collect();
+        assert_eq!(picked, HashSet::from([tracks[1].clone(), tracks[4].clone(), tracks[5].clone()]));
+    }
+
+    // Scan `dir` to the end, returning what it found and the events it sent
+    fn scan(dir: &Path, options: ScanOptions) -> (Vec<PathBuf>, Vec<ScanEvent>) {
+        let (events, receiver) = std::sync::mpsc::channel();
+        let files = scan_music_directory_streaming(dir, &options, &CancelToken::new(), &events).unwrap();
+        (files, receiver.try_iter().collect())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_reported_and_walked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("album")).unwrap();
+        std::fs::write(dir.path().join("album/a.mp3"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("album/loop")).unwrap();
+
+        let follow = ScanOptions { follow_symlinks: true, ..Default::default() };
+        let (files, events) = scan(dir.path(), follow);
+        assert_eq!(files, [dir.path().join("album/a.mp3")]);
+        assert!(events.iter().any(|event| matches!(event, ScanEvent::Error { path: Some(path), .. } if path.ends_with("loop"))));
+
+        // Not followed, the link is just skipped
+        let (files, events) = scan(dir.path(), ScanOptions::default());
+        assert_eq!(files, [dir.path().join("album/a.mp3")]);
+        assert!(!events.iter().any(|event| matches!(event, ScanEvent::Error { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tracks_reached_through_links_are_listed_once_with_dedupe() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("album")).unwrap();
+        std::fs::write(dir.path().join("album/a.mp3"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("album"), dir.path().join("linked album")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("album/a.mp3"), dir.path().join("b.mp3")).unwrap();
+
+        let (files, _) = scan(dir.path(), ScanOptions { follow_symlinks: true, ..Default::default() });
+        assert_eq!(files.len(), 3);
+
+        let options = ScanOptions { follow_symlinks: true, dedupe_by_inode: true, ..Default::default() };
+        let (files, events) = scan(dir.path(), options);
+        assert_eq!(files.len(), 1);
+        let skipped = events.iter().filter(|event| matches!(event, ScanEvent::DuplicateSkipped(_))).count();
+        // The linked folder is skipped whole, so its copy of the track isn't reached
+        assert_eq!(skipped, 2);
+
+        let options = ScanOptions { follow_symlinks: true, same_file_system: true, dedupe_by_inode: true };
+        assert_eq!(scan(dir.path(), options).0.len(), 1);
+    }
+
+    #[test]
+    fn scans_stop_partway_when_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        for folder in 0..100 {
+            let folder = dir.path().join(folder.to_string());
+            std::fs::create_dir(&folder).unwrap();
+            for track in 0..50 {
+                std::fs::write(folder.join(format!("{}.mp3", track)), b"").unwrap();
+            }
+        }
+        let cancel = CancelToken::new();
+        let (events, receiver) = std::sync::mpsc::channel();
+        let scanning = {
+            let (path, cancel) = (dir.path().to_path_buf(), cancel.clone());
+            std::thread::spawn(move || scan_music_directory_streaming(&path, &ScanOptions::default(), &cancel, &events))
+        };
+        // Cancel as soon as the walk starts
+        let mut matched = 0;
+        for event in receiver.iter() {
+            match event {
+                ScanEvent::Started { .. } => cancel.cancel(),
+                ScanEvent::FileMatched(_) => matched += 1,
+                ScanEvent::Cancelled => break,
+                _ => {}
+            }
+        }
+        assert!(scanning.join().unwrap().is_err());
+        assert!(matched < 5000, "the scan ran to the end");
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use crate::audio::AudioPlayer;
+use crate::config::RepeatMode;
+use crate::engine::Event;
+use crate::library;
+use crate::queue::{self, Entry};
+
+/// A track that was played far enough to count, or skipped before that.
+/// Front ends record these with `Library::record_listen`.
+#[derive(Clone)]
+pub struct Listen {
+    pub path: PathBuf,
+    pub seconds: f32,
+    pub skipped: bool,
+}
+
+/// Plays through a playlist: the tracks, where playback is in them, the Up Next
+/// queue, and which listens count as plays. What happens along the way is
+/// collected as `Event`s; `PlayerEngine` runs one on its own thread.
+pub struct Player {
+    audio: Option<AudioPlayer>,
+    pub playlist: Vec<Entry>,
+    pub up_next: VecDeque<Entry>,
+    current: usize,
+    listening: Option<(PathBuf, bool)>, // (track being listened to, already counted as played)
+    events: Vec<Event>,
+}
+
+impl Player {
+    /// Without an audio device the playlist can still be edited, but nothing plays.
+    pub fn new(audio: Option<AudioPlayer>) -> Self {
+        Self {
+            audio,
+            playlist: Vec::new(),
+            up_next: VecDeque::new(),
+            current: 0,
+            listening: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn audio(&self) -> Option<&AudioPlayer> {
+        self.audio.as_ref()
+    }
+
+    pub fn audio_mut(&mut self) -> Option<&mut AudioPlayer> {
+        self.audio.as_mut()
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&self) -> Option<&Entry> {
+        self.playlist.get(self.current)
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.audio.as_ref().is_some_and(|audio| audio.is_playing())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.audio.as_ref().is_some_and(|audio| audio.is_paused())
+    }
+
+    /// Nothing is playing or paused: playback was stopped, the playlist ended or
+    /// the current track could not be played.
+    pub fn is_stopped(&self) -> bool {
+        !self.is_playing() && !self.is_paused()
+    }
+
+    pub fn position(&self) -> Option<Duration> {
+        self.audio.as_ref().and_then(|audio| audio.position())
+    }
+
+    /// The current track has played to the end and `track_finished` should be called.
+    pub fn is_finished(&self) -> bool {
+        self.audio.as_ref().is_some_and(|audio| audio.has_finished()) && !self.playlist.is_empty()
+    }
+
+    /// The current track is the last one and nothing is queued.
+    pub fn at_end(&self) -> bool {
+        self.up_next.is_empty() && self.current + 1 >= self.playlist.len()
+    }
+
+    /// Replace the playlist, stopping playback.
+    pub fn load(&mut self, entries: Vec<Entry>) {
+        self.playlist = entries;
+        self.current = 0;
+        self.stop();
+        self.events.push(Event::PlaylistChanged);
+    }
+
+    pub fn append(&mut self, entries: Vec<Entry>) {
+        if !entries.is_empty() {
+            self.playlist.extend(entries);
+            self.events.push(Event::PlaylistChanged);
+        }
+    }
+
+    /// Replace the tags of entries loaded at the given indices, finding the
+    /// ones that were moved or queued since by path.
+    pub fn update_metadata(&mut self, updates: Vec<(usize, Entry)>) {
+        for (index, (path, metadata)) in updates {
+            if self.playlist.get(index).is_some_and(|(entry_path, _)| *entry_path == path) {
+                self.playlist[index].1 = metadata;
+                continue;
+            }
+            for entry in self.playlist.iter_mut().chain(self.up_next.iter_mut()) {
+                if entry.0 == path {
+                    entry.1 = metadata.clone();
+                }
+            }
+        }
+        self.events.push(Event::PlaylistChanged);
+    }
+
+    /// Queue tracks to play after the current one, ahead of what's queued with `next`.
+    pub fn enqueue(&mut self, entries: Vec<Entry>, next: bool) {
+        if next {
+            for entry in entries.into_iter().rev() {
+                self.up_next.push_front(entry);
+            }
+        } else {
+            self.up_next.extend(entries);
+        }
+        self.events.push(Event::PlaylistChanged);
+    }
+
+    /// Take one track out of the Up Next queue, or all of them with `None`.
+    pub fn unqueue(&mut self, index: Option<usize>) {
+        match index {
+            Some(index) => {
+                self.up_next.remove(index);
+            }
+            None => self.up_next.clear(),
+        }
+        self.events.push(Event::PlaylistChanged);
+    }
+
+    pub fn set_volume(&mut self, volume: f32) -> Result<()> {
+        let audio = self.audio.as_mut().ok_or_else(|| anyhow!("No audio device"))?;
+        audio.set_volume(volume);
+        self.events.push(Event::VolumeChanged(audio.volume()));
+        Ok(())
+    }
+
+    pub fn play_index(&mut self, index: usize) {
+        // An index checked against the playlist before it last changed can be
+        // past its end by now
+        if index >= self.playlist.len() {
+            return;
+        }
+        self.current = index;
+        if let (Some(audio), Some((path, metadata))) = (self.audio.as_mut(), self.playlist.get(index)) {
+            match audio.play(path) {
+                Ok(()) => self.events.push(Event::TrackStarted { index, path: path.clone() }),
+                Err(e) => self.events.push(Event::Error {
+                    path: Some(path.clone()),
+                    message: format!("Error playing track '{}': {}", metadata.title, e),
+                }),
+            }
+        }
+    }
+
+    /// Queued tracks play first; otherwise move down the playlist, going back
+    /// to the start at the end only when `wrap` is set.
+    pub fn next_track(&mut self, wrap: bool) {
+        if let Some(index) = queue::take_next(&mut self.playlist, self.current, &mut self.up_next) {
+            self.events.push(Event::PlaylistChanged);
+            self.play_index(index);
+        } else if self.current + 1 < self.playlist.len() {
+            self.play_index(self.current + 1);
+        } else if wrap && !self.playlist.is_empty() {
+            self.play_index(0);
+        }
+    }
+
+    pub fn previous_track(&mut self) {
+        if self.current > 0 {
+            self.skip_listening();
+            self.play_index(self.current - 1);
+        }
+    }
+
+    /// Resume a paused track, or start the current one.
+    pub fn play(&mut self) {
+        let Some(audio) = self.audio.as_mut() else {
+            return;
+        };
+        if audio.is_playing() {
+            return;
+        }
+        if audio.is_paused() {
+            audio.resume();
+        } else if self.current < self.playlist.len() {
+            self.play_index(self.current);
+        } else if !self.playlist.is_empty() {
+            self.play_index(0);
+        }
+    }
+
+    /// Pause, resume a paused track, or start the current one.
+    pub fn toggle_playback(&mut self) {
+        if self.is_playing() {
+            self.pause();
+        } else {
+            self.play();
+        }
+    }
+
+    pub fn pause(&mut self) {
+        if let Some(audio) = self.audio.as_mut() {
+            audio.pause();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(audio) = self.audio.as_mut() {
+            audio.stop();
+        }
+    }
+
+    pub fn seek(&mut self, position: Duration) -> Result<()> {
+        match self.audio.as_mut() {
+            Some(audio) => audio.seek(position),
+            None => Err(anyhow!("No audio device")),
+        }
+    }
+
+    /// Start playing `index` from `position`, as when resuming an earlier session.
+    /// A position already past the play threshold was counted back then.
+    pub fn resume_at(&mut self, index: usize, position: Duration, paused: bool) {
+        self.current = index.min(self.playlist.len().saturating_sub(1));
+        let (Some(audio), Some((path, metadata))) = (self.audio.as_mut(), self.playlist.get(self.current)) else {
+            return;
+        };
+        if let Err(e) = audio.play(path) {
+            self.events.push(Event::Error {
+                path: Some(path.clone()),
+                message: format!("Error playing track '{}': {}", metadata.title, e),
+            });
+            return;
+        }
+        self.events.push(Event::TrackStarted { index: self.current, path: path.clone() });
+        if paused {
+            audio.pause();
+        }
+        let counted = library::counts_as_play(position.as_secs_f32(), metadata.duration);
+        self.listening = Some((path.clone(), counted));
+        if !position.is_zero() {
+            if let Err(e) = audio.seek(position) {
+                self.events.push(Event::Error { path: None, message: format!("Could not restore position: {}", e) });
+            }
+        }
+    }
+
+    /// Move a playlist entry, keeping the current track current.
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        queue::move_entry(&mut self.playlist, &mut self.current, from, to);
+        self.events.push(Event::PlaylistChanged);
+    }
+
+    pub fn remove_entry(&mut self, index: usize) -> Option<Entry> {
+        let removed = queue::remove_entry(&mut self.playlist, &mut self.current, index);
+        if removed.is_some() {
+            self.events.push(Event::PlaylistChanged);
+        }
+        removed
+    }
+
+    /// Follow the current track and count it as played once it passes the
+    /// threshold. A new track, or the same one started again, begins a new listen.
+    pub fn update_listening(&mut self) {
+        let Some(position) = self.position() else {
+            return;
+        };
+        let Some((path, metadata)) = self.playlist.get(self.current) else {
+            return;
+        };
+        let seconds = position.as_secs_f32();
+        let restarted = seconds < 1.0 && self.listening.as_ref().is_some_and(|(_, counted)| *counted);
+        if restarted || self.listening.as_ref().is_none_or(|(listening, _)| listening != path) {
+            self.listening = Some((path.clone(), false));
+        }
+        if let Some((path, counted)) = &mut self.listening {
+            if !*counted && library::counts_as_play(seconds, metadata.duration) {
+                *counted = true;
+                self.events.push(Event::Listened(Listen { path: path.clone(), seconds, skipped: false }));
+            }
+        }
+    }
+
+    /// The listener moved on from the current track; before the play threshold
+    /// that's a skip.
+    pub fn skip_listening(&mut self) {
+        let Some((path, counted)) = self.listening.take() else {
+            return;
+        };
+        if !counted {
+            let seconds = self.position().map(|p| p.as_secs_f32()).unwrap_or(0.0);
+            self.events.push(Event::Listened(Listen { path, seconds, skipped: true }));
+        }
+    }
+
+    /// Events since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Move on once the current track has played to the end. With
+    /// `RepeatMode::AutoExtend` the engine appends the next batch while the
+    /// last track plays; without one this stops like `RepeatMode::Off`.
+    pub fn track_finished(&mut self, repeat: RepeatMode) {
+        if let Some((path, _)) = self.current() {
+            self.events.push(Event::TrackEnded { index: self.current, path: path.clone() });
+        }
+        // Playing to the end always counts, however short the track
+        if let Some((path, counted)) = self.listening.take() {
+            if !counted {
+                let seconds = self.current().and_then(|(_, m)| m.duration).unwrap_or(0.0);
+                self.events.push(Event::Listened(Listen { path, seconds, skipped: false }));
+            }
+        }
+        match repeat {
+            RepeatMode::One => self.play_index(self.current),
+            RepeatMode::All => self.next_track(true),
+            RepeatMode::Off | RepeatMode::AutoExtend if self.at_end() => self.stop_at_end(),
+            RepeatMode::Off | RepeatMode::AutoExtend => self.next_track(false),
+        }
+    }
+
+    // Stop after the last track, ready to start again from the top
+    fn stop_at_end(&mut self) {
+        self.stop();
+        self.current = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SongMetadata;
+
+    fn entry(name: &str) -> Entry {
+        (PathBuf::from(name), SongMetadata::default())
+    }
+
+    #[test]
+    fn indexes_past_the_end_of_a_shrunk_playlist_are_ignored() {
+        let mut player = Player::new(None);
+        player.load(["a.mp3", "b.mp3", "c.mp3"].map(entry).to_vec());
+        player.play_index(2);
+        // A client that read the old length asks for the last track after it's gone
+        player.remove_entry(2);
+        player.play_index(2);
+        assert_eq!(player.current_index(), 1);
+
+        player.up_next.push_back(entry("x.mp3"));
+        player.next_track(false);
+        let paths: Vec<&str> = player.playlist.iter().map(|(path, _)| path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["a.mp3", "b.mp3", "x.mp3"]);
+        assert_eq!(player.current_index(), 2);
+    }
+}
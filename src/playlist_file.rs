@@ -0,0 +1,610 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{anyhow, Result};
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use crate::metadata::SongMetadata;
+use crate::storage::write_atomic;
+
+/// Playlist file formats that can be read and written.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// Pick the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        match ext.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "pls" => Some(PlaylistFormat::Pls),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            _ => None,
+        }
+    }
+}
+
+/// Write `entries` to `playlist` in the format matching its extension.
+pub fn export(playlist: &Path, entries: &[(PathBuf, SongMetadata)], relative: bool) -> Result<()> {
+    match PlaylistFormat::from_path(playlist) {
+        Some(PlaylistFormat::M3u) => export_m3u(playlist, entries, relative),
+        Some(PlaylistFormat::Pls) => export_pls(playlist, entries, relative),
+        Some(PlaylistFormat::Xspf) => export_xspf(playlist, entries, relative),
+        None => Err(anyhow!("Unsupported playlist format: {}", playlist.display())),
+    }
+}
+
+/// Read `playlist` in the format matching its extension.
+pub fn import(playlist: &Path) -> Result<ImportResult> {
+    match PlaylistFormat::from_path(playlist) {
+        Some(PlaylistFormat::M3u) => import_m3u(playlist),
+        Some(PlaylistFormat::Pls) => import_pls(playlist),
+        Some(PlaylistFormat::Xspf) => import_xspf(playlist),
+        None => Err(anyhow!("Unsupported playlist format: {}", playlist.display())),
+    }
+}
+
+/// Entries read from a playlist file, plus the ones that could not be found.
+pub struct ImportResult {
+    pub entries: Vec<(PathBuf, SongMetadata)>,
+    /// Human-readable description of every entry that was skipped.
+    pub missing: Vec<String>,
+}
+
+// Express `path` relative to `base`, or return it unchanged when that isn't
+// possible (different drive on Windows, or a relative input)
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    if !path.is_absolute() || !base.is_absolute() {
+        return path.to_path_buf();
+    }
+    let path_parts: Vec<Component> = path.components().collect();
+    let base_parts: Vec<Component> = base.components().collect();
+    // The first component is the root or drive prefix; if those differ there is no relative path
+    if path_parts.first() != base_parts.first() {
+        return path.to_path_buf();
+    }
+
+    let common = path_parts
+        .iter()
+        .zip(&base_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..base_parts.len() {
+        relative.push("..");
+    }
+    for part in &path_parts[common..] {
+        relative.push(part.as_os_str());
+    }
+    relative
+}
+
+fn entry_path(path: &Path, playlist_dir: &Path, relative: bool) -> String {
+    if relative {
+        relative_to(path, playlist_dir).to_string_lossy().to_string()
+    } else {
+        path.to_string_lossy().to_string()
+    }
+}
+
+// Turn a playlist entry into a path on disk, resolving relative entries against
+// the playlist's own directory. Returns `None` for remote URLs.
+fn resolve_entry(entry: &str, playlist_dir: &Path) -> Option<PathBuf> {
+    let path = if let Some(rest) = entry.strip_prefix("file://") {
+        // file:///home/... on Unix, file:///C:/... on Windows
+        let decoded = percent_decode(rest);
+        if cfg!(windows) {
+            PathBuf::from(decoded.trim_start_matches('/'))
+        } else {
+            PathBuf::from(decoded)
+        }
+    } else if entry.contains("://") {
+        return None;
+    } else {
+        // Playlists written on the other OS may use the other separator
+        PathBuf::from(entry.replace(if cfg!(windows) { '/' } else { '\\' }, std::path::MAIN_SEPARATOR_STR))
+    };
+
+    if path.is_absolute() {
+        Some(normalize(&path))
+    } else {
+        Some(normalize(&playlist_dir.join(path)))
+    }
+}
+
+// Drop `.` and `..` components so resolved entries compare equal to library paths.
+// Done lexically; symlinks are not resolved.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn playlist_dir(playlist: &Path) -> PathBuf {
+    playlist
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+// Metadata for an entry, starting from whatever the playlist said about it
+fn entry_metadata(path: &Path, title: Option<String>, artist: Option<String>, album: Option<String>, duration: Option<f32>) -> SongMetadata {
+    SongMetadata {
+        title: title.unwrap_or_else(|| {
+            path.file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "Unknown".to_string())
+        }),
+        artist: artist.unwrap_or_default(),
+        album: album.unwrap_or_default(),
+        duration,
+        ..Default::default()
+    }
+}
+
+// "Artist - Title", the display form M3U and PLS use for titles
+fn display_title(metadata: &SongMetadata) -> String {
+    let display = if metadata.artist.is_empty() {
+        metadata.title.clone()
+    } else {
+        format!("{} - {}", metadata.artist, metadata.title)
+    };
+    // A newline in a tag would end the line early
+    display.replace(['\r', '\n'], " ")
+}
+
+// Artist and title from an "Artist - Title" display title, for formats with
+// no artist field of their own. Titles often contain " - " themselves
+// ("Song - Live"), so anything but exactly one separator is kept whole as the title.
+fn split_display_title(display: String) -> (Option<String>, Option<String>) {
+    if display.is_empty() {
+        return (None, None);
+    }
+    match display.split_once(" - ") {
+        Some((artist, title)) if !title.contains(" - ") && !artist.is_empty() && !title.is_empty() => {
+            (Some(artist.to_string()), Some(title.to_string()))
+        }
+        _ => (None, Some(display)),
+    }
+}
+
+// Check that a resolved entry exists, recording it as missing otherwise
+fn check_entry(result: &mut ImportResult, entry: &str, path: Option<PathBuf>, location: String) -> Option<PathBuf> {
+    match path {
+        None => {
+            result.missing.push(format!("{}: {} (remote entries are not supported)", location, entry));
+            None
+        }
+        Some(path) if !path.is_file() => {
+            result.missing.push(format!("{}: {}", location, path.display()));
+            None
+        }
+        Some(path) => Some(path),
+    }
+}
+
+fn read_text(playlist: &Path) -> Result<String> {
+    // Older playlists are often in a legacy codepage; decode leniently instead of failing
+    let bytes = std::fs::read(playlist)?;
+    Ok(String::from_utf8_lossy(&bytes).trim_start_matches('\u{feff}').to_string())
+}
+
+// Seconds to the millisecond, without a fraction for whole seconds
+fn format_seconds(seconds: f32) -> String {
+    let millis = (seconds * 1000.0).round() as i64;
+    if millis % 1000 == 0 {
+        (millis / 1000).to_string()
+    } else {
+        format!("{:.3}", millis as f64 / 1000.0).trim_end_matches('0').to_string()
+    }
+}
+
+/// Write an extended M3U playlist. With `relative` set, entries are written
+/// relative to the playlist's directory where possible. Artist and album go
+/// in `#EXTART` and `#EXTALB` lines as well as the `#EXTINF` display title,
+/// and durations keep their fraction to the millisecond.
+pub fn export_m3u(playlist: &Path, entries: &[(PathBuf, SongMetadata)], relative: bool) -> Result<()> {
+    let dir = playlist_dir(playlist);
+    let mut out = String::from("#EXTM3U\n");
+    for (path, metadata) in entries {
+        let duration = metadata.duration.map(format_seconds).unwrap_or_else(|| "-1".to_string());
+        out.push_str(&format!("#EXTINF:{},{}\n", duration, display_title(metadata)));
+        for (directive, value) in [("#EXTART", &metadata.artist), ("#EXTALB", &metadata.album)] {
+            if !value.is_empty() {
+                out.push_str(&format!("{}:{}\n", directive, value.replace(['\r', '\n'], " ")));
+            }
+        }
+        out.push_str(&entry_path(path, &dir, relative));
+        out.push('\n');
+    }
+    write_atomic(playlist, out.as_bytes())?;
+    Ok(())
+}
+
+/// Read an M3U or M3U8 playlist. Entries that don't exist on disk are reported
+/// in `missing` rather than returned.
+pub fn import_m3u(playlist: &Path) -> Result<ImportResult> {
+    let contents = read_text(playlist)?;
+    let dir = playlist_dir(playlist);
+
+    let mut result = ImportResult { entries: Vec::new(), missing: Vec::new() };
+    let mut pending_info: Option<(Option<f32>, String)> = None;
+    let mut pending_artist: Option<String> = None;
+    let mut pending_album: Option<String> = None;
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (duration, display) = info.split_once(',').unwrap_or((info, ""));
+            // Attributes like tvg-id="..." may follow the duration
+            let duration = duration
+                .split_whitespace()
+                .next()
+                .and_then(|d| d.parse::<f32>().ok())
+                .filter(|d| *d >= 0.0);
+            pending_info = Some((duration, display.trim().to_string()));
+            continue;
+        }
+        if let Some(artist) = line.strip_prefix("#EXTART:") {
+            pending_artist = Some(artist.trim().to_string());
+            continue;
+        }
+        if let Some(album) = line.strip_prefix("#EXTALB:") {
+            pending_album = Some(album.trim().to_string());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (duration, display) = pending_info.take().unwrap_or((None, String::new()));
+        let (artist, album) = (pending_artist.take(), pending_album.take());
+        let location = format!("Line {}", line_number + 1);
+        let Some(path) = check_entry(&mut result, line, resolve_entry(line, &dir), location) else {
+            continue;
+        };
+
+        // With an #EXTART line the display title doesn't have to be guessed apart
+        let (artist, title) = match artist {
+            Some(artist) => {
+                let title = display.strip_prefix(&format!("{} - ", artist)).unwrap_or(&display).to_string();
+                (Some(artist), Some(title).filter(|title| !title.is_empty()))
+            }
+            None => split_display_title(display),
+        };
+        let metadata = entry_metadata(&path, title, artist, album, duration);
+        result.entries.push((path, metadata));
+    }
+
+    Ok(result)
+}
+
+/// Write a PLS (version 2) playlist. PLS has no artist or album fields and
+/// whole-second lengths, so the artist only survives in the display title, the
+/// album is lost and durations are rounded.
+pub fn export_pls(playlist: &Path, entries: &[(PathBuf, SongMetadata)], relative: bool) -> Result<()> {
+    let dir = playlist_dir(playlist);
+    let mut out = String::from("[playlist]\n");
+    for (i, (path, metadata)) in entries.iter().enumerate() {
+        let n = i + 1;
+        out.push_str(&format!("File{}={}\n", n, entry_path(path, &dir, relative)));
+        out.push_str(&format!("Title{}={}\n", n, display_title(metadata)));
+        let length = metadata.duration.map(|d| d.round() as i64).unwrap_or(-1);
+        out.push_str(&format!("Length{}={}\n", n, length));
+    }
+    out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+    write_atomic(playlist, out.as_bytes())?;
+    Ok(())
+}
+
+// FileN / TitleN / LengthN keys sharing the same N
+#[derive(Default)]
+struct PlsEntry {
+    file: Option<String>,
+    title: Option<String>,
+    length: Option<f32>,
+}
+
+/// Read a PLS playlist. Entries are ordered by their number, not their position in the file.
+pub fn import_pls(playlist: &Path) -> Result<ImportResult> {
+    let contents = read_text(playlist)?;
+    let dir = playlist_dir(playlist);
+
+    let mut numbered: BTreeMap<u32, PlsEntry> = BTreeMap::new();
+    for line in contents.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let value = value.trim().to_string();
+        let field = ["file", "title", "length"]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, key.strip_prefix(prefix)?.parse::<u32>().ok()?)));
+        let Some((field, n)) = field else {
+            continue;
+        };
+        let entry = numbered.entry(n).or_default();
+        match field {
+            "file" => entry.file = Some(value),
+            "title" => entry.title = Some(value),
+            _ => entry.length = value.parse::<f32>().ok().filter(|d| *d >= 0.0),
+        }
+    }
+
+    let mut result = ImportResult { entries: Vec::new(), missing: Vec::new() };
+    for (n, PlsEntry { file, title, length }) in numbered {
+        let Some(file) = file else {
+            continue;
+        };
+        let location = format!("Entry {}", n);
+        let Some(path) = check_entry(&mut result, &file, resolve_entry(&file, &dir), location) else {
+            continue;
+        };
+        let (artist, title) = split_display_title(title.unwrap_or_default());
+        let metadata = entry_metadata(&path, title, artist, None, length);
+        result.entries.push((path, metadata));
+    }
+
+    Ok(result)
+}
+
+// Percent-encode a path for use in a URI, keeping '/' as the separator. A ':'
+// is encoded too, or a relative "a:b.mp3" would read as a URI with scheme "a".
+fn percent_encode(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn location_uri(path: &Path, playlist_dir: &Path, relative: bool) -> String {
+    let entry = entry_path(path, playlist_dir, relative).replace('\\', "/");
+    let relative_path = Path::new(&entry).is_relative() && !entry.starts_with('/');
+    if relative_path {
+        percent_encode(&entry)
+    } else if entry.starts_with('/') {
+        format!("file://{}", percent_encode(&entry))
+    } else {
+        // Windows drive paths: file:///C:/..., where the drive's colon stays as is
+        let (drive, rest) = entry.split_at(entry.find('/').unwrap_or(entry.len()));
+        format!("file:///{}{}", drive, percent_encode(rest))
+    }
+}
+
+/// A percent-encoded `file://` URI for an absolute path.
+pub fn file_uri(path: &Path) -> String {
+    location_uri(path, Path::new(""), false)
+}
+
+/// Write an XSPF (XML Shareable Playlist Format) playlist. Title, creator and
+/// album have their own elements; durations are kept to the millisecond.
+pub fn export_xspf(playlist: &Path, entries: &[(PathBuf, SongMetadata)], relative: bool) -> Result<()> {
+    let dir = playlist_dir(playlist);
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n");
+    for (path, metadata) in entries {
+        out.push_str("    <track>\n");
+        out.push_str(&format!("      <location>{}</location>\n", escape(location_uri(path, &dir, relative))));
+        for (element, value) in [("title", &metadata.title), ("creator", &metadata.artist), ("album", &metadata.album)] {
+            if !value.is_empty() {
+                out.push_str(&format!("      <{0}>{1}</{0}>\n", element, escape(value.as_str())));
+            }
+        }
+        if let Some(duration) = metadata.duration {
+            // XSPF durations are in milliseconds
+            out.push_str(&format!("      <duration>{}</duration>\n", (duration * 1000.0).round() as u64));
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    write_atomic(playlist, out.as_bytes())?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct XspfTrack {
+    location: Option<String>,
+    title: Option<String>,
+    creator: Option<String>,
+    album: Option<String>,
+    duration: Option<f32>,
+}
+
+/// Read an XSPF playlist. Only the first `location` of each track is used.
+pub fn import_xspf(playlist: &Path) -> Result<ImportResult> {
+    let contents = read_text(playlist)?;
+    let dir = playlist_dir(playlist);
+    let mut reader = Reader::from_str(&contents);
+    reader.config_mut().trim_text(true);
+
+    let mut tracks = Vec::new();
+    let mut track: Option<XspfTrack> = None;
+    let mut element: Vec<u8> = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                if name == b"track" {
+                    track = Some(XspfTrack::default());
+                }
+                element = name;
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"track" {
+                    tracks.extend(track.take());
+                }
+                element.clear();
+            }
+            event @ (Event::Text(_) | Event::CData(_)) => {
+                let text = match event {
+                    Event::Text(t) => t.unescape()?.into_owned(),
+                    Event::CData(c) => String::from_utf8_lossy(&c).into_owned(),
+                    _ => unreachable!(),
+                };
+                let Some(track) = track.as_mut() else {
+                    continue;
+                };
+                match element.as_slice() {
+                    b"location" if track.location.is_none() => track.location = Some(text),
+                    b"title" => track.title = Some(text),
+                    b"creator" => track.creator = Some(text),
+                    b"album" => track.album = Some(text),
+                    b"duration" => track.duration = text.parse::<f32>().ok().map(|ms| ms / 1000.0),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let mut result = ImportResult { entries: Vec::new(), missing: Vec::new() };
+    for (i, track) in tracks.into_iter().enumerate() {
+        let Some(location) = track.location else {
+            result.missing.push(format!("Track {}: no location", i + 1));
+            continue;
+        };
+        // Relative locations are URI references too, so they may be percent-encoded
+        let entry = if location.contains("://") { location.clone() } else { percent_decode(&location) };
+        let Some(path) = check_entry(&mut result, &location, resolve_entry(&entry, &dir), format!("Track {}", i + 1)) else {
+            continue;
+        };
+        let metadata = entry_metadata(&path, track.title, track.creator, track.album, track.duration);
+        result.entries.push((path, metadata));
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_uris_are_percent_encoded() {
+        assert_eq!(file_uri(Path::new("/tmp/art #1/a b%.jpg")), "file:///tmp/art%20%231/a%20b%25.jpg");
+    }
+
+    #[test]
+    fn display_titles_split_only_on_a_single_separator() {
+        let split = |display: &str| split_display_title(display.to_string());
+        assert_eq!(split("Artist - Title"), (Some("Artist".to_string()), Some("Title".to_string())));
+        assert_eq!(split("Artist - Title - Live"), (None, Some("Artist - Title - Live".to_string())));
+        assert_eq!(split("Just a title"), (None, Some("Just a title".to_string())));
+        assert_eq!(split(""), (None, None));
+    }
+
+    #[test]
+    fn relative_locations_encode_colons() {
+        let dir = Path::new("/music");
+        assert_eq!(location_uri(Path::new("/music/a:b.mp3"), dir, true), "a%3Ab.mp3");
+        assert_eq!(location_uri(Path::new("/music/a b.mp3"), dir, false), "file:///music/a%20b.mp3");
+        assert_eq!(percent_decode("a%3Ab.mp3"), "a:b.mp3");
+    }
+
+    fn song(title: &str, artist: &str, album: &str, duration: Option<f32>) -> SongMetadata {
+        SongMetadata {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            duration,
+            ..Default::default()
+        }
+    }
+
+    type Entries = Vec<(PathBuf, SongMetadata)>;
+
+    // Export a few entries as `name` next to the music and read them back
+    fn round_trip(name: &str, relative: bool) -> (Entries, Entries) {
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        std::fs::create_dir(&music).unwrap();
+        let entries: Entries = [
+            ("one.mp3", song("Song", "Artist", "Album", Some(183.25))),
+            ("two:live.flac", song("Song - Live", "Other Artist", "Live & Loud", Some(240.0))),
+            ("three.ogg", song("Untagged", "", "", None)),
+        ]
+        .into_iter()
+        .map(|(file, metadata)| {
+            let path = music.join(file);
+            std::fs::write(&path, b"").unwrap();
+            (path, metadata)
+        })
+        .collect();
+
+        let playlist = dir.path().join(name);
+        export(&playlist, &entries, relative).unwrap();
+        let imported = import(&playlist).unwrap();
+        assert!(imported.missing.is_empty(), "{:?}", imported.missing);
+        (entries, imported.entries)
+    }
+
+    fn summary(entries: &[(PathBuf, SongMetadata)]) -> Vec<(PathBuf, String, String, String, Option<i64>)> {
+        entries
+            .iter()
+            .map(|(path, m)| (path.clone(), m.title.clone(), m.artist.clone(), m.album.clone(), m.duration.map(|d| (d * 1000.0).round() as i64)))
+            .collect()
+    }
+
+    #[test]
+    fn m3u8_and_xspf_keep_everything() {
+        for name in ["list.m3u8", "list.xspf"] {
+            for relative in [false, true] {
+                let (exported, imported) = round_trip(name, relative);
+                assert_eq!(summary(&imported), summary(&exported), "{} relative={}", name, relative);
+            }
+        }
+    }
+
+    #[test]
+    fn pls_keeps_paths_titles_and_whole_seconds() {
+        let (exported, imported) = round_trip("list.pls", true);
+        let paths: Vec<&PathBuf> = exported.iter().map(|(path, _)| path).collect();
+        assert_eq!(
+            summary(&imported),
+            vec![
+                (paths[0].clone(), "Song".to_string(), "Artist".to_string(), String::new(), Some(183_000)),
+                // Two separators can't be split reliably, so it all stays in the title
+                (paths[1].clone(), "Other Artist - Song - Live".to_string(), String::new(), String::new(), Some(240_000)),
+                (paths[2].clone(), "Untagged".to_string(), String::new(), String::new(), None),
+            ]
+        );
+    }
+}
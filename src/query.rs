@@ -0,0 +1,411 @@
+use std::path::Path;
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use crate::library::TrackStats;
+use crate::metadata::SongMetadata;
+
+// Filters for shuffles, e.g. `genre:jazz year:<1970 duration:>180 -album:live`.
+//
+// Every term has to match. A term is `field:value` or a bare word, which is
+// looked for in the title, artist, album and path. Text matches ignore case and
+// only need to appear somewhere in the field. `year` and `duration` compare
+// numbers with `<`, `<=`, `>`, `>=`, `=` or a range like `1960..1969`; durations
+// are seconds or `m:ss`. `added` and `played` compare how long ago a track was
+// added or last played, written with h, d, w or y: `added:<7d` is "added in the
+// last week" and `played:>30d` is "not played in 30 days", which includes
+// tracks never played. `plays` and `skips` compare how often a track was played
+// through or skipped, and `rating` its stars; unrated tracks never match a rating.
+// A leading `-` excludes matching tracks, and quotes keep
+// spaces and colons inside a value: `artist:"miles davis"`.
+
+#[derive(Clone, Copy)]
+enum TextField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Path,
+}
+
+#[derive(Clone, Copy)]
+enum NumberField {
+    Year,
+    Duration,
+    // Ages in seconds
+    Added,
+    Played,
+    Plays,
+    Skips,
+    Rating,
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+}
+
+enum Condition {
+    // `None` searches the title, artist, album and path
+    Text(Option<TextField>, String),
+    Number(NumberField, Comparison, f32),
+    Range(NumberField, f32, f32),
+}
+
+struct Term {
+    negated: bool,
+    condition: Condition,
+}
+
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+// A word of the query with its quotes removed, and where its first unquoted
+// colon is so `"a:b"` stays a plain word
+struct Token {
+    text: String,
+    colon: Option<usize>,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(tokens);
+        }
+
+        let mut token = Token { text: String::new(), colon: None };
+        let mut quoted = false;
+        while let Some(c) = chars.next_if(|c| quoted || !c.is_whitespace()) {
+            match c {
+                '"' => quoted = !quoted,
+                ':' if !quoted && token.colon.is_none() => {
+                    token.colon = Some(token.text.len());
+                    token.text.push(c);
+                }
+                _ => token.text.push(c),
+            }
+        }
+        if quoted {
+            return Err(anyhow!("Missing closing quote"));
+        }
+        tokens.push(token);
+    }
+}
+
+fn parse_number(field: NumberField, text: &str) -> Result<f32> {
+    let value = match (field, text.split_once(':')) {
+        (NumberField::Duration, Some((minutes, seconds))) => {
+            let minutes: u32 = minutes.parse().ok().ok_or_else(|| anyhow!("'{}' isn't a duration", text))?;
+            let seconds: f32 = seconds.parse().ok().ok_or_else(|| anyhow!("'{}' isn't a duration", text))?;
+            Some(minutes as f32 * 60.0 + seconds)
+        }
+        (NumberField::Added | NumberField::Played, _) => return parse_age(text),
+        _ => text.parse().ok(),
+    };
+    value.ok_or_else(|| anyhow!("'{}' isn't a number", text))
+}
+
+// "36h", "7d", "2w" or "1y" in seconds
+fn parse_age(text: &str) -> Result<f32> {
+    let error = || anyhow!("'{}' isn't an age like 12h, 7d, 2w or 1y", text);
+    let unit = text.chars().last().ok_or_else(error)?;
+    let seconds = match unit {
+        'h' => 3600.0,
+        'd' => 86400.0,
+        'w' => 7.0 * 86400.0,
+        'y' => 365.0 * 86400.0,
+        _ => return Err(error()),
+    };
+    let count: f32 = text[..text.len() - 1].parse().map_err(|_| error())?;
+    Ok(count * seconds)
+}
+
+fn parse_comparison(field: NumberField, value: &str) -> Result<Condition> {
+    if let Some((low, high)) = value.split_once("..") {
+        return Ok(Condition::Range(field, parse_number(field, low)?, parse_number(field, high)?));
+    }
+    // Two-character operators first so `<=` isn't read as `<` and `=5`
+    let operators = [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+    ];
+    let (comparison, number) = operators
+        .iter()
+        .find_map(|(op, comparison)| value.strip_prefix(op).map(|rest| (*comparison, rest)))
+        .unwrap_or((Comparison::Equal, value));
+    Ok(Condition::Number(field, comparison, parse_number(field, number)?))
+}
+
+fn parse_term(token: Token) -> Result<Term> {
+    let (negated, offset) = match token.text.strip_prefix('-') {
+        Some(_) => (true, 1),
+        None => (false, 0),
+    };
+    let text = &token.text[offset..];
+    if text.is_empty() {
+        return Err(anyhow!("'-' needs something to exclude"));
+    }
+
+    let Some(colon) = token.colon.map(|c| c - offset) else {
+        return Ok(Term { negated, condition: Condition::Text(None, text.to_lowercase()) });
+    };
+    let (field, value) = (&text[..colon], &text[colon + 1..]);
+    if value.is_empty() {
+        return Err(anyhow!("'{}:' needs a value", field));
+    }
+    let text_condition = |field| Condition::Text(Some(field), value.to_lowercase());
+    let condition = match field.to_lowercase().as_str() {
+        "title" => text_condition(TextField::Title),
+        "artist" => text_condition(TextField::Artist),
+        "album" => text_condition(TextField::Album),
+        "genre" => text_condition(TextField::Genre),
+        "path" => text_condition(TextField::Path),
+        "year" => parse_comparison(NumberField::Year, value)?,
+        "duration" => parse_comparison(NumberField::Duration, value)?,
+        "added" => parse_comparison(NumberField::Added, value)?,
+        "played" => parse_comparison(NumberField::Played, value)?,
+        "plays" => parse_comparison(NumberField::Plays, value)?,
+        "skips" => parse_comparison(NumberField::Skips, value)?,
+        "rating" => parse_comparison(NumberField::Rating, value)?,
+        _ => {
+            return Err(anyhow!(
+                "Unknown field '{}' (try title, artist, album, genre, path, year, duration, added, played, plays, skips or rating)",
+                field
+            ))
+        }
+    };
+    Ok(Term { negated, condition })
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(needle)
+}
+
+fn age(time: SystemTime) -> f32 {
+    SystemTime::now().duration_since(time).map(|d| d.as_secs_f32()).unwrap_or(0.0)
+}
+
+fn number(field: NumberField, metadata: &SongMetadata, stats: &TrackStats) -> Option<f32> {
+    match field {
+        NumberField::Year => metadata.year.map(|y| y as f32),
+        NumberField::Duration => metadata.duration,
+        NumberField::Added => stats.added_at.map(age),
+        // A track that was never played has gone unplayed forever
+        NumberField::Played => Some(stats.last_played.map(age).unwrap_or(f32::INFINITY)),
+        NumberField::Plays => Some(stats.play_count as f32),
+        NumberField::Skips => Some(stats.skip_count as f32),
+        NumberField::Rating => stats.rating.map(|r| r as f32),
+    }
+}
+
+impl Term {
+    fn matches(&self, path: &Path, metadata: &SongMetadata, stats: &TrackStats) -> bool {
+        let text = |field| match field {
+            TextField::Title => metadata.title.clone(),
+            TextField::Artist => metadata.artist.clone(),
+            TextField::Album => metadata.album.clone(),
+            TextField::Genre => metadata.genre.clone(),
+            TextField::Path => path.to_string_lossy().to_string(),
+        };
+        let matched = match &self.condition {
+            Condition::Text(Some(field), value) => contains(&text(*field), value),
+            Condition::Text(None, value) => [TextField::Title, TextField::Artist, TextField::Album, TextField::Path]
+                .into_iter()
+                .any(|field| contains(&text(field), value)),
+            // Tracks without the tag never match a comparison
+            Condition::Number(field, comparison, value) => number(*field, metadata, stats).is_some_and(|n| match comparison {
+                Comparison::Less => n < *value,
+                Comparison::LessOrEqual => n <= *value,
+                Comparison::Greater => n > *value,
+                Comparison::GreaterOrEqual => n >= *value,
+                // Durations are read to fractions of a second, but written to the second
+                Comparison::Equal => n.round() == value.round(),
+            }),
+            Condition::Range(field, low, high) => number(*field, metadata, stats).is_some_and(|n| n >= *low && n <= *high),
+        };
+        matched != self.negated
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self> {
+        let terms = tokenize(text)?.into_iter().map(parse_term).collect::<Result<_>>()?;
+        Ok(Self { terms })
+    }
+
+    pub fn matches(&self, path: &Path, metadata: &SongMetadata, stats: &TrackStats) -> bool {
+        self.terms.iter().all(|term| term.matches(path, metadata, stats))
+    }
+
+    /// Whether it compares how long ago tracks were added or played, so what
+    /// it matches changes as time passes.
+    pub fn uses_ages(&self) -> bool {
+        self.terms.iter().any(|term| match term.condition {
+            Condition::Number(field, ..) | Condition::Range(field, ..) => matches!(field, NumberField::Added | NumberField::Played),
+            Condition::Text(..) => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn song() -> SongMetadata {
+        SongMetadata {
+            title: "So What".to_string(),
+            artist: "Miles Davis".to_string(),
+            album: "Kind of Blue".to_string(),
+            genre: "Jazz".to_string(),
+            year: Some(1959),
+            duration: Some(562.4),
+            ..Default::default()
+        }
+    }
+
+    fn days_ago(days: u64) -> Option<SystemTime> {
+        Some(SystemTime::now() - Duration::from_secs(days * 86400))
+    }
+
+    fn stats() -> TrackStats {
+        TrackStats {
+            added_at: days_ago(3),
+            last_played: days_ago(40),
+            play_count: 12,
+            skip_count: 2,
+            rating: Some(4),
+            banned: false,
+        }
+    }
+
+    fn matches(query: &str) -> bool {
+        matches_with(query, &song(), &stats())
+    }
+
+    fn matches_with(query: &str, metadata: &SongMetadata, stats: &TrackStats) -> bool {
+        let path = Path::new("/music/Miles Davis/Kind of Blue/01 So What.flac");
+        Query::parse(query).unwrap().matches(path, metadata, stats)
+    }
+
+    fn error(query: &str) -> String {
+        Query::parse(query).err().expect("the query should be refused").to_string()
+    }
+
+    #[test]
+    fn text_fields_and_bare_words_match_anywhere_ignoring_case() {
+        assert!(matches(""));
+        assert!(matches("genre:jazz"));
+        assert!(matches("ARTIST:miles title:what album:blue"));
+        assert!(matches("path:.flac"));
+        assert!(!matches("genre:rock"));
+        // Bare words look in the title, artist, album and path, but not the genre
+        assert!(matches("kind"));
+        assert!(matches("01"));
+        assert!(!matches("jazz"));
+        // Every term has to match
+        assert!(!matches("miles coltrane"));
+    }
+
+    #[test]
+    fn a_leading_dash_excludes() {
+        assert!(matches("-album:live"));
+        assert!(!matches("-genre:jazz"));
+        assert!(matches("miles -rock"));
+        assert!(!matches("-miles"));
+    }
+
+    #[test]
+    fn quotes_keep_spaces_and_colons_in_one_word() {
+        assert!(matches(r#"artist:"miles davis""#));
+        assert!(!matches(r#"artist:"davis miles""#));
+        // Without quotes the second word is searched on its own
+        assert!(matches("artist:miles davis"));
+        // A quoted colon doesn't make a field
+        assert!(!matches(r#""so: what""#));
+        assert!(matches(r#"-"kind of red""#));
+    }
+
+    #[test]
+    fn numbers_compare_with_operators_and_ranges() {
+        assert!(matches("year:1959"));
+        assert!(matches("year:=1959"));
+        assert!(matches("year:<1960 year:<=1959 year:>1958 year:>=1959"));
+        assert!(!matches("year:<1959"));
+        assert!(!matches("year:>1959"));
+        assert!(matches("year:1950..1959"));
+        assert!(!matches("year:1960..1969"));
+        assert!(!matches("-year:1959"));
+        // Tracks without a year never match a comparison, but do match its exclusion
+        let undated = SongMetadata { year: None, ..song() };
+        assert!(!matches_with("year:<2000", &undated, &stats()));
+        assert!(!matches_with("year:1900..2100", &undated, &stats()));
+        assert!(matches_with("-year:<2000", &undated, &stats()));
+    }
+
+    #[test]
+    fn durations_are_seconds_or_minutes_and_seconds() {
+        assert!(matches("duration:>540"));
+        assert!(matches("duration:>9:00 duration:<9:30"));
+        assert!(matches("duration:9:00..9:30"));
+        assert!(!matches("duration:<3:00"));
+        // Equal is to the second, as durations are written
+        assert!(matches("duration:9:22"));
+        assert!(matches("duration:=562"));
+        assert!(!matches("duration:9:23"));
+    }
+
+    #[test]
+    fn ages_count_back_from_now() {
+        assert!(matches("added:<7d"));
+        assert!(matches("added:>48h"));
+        assert!(!matches("added:<1d"));
+        assert!(matches("played:>30d"));
+        assert!(matches("played:4w..6w"));
+        assert!(!matches("played:<1w"));
+        assert!(matches("added:<1y played:<1y"));
+        assert!(Query::parse("genre:jazz -played:<1w").unwrap().uses_ages());
+        assert!(!Query::parse("genre:jazz plays:>5 added").unwrap().uses_ages());
+        // Tracks never played have gone unplayed for longer than anything
+        let unplayed = TrackStats { last_played: None, ..stats() };
+        assert!(matches_with("played:>100y", &song(), &unplayed));
+        assert!(!matches_with("played:<100y", &song(), &unplayed));
+    }
+
+    #[test]
+    fn plays_skips_and_ratings_come_from_the_library() {
+        assert!(matches("plays:>10 plays:12 skips:<3 skips:2"));
+        assert!(!matches("plays:<10"));
+        assert!(matches("rating:>=4 rating:4"));
+        assert!(!matches("rating:5"));
+        // Unrated tracks never match a rating
+        let unrated = TrackStats { rating: None, ..stats() };
+        assert!(!matches_with("rating:<=5", &song(), &unrated));
+        assert!(matches_with("-rating:>=1", &song(), &unrated));
+    }
+
+    #[test]
+    fn malformed_queries_are_refused() {
+        assert_eq!(error(r#"artist:"miles"#), "Missing closing quote");
+        assert_eq!(error("-"), "'-' needs something to exclude");
+        assert_eq!(error("genre:"), "'genre:' needs a value");
+        assert!(error("mood:happy").starts_with("Unknown field 'mood'"));
+        assert_eq!(error("year:nineteen"), "'nineteen' isn't a number");
+        assert_eq!(error("year:<"), "'' isn't a number");
+        assert_eq!(error("year:1960.."), "'' isn't a number");
+        assert_eq!(error("duration:3:xx"), "'3:xx' isn't a duration");
+        assert_eq!(error("added:<7"), "'7' isn't an age like 12h, 7d, 2w or 1y");
+        assert_eq!(error("played:>d"), "'d' isn't an age like 12h, 7d, 2w or 1y");
+        assert_eq!(error("added:"), "'added:' needs a value");
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use crate::metadata::SongMetadata;
+
+pub type Entry = (PathBuf, SongMetadata);
+
+/// Move the entry at `from` so it ends up at index `to`, keeping `current`
+/// pointing at the same track.
+pub fn move_entry<T>(list: &mut Vec<T>, current: &mut usize, from: usize, to: usize) {
+    if from >= list.len() || to >= list.len() || from == to {
+        return;
+    }
+    let entry = list.remove(from);
+    list.insert(to, entry);
+
+    if *current == from {
+        *current = to;
+    } else if from < *current && to >= *current {
+        *current -= 1;
+    } else if from > *current && to <= *current {
+        *current += 1;
+    }
+}
+
+/// Remove the entry at `index`. Entries before `current` shift it down by one;
+/// removing the current entry leaves `current` on the track that followed it.
+pub fn remove_entry<T>(list: &mut Vec<T>, current: &mut usize, index: usize) -> Option<T> {
+    if index >= list.len() {
+        return None;
+    }
+    let entry = list.remove(index);
+    if index < *current {
+        *current -= 1;
+    }
+    if *current >= list.len() {
+        *current = list.len().saturating_sub(1);
+    }
+    Some(entry)
+}
+
+/// Take the first queued track and place it right after `current` in the
+/// playlist, returning the index to play. A track queued from later in the
+/// playlist is moved rather than duplicated so it doesn't play twice.
+pub fn take_next(playlist: &mut Vec<Entry>, current: usize, up_next: &mut VecDeque<Entry>) -> Option<usize> {
+    let entry = up_next.pop_front()?;
+    if playlist.is_empty() {
+        playlist.push(entry);
+        return Some(0);
+    }
+
+    let target = (current + 1).min(playlist.len());
+    let later = playlist
+        .iter()
+        .skip(target)
+        .position(|(path, _)| *path == entry.0)
+        .map(|i| i + target);
+    match later {
+        Some(index) => {
+            let mut current = current;
+            move_entry(playlist, &mut current, index, target);
+        }
+        None => playlist.insert(target, entry),
+    }
+    Some(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> Entry {
+        (PathBuf::from(name), SongMetadata::default())
+    }
+
+    fn names(playlist: &[Entry]) -> Vec<String> {
+        playlist.iter().map(|(path, _)| path.to_string_lossy().into_owned()).collect()
+    }
+
+    // Moves `from` to `to` in "a".."e" with "c" current, returning the new
+    // order and where "c" ended up
+    fn moved(from: usize, to: usize) -> (String, usize) {
+        let mut list = vec!['a', 'b', 'c', 'd', 'e'];
+        let mut current = 2;
+        move_entry(&mut list, &mut current, from, to);
+        assert_eq!(list[current], 'c');
+        (list.into_iter().collect(), current)
+    }
+
+    #[test]
+    fn moves_keep_the_current_track() {
+        // Within the tracks before it, or after it
+        assert_eq!(moved(0, 1), ("bacde".to_string(), 2));
+        assert_eq!(moved(4, 3), ("abced".to_string(), 2));
+        // From before it to after it, and back
+        assert_eq!(moved(0, 4), ("bcdea".to_string(), 1));
+        assert_eq!(moved(4, 0), ("eabcd".to_string(), 3));
+        // Onto its place, from either side
+        assert_eq!(moved(1, 2), ("acbde".to_string(), 1));
+        assert_eq!(moved(3, 2), ("abdce".to_string(), 3));
+        // The current track itself
+        assert_eq!(moved(2, 0), ("cabde".to_string(), 0));
+        assert_eq!(moved(2, 4), ("abdec".to_string(), 4));
+        // Out of range does nothing
+        assert_eq!(moved(5, 0), ("abcde".to_string(), 2));
+        assert_eq!(moved(0, 5), ("abcde".to_string(), 2));
+    }
+
+    #[test]
+    fn removing_keeps_the_current_track_or_the_one_after_it() {
+        let mut list = vec!['a', 'b', 'c', 'd'];
+        let mut current = 2;
+        assert_eq!(remove_entry(&mut list, &mut current, 0), Some('a'));
+        assert_eq!((list[current], current), ('c', 1));
+        assert_eq!(remove_entry(&mut list, &mut current, 2), Some('d'));
+        assert_eq!((list[current], current), ('c', 1));
+
+        // The current track gives way to the next one
+        let mut list = vec!['a', 'b', 'c'];
+        let mut current = 1;
+        remove_entry(&mut list, &mut current, 1);
+        assert_eq!(list[current], 'c');
+        // or, at the end, the one before it
+        remove_entry(&mut list, &mut current, 1);
+        assert_eq!((list.as_slice(), current), (&['a'][..], 0));
+        remove_entry(&mut list, &mut current, 0);
+        assert_eq!((list.len(), current), (0, 0));
+        assert_eq!(remove_entry(&mut list, &mut current, 0), None);
+    }
+
+    #[test]
+    fn queued_tracks_go_right_after_the_current_one() {
+        let mut playlist: Vec<Entry> = ["a", "b", "c", "d"].map(entry).to_vec();
+        let mut up_next: VecDeque<Entry> = ["d", "a", "x"].map(entry).into();
+
+        // From later in the playlist it moves instead of playing twice
+        assert_eq!(take_next(&mut playlist, 1, &mut up_next), Some(2));
+        assert_eq!(names(&playlist), ["a", "b", "d", "c"]);
+        // From earlier it plays again
+        assert_eq!(take_next(&mut playlist, 2, &mut up_next), Some(3));
+        assert_eq!(names(&playlist), ["a", "b", "d", "a", "c"]);
+        // From outside the playlist it's added
+        assert_eq!(take_next(&mut playlist, 4, &mut up_next), Some(5));
+        assert_eq!(names(&playlist), ["a", "b", "d", "a", "c", "x"]);
+        assert_eq!(take_next(&mut playlist, 5, &mut up_next), None);
+    }
+
+    #[test]
+    fn queued_tracks_survive_a_current_index_past_the_end() {
+        let mut playlist: Vec<Entry> = ["a", "b"].map(entry).to_vec();
+        let mut up_next: VecDeque<Entry> = ["x"].map(entry).into();
+        assert_eq!(take_next(&mut playlist, 7, &mut up_next), Some(2));
+        assert_eq!(names(&playlist), ["a", "b", "x"]);
+
+        let mut empty = Vec::new();
+        up_next.push_back(entry("y"));
+        assert_eq!(take_next(&mut empty, 3, &mut up_next), Some(0));
+        assert_eq!(names(&empty), ["y"]);
+    }
+}
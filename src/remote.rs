@@ -0,0 +1,635 @@
+// The remote control: a small HTTP server so phones and scripts on the network
+// can drive the player. Everything under /api needs the token, sent as
+// `Authorization: Bearer <token>` or `?token=<token>`; the page at / only
+// carries the script that asks for it.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::config::RemoteSettings;
+use crate::engine::{Command, Event, PlayerEngine};
+use crate::library::Library;
+use crate::metadata::SongMetadata;
+
+const PAGE: &str = include_str!("remote.html");
+// Largest request body read; commands are a few bytes of JSON
+const MAX_BODY: u64 = 64 * 1024;
+// An idle event stream gets a comment line this often, so phones and proxies
+// keep it open and a client that went away is noticed
+const KEEPALIVE: Duration = Duration::from_secs(15);
+// Requests are answered by this many threads, each with its own library
+// connection, so a slow shuffle holds up only one of them
+const WORKERS: usize = 4;
+// Event streams each keep a thread for as long as they are open
+const MAX_EVENT_STREAMS: usize = 8;
+// How often idle workers check whether the server stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How long dropping the server waits for the workers to let go of the port
+const STOP_WAIT: Duration = Duration::from_secs(1);
+const DEFAULT_SHUFFLE_COUNT: usize = 100;
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+/// A new random token for `RemoteSettings::token`.
+pub fn generate_token() -> String {
+    Alphanumeric.sample_string(&mut rand::rng(), 24)
+}
+
+/// This machine's address on the local network, to show where to connect.
+/// Connecting a UDP socket sends nothing; it only picks the outgoing interface.
+pub fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 80)).ok()?;
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+/// The running server. Dropping it stops taking requests and ends the event
+/// streams, waiting at most a second for requests being answered.
+pub struct RemoteServer {
+    server: Arc<Server>,
+    workers: Vec<JoinHandle<()>>,
+    stopped: Arc<AtomicBool>,
+    port: u16,
+    lan: bool,
+    token: String,
+}
+
+impl RemoteServer {
+    /// Listen on `settings.port`, on localhost or with `settings.lan` on every
+    /// interface, and drive `engine`. Shuffles and searches use the library in
+    /// the config directory.
+    pub fn start(settings: &RemoteSettings, engine: PlayerEngine, sync_ratings: bool) -> Result<Self> {
+        if settings.token.is_empty() {
+            return Err(anyhow!("The remote control needs a token"));
+        }
+        let ip = if settings.lan { Ipv4Addr::UNSPECIFIED } else { Ipv4Addr::LOCALHOST };
+        let server = Server::http((ip, settings.port))
+            .map_err(|e| anyhow!("Could not listen on port {}: {}", settings.port, e))?;
+        // Port 0 picks a free one
+        let port = server.server_addr().to_ip().map_or(settings.port, |address| address.port());
+
+        let server = Arc::new(server);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let context = Arc::new(Context {
+            engine,
+            token: settings.token.clone(),
+            sync_ratings,
+            event_streams: AtomicUsize::new(0),
+            stopped: Arc::clone(&stopped),
+        });
+        let workers = (0..WORKERS)
+            .map(|_| {
+                let (server, context) = (Arc::clone(&server), Arc::clone(&context));
+                thread::spawn(move || {
+                    let mut library = None;
+                    while !context.stopped.load(Ordering::Relaxed) {
+                        match server.recv_timeout(POLL_INTERVAL) {
+                            Ok(Some(request)) => handle(&context, &mut library, request),
+                            Ok(None) => {}
+                            Err(e) => eprintln!("Remote control: {}", e),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            server,
+            workers,
+            stopped,
+            port,
+            lan: settings.lan,
+            token: settings.token.clone(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Address of the web page, with the token for it to pick up.
+    pub fn url(&self) -> String {
+        let host = match (self.lan, lan_address()) {
+            (true, Some(ip)) => ip,
+            _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        format!("http://{}:{}/#token={}", host, self.port, self.token)
+    }
+}
+
+impl Drop for RemoteServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for _ in &self.workers {
+            self.server.unblock();
+        }
+        // Once the workers let go of the server the port is closed, so a
+        // restarted server can take it. Idle ones do straight away; one busy
+        // with a long shuffle is left to finish on its own rather than hold
+        // up whoever dropped this.
+        let deadline = Instant::now() + STOP_WAIT;
+        while self.workers.iter().any(|worker| !worker.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+struct Context {
+    engine: PlayerEngine,
+    token: String,
+    sync_ratings: bool,
+    event_streams: AtomicUsize,
+    stopped: Arc<AtomicBool>,
+}
+
+// A worker's own library connection, opened on first use
+fn library(library: &mut Option<Library>) -> Result<&Library, Failure> {
+    if library.is_none() {
+        *library = Some(Library::open()?);
+    }
+    Ok(library.as_ref().expect("opened above"))
+}
+
+// Counts an open event stream until dropped
+struct EventStream<'a>(&'a AtomicUsize);
+
+impl<'a> EventStream<'a> {
+    fn open(count: &'a AtomicUsize) -> Option<Self> {
+        count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < MAX_EVENT_STREAMS).then_some(open + 1))
+            .ok()
+            .map(|_| Self(count))
+    }
+}
+
+impl Drop for EventStream<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Why a request failed: the status code and a message for the client
+struct Failure(u16, String);
+
+impl Failure {
+    fn bad_request(message: impl ToString) -> Self {
+        Failure(400, message.to_string())
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        Failure(500, e.to_string())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PlayBody {
+    index: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SeekBody {
+    /// Seconds into the current track.
+    position: f64,
+}
+
+#[derive(Deserialize)]
+struct VolumeBody {
+    /// 0.0 to 1.0.
+    volume: f32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ShuffleBody {
+    count: Option<usize>,
+    filter: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EnqueueBody {
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    next: bool,
+}
+
+fn handle(context: &Arc<Context>, library: &mut Option<Library>, mut request: Request) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let query = parse_query(query);
+
+    if matches!(path, "/" | "/index.html") {
+        let response = Response::from_string(PAGE).with_header(header("Content-Type", "text/html; charset=utf-8"));
+        // Failing to answer only means the client went away
+        let _ = request.respond(response);
+        return;
+    }
+    if !authorized(&request, &query, &context.token) {
+        reply(request, Err(Failure(401, "Missing or wrong token".to_string())));
+        return;
+    }
+    if path == "/api/events" && *request.method() == Method::Get {
+        let context = Arc::clone(context);
+        thread::spawn(move || match EventStream::open(&context.event_streams) {
+            Some(_open) => stream_events(&context, request),
+            None => reply(request, Err(Failure(503, "Too many event streams are open".to_string()))),
+        });
+        return;
+    }
+
+    let mut body = String::new();
+    if request.as_reader().take(MAX_BODY).read_to_string(&mut body).is_err() {
+        reply(request, Err(Failure::bad_request("Could not read the request body")));
+        return;
+    }
+    let result = route(context, library, request.method(), path, &query, &body);
+    reply(request, result);
+}
+
+// The answer to an API request; `None` is a command that has nothing to say
+fn route(context: &Context, library: &mut Option<Library>, method: &Method, path: &str, query: &HashMap<String, String>, body: &str) -> Result<Option<Value>, Failure> {
+    let engine = &context.engine;
+    let command = match (method, path) {
+        (Method::Get, "/api/now-playing") => return Ok(Some(now_playing(engine))),
+        (Method::Get, "/api/playlist") => return Ok(Some(playlist(engine))),
+        (Method::Get, "/api/search") => {
+            let text = query.get("q").map(String::as_str).unwrap_or_default();
+            let limit = match query.get("limit") {
+                Some(limit) => limit.parse().map_err(|_| Failure::bad_request("limit must be a number"))?,
+                None => DEFAULT_SEARCH_LIMIT,
+            };
+            let tracks = self::library(library)?.search(text, limit)?;
+            let tracks: Vec<Value> = tracks.iter().map(|(path, metadata)| track_json(path, metadata)).collect();
+            return Ok(Some(json!({ "tracks": tracks })));
+        }
+        (Method::Post, "/api/play") => {
+            let PlayBody { index } = parse_body(body)?.unwrap_or_default();
+            if index.is_some_and(|index| index >= engine.player().playlist.len()) {
+                return Err(Failure::bad_request("No track at that index"));
+            }
+            Command::Play(index)
+        }
+        (Method::Post, "/api/pause") => Command::Pause,
+        (Method::Post, "/api/toggle") => Command::TogglePlayback,
+        (Method::Post, "/api/stop") => Command::Stop,
+        (Method::Post, "/api/next") => Command::Next,
+        (Method::Post, "/api/previous") => Command::Previous,
+        (Method::Post, "/api/seek") => {
+            let SeekBody { position } = parse_body(body)?.ok_or_else(|| Failure::bad_request("Expected {\"position\": seconds}"))?;
+            if !position.is_finite() {
+                return Err(Failure::bad_request("position must be a number of seconds"));
+            }
+            Command::Seek(Duration::from_secs_f64(position.max(0.0)))
+        }
+        (Method::Post, "/api/volume") => {
+            let VolumeBody { volume } = parse_body(body)?.ok_or_else(|| Failure::bad_request("Expected {\"volume\": 0.0 to 1.0}"))?;
+            if !volume.is_finite() {
+                return Err(Failure::bad_request("volume must be between 0.0 and 1.0"));
+            }
+            if engine.player().audio().is_none() {
+                return Err(Failure(503, "No audio device".to_string()));
+            }
+            Command::SetVolume(volume.clamp(0.0, 1.0))
+        }
+        (Method::Post, "/api/shuffle") => {
+            let ShuffleBody { count, filter } = parse_body(body)?.unwrap_or_default();
+            let count = count.unwrap_or(DEFAULT_SHUFFLE_COUNT).clamp(1, 10_000);
+            let entries = self::library(library)?
+                .shuffle(count, filter.as_deref(), context.sync_ratings)
+                .map_err(Failure::bad_request)?;
+            // Answered from the new tracks, as the engine may not have loaded them yet
+            let tracks: Vec<Value> = entries.iter().map(|(path, metadata)| track_json(path, metadata)).collect();
+            let up_next: Vec<Value> = engine.player().up_next.iter().map(|(path, metadata)| track_json(path, metadata)).collect();
+            engine.send(Command::Load(entries));
+            engine.send(Command::Play(Some(0)));
+            return Ok(Some(json!({ "current": 0, "tracks": tracks, "up_next": up_next })));
+        }
+        (Method::Post, "/api/enqueue") => {
+            let EnqueueBody { paths, next } = parse_body(body)?.ok_or_else(|| Failure::bad_request("Expected {\"paths\": [...]}"))?;
+            let library = self::library(library)?;
+            let reader = library.metadata_reader(context.sync_ratings);
+            let entries = paths
+                .into_iter()
+                .map(|path| {
+                    // Only library tracks, so the API can't be used to open arbitrary files
+                    if !library.db().has_track(&path)? {
+                        return Err(Failure::bad_request(format!("Not in the library: {}", path.display())));
+                    }
+                    let metadata = SongMetadata { album_art: None, ..reader.read_or_placeholder(&path) };
+                    Ok((path, metadata))
+                })
+                .collect::<Result<Vec<_>, Failure>>()?;
+            Command::Enqueue { entries, next }
+        }
+        _ => return Err(Failure(404, format!("No endpoint {} {}", method, path))),
+    };
+    engine.send(command);
+    Ok(None)
+}
+
+// The JSON body, or `None` when there is none
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &str) -> Result<Option<T>, Failure> {
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(body).map(Some).map_err(Failure::bad_request)
+}
+
+fn reply(request: Request, result: Result<Option<Value>, Failure>) {
+    let (status, body) = match result {
+        Ok(Some(body)) => (200, Some(body)),
+        Ok(None) => (204, None),
+        Err(Failure(status, message)) => (status, Some(json!({ "error": message }))),
+    };
+    let response = match body {
+        Some(body) => Response::from_string(body.to_string()).with_header(header("Content-Type", "application/json")),
+        None => Response::from_string(String::new()),
+    };
+    // Failing to answer only means the client went away
+    let _ = request.respond(response.with_status_code(status));
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field, value).expect("valid header")
+}
+
+fn authorized(request: &Request, query: &HashMap<String, String>, token: &str) -> bool {
+    let bearer = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "));
+    bearer.or(query.get("token").map(String::as_str)).is_some_and(|given| tokens_match(given, token))
+}
+
+// Looks at every byte of the token whatever the guess, so how long the check
+// takes doesn't tell how much of a guess was right
+fn tokens_match(given: &str, token: &str) -> bool {
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    let mut difference = given.len() ^ token.len();
+    for (i, byte) in token.iter().enumerate() {
+        difference |= usize::from(byte ^ given.get(i).copied().unwrap_or(0));
+    }
+    difference == 0
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+// Undo URL form encoding: `+` for spaces and `%XX` escapes
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3).filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()));
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn track_json(path: &Path, metadata: &SongMetadata) -> Value {
+    json!({
+        "path": path.to_string_lossy(),
+        "title": metadata.title,
+        "artist": metadata.artist,
+        "album": metadata.album,
+        "genre": metadata.genre,
+        "year": metadata.year,
+        "duration": metadata.duration,
+    })
+}
+
+fn now_playing(engine: &PlayerEngine) -> Value {
+    let player = engine.player();
+    let state = if player.is_playing() {
+        "playing"
+    } else if player.is_paused() {
+        "paused"
+    } else {
+        "stopped"
+    };
+    json!({
+        "state": state,
+        "index": player.current().map(|_| player.current_index()),
+        "track": player.current().map(|(path, metadata)| track_json(path, metadata)),
+        "position": player.position().map(|position| position.as_secs_f64()),
+        "volume": player.audio().map(|audio| audio.volume()),
+    })
+}
+
+fn playlist(engine: &PlayerEngine) -> Value {
+    let player = engine.player();
+    let tracks: Vec<Value> = player.playlist.iter().map(|(path, metadata)| track_json(path, metadata)).collect();
+    let up_next: Vec<Value> = player.up_next.iter().map(|(path, metadata)| track_json(path, metadata)).collect();
+    json!({
+        "current": player.current_index(),
+        "tracks": tracks,
+        "up_next": up_next,
+    })
+}
+
+// Player events as server-sent events, until the client goes away or the
+// server stops. The response is written by hand so each event is flushed as
+// it happens.
+fn stream_events(context: &Context, request: Request) {
+    let events = context.engine.subscribe();
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+    let mut last_write = Instant::now();
+    while !context.stopped.load(Ordering::Relaxed) {
+        let message = match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => event_message(&context.engine, &event),
+            Err(RecvTimeoutError::Timeout) if last_write.elapsed() >= KEEPALIVE => ": keepalive\n\n".to_string(),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if writer.write_all(message.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            break;
+        }
+        last_write = Instant::now();
+    }
+}
+
+fn event_message(engine: &PlayerEngine, event: &Event) -> String {
+    let (name, data) = match event {
+        Event::TrackStarted { index, path } => {
+            let track = match engine.player().playlist.get(*index) {
+                Some((entry_path, metadata)) if entry_path == path => track_json(path, metadata),
+                _ => track_json(path, &SongMetadata::from_file_name(path)),
+            };
+            ("track-started", json!({ "index": index, "track": track }))
+        }
+        Event::TrackEnded { index, path } => ("track-ended", json!({ "index": index, "path": path.to_string_lossy() })),
+        Event::PositionChanged(position) => ("position", json!({ "position": position.as_secs_f64() })),
+        Event::Error { path, message } => (
+            "error",
+            json!({ "path": path.as_ref().map(|path| path.to_string_lossy()), "message": message }),
+        ),
+        Event::Listened(listen) => (
+            "listened",
+            json!({ "path": listen.path.to_string_lossy(), "seconds": listen.seconds, "skipped": listen.skipped }),
+        ),
+        Event::PlaylistChanged => ("playlist-changed", json!({})),
+        Event::VolumeChanged(volume) => ("volume", json!({ "volume": volume })),
+    };
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use crate::config::RepeatMode;
+    use crate::player::Player;
+
+    const TOKEN: &str = "secret-token";
+
+    fn start() -> (RemoteServer, PlayerEngine) {
+        let engine = PlayerEngine::start(Player::new(None), RepeatMode::Off);
+        let settings = RemoteSettings { enabled: true, lan: false, port: 0, token: TOKEN.to_string() };
+        let server = RemoteServer::start(&settings, engine.clone(), false).expect("server starts");
+        (server, engine)
+    }
+
+    fn connect(server: &RemoteServer, method: &str, path: &str, token: Option<&str>, body: &str) -> TcpStream {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, server.port())).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let authorization = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method, path, authorization, body.len(), body
+        )
+        .unwrap();
+        stream
+    }
+
+    // The status code of a request
+    fn status(server: &RemoteServer, method: &str, path: &str, token: Option<&str>, body: &str) -> u16 {
+        let mut response = String::new();
+        connect(server, method, path, token, body).read_to_string(&mut response).unwrap();
+        response.split(' ').nth(1).and_then(|code| code.parse().ok()).expect("a status line")
+    }
+
+    #[test]
+    fn tokens_are_checked() {
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abd", "abc"));
+        assert!(!tokens_match("ab", "abc"));
+        assert!(!tokens_match("abcd", "abc"));
+        assert!(!tokens_match("", "abc"));
+
+        let (server, _engine) = start();
+        assert_eq!(status(&server, "GET", "/api/now-playing", None, ""), 401);
+        assert_eq!(status(&server, "GET", "/api/now-playing", Some("secret-tokem"), ""), 401);
+        assert_eq!(status(&server, "GET", "/api/now-playing?token=secret", None, ""), 401);
+        assert_eq!(status(&server, "GET", "/api/now-playing", Some(TOKEN), ""), 200);
+        assert_eq!(status(&server, "GET", &format!("/api/now-playing?token={}", TOKEN), None, ""), 200);
+        // The page itself carries nothing private
+        assert_eq!(status(&server, "GET", "/", None, ""), 200);
+    }
+
+    #[test]
+    fn play_and_pause_drive_the_engine() {
+        let (server, engine) = start();
+        let entries = ["a.mp3", "b.mp3"].map(|name| (PathBuf::from(name), SongMetadata::default()));
+        engine.player().load(entries.to_vec());
+
+        assert_eq!(status(&server, "POST", "/api/play", Some(TOKEN), r#"{"index": 2}"#), 400);
+        assert_eq!(status(&server, "POST", "/api/play", Some(TOKEN), r#"{"index": 1}"#), 204);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while engine.player().current_index() != 1 {
+            assert!(Instant::now() < deadline, "the track wasn't picked");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(status(&server, "POST", "/api/pause", Some(TOKEN), ""), 204);
+        assert_eq!(status(&server, "POST", "/api/pause", None, ""), 401);
+    }
+
+    #[test]
+    fn events_are_streamed_to_a_limited_number_of_clients() {
+        let (server, engine) = start();
+        let path = format!("/api/events?token={}", TOKEN);
+        let mut stream = BufReader::new(connect(&server, "GET", &path, None, ""));
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+        // Past the headers the stream is subscribed
+        while line != "\r\n" {
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+        }
+
+        engine.send(Command::Load(vec![(PathBuf::from("a.mp3"), SongMetadata::default())]));
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "event: playlist-changed\n");
+
+        // The open stream counts towards the limit
+        let mut open: Vec<_> = (1..MAX_EVENT_STREAMS).map(|_| connect(&server, "GET", &path, None, "")).collect();
+        for stream in &mut open {
+            let mut head = [0; 12];
+            stream.read_exact(&mut head).unwrap();
+            assert_eq!(&head, b"HTTP/1.1 200");
+        }
+        assert_eq!(status(&server, "GET", &path, None, ""), 503);
+        // Other requests are still answered
+        assert_eq!(status(&server, "GET", "/api/playlist", Some(TOKEN), ""), 200);
+    }
+
+    #[test]
+    fn stopping_lets_go_of_the_port_without_waiting_long() {
+        let (server, engine) = start();
+        let port = server.port();
+        assert_eq!(status(&server, "GET", "/api/playlist", Some(TOKEN), ""), 200);
+
+        let stopping = Instant::now();
+        drop(server);
+        assert!(stopping.elapsed() < STOP_WAIT + Duration::from_millis(500));
+        // Idle workers are gone, so a restart can take the same port once
+        // the listening thread has closed it
+        let settings = RemoteSettings { enabled: true, lan: false, port, token: TOKEN.to_string() };
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let restarted = loop {
+            match RemoteServer::start(&settings, engine.clone(), false) {
+                Ok(server) => break server,
+                Err(e) => assert!(Instant::now() < deadline, "the port wasn't let go: {}", e),
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert_eq!(status(&restarted, "GET", "/api/playlist", Some(TOKEN), ""), 200);
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Deserialize};
+use crate::config::get_config_dir;
+use crate::storage;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedPlaylist {
+    pub name: String,
+    pub saved_at: SystemTime,
+    pub tracks: Vec<PathBuf>,
+}
+
+// Named playlists kept by the user, stored in saved_playlists.json
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedPlaylists {
+    pub playlists: Vec<SavedPlaylist>,
+}
+
+fn get_saved_playlists_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("saved_playlists.json"))
+}
+
+impl SavedPlaylists {
+    pub fn load() -> Self {
+        get_saved_playlists_path().map_or_else(Self::default, |path| Self::load_from(&path))
+    }
+
+    fn load_from(path: &Path) -> Self {
+        storage::read_json(path).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = get_saved_playlists_path() {
+            self.save_to(&path);
+        }
+    }
+
+    fn save_to(&self, path: &Path) {
+        if let Err(e) = storage::write_json(path, self) {
+            eprintln!("Could not save {}: {}", path.display(), e);
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.playlists.iter().position(|p| p.name == name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SavedPlaylist> {
+        self.playlists.iter().find(|p| p.name == name)
+    }
+
+    /// Save `tracks` under `name`, replacing any playlist with that name.
+    pub fn store(&mut self, name: &str, tracks: Vec<PathBuf>) -> Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("Playlist name can't be empty"));
+        }
+        let playlist = SavedPlaylist {
+            name: name.to_string(),
+            saved_at: SystemTime::now(),
+            tracks,
+        };
+        match self.position(name) {
+            Some(i) => self.playlists[i] = playlist,
+            None => self.playlists.push(playlist),
+        }
+        self.playlists.sort_by_key(|p| p.name.to_lowercase());
+        Ok(())
+    }
+
+    /// `base` followed by the first number no playlist is named with.
+    pub fn unused_name(&self, base: &str) -> String {
+        (1..)
+            .map(|n| format!("{} {}", base, n))
+            .find(|name| self.position(name).is_none())
+            .expect("one of the numbers is free")
+    }
+
+    pub fn rename(&mut self, old_name: &str, new_name: &str) -> Result<()> {
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            return Err(anyhow!("Playlist name can't be empty"));
+        }
+        if new_name != old_name && self.position(new_name).is_some() {
+            return Err(anyhow!("A playlist named '{}' already exists", new_name));
+        }
+        let i = self.position(old_name).ok_or_else(|| anyhow!("No playlist named '{}'", old_name))?;
+        self.playlists[i].name = new_name.to_string();
+        self.playlists.sort_by_key(|p| p.name.to_lowercase());
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) {
+        self.playlists.retain(|p| p.name != name);
+    }
+
+    /// Add the tracks of `source` to the end of `target`.
+    pub fn append(&mut self, source: &str, target: &str) -> Result<()> {
+        let tracks = self
+            .get(source)
+            .ok_or_else(|| anyhow!("No playlist named '{}'", source))?
+            .tracks
+            .clone();
+        let i = self.position(target).ok_or_else(|| anyhow!("No playlist named '{}'", target))?;
+        self.playlists[i].tracks.extend(tracks);
+        self.playlists[i].saved_at = SystemTime::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracks(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(|name| PathBuf::from(format!("/music/{}.mp3", name))).collect()
+    }
+
+    fn names(playlists: &SavedPlaylists) -> Vec<&str> {
+        playlists.playlists.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn playlists_are_saved_renamed_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("saved_playlists.json");
+        let mut playlists = SavedPlaylists::default();
+        playlists.store(" Road trip ", tracks(&["a", "b"])).unwrap();
+        playlists.store("evening", tracks(&["c"])).unwrap();
+        assert!(playlists.store("  ", tracks(&["a"])).is_err());
+        // The same name replaces the playlist
+        playlists.store("Road trip", tracks(&["b", "a", "c"])).unwrap();
+        playlists.save_to(&path);
+
+        let mut loaded = SavedPlaylists::load_from(&path);
+        assert_eq!(names(&loaded), ["evening", "Road trip"]);
+        assert_eq!(loaded.get("Road trip").unwrap().tracks, tracks(&["b", "a", "c"]));
+
+        assert!(loaded.rename("evening", "Road trip").is_err());
+        assert!(loaded.rename("evening", "").is_err());
+        assert!(loaded.rename("nope", "Other").is_err());
+        loaded.rename("evening", "Playlist 1").unwrap();
+        loaded.append("Playlist 1", "Road trip").unwrap();
+        loaded.delete("Playlist 1");
+        loaded.save_to(&path);
+
+        let reloaded = SavedPlaylists::load_from(&path);
+        assert_eq!(names(&reloaded), ["Road trip"]);
+        assert_eq!(reloaded.get("Road trip").unwrap().tracks, tracks(&["b", "a", "c", "c"]));
+        assert!(SavedPlaylists::load_from(&dir.path().join("missing.json")).playlists.is_empty());
+    }
+}
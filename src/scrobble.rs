@@ -0,0 +1,645 @@
+// Scrobbling: "now playing" notices and finished listens sent to ListenBrainz
+// and Last.fm-compatible services. Listens that can't be sent wait in
+// scrobble_queue.json and are retried, oldest first.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::{get_config_dir, ScrobbleSettings};
+use crate::engine::{Event, PlayerEngine};
+use crate::library::{self, Library};
+use crate::metadata::SongMetadata;
+use crate::player::Listen;
+use crate::storage;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// How long queued listens wait after a failed attempt
+const RETRY_INTERVAL: Duration = Duration::from_secs(120);
+// How often the sending thread checks whether it should stop
+const POLL: Duration = Duration::from_millis(500);
+// Beyond this the oldest queued listens are dropped
+const MAX_QUEUED: usize = 10_000;
+const CLIENT_NAME: &str = "Music Shuffler";
+
+// Held by a sending thread from loading the queue file to its last save, so a
+// restarted scrobbler waits for the one it replaces
+static QUEUE_FILE: Mutex<()> = Mutex::new(());
+
+/// Whether a listen gets scrobbled, under the rules both services use: the
+/// track is longer than 30 seconds, and half of it or four minutes was played.
+pub fn should_scrobble(seconds: f32, duration: Option<f32>) -> bool {
+    duration.is_none_or(|d| d > 30.0) && library::counts_as_play(seconds, duration)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Service {
+    ListenBrainz,
+    LastFm,
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Service::ListenBrainz => "ListenBrainz",
+            Service::LastFm => "Last.fm",
+        })
+    }
+}
+
+/// What's submitted about a track.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Track {
+    pub artist: String,
+    pub title: String,
+    pub album: String,
+    pub duration: Option<f32>,
+}
+
+impl Track {
+    /// `None` for tracks without the artist and title every service needs.
+    pub fn from_metadata(metadata: &SongMetadata) -> Option<Self> {
+        // Tag reading fills in placeholders for missing tags
+        let known = |value: &str, placeholder: &str| (value != placeholder).then(|| value.trim().to_string());
+        let artist = known(&metadata.artist, "Unknown Artist").filter(|a| !a.is_empty())?;
+        let title = metadata.title.trim();
+        if title.is_empty() {
+            return None;
+        }
+        Some(Self {
+            artist,
+            title: title.to_string(),
+            album: known(&metadata.album, "Unknown Album").unwrap_or_default(),
+            duration: metadata.duration,
+        })
+    }
+}
+
+// Why a submission failed. Rejected ones would fail again, so they aren't
+// retried; refused credentials would too until they're changed in the
+// settings, so the listens wait for that.
+enum Failure {
+    Retry(String),
+    Rejected(String),
+    Unauthorized(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Retry(message) | Failure::Rejected(message) | Failure::Unauthorized(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(status, response) => {
+                let body: Value = response.into_json().unwrap_or_default();
+                // ListenBrainz explains in "error", Last.fm in "message" with a
+                // numeric "error"
+                let message = body["error"].as_str()
+                    .or(body["message"].as_str())
+                    .map_or_else(|| format!("HTTP status {}", status), str::to_string);
+                // Last.fm's 4, 9, 10, 13 and 26 are bad credentials, an invalid
+                // session, key or signature; 11 and 16 are outages, 29 rate
+                // limiting
+                let code = body["error"].as_u64();
+                let unauthorized = matches!(status, 401 | 403)
+                    || code.is_some_and(|code| matches!(code, 4 | 9 | 10 | 13 | 26));
+                let retry = status == 429 || status >= 500 || code.is_some_and(|code| matches!(code, 11 | 16 | 29));
+                if unauthorized {
+                    Failure::Unauthorized(message)
+                } else if retry {
+                    Failure::Retry(message)
+                } else {
+                    Failure::Rejected(message)
+                }
+            }
+            ureq::Error::Transport(e) => Failure::Retry(e.to_string()),
+        }
+    }
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(TIMEOUT)
+        .user_agent(concat!("music-shuffler/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+// Last.fm signs requests with an MD5 of the parameters in name order and the
+// API secret; `format` isn't part of it.
+fn sign_lastfm(params: &mut Vec<(&'static str, String)>, secret: &str) {
+    params.sort_by_key(|(name, _)| *name);
+    let mut text: String = params.iter().map(|(name, value)| format!("{}{}", name, value)).collect();
+    text.push_str(secret);
+    params.push(("api_sig", format!("{:x}", md5::compute(text))));
+    params.push(("format", "json".to_string()));
+}
+
+/// Log in to Last.fm, or the compatible service in `settings`, for the
+/// session key scrobbling needs. Needs the API key and secret.
+pub fn lastfm_login(settings: &ScrobbleSettings, username: &str, password: &str) -> Result<String> {
+    if settings.lastfm_api_key.is_empty() || settings.lastfm_api_secret.is_empty() {
+        return Err(anyhow!("Enter the API key and secret first"));
+    }
+    if username.is_empty() || password.is_empty() {
+        return Err(anyhow!("Enter a username and password"));
+    }
+    let mut params = vec![
+        ("method", "auth.getMobileSession".to_string()),
+        ("username", username.to_string()),
+        ("password", password.to_string()),
+        ("api_key", settings.lastfm_api_key.clone()),
+    ];
+    sign_lastfm(&mut params, &settings.lastfm_api_secret);
+    let form: Vec<(&str, &str)> = params.iter().map(|(name, value)| (*name, value.as_str())).collect();
+    let body: Value = match agent().post(&settings.lastfm_url).send_form(&form) {
+        Ok(response) => response.into_json()?,
+        Err(e) => return Err(anyhow!("{}", Failure::from(e))),
+    };
+    body["session"]["key"].as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("The server didn't send a session key"))
+}
+
+// Submits to whichever services are turned on and set up
+struct Client {
+    settings: ScrobbleSettings,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    fn new(settings: ScrobbleSettings) -> Self {
+        Self { settings, agent: agent() }
+    }
+
+    fn services(&self) -> Vec<Service> {
+        let settings = &self.settings;
+        let mut services = Vec::new();
+        if settings.listenbrainz && !settings.listenbrainz_token.is_empty() {
+            services.push(Service::ListenBrainz);
+        }
+        if settings.lastfm && !settings.lastfm_api_key.is_empty() && !settings.lastfm_session_key.is_empty() {
+            services.push(Service::LastFm);
+        }
+        services
+    }
+
+    fn now_playing(&self, service: Service, track: &Track) -> Result<(), Failure> {
+        match service {
+            Service::ListenBrainz => self.listenbrainz("playing_now", listenbrainz_listen(track, None)),
+            Service::LastFm => self.lastfm("track.updateNowPlaying", track, None),
+        }
+    }
+
+    fn scrobble(&self, scrobble: &Scrobble) -> Result<(), Failure> {
+        let track = &scrobble.track;
+        match scrobble.service {
+            Service::ListenBrainz => self.listenbrainz("single", listenbrainz_listen(track, Some(scrobble.listened_at))),
+            Service::LastFm => self.lastfm("track.scrobble", track, Some(scrobble.listened_at)),
+        }
+    }
+
+    fn listenbrainz(&self, listen_type: &str, listen: Value) -> Result<(), Failure> {
+        let url = format!("{}/1/submit-listens", self.settings.listenbrainz_url.trim_end_matches('/'));
+        self.agent.post(&url)
+            .set("Authorization", &format!("Token {}", self.settings.listenbrainz_token))
+            .send_json(json!({ "listen_type": listen_type, "payload": [listen] }))?;
+        Ok(())
+    }
+
+    fn lastfm(&self, method: &str, track: &Track, timestamp: Option<u64>) -> Result<(), Failure> {
+        let settings = &self.settings;
+        let mut params = vec![
+            ("method", method.to_string()),
+            ("artist", track.artist.clone()),
+            ("track", track.title.clone()),
+            ("api_key", settings.lastfm_api_key.clone()),
+            ("sk", settings.lastfm_session_key.clone()),
+        ];
+        if !track.album.is_empty() {
+            params.push(("album", track.album.clone()));
+        }
+        if let Some(duration) = track.duration {
+            params.push(("duration", (duration.round() as u64).to_string()));
+        }
+        if let Some(timestamp) = timestamp {
+            params.push(("timestamp", timestamp.to_string()));
+        }
+        sign_lastfm(&mut params, &settings.lastfm_api_secret);
+        let form: Vec<(&str, &str)> = params.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        self.agent.post(&settings.lastfm_url).send_form(&form)?;
+        Ok(())
+    }
+}
+
+fn listenbrainz_listen(track: &Track, listened_at: Option<u64>) -> Value {
+    let mut metadata = json!({
+        "artist_name": track.artist,
+        "track_name": track.title,
+        "additional_info": {
+            "media_player": CLIENT_NAME,
+            "submission_client": CLIENT_NAME,
+            "submission_client_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+    if !track.album.is_empty() {
+        metadata["release_name"] = json!(track.album);
+    }
+    if let Some(duration) = track.duration {
+        metadata["additional_info"]["duration_ms"] = json!((duration * 1000.0).round() as u64);
+    }
+    let mut listen = json!({ "track_metadata": metadata });
+    if let Some(listened_at) = listened_at {
+        listen["listened_at"] = json!(listened_at);
+    }
+    listen
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Scrobble {
+    service: Service,
+    track: Track,
+    /// When the listen started, in seconds since the Unix epoch.
+    listened_at: u64,
+}
+
+// Listens not yet accepted, stored in scrobble_queue.json
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Queue {
+    scrobbles: Vec<Scrobble>,
+    // Where it was loaded from and is saved to; without one it isn't saved
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+fn get_queue_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("scrobble_queue.json"))
+}
+
+impl Queue {
+    fn load() -> Self {
+        get_queue_path().map_or_else(Self::default, |path| Self::load_from(&path))
+    }
+
+    fn load_from(path: &Path) -> Self {
+        let queue: Self = storage::read_json(path).unwrap_or_default();
+        Self { path: Some(path.to_path_buf()), ..queue }
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = storage::write_json(path, self) {
+                eprintln!("Could not save {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn push(&mut self, scrobble: Scrobble) {
+        self.scrobbles.push(scrobble);
+        if self.scrobbles.len() > MAX_QUEUED {
+            let excess = self.scrobbles.len() - MAX_QUEUED;
+            self.scrobbles.drain(..excess);
+        }
+    }
+
+    /// Send what's queued, oldest first, except to the `refused` services. A
+    /// service that fails is left alone until the next call, so its listens
+    /// stay in order; one that refuses the credentials is added to `refused`.
+    /// Returns whether anything is left to retry.
+    fn send(&mut self, client: &Client, refused: &mut Vec<(Service, String)>, stopped: &AtomicBool) -> bool {
+        let services = client.services();
+        let mut failed = Vec::new();
+        let mut changed = false;
+        self.scrobbles.retain(|scrobble| {
+            let skipped = failed.contains(&scrobble.service) || refused.iter().any(|(service, _)| *service == scrobble.service);
+            if !services.contains(&scrobble.service) || skipped || stopped.load(Ordering::Relaxed) {
+                return true;
+            }
+            changed = true;
+            match client.scrobble(scrobble) {
+                Ok(()) => false,
+                Err(Failure::Retry(message)) => {
+                    eprintln!("Could not scrobble to {}: {} (will retry)", scrobble.service, message);
+                    failed.push(scrobble.service);
+                    true
+                }
+                Err(Failure::Rejected(message)) => {
+                    eprintln!("{} rejected {} - {}: {}", scrobble.service, scrobble.track.artist, scrobble.track.title, message);
+                    false
+                }
+                Err(Failure::Unauthorized(message)) => {
+                    eprintln!("{} refused the credentials: {}", scrobble.service, message);
+                    refused.push((scrobble.service, message));
+                    true
+                }
+            }
+        });
+        if changed {
+            self.save();
+        }
+        !failed.is_empty()
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Scrobbles what a `PlayerEngine` plays, from a thread of its own so slow
+/// servers never hold up playback. Dropping it tells the thread to stop,
+/// without waiting for a request in flight; listens it couldn't send are kept
+/// for the next one.
+pub struct Scrobbler {
+    stopped: Arc<AtomicBool>,
+    queued: Arc<AtomicUsize>,
+    refused: Arc<Mutex<Vec<(Service, String)>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrobbler {
+    /// Start scrobbling `engine` to the services turned on in `settings`.
+    /// Tags come from the library in the config directory.
+    pub fn start(settings: &ScrobbleSettings, engine: &PlayerEngine, sync_ratings: bool) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let refused = Arc::new(Mutex::new(Vec::new()));
+        let mut sender = Sender {
+            client: Client::new(settings.clone()),
+            queue: Queue::default(),
+            library: None,
+            sync_ratings,
+            refused: Vec::new(),
+            shared_refused: Arc::clone(&refused),
+            stopped: Arc::clone(&stopped),
+            queued: Arc::clone(&queued),
+        };
+        let events = engine.subscribe();
+        let thread = thread::spawn(move || {
+            let _queue_file = QUEUE_FILE.lock().unwrap_or_else(|e| e.into_inner());
+            sender.queue = Queue::load();
+            sender.run(events);
+        });
+        Self { stopped, queued, refused, thread: Some(thread) }
+    }
+
+    /// Stop, waiting until the listens the engine has already reported are
+    /// queued and saved, as before the process exits.
+    pub fn finish(mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Listens waiting to be sent again.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Why services refused the credentials in the settings. Their listens
+    /// wait until the settings change.
+    pub fn refused(&self) -> Vec<String> {
+        let refused = self.refused.lock().unwrap_or_else(|e| e.into_inner());
+        refused.iter().map(|(service, message)| format!("{} refused the credentials: {}", service, message)).collect()
+    }
+}
+
+impl Drop for Scrobbler {
+    fn drop(&mut self) {
+        // The thread saves the queue and ends once it sees this
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+// The scrobbling thread's state
+struct Sender {
+    client: Client,
+    queue: Queue,
+    // Opened on first use
+    library: Option<Library>,
+    sync_ratings: bool,
+    // Services that refused the credentials, and why
+    refused: Vec<(Service, String)>,
+    shared_refused: Arc<Mutex<Vec<(Service, String)>>>,
+    stopped: Arc<AtomicBool>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Sender {
+    fn run(&mut self, events: Receiver<Event>) {
+        // Whatever was left over from last time goes first
+        let mut retry_at = Some(Instant::now());
+        loop {
+            if self.stopped.load(Ordering::Relaxed) {
+                // Keep listens that came in too late to send
+                for event in events.try_iter() {
+                    if let Event::Listened(listen) = event {
+                        self.enqueue(&listen);
+                    }
+                }
+                self.queue.save();
+                break;
+            }
+            if retry_at.is_some_and(|at| Instant::now() >= at) {
+                retry_at = self.send_queue();
+            }
+            match events.recv_timeout(POLL) {
+                Ok(Event::TrackStarted { path, .. }) => self.now_playing(&path),
+                Ok(Event::Listened(listen)) => {
+                    if self.enqueue(&listen) {
+                        retry_at = self.send_queue();
+                    }
+                }
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    // When to try again, if anything failed
+    fn send_queue(&mut self) -> Option<Instant> {
+        let retry = self.queue.send(&self.client, &mut self.refused, &self.stopped);
+        self.queued.store(self.queue.scrobbles.len(), Ordering::Relaxed);
+        self.publish_refused();
+        retry.then(|| Instant::now() + RETRY_INTERVAL)
+    }
+
+    fn publish_refused(&self) {
+        *self.shared_refused.lock().unwrap_or_else(|e| e.into_inner()) = self.refused.clone();
+    }
+
+    fn track(&mut self, path: &Path) -> Option<Track> {
+        if self.library.is_none() {
+            match Library::open() {
+                Ok(library) => self.library = Some(library),
+                Err(e) => {
+                    eprintln!("Scrobbling can't read tags: {}", e);
+                    return None;
+                }
+            }
+        }
+        let metadata = self.library.as_ref()?.metadata_reader(self.sync_ratings).read(path)?;
+        Track::from_metadata(&metadata)
+    }
+
+    fn now_playing(&mut self, path: &Path) {
+        let Some(track) = self.track(path) else {
+            return;
+        };
+        for service in self.client.services() {
+            if self.refused.iter().any(|(refused, _)| *refused == service) {
+                continue;
+            }
+            // Only worth sending while it's true, so never queued
+            match self.client.now_playing(service, &track) {
+                Ok(()) => {}
+                Err(Failure::Unauthorized(message)) => {
+                    eprintln!("{} refused the credentials: {}", service, message);
+                    self.refused.push((service, message));
+                    self.publish_refused();
+                }
+                Err(e) => eprintln!("Could not send now playing to {}: {}", service, e),
+            }
+        }
+    }
+
+    // Queue a listen for every service if it counts; returns whether it did
+    fn enqueue(&mut self, listen: &Listen) -> bool {
+        let services = self.client.services();
+        if listen.skipped || services.is_empty() {
+            return false;
+        }
+        let Some(track) = self.track(&listen.path) else {
+            return false;
+        };
+        if !should_scrobble(listen.seconds, track.duration) {
+            return false;
+        }
+        let listened_at = unix_time().saturating_sub(listen.seconds as u64);
+        for service in services {
+            self.queue.push(Scrobble { service, track: track.clone(), listened_at });
+        }
+        self.queue.save();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use tiny_http::{Response, Server};
+
+    // A ListenBrainz stand-in answering requests with `statuses` in turn,
+    // handing over the title of each track submitted
+    fn listenbrainz(statuses: Vec<u16>) -> (ScrobbleSettings, mpsc::Receiver<String>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let (titles, received) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let Ok(mut request) = server.recv() else {
+                    return;
+                };
+                let body: Value = serde_json::from_reader(request.as_reader()).unwrap_or_default();
+                let _ = titles.send(body["payload"][0]["track_metadata"]["track_name"].as_str().unwrap_or_default().to_string());
+                let answer = json!({ "error": format!("status {}", status) }).to_string();
+                let _ = request.respond(Response::from_string(answer).with_status_code(status));
+            }
+        });
+        let settings = ScrobbleSettings {
+            listenbrainz: true,
+            listenbrainz_url: format!("http://127.0.0.1:{}", port),
+            listenbrainz_token: "token".to_string(),
+            ..Default::default()
+        };
+        (settings, received)
+    }
+
+    fn scrobble(service: Service, title: &str) -> Scrobble {
+        let track = Track { artist: "Artist".to_string(), title: title.to_string(), album: String::new(), duration: Some(200.0) };
+        Scrobble { service, track, listened_at: 1_700_000_000 }
+    }
+
+    fn titles(queue: &Queue) -> Vec<&str> {
+        queue.scrobbles.iter().map(|scrobble| scrobble.track.title.as_str()).collect()
+    }
+
+    #[test]
+    fn listens_are_scrobbled_past_30_seconds_and_half_or_four_minutes() {
+        // Tracks of 30 seconds or less never are
+        assert!(!should_scrobble(30.0, Some(30.0)));
+        assert!(should_scrobble(16.0, Some(31.0)));
+        // Otherwise half of it, or four minutes of a long one
+        assert!(!should_scrobble(99.0, Some(200.0)));
+        assert!(should_scrobble(100.0, Some(200.0)));
+        assert!(!should_scrobble(239.0, Some(600.0)));
+        assert!(should_scrobble(240.0, Some(600.0)));
+        // Without a known length only four minutes count
+        assert!(!should_scrobble(200.0, None));
+        assert!(should_scrobble(240.0, None));
+    }
+
+    #[test]
+    fn the_queue_keeps_the_newest_listens() {
+        let mut queue = Queue::default();
+        for i in 0..MAX_QUEUED + 5 {
+            queue.push(scrobble(Service::ListenBrainz, &i.to_string()));
+        }
+        assert_eq!(queue.scrobbles.len(), MAX_QUEUED);
+        assert_eq!(queue.scrobbles[0].track.title, "5");
+        assert_eq!(queue.scrobbles.last().unwrap().track.title, (MAX_QUEUED + 4).to_string());
+    }
+
+    #[test]
+    fn queued_listens_are_sent_retried_and_held_for_new_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scrobble_queue.json");
+        let mut queue = Queue::load_from(&path);
+        for title in ["accepted", "rejected", "busy", "after busy"] {
+            queue.push(scrobble(Service::ListenBrainz, title));
+        }
+        // Services that aren't set up keep their listens
+        queue.push(scrobble(Service::LastFm, "for last.fm"));
+        queue.save();
+
+        // A failure leaves the rest for that service until the next attempt
+        let (settings, sent) = listenbrainz(vec![200, 400, 503, 401]);
+        let client = Client::new(settings);
+        let mut refused = Vec::new();
+        let stopped = AtomicBool::new(false);
+        assert!(queue.send(&client, &mut refused, &stopped));
+        assert_eq!(sent.try_iter().collect::<Vec<_>>(), ["accepted", "rejected", "busy"]);
+        assert_eq!(titles(&queue), ["busy", "after busy", "for last.fm"]);
+        assert!(refused.is_empty());
+        assert_eq!(titles(&Queue::load_from(&path)), titles(&queue));
+
+        // Refused credentials hold every listen for that service
+        assert!(!queue.send(&client, &mut refused, &stopped));
+        assert_eq!(sent.recv().unwrap(), "busy");
+        assert_eq!(titles(&queue), ["busy", "after busy", "for last.fm"]);
+        assert_eq!(refused.len(), 1);
+        assert_eq!(refused[0].0, Service::ListenBrainz);
+        assert_eq!(refused[0].1, "status 401");
+        // And they aren't tried again until the credentials change
+        assert!(!queue.send(&client, &mut refused, &stopped));
+        assert!(sent.try_recv().is_err());
+
+        // Nothing is sent once stopping
+        refused.clear();
+        stopped.store(true, Ordering::Relaxed);
+        assert!(!queue.send(&client, &mut refused, &stopped));
+        assert_eq!(titles(&Queue::load_from(&path)), ["busy", "after busy", "for last.fm"]);
+    }
+}
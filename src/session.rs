@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use crate::config::get_config_dir;
+use crate::storage;
+
+// Playback state saved on exit and periodically, restored at startup
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub playlist: Vec<PathBuf>,
+    pub current_index: usize,
+    /// Position within the current track, in seconds.
+    pub position_secs: f32,
+    pub playing: bool,
+    /// Number of tracks picked by "Generate Playlist".
+    pub shuffle_count: usize,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            playlist: Vec::new(),
+            current_index: 0,
+            position_secs: 0.0,
+            playing: false,
+            shuffle_count: 100,
+        }
+    }
+}
+
+/// Where playback picks up from a saved session.
+pub struct Restored {
+    pub playlist: Vec<PathBuf>,
+    pub index: usize,
+    pub position: Duration,
+}
+
+fn get_session_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("session.json"))
+}
+
+impl Session {
+    pub fn load() -> Self {
+        get_session_path().map_or_else(Self::default, |path| Self::load_from(&path))
+    }
+
+    fn load_from(path: &Path) -> Self {
+        storage::read_json(path).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = get_session_path() {
+            self.save_to(&path);
+        }
+    }
+
+    fn save_to(&self, path: &Path) {
+        if let Err(e) = storage::write_json(path, self) {
+            eprintln!("Could not save {}: {}", path.display(), e);
+        }
+    }
+
+    /// The playlist without the tracks deleted since it was saved, kept on the
+    /// same song. `None` when no track is left.
+    pub fn restore(&self) -> Option<Restored> {
+        let removed_before = self.playlist.iter()
+            .take(self.current_index)
+            .filter(|path| !path.is_file())
+            .count();
+        let current_exists = self.playlist.get(self.current_index).is_some_and(|p| p.is_file());
+        let playlist: Vec<PathBuf> = self.playlist.iter().filter(|path| path.is_file()).cloned().collect();
+        if playlist.is_empty() {
+            return None;
+        }
+        // A deleted current track gives way to the one after it
+        let index = self.current_index.saturating_sub(removed_before).min(playlist.len() - 1);
+        // Only resume mid-track if the saved position belongs to this song
+        let position = if current_exists { Duration::from_secs_f32(self.position_secs.max(0.0)) } else { Duration::ZERO };
+        Some(Restored { playlist, index, position })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_resume_on_the_same_song() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracks: Vec<PathBuf> = ["a", "b", "c", "d"].iter().map(|name| dir.path().join(format!("{}.mp3", name))).collect();
+        for track in &tracks {
+            std::fs::write(track, b"").unwrap();
+        }
+        let path = dir.path().join("session.json");
+        let session = Session { playlist: tracks.clone(), current_index: 2, position_secs: 42.5, playing: true, shuffle_count: 30 };
+        session.save_to(&path);
+
+        let loaded = Session::load_from(&path);
+        assert_eq!((loaded.shuffle_count, loaded.playing), (30, true));
+        let restored = loaded.restore().unwrap();
+        assert_eq!(restored.playlist, tracks);
+        assert_eq!((restored.index, restored.position), (2, Duration::from_secs_f32(42.5)));
+
+        // Tracks deleted before the current one shift it down
+        std::fs::remove_file(&tracks[0]).unwrap();
+        let restored = loaded.restore().unwrap();
+        assert_eq!(restored.playlist, tracks[1..]);
+        assert_eq!((restored.index, restored.position), (1, Duration::from_secs_f32(42.5)));
+
+        // A deleted current track starts the next one from the top
+        std::fs::remove_file(&tracks[2]).unwrap();
+        let restored = loaded.restore().unwrap();
+        assert_eq!(restored.playlist, [tracks[1].clone(), tracks[3].clone()]);
+        assert_eq!((restored.index, restored.position), (1, Duration::ZERO));
+
+        // or the last one when it was at the end
+        std::fs::remove_file(&tracks[3]).unwrap();
+        assert_eq!(loaded.restore().unwrap().index, 0);
+        std::fs::remove_file(&tracks[1]).unwrap();
+        assert!(loaded.restore().is_none());
+
+        assert_eq!(Session::load_from(&dir.path().join("missing.json")).shuffle_count, 100);
+    }
+}
@@ -0,0 +1,206 @@
+// Keyboard shortcuts in the window. Bindings are kept as text in the settings
+// so they can be changed by editing settings.json, and parsed into egui
+// shortcuts when checked.
+
+use eframe::egui::{self, Key, KeyboardShortcut, Modifiers};
+use music_shuffler::config::KeyBindings;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    PlayPause,
+    SeekBack,
+    SeekForward,
+    Previous,
+    Next,
+    VolumeUp,
+    VolumeDown,
+    Search,
+    GeneratePlaylist,
+    SavePlaylist,
+    Help,
+}
+
+impl Action {
+    pub const ALL: [Action; 11] = [
+        Action::PlayPause,
+        Action::SeekBack,
+        Action::SeekForward,
+        Action::Previous,
+        Action::Next,
+        Action::VolumeUp,
+        Action::VolumeDown,
+        Action::Search,
+        Action::GeneratePlaylist,
+        Action::SavePlaylist,
+        Action::Help,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::PlayPause => "Play / pause",
+            Action::SeekBack => "Back 10 seconds",
+            Action::SeekForward => "Forward 10 seconds",
+            Action::Previous => "Previous track",
+            Action::Next => "Next track",
+            Action::VolumeUp => "Volume up",
+            Action::VolumeDown => "Volume down",
+            Action::Search => "Search the library",
+            Action::GeneratePlaylist => "Generate playlist",
+            Action::SavePlaylist => "Save playlist under its last name, or a new one",
+            Action::Help => "Show these shortcuts",
+        }
+    }
+
+    pub fn binding(self, bindings: &KeyBindings) -> &str {
+        match self {
+            Action::PlayPause => &bindings.play_pause,
+            Action::SeekBack => &bindings.seek_back,
+            Action::SeekForward => &bindings.seek_forward,
+            Action::Previous => &bindings.previous,
+            Action::Next => &bindings.next,
+            Action::VolumeUp => &bindings.volume_up,
+            Action::VolumeDown => &bindings.volume_down,
+            Action::Search => &bindings.search,
+            Action::GeneratePlaylist => &bindings.generate_playlist,
+            Action::SavePlaylist => &bindings.save_playlist,
+            Action::Help => &bindings.help,
+        }
+    }
+
+    pub fn binding_mut(self, bindings: &mut KeyBindings) -> &mut String {
+        match self {
+            Action::PlayPause => &mut bindings.play_pause,
+            Action::SeekBack => &mut bindings.seek_back,
+            Action::SeekForward => &mut bindings.seek_forward,
+            Action::Previous => &mut bindings.previous,
+            Action::Next => &mut bindings.next,
+            Action::VolumeUp => &mut bindings.volume_up,
+            Action::VolumeDown => &mut bindings.volume_down,
+            Action::Search => &mut bindings.search,
+            Action::GeneratePlaylist => &mut bindings.generate_playlist,
+            Action::SavePlaylist => &mut bindings.save_playlist,
+            Action::Help => &mut bindings.help,
+        }
+    }
+}
+
+/// Parse a binding like "Ctrl+Shift+S". `None` for an empty one, which is
+/// turned off.
+pub fn parse(text: &str) -> Result<Option<KeyboardShortcut>, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    // The plus key itself can be written "+", as in "Ctrl++"
+    let (modifier_names, key_name) = if text == "+" {
+        ("", "+")
+    } else if let Some(modifier_names) = text.strip_suffix("++") {
+        (modifier_names, "+")
+    } else {
+        text.rsplit_once('+').unwrap_or(("", text))
+    };
+
+    let mut modifiers = Modifiers::NONE;
+    for name in modifier_names.split('+').map(str::trim).filter(|name| !name.is_empty()) {
+        modifiers |= match name.to_lowercase().as_str() {
+            "ctrl" | "control" | "cmd" | "command" => Modifiers::COMMAND,
+            "shift" => Modifiers::SHIFT,
+            "alt" | "option" => Modifiers::ALT,
+            _ => return Err(format!("Unknown modifier \"{}\" in \"{}\"", name, text)),
+        };
+    }
+    let key = Key::from_name(key_name.trim())
+        .ok_or_else(|| format!("Unknown key \"{}\" in \"{}\"", key_name.trim(), text))?;
+    Ok(Some(KeyboardShortcut::new(modifiers, key)))
+}
+
+/// The actions whose shortcuts were pressed this frame. Their key presses are
+/// consumed so widgets don't also act on them. While a text field has focus
+/// only shortcuts with Ctrl apply, so typing a space doesn't pause.
+pub fn pressed(ctx: &egui::Context, bindings: &KeyBindings) -> Vec<Action> {
+    let typing = ctx.wants_keyboard_input();
+    let mut shortcuts: Vec<(Action, KeyboardShortcut)> = Action::ALL.iter()
+        .filter_map(|&action| Some((action, parse(action.binding(bindings)).ok()??)))
+        .filter(|(_, shortcut)| !typing || shortcut.modifiers.command)
+        .collect();
+    // Extra Shift and Alt are ignored when matching, so the most specific
+    // shortcuts have to be checked first
+    shortcuts.sort_by_key(|(_, shortcut)| {
+        let modifiers = shortcut.modifiers;
+        std::cmp::Reverse(modifiers.command as u8 + modifiers.shift as u8 + modifiers.alt as u8)
+    });
+    ctx.input_mut(|input| {
+        let (mut pressed, missed): (Vec<_>, Vec<_>) = shortcuts.into_iter()
+            .partition(|(_, shortcut)| input.consume_shortcut(shortcut));
+        // Only once no shortcut wants the keys as they are
+        pressed.extend(missed.into_iter().filter(|(_, shortcut)| consume_alternative(input, shortcut)));
+        pressed.into_iter().map(|(action, _)| action).collect()
+    })
+}
+
+// "+" is Shift and "=" on some layouts and a key of its own on others, and
+// can come through as the "=" key or only as typed text. "-" can come
+// through as text too.
+fn consume_alternative(input: &mut egui::InputState, shortcut: &KeyboardShortcut) -> bool {
+    let (unshifted, text) = match shortcut.logical_key {
+        Key::Plus => (Some(Key::Equals), "+"),
+        Key::Minus => (None, "-"),
+        _ => return false,
+    };
+    if unshifted.is_some_and(|key| input.consume_key(shortcut.modifiers, key)) {
+        return true;
+    }
+    // Text isn't typed while Ctrl is held
+    if shortcut.modifiers.command || !input.modifiers.matches_logically(shortcut.modifiers) {
+        return false;
+    }
+    match input.events.iter().position(|event| matches!(event, egui::Event::Text(typed) if typed == text)) {
+        Some(index) => {
+            input.events.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key, modifiers: Modifiers) -> egui::Event {
+        egui::Event::Key { key, physical_key: None, pressed: true, repeat: false, modifiers }
+    }
+
+    // The actions pressed in a frame with `events`
+    fn press(events: Vec<egui::Event>) -> Vec<Action> {
+        let ctx = egui::Context::default();
+        let mut actions = Vec::new();
+        let _ = ctx.run(egui::RawInput { events, ..Default::default() }, |ctx| {
+            actions = pressed(ctx, &KeyBindings::default());
+        });
+        actions
+    }
+
+    #[test]
+    fn plus_and_minus_work_on_any_layout() {
+        let text = |text: &str| egui::Event::Text(text.to_string());
+        assert!(press(vec![key(Key::Plus, Modifiers::SHIFT), text("+")]) == [Action::VolumeUp]);
+        assert!(press(vec![key(Key::Equals, Modifiers::SHIFT), text("+")]) == [Action::VolumeUp]);
+        assert!(press(vec![key(Key::Equals, Modifiers::NONE)]) == [Action::VolumeUp]);
+        assert!(press(vec![text("+")]) == [Action::VolumeUp]);
+        assert!(press(vec![text("-")]) == [Action::VolumeDown]);
+        assert!(press(vec![key(Key::Minus, Modifiers::NONE), text("-")]) == [Action::VolumeDown]);
+        assert!(press(vec![text("a")]).is_empty());
+        // Ctrl+F is Search whatever else is held
+        assert!(press(vec![key(Key::F, Modifiers::COMMAND | Modifiers::SHIFT)]) == [Action::Search]);
+    }
+
+    #[test]
+    fn bindings_are_parsed() {
+        assert!(parse("Ctrl+Shift+S").unwrap() == Some(KeyboardShortcut::new(Modifiers::COMMAND | Modifiers::SHIFT, Key::S)));
+        assert!(parse("Ctrl++").unwrap() == Some(KeyboardShortcut::new(Modifiers::COMMAND, Key::Plus)));
+        assert!(parse(" ").unwrap().is_none());
+        assert!(parse("Hyper+S").is_err());
+        assert!(parse("Ctrl+Nope").is_err());
+    }
+}
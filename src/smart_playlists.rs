@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Deserialize};
+use crate::config::get_config_dir;
+use crate::query::Query;
+use crate::storage;
+
+/// A named filter over the library, e.g. `added:<7d` or `played:>30d genre:jazz`.
+/// Its tracks are whatever currently matches, so it follows the library as it changes.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    pub name: String,
+    pub rules: String,
+}
+
+// Stored in smart_playlists.json next to the other settings
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmartPlaylists {
+    pub playlists: Vec<SmartPlaylist>,
+}
+
+fn get_smart_playlists_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("smart_playlists.json"))
+}
+
+impl SmartPlaylists {
+    pub fn load() -> Self {
+        get_smart_playlists_path().map_or_else(Self::default, |path| Self::load_from(&path))
+    }
+
+    fn load_from(path: &Path) -> Self {
+        storage::read_json(path).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = get_smart_playlists_path() {
+            self.save_to(&path);
+        }
+    }
+
+    fn save_to(&self, path: &Path) {
+        if let Err(e) = storage::write_json(path, self) {
+            eprintln!("Could not save {}: {}", path.display(), e);
+        }
+    }
+
+    /// Save `rules` under `name`, replacing any smart playlist with that name.
+    /// Rules that don't parse are refused.
+    pub fn store(&mut self, name: &str, rules: &str) -> Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("Playlist name can't be empty"));
+        }
+        Query::parse(rules)?;
+        let playlist = SmartPlaylist {
+            name: name.to_string(),
+            rules: rules.trim().to_string(),
+        };
+        match self.playlists.iter().position(|p| p.name == name) {
+            Some(i) => self.playlists[i] = playlist,
+            None => self.playlists.push(playlist),
+        }
+        self.playlists.sort_by_key(|p| p.name.to_lowercase());
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) {
+        self.playlists.retain(|p| p.name != name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(smart: &SmartPlaylists) -> Vec<(&str, &str)> {
+        smart.playlists.iter().map(|p| (p.name.as_str(), p.rules.as_str())).collect()
+    }
+
+    #[test]
+    fn smart_playlists_are_stored_by_name_and_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("smart_playlists.json");
+        let mut smart = SmartPlaylists::default();
+        smart.store(" new jazz ", " genre:jazz added:<30d ").unwrap();
+        smart.store("Forgotten", "played:>1y").unwrap();
+        // The same name replaces the rules
+        smart.store("new jazz", "genre:jazz added:<7d").unwrap();
+        assert_eq!(summary(&smart), [("Forgotten", "played:>1y"), ("new jazz", "genre:jazz added:<7d")]);
+
+        // Names have to be given and rules have to parse
+        assert!(smart.store("  ", "genre:jazz").is_err());
+        assert!(smart.store("Broken", "year:>soon").is_err());
+        assert!(smart.store("Forgotten", "mood:sad").is_err());
+        assert_eq!(summary(&smart), [("Forgotten", "played:>1y"), ("new jazz", "genre:jazz added:<7d")]);
+
+        smart.save_to(&path);
+        let mut loaded = SmartPlaylists::load_from(&path);
+        assert_eq!(summary(&loaded), summary(&smart));
+        loaded.delete("Forgotten");
+        loaded.delete("nothing by this name");
+        loaded.save_to(&path);
+        assert_eq!(summary(&SmartPlaylists::load_from(&path)), [("new jazz", "genre:jazz added:<7d")]);
+    }
+
+    #[test]
+    fn missing_or_damaged_files_load_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("smart_playlists.json");
+        assert!(SmartPlaylists::load_from(&path).playlists.is_empty());
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(SmartPlaylists::load_from(&path).playlists.is_empty());
+    }
+}
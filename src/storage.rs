@@ -0,0 +1,105 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// `<path>.bak`, where the previous version of a file is kept.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Replace `path` with `contents` so that a crash leaves either the old or the
+/// new file, never a partial one. The previous version is kept as a backup.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = temp_path(path);
+    {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+
+    if path.exists() {
+        let _ = fs::copy(path, backup_path(path));
+    }
+    fs::rename(&temp, path)?;
+
+    // Persist the rename itself; directories can't be opened this way on Windows
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let contents = serde_json::to_vec_pretty(value)?;
+    write_atomic(path, &contents)
+}
+
+/// Read a JSON file, falling back to its backup when the file is missing or
+/// can't be parsed. Returns `None` when neither is usable.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let parse = |path: &Path| -> Result<T> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    };
+
+    match parse(path) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            let backup = backup_path(path);
+            if !path.exists() && !backup.exists() {
+                return None;
+            }
+            if path.exists() {
+                eprintln!("Could not read {}: {}", path.display(), e);
+            }
+            match parse(&backup) {
+                Ok(value) => {
+                    eprintln!("Recovered {} from {}", path.display(), backup.display());
+                    Some(value)
+                }
+                Err(_) => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_json_falls_back_to_the_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        assert_eq!(read_json::<u32>(&path), None);
+
+        write_json(&path, &1u32).unwrap();
+        write_json(&path, &2u32).unwrap();
+        assert_eq!(read_json::<u32>(&path), Some(2));
+
+        fs::write(&path, "{ broken").unwrap();
+        assert_eq!(read_json::<u32>(&path), Some(1));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read_json::<u32>(&path), Some(1));
+    }
+}